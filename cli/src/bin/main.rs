@@ -22,7 +22,7 @@ use sputnikvm_network_classic::{MainnetFrontierPatch, MainnetHomesteadPatch, Mai
 use gethrpc::{GethRPCClient, NormalGethRPCClient, RPCBlock};
 use std::str::FromStr;
 use std::ops::DerefMut;
-use std::rc::Rc;
+use std::sync::Arc;
 
 fn from_rpc_block(block: &RPCBlock) -> HeaderParams {
     HeaderParams {
@@ -31,6 +31,8 @@ fn from_rpc_block(block: &RPCBlock) -> HeaderParams {
         number: U256::from_str(&block.number).unwrap(),
         difficulty: U256::from_str(&block.difficulty).unwrap(),
         gas_limit: Gas::from_str(&block.gas_limit).unwrap(),
+        base_fee: M256::zero(),
+        prev_randao: H256::default(),
     }
 }
 
@@ -66,7 +68,7 @@ fn profile_fire_without_rpc(vm: &mut VM) {
                 });
             },
             VMStatus::ExitedOk | VMStatus::ExitedErr(_) |
-            VMStatus::ExitedNotSupported(_) => return,
+            VMStatus::ExitedNotSupported(_) | VMStatus::Rejected(_) => return,
         }
     }
 }
@@ -113,7 +115,7 @@ fn handle_fire_with_rpc<T: GethRPCClient>(client: &mut T, vm: &mut VM, block_num
                         nonce: nonce,
                         address: address,
                         balance: balance,
-                        code: Rc::new(code),
+                        code: Arc::new(code),
                     }).unwrap();
                 }
             },
@@ -132,7 +134,7 @@ fn handle_fire_with_rpc<T: GethRPCClient>(client: &mut T, vm: &mut VM, block_num
                                                      &block_number)).unwrap();
                 vm.commit_account(AccountCommitment::Code {
                     address: address,
-                    code: Rc::new(code),
+                    code: Arc::new(code),
                 }).unwrap();
             },
             Err(RequireError::Blockhash(number)) => {
@@ -184,6 +186,8 @@ fn main() {
             number: U256::from_str(block_number).unwrap(),
             difficulty: U256::zero(),
             gas_limit: Gas::zero(),
+            base_fee: M256::zero(),
+            prev_randao: H256::default(),
         }
     };
 
@@ -196,8 +200,8 @@ fn main() {
     let mut vm: Box<VM> = if matches.is_present("CODE") {
         let context = Context {
             address, caller, gas_limit, gas_price, value,
-            code: Rc::new(code),
-            data: Rc::new(data),
+            code: Arc::new(code),
+            data: Arc::new(data),
             origin: caller,
             apprent_value: value,
             is_system: false,
@@ -215,7 +219,7 @@ fn main() {
         let transaction = ValidTransaction {
             caller: Some(caller),
             value, gas_limit, gas_price,
-            input: Rc::new(data),
+            input: Arc::new(data),
             nonce: match client {
                 Some(ref mut client) => {
                     U256::from_str(&client.get_transaction_count(&format!("0x{:x}", caller),
@@ -228,6 +232,7 @@ fn main() {
             } else {
                 TransactionAction::Call(address)
             },
+            access_list: Vec::new(),
         };
 
         match matches.value_of("PATCH") {
@@ -7,7 +7,7 @@ use sputnikvm::{Log, Context,
 use serde_json::Value;
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct JSONBlock {
     codes: HashMap<Address, Vec<u8>>,
@@ -34,6 +34,8 @@ impl JSONBlock {
             number: self.number,
             difficulty: self.difficulty,
             gas_limit: self.gas_limit,
+            base_fee: M256::zero(),
+            prev_randao: H256::default(),
         }
     }
 
@@ -45,7 +47,7 @@ impl JSONBlock {
         AccountCommitment::Full {
             address: address,
             balance: balance,
-            code: Rc::new(code.into()),
+            code: Arc::new(code.into()),
             nonce: nonce
         }
     }
@@ -71,7 +73,7 @@ impl JSONBlock {
 
         AccountCommitment::Code {
             address: address,
-            code: Rc::new(code.clone()),
+            code: Arc::new(code.clone()),
         }
     }
 
@@ -255,8 +257,8 @@ pub fn create_context(v: &Value) -> Context {
     Context {
         address: address,
         caller: caller,
-        code: Rc::new(code),
-        data: Rc::new(data),
+        code: Arc::new(code),
+        data: Arc::new(data),
         gas_limit: gas,
         gas_price: gas_price,
         origin: origin,
@@ -2,10 +2,18 @@ extern crate sputnikvm;
 extern crate serde_json;
 extern crate hexutil;
 extern crate bigint;
+extern crate sputnikvm_stateful;
+extern crate trie;
+extern crate block;
+extern crate rlp;
+extern crate sha3;
+extern crate secp256k1;
 
 mod blockchain;
+mod spectest;
 
 pub use self::blockchain::{JSONBlock, create_block, create_context};
+pub use self::spectest::{TestResult, run_state_test};
 
 use serde_json::Value;
 use std::str::FromStr;
@@ -0,0 +1,298 @@
+use bigint::{M256, U256, H256, Address};
+use hexutil::*;
+use sputnikvm::{Log, Patch, HeaderParams, VM, SeqTransactionVM,
+                ValidTransaction, TransactionAction, AccountState, AccountCommitment,
+                EmbeddedPatch, EmbeddedByzantiumPatch};
+use sputnikvm_stateful::{MemoryStateful, LiteralAccount};
+use trie::MemoryDatabase;
+use secp256k1::SECP256K1;
+use secp256k1::key::{SecretKey, PublicKey};
+use sha3::{Digest, Keccak256};
+use rlp;
+
+use serde_json::{self, Value};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Outcome of running a state test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    /// All post-states of the supported forks match.
+    Passed,
+    /// None of the forks in the post section has a matching patch.
+    UnsupportedFork,
+    /// The test JSON misses a field, or a field has an invalid value.
+    /// `field` is the path of the field in the test.
+    InvalidTest {
+        name: String, field: String,
+    },
+    /// The transaction is valid, while the post-state expects it to
+    /// be rejected with the given exception.
+    MissingException {
+        name: String, fork: String, index: usize,
+        exception: String,
+    },
+    /// The state root after the transaction differs from the expected
+    /// one. `index` is the position of the post-state in its fork.
+    StateRootMismatch {
+        name: String, fork: String, index: usize,
+        expected: H256, actual: H256,
+    },
+    /// The hash of the RLP encoded logs differs from the expected one.
+    LogsHashMismatch {
+        name: String, fork: String, index: usize,
+        expected: H256, actual: H256,
+    },
+}
+
+/// Fields of a test, read with the name of the test to report
+/// invalid values.
+struct Fields<'a> {
+    name: &'a str,
+}
+
+impl<'a> Fields<'a> {
+    fn invalid(&self, field: &str) -> TestResult {
+        TestResult::InvalidTest { name: self.name.into(), field: field.into() }
+    }
+
+    fn str<'v>(&self, v: &'v Value, field: &str) -> Result<&'v str, TestResult> {
+        v.as_str().ok_or_else(|| self.invalid(field))
+    }
+
+    fn parse<T: FromStr>(&self, v: &Value, field: &str) -> Result<T, TestResult> {
+        T::from_str(self.str(v, field)?).map_err(|_| self.invalid(field))
+    }
+
+    fn hex(&self, v: &Value, field: &str) -> Result<Vec<u8>, TestResult> {
+        read_hex(self.str(v, field)?).map_err(|_| self.invalid(field))
+    }
+
+    fn index(&self, v: &Value, field: &str) -> Result<usize, TestResult> {
+        v.as_u64().map(|index| index as usize).ok_or_else(|| self.invalid(field))
+    }
+
+    fn object<'v>(&self, v: &'v Value, field: &str) -> Result<&'v serde_json::Map<String, Value>, TestResult> {
+        v.as_object().ok_or_else(|| self.invalid(field))
+    }
+
+    /// Element of a transaction field listing the values of all
+    /// post-states.
+    fn nth<'v>(&self, v: &'v Value, index: usize, field: &str) -> Result<&'v Value, TestResult> {
+        v.as_array().and_then(|values| values.get(index)).ok_or_else(|| self.invalid(field))
+    }
+}
+
+fn create_header(fields: &Fields, env: &Value) -> Result<HeaderParams, TestResult> {
+    Ok(HeaderParams {
+        beneficiary: fields.parse(&env["currentCoinbase"], "env.currentCoinbase")?,
+        timestamp: fields.parse::<U256>(&env["currentTimestamp"], "env.currentTimestamp")?.into(),
+        number: fields.parse(&env["currentNumber"], "env.currentNumber")?,
+        difficulty: fields.parse(&env["currentDifficulty"], "env.currentDifficulty")?,
+        gas_limit: fields.parse(&env["currentGasLimit"], "env.currentGasLimit")?,
+        base_fee: match env["currentBaseFee"] {
+            Value::Null => M256::zero(),
+            ref val => fields.parse(val, "env.currentBaseFee")?,
+        },
+        prev_randao: match env["currentRandom"] {
+            Value::Null => H256::default(),
+            ref val => fields.parse(val, "env.currentRandom")?,
+        },
+    })
+}
+
+fn create_accounts(fields: &Fields, pre: &Value) -> Result<Vec<(Address, LiteralAccount)>, TestResult> {
+    let mut accounts = Vec::new();
+
+    for (address, data) in fields.object(pre, "pre")? {
+        let mut storage = HashMap::new();
+        for (index, value) in fields.object(&data["storage"], "pre.storage")? {
+            storage.insert(U256::from_str(index.as_str()).map_err(|_| fields.invalid("pre.storage"))?,
+                           fields.parse::<M256>(value, "pre.storage")?);
+        }
+
+        accounts.push((Address::from_str(address.as_str()).map_err(|_| fields.invalid("pre"))?, LiteralAccount {
+            nonce: fields.parse(&data["nonce"], "pre.nonce")?,
+            balance: fields.parse(&data["balance"], "pre.balance")?,
+            code: fields.hex(&data["code"], "pre.code")?,
+            storage,
+        }));
+    }
+
+    Ok(accounts)
+}
+
+/// Hashes of the most recent blocks, in the order expected by
+/// `Stateful::execute`. The test JSON files don't contain any block,
+/// so the hash of block `n` is the Keccak hash of its decimal string,
+/// as in the reference test runners.
+fn create_block_hashes(number: U256) -> Vec<H256> {
+    let number = number.as_u64();
+    let mut hashes = Vec::new();
+    for i in 0..(min(number, 256) + 1) {
+        let digest = Keccak256::digest((number - i).to_string().as_bytes());
+        hashes.push(H256::from(digest.as_slice()));
+    }
+    hashes
+}
+
+/// Address of the transaction sender. Filled tests name it directly,
+/// older ones only have the secret key it is derived from.
+fn sender(fields: &Fields, transaction: &Value) -> Result<Address, TestResult> {
+    match transaction["sender"] {
+        Value::Null => {
+            let secret_key = SecretKey::from_slice(
+                &SECP256K1, &fields.hex(&transaction["secretKey"], "transaction.secretKey")?)
+                .map_err(|_| fields.invalid("transaction.secretKey"))?;
+            let public_key = PublicKey::from_secret_key(&SECP256K1, &secret_key)
+                .map_err(|_| fields.invalid("transaction.secretKey"))?;
+            let key = public_key.serialize_vec(&SECP256K1, false);
+            let address = Address::from(H256::from(Keccak256::digest(&key[1..65]).as_slice()));
+            // The array vector of secp256k1 reads past its length when
+            // dropped, the same as in the ECREC precompile, so it is
+            // forgotten instead.
+            mem::forget(key);
+            Ok(address)
+        },
+        ref sender => fields.parse(sender, "transaction.sender"),
+    }
+}
+
+fn run_post<P: Patch>(
+    name: &str, fork: &str, index: usize, test: &Value, post: &Value
+) -> Result<TestResult, TestResult> {
+    let fields = Fields { name };
+    let transaction = &test["transaction"];
+    let indexes = &post["indexes"];
+    let data = fields.index(&indexes["data"], "post.indexes.data")?;
+    let gas = fields.index(&indexes["gas"], "post.indexes.gas")?;
+    let value = fields.index(&indexes["value"], "post.indexes.value")?;
+
+    let accounts = create_accounts(&fields, &test["pre"])?;
+    let database = MemoryDatabase::default();
+    let mut stateful = MemoryStateful::empty(&database);
+    stateful.sets(&accounts);
+
+    let header = create_header(&fields, &test["env"])?;
+    let block_hashes = create_block_hashes(header.number);
+
+    let caller = sender(&fields, transaction)?;
+    let action = match fields.str(&transaction["to"], "transaction.to")? {
+        "" => TransactionAction::Create,
+        to => TransactionAction::Call(Address::from_str(to).map_err(|_| fields.invalid("transaction.to"))?),
+    };
+    let transaction = ValidTransaction {
+        caller: Some(caller),
+        nonce: fields.parse(&transaction["nonce"], "transaction.nonce")?,
+        gas_price: fields.parse(&transaction["gasPrice"], "transaction.gasPrice")?,
+        gas_limit: fields.parse(fields.nth(&transaction["gasLimit"], gas, "transaction.gasLimit")?,
+                                "transaction.gasLimit")?,
+        value: fields.parse(fields.nth(&transaction["value"], value, "transaction.value")?,
+                            "transaction.value")?,
+        input: Arc::new(fields.hex(fields.nth(&transaction["data"], data, "transaction.data")?,
+                                   "transaction.data")?),
+        action,
+        access_list: Vec::new(),
+    };
+
+    let mut account_state = AccountState::<P::Account>::default();
+    account_state.commit(match accounts.iter().find(|&&(address, _)| address == caller) {
+        Some(&(address, ref account)) => AccountCommitment::Full {
+            nonce: account.nonce,
+            address,
+            balance: account.balance,
+            code: Arc::new(account.code.clone()),
+        },
+        None => AccountCommitment::Nonexist(caller),
+    }).unwrap();
+
+    // An invalid transaction is not included in the block, and leaves
+    // the pre-state unchanged. Older tests have no `expectException`,
+    // and only expect the unchanged state root.
+    let checked = transaction.check::<P>(&account_state).map_err(|_| fields.invalid("transaction.sender"))?;
+    let logs: Vec<Log> = match (checked, &post["expectException"]) {
+        (Ok(()), &Value::Null) => {
+            let vm: SeqTransactionVM<P> = stateful.execute(transaction, header, &block_hashes);
+            vm.logs().into()
+        },
+        (Ok(()), exception) => {
+            return Ok(TestResult::MissingException {
+                name: name.into(), fork: fork.into(), index,
+                exception: fields.str(exception, "post.expectException")?.into(),
+            });
+        },
+        (Err(_), _) => Vec::new(),
+    };
+
+    let expected = fields.parse(&post["hash"], "post.hash")?;
+    let actual = stateful.root();
+    if expected != actual {
+        return Ok(TestResult::StateRootMismatch {
+            name: name.into(), fork: fork.into(), index,
+            expected, actual,
+        });
+    }
+
+    if post["logs"].is_string() {
+        let expected = fields.parse(&post["logs"], "post.logs")?;
+        let actual = H256::from(Keccak256::digest(&rlp::encode_list::<Log, Log>(&logs)).as_slice());
+        if expected != actual {
+            return Ok(TestResult::LogsHashMismatch {
+                name: name.into(), fork: fork.into(), index,
+                expected, actual,
+            });
+        }
+    }
+
+    Ok(TestResult::Passed)
+}
+
+/// Run all tests in a `GeneralStateTests` JSON file. Each post-state
+/// is checked against a fresh state built from the pre-state. Forks
+/// without a matching patch are skipped.
+pub fn run_state_test(json: &str) -> TestResult {
+    let tests: Value = match serde_json::from_str(json) {
+        Ok(tests) => tests,
+        Err(_) => return TestResult::InvalidTest { name: String::new(), field: String::new() },
+    };
+    let tests = match tests.as_object() {
+        Some(tests) => tests,
+        None => return TestResult::InvalidTest { name: String::new(), field: String::new() },
+    };
+    let mut ran = false;
+
+    for (name, test) in tests {
+        let posts = match test["post"].as_object() {
+            Some(posts) => posts,
+            None => return TestResult::InvalidTest { name: name.clone(), field: "post".into() },
+        };
+        for (fork, posts) in posts {
+            let posts = match posts.as_array() {
+                Some(posts) => posts,
+                None => return TestResult::InvalidTest { name: name.clone(), field: "post".into() },
+            };
+            for (index, post) in posts.iter().enumerate() {
+                let result = match fork.as_str() {
+                    "EIP150" => run_post::<EmbeddedPatch>(name, fork, index, test, post),
+                    "Byzantium" => run_post::<EmbeddedByzantiumPatch>(name, fork, index, test, post),
+                    _ => continue,
+                };
+                ran = true;
+
+                match result {
+                    Ok(TestResult::Passed) => (),
+                    Ok(result) | Err(result) => return result,
+                }
+            }
+        }
+    }
+
+    if ran {
+        TestResult::Passed
+    } else {
+        TestResult::UnsupportedFork
+    }
+}
@@ -0,0 +1,44 @@
+#![allow(non_snake_case)]
+
+extern crate jsontests;
+
+use jsontests::{TestResult, run_state_test};
+
+#[test]
+fn storeAndLog() {
+    assert_eq!(run_state_test(include_str!("../res/files/stExampleTest.json")), TestResult::Passed);
+}
+
+#[test]
+fn add11() {
+    assert_eq!(run_state_test(include_str!("../res/files/add11.json")), TestResult::Passed);
+}
+
+#[test]
+fn add11NonceTooHigh() {
+    assert_eq!(run_state_test(include_str!("../res/files/add11NonceTooHigh.json")), TestResult::Passed);
+}
+
+#[test]
+fn missingException() {
+    let json = include_str!("../res/files/add11NonceTooHigh.json").replace("\"nonce\" : \"0x01\"", "\"nonce\" : \"0x00\"");
+    assert_eq!(run_state_test(&json), TestResult::MissingException {
+        name: "add11NonceTooHigh".into(), fork: "Byzantium".into(), index: 0,
+        exception: "TR_NonceTooHigh".into(),
+    });
+}
+
+#[test]
+fn invalidTest() {
+    let json = include_str!("../res/files/add11.json").replace("\"currentNumber\"", "\"number\"");
+    assert_eq!(run_state_test(&json), TestResult::InvalidTest {
+        name: "add11".into(), field: "env.currentNumber".into(),
+    });
+}
+
+#[test]
+fn senderFromSecretKey() {
+    let json = include_str!("../res/files/add11.json")
+        .replace("\"sender\" : \"0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b\",", "");
+    assert_eq!(run_state_test(&json), TestResult::Passed);
+}
@@ -6,7 +6,7 @@ extern crate sputnikvm_precompiled_bn128;
 use bigint::{Gas, U256, H160, Address};
 use sputnikvm::{Precompiled, AccountPatch, Patch,
                 ID_PRECOMPILED, ECREC_PRECOMPILED, SHA256_PRECOMPILED, RIP160_PRECOMPILED};
-use sputnikvm_precompiled_modexp::MODEXP_PRECOMPILED;
+use sputnikvm_precompiled_modexp::{MODEXP_PRECOMPILED, MODEXP_EIP2565_PRECOMPILED};
 use sputnikvm_precompiled_bn128::{BN128_ADD_PRECOMPILED, BN128_MUL_PRECOMPILED, BN128_PAIRING_PRECOMPILED};
 
 pub static FRONTIER_PRECOMPILEDS: [(Address, Option<&'static [u8]>, &'static Precompiled); 4] = [
@@ -51,6 +51,35 @@ pub static BYZANTIUM_PRECOMPILEDS: [(Address, Option<&'static [u8]>, &'static Pr
      &BN128_PAIRING_PRECOMPILED),
 ];
 
+/// Byzantium precompiled contracts with ModExp repriced by EIP-2565,
+/// for patches implementing Berlin.
+pub static BERLIN_PRECOMPILEDS: [(Address, Option<&'static [u8]>, &'static Precompiled); 8] = [
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x01]),
+     None,
+     &ECREC_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x02]),
+     None,
+     &SHA256_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x03]),
+     None,
+     &RIP160_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x04]),
+     None,
+     &ID_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x05]),
+     None,
+     &MODEXP_EIP2565_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x06]),
+     None,
+     &BN128_ADD_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x07]),
+     None,
+     &BN128_MUL_PRECOMPILED),
+    (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x08]),
+     None,
+     &BN128_PAIRING_PRECOMPILED),
+];
+
 pub struct FrontierAccountPatch;
 impl AccountPatch for FrontierAccountPatch {
     fn initial_nonce() -> U256 { U256::zero() }
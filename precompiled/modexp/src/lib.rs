@@ -5,141 +5,196 @@ extern crate sputnikvm;
 #[cfg(test)]
 extern crate hexutil;
 
-use std::rc::Rc;
+use std::cmp;
+use std::sync::Arc;
 use bigint::{Gas, U256};
 
 use sputnikvm::Precompiled;
 use sputnikvm::errors::{OnChainError, RuntimeError, NotSupportedError};
 
 pub static MODEXP_PRECOMPILED: ModexpPrecompiled = ModexpPrecompiled;
+pub static MODEXP_EIP2565_PRECOMPILED: ModexpEIP2565Precompiled = ModexpEIP2565Precompiled;
 
+/// ModExp precompiled contract priced as in EIP-198.
 pub struct ModexpPrecompiled;
 impl Precompiled for ModexpPrecompiled {
-    fn gas_and_step(&self, data: &[u8], gas_limit: Gas) -> Result<(Gas, Rc<Vec<u8>>), RuntimeError> {
-        use std::cmp;
-        use num_bigint::BigUint;
-
-        fn adjusted_exponent_length(exponent_length: U256, base_length: U256, data: &[u8]) -> U256 {
-            let mut exp32_arr = Vec::new();
-            for i in 0..32 {
-                if U256::from(96) + base_length + U256::from(i) >= U256::from(data.len()) {
-                    exp32_arr.push(0u8);
-                } else {
-                    let base_length_usize: usize = base_length.as_usize();
-                    let data_i: usize = 96 + base_length_usize + i;
-                    exp32_arr.push(data[data_i]);
-                }
-            }
-            let exp32 = U256::from(exp32_arr.as_slice());
+    fn gas_and_step(&self, data: &[u8], gas_limit: Gas) -> Result<(Gas, Arc<Vec<u8>>), RuntimeError> {
+        let data = padded(data);
+        let gas = eip198_gas(&data)?;
+        step(&data, gas, gas_limit)
+    }
+}
 
-            if exponent_length <= U256::from(32) && exp32 == U256::zero() {
-                U256::zero()
-            } else if exponent_length <= U256::from(32) {
-                U256::from(exp32.bits())
-            } else {
-                U256::from(8) * (exponent_length - U256::from(32)) + U256::from(exp32.bits())
-            }
+/// ModExp precompiled contract priced as in EIP-2565, enabled from
+/// Berlin.
+pub struct ModexpEIP2565Precompiled;
+impl Precompiled for ModexpEIP2565Precompiled {
+    fn gas_and_step(&self, data: &[u8], gas_limit: Gas) -> Result<(Gas, Arc<Vec<u8>>), RuntimeError> {
+        let data = padded(data);
+        let gas = eip2565_gas(&data)?;
+        step(&data, gas, gas_limit)
+    }
+}
+
+/// Pad data to be at least 32 * 3 bytes.
+fn padded(data: &[u8]) -> Vec<u8> {
+    let mut data: Vec<u8> = data.into();
+    while data.len() < 32 * 3 {
+        data.push(0);
+    }
+    data
+}
+
+fn lengths(data: &[u8]) -> (U256, U256, U256) {
+    (U256::from(&data[0..32]), U256::from(&data[32..64]), U256::from(&data[64..96]))
+}
+
+/// The adjusted exponent length of EIP-198, which is also the
+/// iteration count of EIP-2565. This is the index of the highest bit
+/// of the first (at most) 32 bytes of the exponent, plus 8 bits for
+/// each following byte.
+fn adjusted_exponent_length(exponent_length: U256, base_length: U256, data: &[u8]) -> U256 {
+    let head_length = cmp::min(exponent_length, U256::from(32)).as_usize();
+    let mut exp32_arr = Vec::new();
+    for i in 0..head_length {
+        if U256::from(96) + base_length + U256::from(i) >= U256::from(data.len()) {
+            exp32_arr.push(0u8);
+        } else {
+            let base_length_usize: usize = base_length.as_usize();
+            let data_i: usize = 96 + base_length_usize + i;
+            exp32_arr.push(data[data_i]);
         }
+    }
+    let exp32 = U256::from(exp32_arr.as_slice());
+    let highest_bit = if exp32 == U256::zero() { U256::zero() } else { U256::from(exp32.bits() - 1) };
 
-        fn mult_complexity(x: U256) -> Result<U256, RuntimeError> {
-            if x <= U256::from(64) {
-                Ok(x * x)
-            } else if x <= U256::from(1024) {
-                Ok(x * x / U256::from(4) + U256::from(96) * x - U256::from(3072))
+    if exponent_length <= U256::from(32) {
+        highest_bit
+    } else {
+        U256::from(8) * (exponent_length - U256::from(32)) + highest_bit
+    }
+}
+
+fn eip198_gas(data: &[u8]) -> Result<Gas, RuntimeError> {
+    fn mult_complexity(x: U256) -> Result<U256, RuntimeError> {
+        if x <= U256::from(64) {
+            Ok(x * x)
+        } else if x <= U256::from(1024) {
+            Ok(x * x / U256::from(4) + U256::from(96) * x - U256::from(3072))
+        } else {
+            let (sqr, o) = x.overflowing_mul(x);
+            if o {
+                Err(RuntimeError::OnChain(OnChainError::EmptyGas))
             } else {
-                let (sqr, o) = x.overflowing_mul(x);
-                if o {
-                    Err(RuntimeError::OnChain(OnChainError::EmptyGas))
-                } else {
-                    Ok(sqr / U256::from(16) + U256::from(480) * x - U256::from(199680))
-                }
+                Ok(sqr / U256::from(16) + U256::from(480) * x - U256::from(199680))
             }
         }
+    }
 
-        // Padding data to be at least 32 * 3 bytes.
-        let mut data: Vec<u8> = data.into();
-        while data.len() < 32 * 3 {
-            data.push(0);
-        }
+    let (base_length, exponent_length, modulus_length) = lengths(data);
 
-        let base_length = U256::from(&data[0..32]);
-        let exponent_length = U256::from(&data[32..64]);
-        let modulus_length = U256::from(&data[64..96]);
+    let op1 = mult_complexity(cmp::max(modulus_length, base_length))?;
+    let op2 = cmp::max(adjusted_exponent_length(exponent_length, base_length, data), U256::from(1));
+    let (r, o) = op1.overflowing_mul(op2);
+    if o {
+        return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
+    }
+    Ok((r / U256::from(20)).into())
+}
 
-        let op1 = mult_complexity(cmp::max(modulus_length, base_length))?;
-        let op2 = cmp::max(adjusted_exponent_length(exponent_length, base_length, &data), U256::from(1)) / U256::from(20);
-        let (r, o) = op1.overflowing_mul(op2);
-        if o {
-            return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
-        }
-        let gas: Gas = r.into();
+fn eip2565_gas(data: &[u8]) -> Result<Gas, RuntimeError> {
+    let (base_length, exponent_length, modulus_length) = lengths(data);
 
-        if gas > gas_limit {
-            return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
-        }
+    let max_length = cmp::max(modulus_length, base_length);
+    if max_length > U256::from(u64::max_value()) {
+        return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
+    }
+    let words = (max_length + U256::from(7)) / U256::from(8);
+    let (mult_complexity, o) = words.overflowing_mul(words);
+    if o {
+        return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
+    }
 
-        if base_length > U256::from(usize::max_value()) ||
-            exponent_length > U256::from(usize::max_value()) ||
-            modulus_length > U256::from(usize::max_value())
-        {
-            return Err(RuntimeError::NotSupported(NotSupportedError::MemoryIndexNotSupported));
-        }
+    let iteration_count = cmp::max(adjusted_exponent_length(exponent_length, base_length, data), U256::from(1));
+    let (r, o) = mult_complexity.overflowing_mul(iteration_count);
+    if o {
+        return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
+    }
+    Ok(cmp::max(r / U256::from(3), U256::from(200)).into())
+}
 
-        let base_length: usize = base_length.as_usize();
-        let exponent_length: usize = exponent_length.as_usize();
-        let modulus_length: usize = modulus_length.as_usize();
+fn step(data: &[u8], gas: Gas, gas_limit: Gas) -> Result<(Gas, Arc<Vec<u8>>), RuntimeError> {
+    use num_bigint::BigUint;
 
-        let mut base_arr = Vec::new();
-        let mut exponent_arr = Vec::new();
-        let mut modulus_arr = Vec::new();
+    if gas > gas_limit {
+        return Err(RuntimeError::OnChain(OnChainError::EmptyGas));
+    }
 
-        for i in 0..base_length {
-            if 96 + i >= data.len() {
-                base_arr.push(0u8);
-            } else {
-                base_arr.push(data[96 + i]);
-            }
+    let (base_length, exponent_length, modulus_length) = lengths(data);
+    if base_length > U256::from(usize::max_value()) ||
+        exponent_length > U256::from(usize::max_value()) ||
+        modulus_length > U256::from(usize::max_value())
+    {
+        return Err(RuntimeError::NotSupported(NotSupportedError::MemoryIndexNotSupported));
+    }
+
+    let base_length: usize = base_length.as_usize();
+    let exponent_length: usize = exponent_length.as_usize();
+    let modulus_length: usize = modulus_length.as_usize();
+
+    let mut base_arr = Vec::new();
+    let mut exponent_arr = Vec::new();
+    let mut modulus_arr = Vec::new();
+
+    for i in 0..base_length {
+        if 96 + i >= data.len() {
+            base_arr.push(0u8);
+        } else {
+            base_arr.push(data[96 + i]);
         }
-        for i in 0..exponent_length {
-            if 96 + base_length + i >= data.len() {
-                exponent_arr.push(0u8);
-            } else {
-                exponent_arr.push(data[96 + base_length + i]);
-            }
+    }
+    for i in 0..exponent_length {
+        if 96 + base_length + i >= data.len() {
+            exponent_arr.push(0u8);
+        } else {
+            exponent_arr.push(data[96 + base_length + i]);
         }
-        for i in 0..modulus_length {
-            if 96 + base_length + exponent_length + i >= data.len() {
-                modulus_arr.push(0u8);
-            } else {
-                modulus_arr.push(data[96 + base_length + exponent_length + i]);
-            }
+    }
+    for i in 0..modulus_length {
+        if 96 + base_length + exponent_length + i >= data.len() {
+            modulus_arr.push(0u8);
+        } else {
+            modulus_arr.push(data[96 + base_length + exponent_length + i]);
         }
+    }
 
-        let base = BigUint::from_bytes_be(&base_arr);
-        let exponent = BigUint::from_bytes_be(&exponent_arr);
-        let modulus = BigUint::from_bytes_be(&modulus_arr);
-
-        let mut result = base.modpow(&exponent, &modulus).to_bytes_be();
-        assert!(result.len() <= modulus_length);
-        while result.len() < modulus_length {
-            result.insert(0, 0u8);
-        }
+    let base = BigUint::from_bytes_be(&base_arr);
+    let exponent = BigUint::from_bytes_be(&exponent_arr);
+    let modulus = BigUint::from_bytes_be(&modulus_arr);
 
-        Ok((gas, Rc::new(result)))
+    let mut result = base.modpow(&exponent, &modulus).to_bytes_be();
+    assert!(result.len() <= modulus_length);
+    while result.len() < modulus_length {
+        result.insert(0, 0u8);
     }
+
+    Ok((gas, Arc::new(result)))
 }
 
 #[cfg(test)]
 mod tests {
     use ::*;
     use hexutil::*;
+    use bigint::{H160, Address};
+    use sputnikvm::{Patch, EmbeddedByzantiumAccountPatch, Context, HeaderParams,
+                    SeqContextVM, VM, VMStatus, AccountCommitment, RequireError};
 
     #[test]
     fn spec_test1() {
         let input = read_hex("00000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000020000000000000000000000000000000000000000000000000000000000000002003fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2efffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f").unwrap();
         let (_, output) = MODEXP_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
         let expected = read_hex("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
-        assert_eq!(expected, Rc::try_unwrap(output).unwrap());
+        assert_eq!(expected, Arc::try_unwrap(output).unwrap());
     }
 
     #[test]
@@ -147,7 +202,7 @@ mod tests {
         let input = read_hex("000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000200000000000000000000000000000000000000000000000000000000000000020fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2efffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f").unwrap();
         let (_, output) = MODEXP_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
         let expected = read_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
-        assert_eq!(expected, Rc::try_unwrap(output).unwrap());
+        assert_eq!(expected, Arc::try_unwrap(output).unwrap());
     }
 
     #[test]
@@ -164,7 +219,7 @@ mod tests {
         let input = read_hex("00000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000002003ffff800000000000000000000000000000000000000000000000000000000000000007").unwrap();
         let (_, output) = MODEXP_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
         let expected = read_hex("3b01b01ac41f2d6e917c6d6a221ce793802469026d9ab7578fa2e79e4da6aaab").unwrap();
-        assert_eq!(expected, Rc::try_unwrap(output).unwrap());
+        assert_eq!(expected, Arc::try_unwrap(output).unwrap());
     }
 
     #[test]
@@ -172,6 +227,124 @@ mod tests {
         let input = read_hex("00000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000002003ffff80").unwrap();
         let (_, output) = MODEXP_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
         let expected = read_hex("3b01b01ac41f2d6e917c6d6a221ce793802469026d9ab7578fa2e79e4da6aaab").unwrap();
-        assert_eq!(expected, Rc::try_unwrap(output).unwrap());
+        assert_eq!(expected, Arc::try_unwrap(output).unwrap());
+    }
+
+    const SPEC_INPUT1: &str = "00000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000020000000000000000000000000000000000000000000000000000000000000002003fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2efffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f";
+    const SPEC_INPUT4: &str = "00000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000002003ffff800000000000000000000000000000000000000000000000000000000000000007";
+
+    #[test]
+    fn eip198_and_eip2565_gas() {
+        let input = read_hex(SPEC_INPUT1).unwrap();
+        let (gas, output) = MODEXP_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
+        assert_eq!(gas, Gas::from(13056usize));
+        let (eip2565_gas, eip2565_output) = MODEXP_EIP2565_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
+        assert_eq!(eip2565_gas, Gas::from(1360usize));
+        assert_eq!(output, eip2565_output);
+    }
+
+    #[test]
+    fn eip2565_gas_floor() {
+        let input = read_hex(SPEC_INPUT4).unwrap();
+        let (gas, _) = MODEXP_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
+        assert_eq!(gas, Gas::from(768usize));
+        let (gas, _) = MODEXP_EIP2565_PRECOMPILED.gas_and_step(&input, Gas::from(10000000usize)).unwrap();
+        assert_eq!(gas, Gas::from(200usize));
+
+        assert!(MODEXP_EIP2565_PRECOMPILED.gas_and_step(&input, Gas::from(199usize)).is_err());
+    }
+
+    static MODEXP_PRECOMPILEDS: [(Address, Option<&'static [u8]>, &'static Precompiled); 1] = [
+        (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x05]),
+         None,
+         &MODEXP_PRECOMPILED),
+    ];
+
+    static MODEXP_EIP2565_PRECOMPILEDS: [(Address, Option<&'static [u8]>, &'static Precompiled); 1] = [
+        (H160([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x05]),
+         None,
+         &MODEXP_EIP2565_PRECOMPILED),
+    ];
+
+    /// Byzantium patch with the given precompiled contracts.
+    macro_rules! precompiled_patch {
+        ($name:ident, $precompileds:expr) => {
+            struct $name;
+            impl Patch for $name {
+                type Account = EmbeddedByzantiumAccountPatch;
+
+                fn code_deposit_limit() -> Option<usize> { Some(0x6000) }
+                fn callstack_limit() -> usize { 1024 }
+                fn gas_extcode() -> Gas { Gas::from(700usize) }
+                fn gas_balance() -> Gas { Gas::from(400usize) }
+                fn gas_sload() -> Gas { Gas::from(200usize) }
+                fn gas_suicide() -> Gas { Gas::from(5000usize) }
+                fn gas_suicide_new_account() -> Gas { Gas::from(25000usize) }
+                fn gas_call() -> Gas { Gas::from(700usize) }
+                fn gas_expbyte() -> Gas { Gas::from(50usize) }
+                fn gas_transaction_create() -> Gas { Gas::from(32000usize) }
+                fn force_code_deposit() -> bool { false }
+                fn has_delegate_call() -> bool { true }
+                fn has_static_call() -> bool { true }
+                fn has_revert() -> bool { true }
+                fn has_return_data() -> bool { true }
+                fn err_on_call_with_more_gas() -> bool { false }
+                fn call_create_l64_after_gas() -> bool { true }
+                fn memory_limit() -> usize { usize::max_value() }
+                fn precompileds() -> &'static [(Address, Option<&'static [u8]>, &'static Precompiled)] {
+                    &$precompileds }
+            }
+        }
+    }
+
+    precompiled_patch!(EIP198Patch, MODEXP_PRECOMPILEDS);
+    precompiled_patch!(EIP2565Patch, MODEXP_EIP2565_PRECOMPILEDS);
+
+    /// CALL the ModExp contract with the call data as input, and
+    /// return the output and the used gas.
+    fn call_modexp<P: Patch>(input: &[u8]) -> (Vec<u8>, Gas) {
+        let code = read_hex("366000600037602060003660006000600561fffff15060206000f3").unwrap();
+        let context = Context {
+            address: Address::default(),
+            caller: Address::default(),
+            code: Arc::new(code),
+            data: Arc::new(input.into()),
+            gas_limit: Gas::from(100000usize),
+            gas_price: Gas::zero(),
+            origin: Address::default(),
+            value: U256::zero(),
+            apprent_value: U256::zero(),
+            is_system: false,
+            is_static: false,
+        };
+        let mut vm = SeqContextVM::<P>::new(context, HeaderParams::default());
+        loop {
+            match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) =>
+                    vm.commit_account(AccountCommitment::Full {
+                        nonce: U256::one(), address, balance: U256::zero(),
+                        code: Arc::new(Vec::new()),
+                    }).unwrap(),
+                Err(err) => panic!("unexpected require {:?}", err),
+            }
+        }
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        (vm.out().into(), vm.used_gas())
+    }
+
+    #[test]
+    fn call_eip2565_modexp() {
+        let input = read_hex(SPEC_INPUT1).unwrap();
+        let (output, gas) = call_modexp::<EIP198Patch>(&input);
+        let (eip2565_output, eip2565_gas) = call_modexp::<EIP2565Patch>(&input);
+
+        let expected = read_hex("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(eip2565_output, expected);
+        assert_eq!(gas - eip2565_gas, Gas::from(13056usize - 1360));
     }
 }
@@ -10,7 +10,7 @@ extern crate hexutil;
 
 use std::fs::File;
 use std::str::FromStr;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::collections::HashMap;
 
 use block::TransactionAction;
@@ -30,6 +30,8 @@ fn from_rpc_block(block: &RPCBlock) -> HeaderParams {
         number: U256::from_str(&block.number).unwrap(),
         difficulty: U256::from_str(&block.difficulty).unwrap(),
         gas_limit: Gas::from_str(&block.gas_limit).unwrap(),
+        base_fee: M256::zero(),
+        prev_randao: H256::default(),
     }
 }
 
@@ -44,8 +46,9 @@ fn from_rpc_transaction(transaction: &RPCTransaction) -> ValidTransaction {
         value: U256::from_str(&transaction.value).unwrap(),
         gas_limit: Gas::from_str(&transaction.gas).unwrap(),
         gas_price: Gas::from_str(&transaction.gas_price).unwrap(),
-        input: Rc::new(read_hex(&transaction.input).unwrap()),
+        input: Arc::new(read_hex(&transaction.input).unwrap()),
         nonce: U256::from_str(&transaction.nonce).unwrap(),
+        access_list: Vec::new(),
     }
 }
 
@@ -84,7 +87,7 @@ fn handle_fire<T: GethRPCClient, P: Patch>(client: &mut T, vm: &mut SeqTransacti
                         nonce: nonce,
                         address: address,
                         balance: balance,
-                        code: Rc::new(code),
+                        code: Arc::new(code),
                     }).unwrap();
                 }
             },
@@ -105,7 +108,7 @@ fn handle_fire<T: GethRPCClient, P: Patch>(client: &mut T, vm: &mut SeqTransacti
                                                      &last_block_number)).unwrap();
                 vm.commit_account(AccountCommitment::Code {
                     address: address,
-                    code: Rc::new(code),
+                    code: Arc::new(code),
                 }).unwrap();
             },
             Err(RequireError::Blockhash(number)) => {
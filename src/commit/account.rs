@@ -9,11 +9,12 @@ use alloc::Vec;
 #[cfg(not(feature = "std"))] use core::marker::PhantomData;
 use bigint::{M256, U256, Address};
 use patch::AccountPatch;
+use sha3::{Digest, Keccak256};
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
-use errors::{RequireError, CommitError};
+use errors::{RequireError, CommitError, BalanceError};
 
 /// Internal representation of an account storage. It will return a
 /// `RequireError` if trying to access non-existing storage.
@@ -40,14 +41,19 @@ impl Storage {
         }
     }
 
-    /// Commit a value into the storage.
-    fn commit(&mut self, index: U256, value: M256) -> Result<(), CommitError> {
+    /// Commit a value into the storage. `original` is the value
+    /// committed before at the index, if any. Committing the same
+    /// value again succeeds, while committing a different one is a
+    /// conflict.
+    fn commit(&mut self, index: U256, value: M256, original: Option<M256>) -> Result<(), CommitError> {
         if !self.partial {
-            return Err(CommitError::InvalidCommitment);
+            return Err(CommitError::InvalidCommitment(self.address));
         }
 
-        if self.storage.contains_key(&index) {
-            return Err(CommitError::AlreadyCommitted);
+        match original {
+            Some(original) if original == value => return Ok(()),
+            Some(_) => return Err(CommitError::StorageConflict(self.address, index)),
+            None => (),
         }
 
         self.storage.insert(index, value);
@@ -79,6 +85,15 @@ impl Storage {
     pub fn len(&self) -> usize {
         self.storage.len()
     }
+
+    /// Return the changed/full items in storage sorted by index, so
+    /// that the output does not depend on the map iteration order.
+    pub fn sorted_entries(&self) -> Vec<(U256, M256)> {
+        let mut entries: Vec<(U256, M256)> = self.storage.iter()
+            .map(|(index, value)| (*index, *value)).collect();
+        entries.sort_by_key(|&(index, _)| index);
+        entries
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -95,7 +110,23 @@ pub enum AccountCommitment {
         /// Account balance.
         balance: U256,
         /// Code associated with this account.
-        code: Rc<Vec<u8>>,
+        code: Arc<Vec<u8>>,
+    },
+    /// Complete account commitment, the same as `Full` but also
+    /// carrying the whole storage of the account. Storage indexes not
+    /// in `storage` are zero, so reading them never requires a
+    /// further commitment.
+    Complete {
+        /// Nonce of the account.
+        nonce: U256,
+        /// Account address.
+        address: Address,
+        /// Account balance.
+        balance: U256,
+        /// Code associated with this account.
+        code: Arc<Vec<u8>>,
+        /// All non-zero storage values of the account.
+        storage: Map<U256, M256>,
     },
     /// Commit only code of the account. The client can keep changing
     /// it in other EVMs if the code remains unchanged.
@@ -103,7 +134,7 @@ pub enum AccountCommitment {
         /// Account address.
         address: Address,
         /// Code associated with this account.
-        code: Rc<Vec<u8>>,
+        code: Arc<Vec<u8>>,
     },
     /// Commit a storage. Must be used given a full account.
     Storage {
@@ -127,6 +158,10 @@ impl AccountCommitment {
                 address,
                 ..
             } => address,
+            &AccountCommitment::Complete {
+                address,
+                ..
+            } => address,
             &AccountCommitment::Code {
                 address,
                 ..
@@ -154,7 +189,7 @@ pub enum AccountChange {
         /// Change storage with given indexes and values.
         changing_storage: Storage,
         /// Code associated with this account.
-        code: Rc<Vec<u8>>,
+        code: Arc<Vec<u8>>,
     },
     /// Only balance is changed, and it is increasing for this address.
     IncreaseBalance(Address, U256),
@@ -169,7 +204,7 @@ pub enum AccountChange {
         /// All storage values of this account, with given indexes and values.
         storage: Storage,
         /// Code associated with this account.
-        code: Rc<Vec<u8>>
+        code: Arc<Vec<u8>>
     },
     /// The account should remain nonexist, or should be deleted if
     /// exists.
@@ -194,11 +229,20 @@ impl AccountChange {
     }
 }
 
+/// Account and code of addresses before they were first changed
+/// after a checkpoint.
+type Journal = Map<Address, (Option<AccountChange>, Option<Arc<Vec<u8>>>)>;
+
 #[derive(Debug)]
 /// A struct that manages the current account state for one EVM.
 pub struct AccountState<A: AccountPatch> {
     accounts: Map<Address, AccountChange>,
-    codes: Map<Address, Rc<Vec<u8>>>,
+    codes: Map<Address, Arc<Vec<u8>>>,
+    checkpoints: Vec<Journal>,
+    committed: Map<Address, AccountCommitment>,
+    original_storage: Map<(Address, U256), M256>,
+    code_hashes: Map<Address, (Arc<Vec<u8>>, M256)>,
+    balance_tally: U256,
     _marker: PhantomData<A>,
 }
 
@@ -207,6 +251,11 @@ impl<A: AccountPatch> Default for AccountState<A> {
         Self {
             accounts: Map::new(),
             codes: Map::new(),
+            checkpoints: Vec::new(),
+            committed: Map::new(),
+            original_storage: Map::new(),
+            code_hashes: Map::new(),
+            balance_tally: U256::zero(),
             _marker: PhantomData,
         }
     }
@@ -217,16 +266,95 @@ impl<A: AccountPatch> Clone for AccountState<A> {
         Self {
             accounts: self.accounts.clone(),
             codes: self.codes.clone(),
+            checkpoints: self.checkpoints.clone(),
+            committed: self.committed.clone(),
+            original_storage: self.original_storage.clone(),
+            code_hashes: self.code_hashes.clone(),
+            balance_tally: self.balance_tally,
             _marker: PhantomData,
         }
     }
 }
 
+fn keccak(code: &[u8]) -> M256 {
+    M256::from(Keccak256::digest(code).as_slice())
+}
+
 fn is_empty(nonce: U256, balance: U256, code: &[u8]) -> bool {
     nonce == U256::zero() && balance == U256::zero() && code == &[]
 }
 
+/// Balance known from an account change. An account only topped up
+/// has its topup, as its committed balance is unknown.
+fn known_balance(account: Option<&AccountChange>) -> U256 {
+    match account {
+        Some(&AccountChange::Full { balance, .. }) => balance,
+        Some(&AccountChange::Create { balance, .. }) => balance,
+        Some(&AccountChange::IncreaseBalance(_, topup)) => topup,
+        Some(&AccountChange::Nonexist(_)) | None => U256::zero(),
+    }
+}
+
 impl<A: AccountPatch> AccountState<A> {
+    /// Record the account and code of the address in the latest
+    /// checkpoint, unless it has changed since the checkpoint.
+    fn journal(&mut self, address: Address) {
+        let accounts = &self.accounts;
+        let codes = &self.codes;
+        if let Some(journal) = self.checkpoints.last_mut() {
+            journal.entry(address).or_insert_with(|| {
+                (accounts.get(&address).cloned(), codes.get(&address).cloned())
+            });
+        }
+    }
+
+    /// Start a checkpoint. Changes made afterwards can be discarded
+    /// by `revert_checkpoint`, or kept by `commit_checkpoint`.
+    /// Checkpoints can be nested.
+    pub fn push_checkpoint(&mut self) {
+        self.checkpoints.push(Journal::new());
+    }
+
+    /// Keep the changes made since the latest checkpoint, and remove
+    /// the checkpoint. They can still be discarded by reverting an
+    /// outer checkpoint.
+    ///
+    /// ### Panic
+    /// Requires a checkpoint to exist.
+    pub fn commit_checkpoint(&mut self) {
+        let journal = self.checkpoints.pop().unwrap();
+        if let Some(outer) = self.checkpoints.last_mut() {
+            for (address, original) in journal {
+                outer.entry(address).or_insert(original);
+            }
+        }
+    }
+
+    /// Discard the changes made since the latest checkpoint, and
+    /// remove the checkpoint. Commitments made since the checkpoint
+    /// are kept.
+    ///
+    /// ### Panic
+    /// Requires a checkpoint to exist.
+    pub fn revert_checkpoint(&mut self) {
+        let journal = self.checkpoints.pop().unwrap();
+        for (address, (account, code)) in journal {
+            match account {
+                Some(account) => { self.accounts.insert(address, account); },
+                None => { self.accounts.remove(&address); },
+            }
+            match code {
+                Some(code) => { self.codes.insert(address, code); },
+                None => { self.codes.remove(&address); },
+            }
+        }
+    }
+
+    /// Number of checkpoints currently started.
+    pub fn checkpoint_depth(&self) -> usize {
+        self.checkpoints.len()
+    }
+
     fn insert_account(&mut self, account: AccountChange) {
         match account {
             AccountChange::Full { nonce, address, balance, changing_storage, code } => {
@@ -274,11 +402,20 @@ impl<A: AccountPatch> AccountState<A> {
         set
     }
 
-    /// Returns all accounts right now in this account state.
+    /// Returns all accounts right now in this account state, both the
+    /// committed accounts that were only read and the changed ones.
     pub fn accounts(&self) -> map::Values<Address, AccountChange> {
         self.accounts.values()
     }
 
+    /// Returns the same accounts as `accounts`, sorted by address, so
+    /// that state dumps are reproducible across runs.
+    pub fn sorted_accounts(&self) -> Vec<&AccountChange> {
+        let mut accounts: Vec<&AccountChange> = self.accounts.values().collect();
+        accounts.sort_by_key(|account| account.address());
+        accounts
+    }
+
     /// Returns Ok(()) if a full account is in this account
     /// state. Otherwise raise a `RequireError`.
     pub fn require(&self, address: Address) -> Result<(), RequireError> {
@@ -311,8 +448,47 @@ impl<A: AccountPatch> AccountState<A> {
         self.storage_read(address, index).and_then(|_| Ok(()))
     }
 
-    /// Commit an account commitment into this account state.
+    /// Commit an account commitment into this account state. A
+    /// commitment identical to the data committed before succeeds
+    /// without change, even if the account was changed since, while a
+    /// different one returns `CommitError::Conflict`, or
+    /// `CommitError::StorageConflict` for a storage value.
+    /// The commitment also applies to the state recorded by checkpoints,
+    /// so reverting a checkpoint never discards it.
     pub fn commit(&mut self, commitment: AccountCommitment) -> Result<(), CommitError> {
+        let address = commitment.address();
+        let committed = self.committed.get(&address).cloned();
+        let original_value = match commitment {
+            AccountCommitment::Storage { index, .. } =>
+                self.original_storage.get(&(address, index)).map(|value| (index, *value)),
+            _ => None,
+        };
+        self.commit_current(commitment.clone())?;
+
+        for journal in &mut self.checkpoints {
+            if let Some(&mut (ref mut account, ref mut code)) = journal.get_mut(&address) {
+                let mut original = AccountState::<A>::default();
+                if let Some(ref committed) = committed {
+                    original.committed.insert(address, committed.clone());
+                }
+                if let Some((index, value)) = original_value {
+                    original.original_storage.insert((address, index), value);
+                }
+                if let Some(account) = account.take() {
+                    original.accounts.insert(address, account);
+                }
+                if let Some(code) = code.take() {
+                    original.codes.insert(address, code);
+                }
+                original.commit_current(commitment.clone())?;
+                *account = original.accounts.remove(&address);
+                *code = original.codes.remove(&address);
+            }
+        }
+        Ok(())
+    }
+
+    fn commit_current(&mut self, commitment: AccountCommitment) -> Result<(), CommitError> {
         match commitment {
             AccountCommitment::Full {
                 nonce,
@@ -320,43 +496,142 @@ impl<A: AccountPatch> AccountState<A> {
                 balance,
                 code
             } => {
-                let account = if self.accounts.contains_key(&address) {
-                    match self.accounts.remove(&address).unwrap() {
-                        AccountChange::Full { .. } => return Err(CommitError::AlreadyCommitted),
-                        AccountChange::Create { .. } => return Err(CommitError::AlreadyCommitted),
-                        AccountChange::Nonexist(_) => return Err(CommitError::AlreadyCommitted),
-                        AccountChange::IncreaseBalance(address, topup) => {
-                            AccountChange::Full {
-                                nonce,
-                                address,
-                                balance: balance + topup,
-                                changing_storage: Storage::new(address, true),
-                                code,
-                            }
-                        },
-                    }
-                } else {
-                    AccountChange::Full {
-                        nonce,
-                        address,
-                        balance,
-                        changing_storage: Storage::new(address, true),
-                        code,
-                    }
+                match self.committed.get(&address) {
+                    Some(&AccountCommitment::Full {
+                        nonce: ref committed_nonce,
+                        balance: ref committed_balance,
+                        code: ref committed_code,
+                        ..
+                    }) |
+                    Some(&AccountCommitment::Complete {
+                        nonce: ref committed_nonce,
+                        balance: ref committed_balance,
+                        code: ref committed_code,
+                        ..
+                    }) => {
+                        return if committed_nonce == &nonce && committed_balance == &balance &&
+                            committed_code == &code
+                        {
+                            Ok(())
+                        } else {
+                            Err(CommitError::Conflict(address))
+                        };
+                    },
+                    _ => (),
+                }
+
+                let account = match self.accounts.get(&address) {
+                    Some(&AccountChange::Full { .. }) => return Err(CommitError::Conflict(address)),
+                    Some(&AccountChange::Create { .. }) => return Err(CommitError::AlreadyCommitted(address)),
+                    Some(&AccountChange::Nonexist(_)) => return Err(CommitError::AlreadyCommitted(address)),
+                    Some(&AccountChange::IncreaseBalance(address, topup)) => {
+                        AccountChange::Full {
+                            nonce,
+                            address,
+                            balance: balance + topup,
+                            changing_storage: Storage::new(address, true),
+                            code: code.clone(),
+                        }
+                    },
+                    None => {
+                        AccountChange::Full {
+                            nonce,
+                            address,
+                            balance,
+                            changing_storage: Storage::new(address, true),
+                            code: code.clone(),
+                        }
+                    },
                 };
 
+                self.committed.insert(address, AccountCommitment::Full { nonce, address, balance, code });
                 self.insert_account(account);
                 self.codes.remove(&address);
+                self.cache_code_hash(address);
+            },
+            AccountCommitment::Complete {
+                nonce,
+                address,
+                balance,
+                code,
+                storage,
+            } => {
+                match self.committed.get(&address) {
+                    Some(AccountCommitment::Complete {
+                        nonce: committed_nonce,
+                        balance: committed_balance,
+                        code: committed_code,
+                        storage: committed_storage,
+                        ..
+                    }) => {
+                        return if committed_nonce == &nonce && committed_balance == &balance &&
+                            committed_code == &code && committed_storage == &storage
+                        {
+                            Ok(())
+                        } else {
+                            Err(CommitError::Conflict(address))
+                        };
+                    },
+                    Some(_) => return Err(CommitError::Conflict(address)),
+                    None => (),
+                }
+
+                let mut complete_storage = Storage::new(address, false);
+                complete_storage.storage = storage.clone();
+                let originals: Vec<(U256, M256)> = complete_storage.storage.iter()
+                    .map(|(index, value)| (*index, *value)).collect();
+
+                let account = match self.accounts.get(&address) {
+                    Some(&AccountChange::Full { .. }) => return Err(CommitError::Conflict(address)),
+                    Some(&AccountChange::Create { .. }) => return Err(CommitError::AlreadyCommitted(address)),
+                    Some(&AccountChange::Nonexist(_)) => return Err(CommitError::AlreadyCommitted(address)),
+                    Some(&AccountChange::IncreaseBalance(address, topup)) => {
+                        AccountChange::Full {
+                            nonce,
+                            address,
+                            balance: balance + topup,
+                            changing_storage: complete_storage,
+                            code: code.clone(),
+                        }
+                    },
+                    None => {
+                        AccountChange::Full {
+                            nonce,
+                            address,
+                            balance,
+                            changing_storage: complete_storage,
+                            code: code.clone(),
+                        }
+                    },
+                };
+
+                self.committed.insert(address, AccountCommitment::Complete {
+                    nonce, address, balance, code, storage,
+                });
+                self.insert_account(account);
+                self.codes.remove(&address);
+                self.cache_code_hash(address);
+                for (index, value) in originals {
+                    self.original_storage.insert((address, index), value);
+                }
             },
             AccountCommitment::Code {
                 address,
                 code,
             } => {
-                if self.accounts.contains_key(&address) || self.codes.contains_key(&address) {
-                    return Err(CommitError::AlreadyCommitted);
+                let current = match self.accounts.get(&address) {
+                    Some(AccountChange::Full { code: current, .. }) => Some(current),
+                    Some(&AccountChange::IncreaseBalance(_, _)) | None => self.codes.get(&address),
+                    Some(_) => return Err(CommitError::AlreadyCommitted(address)),
+                };
+                match current {
+                    Some(current) if current == &code => return Ok(()),
+                    Some(_) => return Err(CommitError::Conflict(address)),
+                    None => (),
                 }
 
                 self.codes.insert(address, code);
+                self.cache_code_hash(address);
             },
             AccountCommitment::Storage {
                 address,
@@ -368,31 +643,30 @@ impl<A: AccountPatch> AccountState<A> {
                         ref mut changing_storage,
                         ..
                     }) => {
-                        changing_storage.commit(index, value)?;
+                        let original = self.original_storage.get(&(address, index)).cloned();
+                        changing_storage.commit(index, value, original)?;
+                        self.original_storage.insert((address, index), value);
                     },
                     _ => {
-                        return Err(CommitError::InvalidCommitment);
+                        return Err(CommitError::InvalidCommitment(address));
                     },
                 }
             },
             AccountCommitment::Nonexist(address) => {
-                let account = if self.accounts.contains_key(&address) {
-                    match self.accounts.remove(&address).unwrap() {
-                        AccountChange::Full { .. } => return Err(CommitError::AlreadyCommitted),
-                        AccountChange::Create { .. } => return Err(CommitError::AlreadyCommitted),
-                        AccountChange::Nonexist(_) => return Err(CommitError::AlreadyCommitted),
-                        AccountChange::IncreaseBalance(address, topup) => {
-                            AccountChange::Create {
-                                nonce: A::initial_nonce(),
-                                address,
-                                balance: topup,
-                                storage: Storage::new(address, false),
-                                code: Rc::new(Vec::new())
-                            }
-                        },
-                    }
-                } else {
-                    AccountChange::Nonexist(address)
+                let account = match self.accounts.get(&address) {
+                    Some(&AccountChange::Full { .. }) => return Err(CommitError::Conflict(address)),
+                    Some(&AccountChange::Create { .. }) => return Err(CommitError::AlreadyCommitted(address)),
+                    Some(&AccountChange::Nonexist(_)) => return Ok(()),
+                    Some(&AccountChange::IncreaseBalance(address, topup)) => {
+                        AccountChange::Create {
+                            nonce: A::initial_nonce(),
+                            address,
+                            balance: topup,
+                            storage: Storage::new(address, false),
+                            code: Arc::new(Vec::new())
+                        }
+                    },
+                    None => AccountChange::Nonexist(address),
                 };
 
                 self.insert_account(account);
@@ -410,13 +684,13 @@ impl<A: AccountPatch> AccountState<A> {
                 if A::empty_considered_exists() {
                     true
                 } else {
-                    is_empty(nonce, balance, code)
+                    !is_empty(nonce, balance, code)
                 }),
             Some(&AccountChange::Full { nonce, balance, ref code, .. }) => Ok(
                 if A::empty_considered_exists() {
                     true
                 } else {
-                    is_empty(nonce, balance, code)
+                    !is_empty(nonce, balance, code)
                 }),
             Some(&AccountChange::Nonexist(_)) => Ok(false),
             Some(&AccountChange::IncreaseBalance(address, topup)) =>
@@ -433,12 +707,12 @@ impl<A: AccountPatch> AccountState<A> {
 
     /// Find code by its address in this account state. If the search
     /// failed, returns a `RequireError`.
-    pub fn code(&self, address: Address) -> Result<Rc<Vec<u8>>, RequireError> {
+    pub fn code(&self, address: Address) -> Result<Arc<Vec<u8>>, RequireError> {
         if self.accounts.contains_key(&address) {
             match self.accounts.get(&address).unwrap() {
                 &AccountChange::Full { ref code, .. } => return Ok(code.clone()),
                 &AccountChange::Create { ref code, .. } => return Ok(code.clone()),
-                &AccountChange::Nonexist(_) => return Ok(Rc::new(Vec::new())),
+                &AccountChange::Nonexist(_) => return Ok(Arc::new(Vec::new())),
                 &AccountChange::IncreaseBalance(_, _) => (),
             }
         }
@@ -450,6 +724,25 @@ impl<A: AccountPatch> AccountState<A> {
         }
     }
 
+    /// Find the Keccak hash of the code by its address in this
+    /// account state. The hash of committed code is cached, so it is
+    /// only computed once. If the search failed, returns a
+    /// `RequireError`.
+    pub fn code_hash(&self, address: Address) -> Result<M256, RequireError> {
+        let code = self.code(address)?;
+        match self.code_hashes.get(&address) {
+            Some(&(ref cached, hash)) if Arc::ptr_eq(cached, &code) => Ok(hash),
+            _ => Ok(keccak(code.as_slice())),
+        }
+    }
+
+    fn cache_code_hash(&mut self, address: Address) {
+        if let Ok(code) = self.code(address) {
+            let hash = keccak(code.as_slice());
+            self.code_hashes.insert(address, (code, hash));
+        }
+    }
+
     /// Find nonce by its address in this account state. If the search
     /// failed, returns a `RequireError`.
     pub fn nonce(&self, address: Address) -> Result<U256, RequireError> {
@@ -516,9 +809,31 @@ impl<A: AccountPatch> AccountState<A> {
         return Err(RequireError::Account(address));
     }
 
+    /// Read the value of an account storage before the current
+    /// transaction, as committed to this account state. Accounts
+    /// created or committed as nonexistent have zero original
+    /// storage.
+    pub fn original_storage(&self, address: Address, index: U256) -> Result<M256, RequireError> {
+        if let Some(value) = self.original_storage.get(&(address, index)) {
+            return Ok(*value);
+        }
+
+        match self.accounts.get(&address) {
+            Some(AccountChange::Full { changing_storage, .. }) => if changing_storage.partial {
+                Err(RequireError::AccountStorage(address, index))
+            } else {
+                Ok(M256::zero())
+            },
+            Some(&AccountChange::Create { .. }) => Ok(M256::zero()),
+            Some(&AccountChange::Nonexist(_)) => Ok(M256::zero()),
+            _ => Err(RequireError::Account(address)),
+        }
+    }
+
     /// Write a value from an account storage. The account will be
     /// created if it is nonexist.
     pub fn storage_write(&mut self, address: Address, index: U256, value: M256) -> Result<(), RequireError> {
+        self.journal(address);
         if self.accounts.contains_key(&address) {
             match self.accounts.get_mut(&address).unwrap() {
                 &mut AccountChange::Full {
@@ -543,7 +858,7 @@ impl<A: AccountPatch> AccountState<A> {
                             address,
                             balance: U256::zero(),
                             storage,
-                            code: Rc::new(Vec::new())
+                            code: Arc::new(Vec::new())
                         };
                         return ret;
                     }
@@ -557,23 +872,24 @@ impl<A: AccountPatch> AccountState<A> {
     /// Create a new account (that should not yet have existed
     /// before).
     pub fn create(&mut self, address: Address, topup: U256) -> Result<(), RequireError> {
+        self.journal(address);
         let account = if self.accounts.contains_key(&address) {
             match self.accounts.remove(&address).unwrap() {
                 AccountChange::Full { balance, .. } => {
                     AccountChange::Create {
-                        address, code: Rc::new(Vec::new()), nonce: A::initial_create_nonce(),
+                        address, code: Arc::new(Vec::new()), nonce: A::initial_create_nonce(),
                         balance: balance + topup, storage: Storage::new(address, false),
                     }
                 },
                 AccountChange::Create { balance, .. } => {
                     AccountChange::Create {
-                        address, code: Rc::new(Vec::new()), nonce: A::initial_create_nonce(),
+                        address, code: Arc::new(Vec::new()), nonce: A::initial_create_nonce(),
                         balance: balance + topup, storage: Storage::new(address, false),
                     }
                 },
                 AccountChange::Nonexist(_) => {
                     AccountChange::Create {
-                        address, code: Rc::new(Vec::new()), nonce: A::initial_create_nonce(),
+                        address, code: Arc::new(Vec::new()), nonce: A::initial_create_nonce(),
                         balance: topup, storage: Storage::new(address, false),
                     }
                 },
@@ -598,7 +914,8 @@ impl<A: AccountPatch> AccountState<A> {
 
     /// Deposit code in to a created account. Only usable in a newly
     /// created account.
-    pub fn code_deposit(&mut self, address: Address, new_code: Rc<Vec<u8>>) {
+    pub fn code_deposit(&mut self, address: Address, new_code: Arc<Vec<u8>>) {
+        self.journal(address);
         match self.accounts.get_mut(&address).unwrap() {
             &mut AccountChange::Create { ref mut code, .. } => {
                 *code = new_code;
@@ -610,6 +927,8 @@ impl<A: AccountPatch> AccountState<A> {
     /// Increase the balance of an account. The account will be
     /// created if it is nonexist in the beginning.
     pub fn increase_balance(&mut self, address: Address, topup: U256) {
+        self.journal(address);
+        let before = known_balance(self.accounts.get(&address));
         let account = match self.accounts.remove(&address) {
             Some(AccountChange::Full {
                 address,
@@ -650,7 +969,7 @@ impl<A: AccountPatch> AccountState<A> {
                     address,
                     balance: topup,
                     storage: Storage::new(address, false),
-                    code: Rc::new(Vec::new())
+                    code: Arc::new(Vec::new())
                 }
             },
             None => {
@@ -658,11 +977,14 @@ impl<A: AccountPatch> AccountState<A> {
             },
         };
         self.insert_account(account);
+        self.tally_balance(address, before);
     }
 
     /// Decrease the balance of an account. The account will be
     /// created if it is nonexist in the beginning.
     pub fn decrease_balance(&mut self, address: Address, withdraw: U256) {
+        self.journal(address);
+        let before = known_balance(self.accounts.get(&address));
         let account = match self.accounts.remove(&address) {
             Some(AccountChange::Full {
                 address,
@@ -699,12 +1021,88 @@ impl<A: AccountPatch> AccountState<A> {
             None => panic!(),
         };
         self.insert_account(account);
+        self.tally_balance(address, before);
+    }
+
+    /// Add the change of the balance of `address` from `before` to
+    /// the running tally of balance changes.
+    fn tally_balance(&mut self, address: Address, before: U256) {
+        let after = known_balance(self.accounts.get(&address));
+        self.balance_tally = self.balance_tally.overflowing_add(after).0.overflowing_sub(before).0;
+    }
+
+    /// Running tally of the balance added minus the balance removed by
+    /// `increase_balance` and `decrease_balance`, wrapping around.
+    /// Only its changes are meaningful: it is unchanged by a value
+    /// transfer, and changes by the amount minted or burnt otherwise.
+    /// Reverting a checkpoint does not undo it.
+    pub fn balance_tally(&self) -> U256 {
+        self.balance_tally
+    }
+
+    /// Move `value` from one account to another, with `sub_balance`
+    /// and `add_balance`. If `from` is not committed, returns a
+    /// `RequireError`, and if its balance is lower than `value`,
+    /// returns `BalanceError::Underflow`, leaving both balances
+    /// unchanged. In debug builds, this asserts that the balance tally
+    /// is unchanged.
+    pub fn transfer(&mut self, from: Address, to: Address, value: U256) -> Result<(), BalanceError> {
+        let tally = self.balance_tally;
+        self.sub_balance(from, value)?;
+        self.add_balance(to, value)?;
+        debug_assert!(self.balance_tally == tally, "value transfer does not conserve the total balance");
+        Ok(())
+    }
+
+    /// Sum of the balances in this account state. An account only
+    /// topped up counts its topup, as its committed balance is
+    /// unknown. The sum saturates at the maximum value. This walks
+    /// every account, see `balance_tally` for a running count.
+    pub fn total_balance(&self) -> U256 {
+        let mut total = U256::zero();
+        for account in self.accounts.values() {
+            total = total.saturating_add(known_balance(Some(account)));
+        }
+        total
+    }
+
+    /// Add to the balance of an account. This never requires the
+    /// account, as the amount is added to the balance committed
+    /// later.
+    pub fn add_balance(&mut self, address: Address, amount: U256) -> Result<(), RequireError> {
+        self.increase_balance(address, amount);
+        Ok(())
+    }
+
+    /// Subtract from the balance of an account. If the account is
+    /// not already commited, returns a `RequireError`. If the balance
+    /// is lower than the amount, returns `BalanceError::Underflow`
+    /// and leaves the balance unchanged.
+    pub fn sub_balance(&mut self, address: Address, amount: U256) -> Result<(), BalanceError> {
+        if self.balance(address)? < amount {
+            return Err(BalanceError::Underflow);
+        }
+        self.decrease_balance(address, amount);
+        Ok(())
+    }
+
+    /// Set the balance of an account. If the account is not already
+    /// commited, returns a `RequireError`.
+    pub fn set_balance(&mut self, address: Address, new_balance: U256) -> Result<(), RequireError> {
+        let balance = self.balance(address)?;
+        if new_balance >= balance {
+            self.increase_balance(address, new_balance - balance);
+        } else {
+            self.decrease_balance(address, balance - new_balance);
+        }
+        Ok(())
     }
 
     /// Set nonce of an account. If the account is not already
     /// commited, returns a `RequireError`. The account will be
     /// created if it is nonexist in the beginning.
     pub fn set_nonce(&mut self, address: Address, new_nonce: U256) -> Result<(), RequireError> {
+        self.journal(address);
         match self.accounts.get_mut(&address) {
             Some(&mut AccountChange::Full {
                 ref mut nonce,
@@ -732,7 +1130,7 @@ impl<A: AccountPatch> AccountState<A> {
                         address,
                         balance: U256::zero(),
                         storage: Storage::new(address, false),
-                        code: Rc::new(Vec::new())
+                        code: Arc::new(Vec::new())
                     };
                     Ok(())
                 } else {
@@ -748,8 +1146,405 @@ impl<A: AccountPatch> AccountState<A> {
     /// Delete an account from this account state. The account is set
     /// to null.
     pub fn remove(&mut self, address: Address) -> Result<(), RequireError> {
+        self.journal(address);
         self.codes.remove(&address);
         self.insert_account(AccountChange::Nonexist(address));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use patch::EmbeddedAccountPatch;
+    use std::str::FromStr;
+
+    fn address(s: &str) -> Address {
+        Address::from_str(s).unwrap()
+    }
+
+    fn account_state() -> AccountState<EmbeddedAccountPatch> {
+        let mut state = AccountState::<EmbeddedAccountPatch>::default();
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(),
+            address: address("0x1000000000000000000000000000000000000000"),
+            balance: U256::from(10u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+        state.commit(AccountCommitment::Storage {
+            address: address("0x1000000000000000000000000000000000000000"),
+            index: U256::one(), value: M256::zero(),
+        }).unwrap();
+        state
+    }
+
+    #[test]
+    fn nested_checkpoints() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let mut state = account_state();
+
+        state.push_checkpoint();
+        state.increase_balance(a, U256::from(5u64));
+        state.storage_write(a, U256::one(), M256::one()).unwrap();
+
+        state.push_checkpoint();
+        state.decrease_balance(a, U256::from(3u64));
+        state.storage_write(a, U256::one(), M256::from(2u64)).unwrap();
+        state.set_nonce(a, U256::one()).unwrap();
+        assert_eq!(state.checkpoint_depth(), 2);
+        state.revert_checkpoint();
+
+        assert_eq!(state.balance(a).unwrap(), U256::from(15u64));
+        assert_eq!(state.storage_read(a, U256::one()).unwrap(), M256::one());
+        assert_eq!(state.nonce(a).unwrap(), U256::zero());
+
+        state.commit_checkpoint();
+        assert_eq!(state.checkpoint_depth(), 0);
+        assert_eq!(state.balance(a).unwrap(), U256::from(15u64));
+
+        state.push_checkpoint();
+        state.remove(a).unwrap();
+        assert!(!state.exists(a).unwrap());
+        state.revert_checkpoint();
+        assert_eq!(state.balance(a).unwrap(), U256::from(15u64));
+    }
+
+    #[test]
+    fn revert_keeps_commitments() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+
+        state.push_checkpoint();
+        state.increase_balance(b, U256::from(5u64));
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: b, balance: U256::from(2u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+        assert_eq!(state.balance(b).unwrap(), U256::from(7u64));
+        state.storage_write(a, U256::one(), M256::one()).unwrap();
+        state.commit(AccountCommitment::Storage {
+            address: a, index: U256::zero(), value: M256::from(4u64),
+        }).unwrap();
+        state.revert_checkpoint();
+
+        assert_eq!(state.balance(b).unwrap(), U256::from(2u64));
+        assert_eq!(state.storage_read(a, U256::zero()).unwrap(), M256::from(4u64));
+        assert_eq!(state.storage_read(a, U256::one()).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn identical_recommit() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let c = address("0x3000000000000000000000000000000000000000");
+        let mut state = account_state();
+        state.commit(AccountCommitment::Code { address: b, code: Arc::new(vec![0x00]) }).unwrap();
+        state.commit(AccountCommitment::Nonexist(c)).unwrap();
+
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: a, balance: U256::from(10u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+        state.commit(AccountCommitment::Storage {
+            address: a, index: U256::one(), value: M256::zero(),
+        }).unwrap();
+        state.commit(AccountCommitment::Code { address: b, code: Arc::new(vec![0x00]) }).unwrap();
+        state.commit(AccountCommitment::Code { address: a, code: Arc::new(Vec::new()) }).unwrap();
+        state.commit(AccountCommitment::Nonexist(c)).unwrap();
+    }
+
+    #[test]
+    fn recommit_after_change() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        let mut storage = Map::new();
+        storage.insert(U256::one(), M256::from(3u64));
+        state.commit(AccountCommitment::Complete {
+            nonce: U256::zero(), address: b, balance: U256::zero(),
+            code: Arc::new(Vec::new()), storage: storage.clone(),
+        }).unwrap();
+
+        state.increase_balance(a, U256::from(5u64));
+        state.set_nonce(a, U256::one()).unwrap();
+        state.storage_write(a, U256::one(), M256::one()).unwrap();
+        state.storage_write(b, U256::one(), M256::zero()).unwrap();
+
+        // Commitments are compared against the committed data, not
+        // the changed one.
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: a, balance: U256::from(10u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+        state.commit(AccountCommitment::Storage {
+            address: a, index: U256::one(), value: M256::zero(),
+        }).unwrap();
+        state.commit(AccountCommitment::Complete {
+            nonce: U256::zero(), address: b, balance: U256::zero(),
+            code: Arc::new(Vec::new()), storage,
+        }).unwrap();
+
+        assert_eq!(state.commit(AccountCommitment::Full {
+            nonce: U256::one(), address: a, balance: U256::from(15u64),
+            code: Arc::new(Vec::new()),
+        }), Err(CommitError::Conflict(a)));
+        assert_eq!(state.commit(AccountCommitment::Storage {
+            address: a, index: U256::one(), value: M256::one(),
+        }), Err(CommitError::StorageConflict(a, U256::one())));
+        assert_eq!(state.commit(AccountCommitment::Complete {
+            nonce: U256::zero(), address: b, balance: U256::zero(),
+            code: Arc::new(Vec::new()), storage: Map::new(),
+        }), Err(CommitError::Conflict(b)));
+
+        assert_eq!(state.balance(a).unwrap(), U256::from(15u64));
+        assert_eq!(state.storage_read(a, U256::one()).unwrap(), M256::one());
+        assert_eq!(state.storage_read(b, U256::one()).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn recommit_under_checkpoint() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let mut state = account_state();
+
+        state.push_checkpoint();
+        state.increase_balance(a, U256::from(5u64));
+        state.storage_write(a, U256::one(), M256::one()).unwrap();
+
+        // The journal of the checkpoint holds the account as it was
+        // committed, and the re-commits are checked against it too.
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: a, balance: U256::from(10u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+        state.commit(AccountCommitment::Storage {
+            address: a, index: U256::one(), value: M256::zero(),
+        }).unwrap();
+        assert_eq!(state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: a, balance: U256::from(15u64),
+            code: Arc::new(Vec::new()),
+        }), Err(CommitError::Conflict(a)));
+        assert_eq!(state.balance(a).unwrap(), U256::from(15u64));
+
+        state.revert_checkpoint();
+        assert_eq!(state.balance(a).unwrap(), U256::from(10u64));
+        assert_eq!(state.storage_read(a, U256::one()).unwrap(), M256::zero());
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: a, balance: U256::from(10u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+    }
+
+    #[test]
+    fn conflicting_recommit() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let mut state = account_state();
+
+        match state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: a, balance: U256::from(11u64),
+            code: Arc::new(Vec::new()),
+        }) {
+            Err(CommitError::Conflict(address)) if address == a => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match state.commit(AccountCommitment::Storage {
+            address: a, index: U256::one(), value: M256::one(),
+        }) {
+            Err(CommitError::StorageConflict(address, index)) if address == a && index == U256::one() => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match state.commit(AccountCommitment::Nonexist(a)) {
+            Err(CommitError::Conflict(address)) if address == a => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // The committed data is left unchanged.
+        assert_eq!(state.balance(a).unwrap(), U256::from(10u64));
+        assert_eq!(state.storage_read(a, U256::one()).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn commit_error_context() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        state.commit(AccountCommitment::Nonexist(b)).unwrap();
+
+        assert_eq!(state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: b, balance: U256::zero(),
+            code: Arc::new(Vec::new()),
+        }), Err(CommitError::AlreadyCommitted(b)));
+        assert_eq!(state.commit(AccountCommitment::Storage {
+            address: b, index: U256::one(), value: M256::zero(),
+        }), Err(CommitError::InvalidCommitment(b)));
+        assert_eq!(state.commit(AccountCommitment::Storage {
+            address: a, index: U256::one(), value: M256::one(),
+        }), Err(CommitError::StorageConflict(a, U256::one())));
+        assert_eq!(format!("{}", CommitError::AlreadyCommitted(b)),
+                   "account 0x2000000000000000000000000000000000000000 already committed");
+    }
+
+    #[test]
+    fn cached_code_hash() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        let code = Arc::new(vec![0x60, 0x00, 0x00]);
+        state.commit(AccountCommitment::Code { address: b, code: code.clone() }).unwrap();
+
+        // The hash is computed once, when the code is committed.
+        assert!(Arc::ptr_eq(&state.code_hashes.get(&b).unwrap().0, &code));
+        assert_eq!(state.code_hash(b).unwrap(), keccak(&code));
+        assert_eq!(state.code_hash(a).unwrap(), keccak(&[]));
+
+        // Changed code is hashed again instead of using the cache.
+        let deposited = Arc::new(vec![0x00]);
+        state.create(a, U256::zero()).unwrap();
+        state.code_deposit(a, deposited.clone());
+        assert_eq!(state.code_hash(a).unwrap(), keccak(&deposited));
+    }
+
+    #[test]
+    fn balance_transfer() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        state.commit(AccountCommitment::Full {
+            nonce: U256::zero(), address: b, balance: U256::from(5u64),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+
+        state.sub_balance(a, U256::from(4u64)).unwrap();
+        state.add_balance(b, U256::from(4u64)).unwrap();
+        assert_eq!(state.balance(a).unwrap(), U256::from(6u64));
+        assert_eq!(state.balance(b).unwrap(), U256::from(9u64));
+        assert_eq!(state.balance(a).unwrap() + state.balance(b).unwrap(), U256::from(15u64));
+
+        match state.sub_balance(a, U256::from(7u64)) {
+            Err(BalanceError::Underflow) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(state.balance(a).unwrap(), U256::from(6u64));
+        match state.sub_balance(address("0x3000000000000000000000000000000000000000"), U256::one()) {
+            Err(BalanceError::Require(RequireError::Account(_))) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        state.set_balance(a, U256::from(2u64)).unwrap();
+        state.set_balance(b, U256::from(13u64)).unwrap();
+        assert_eq!(state.balance(a).unwrap(), U256::from(2u64));
+        assert_eq!(state.balance(b).unwrap(), U256::from(13u64));
+    }
+
+    #[test]
+    fn sorted_accounts() {
+        let addresses = [
+            address("0x3000000000000000000000000000000000000000"),
+            address("0x1000000000000000000000000000000000000000"),
+            address("0x2000000000000000000000000000000000000000"),
+        ];
+        let run = |order: &[usize]| {
+            let mut state = AccountState::<EmbeddedAccountPatch>::default();
+            for &i in order {
+                state.commit(AccountCommitment::Full {
+                    nonce: U256::zero(), address: addresses[i], balance: U256::zero(),
+                    code: Arc::new(Vec::new()),
+                }).unwrap();
+                for &j in order {
+                    state.commit(AccountCommitment::Storage {
+                        address: addresses[i], index: U256::from(j as u64), value: M256::zero(),
+                    }).unwrap();
+                }
+            }
+            for &i in order {
+                state.increase_balance(addresses[i], U256::one());
+                state.storage_write(addresses[i], U256::from(i as u64), M256::one()).unwrap();
+            }
+            state
+        };
+
+        let a = run(&[0, 1, 2]);
+        let b = run(&[2, 0, 1]);
+        let order = |state: &AccountState<EmbeddedAccountPatch>| -> Vec<Address> {
+            state.sorted_accounts().iter().map(|account| account.address()).collect()
+        };
+        assert_eq!(order(&a), vec![addresses[1], addresses[2], addresses[0]]);
+        assert_eq!(order(&a), order(&b));
+
+        let storage = |state: &AccountState<EmbeddedAccountPatch>| -> Vec<(U256, M256)> {
+            match state.sorted_accounts()[2] {
+                AccountChange::Full { changing_storage, .. } => changing_storage.sorted_entries(),
+                account => panic!("unexpected account {:?}", account),
+            }
+        };
+        assert_eq!(storage(&a), vec![(U256::zero(), M256::one()),
+                                     (U256::one(), M256::zero()),
+                                     (U256::from(2u64), M256::zero())]);
+        assert_eq!(storage(&a), storage(&b));
+    }
+
+    #[test]
+    fn transfer_conserves_balance() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        state.commit(AccountCommitment::Nonexist(b)).unwrap();
+        let total = state.total_balance();
+        let tally = state.balance_tally();
+
+        state.transfer(a, b, U256::from(4u64)).unwrap();
+        state.transfer(b, a, U256::one()).unwrap();
+        assert_eq!(state.balance(a).unwrap(), U256::from(7u64));
+        assert_eq!(state.balance(b).unwrap(), U256::from(3u64));
+        assert_eq!(state.total_balance(), total);
+        assert_eq!(state.balance_tally(), tally);
+
+        match state.transfer(b, a, U256::from(4u64)) {
+            Err(BalanceError::Underflow) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(state.balance(a).unwrap(), U256::from(7u64));
+        assert_eq!(state.balance(b).unwrap(), U256::from(3u64));
+    }
+
+    #[test]
+    fn balance_tally() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        let tally = state.balance_tally();
+
+        // Minting to an account not committed counts its topup.
+        state.increase_balance(b, U256::from(3u64));
+        assert_eq!(state.balance_tally(), tally + U256::from(3u64));
+        state.decrease_balance(a, U256::from(5u64));
+        assert_eq!(state.balance_tally().overflowing_add(U256::from(2u64)).0, tally);
+    }
+
+    #[test]
+    fn accounts_include_read_only() {
+        let a = address("0x1000000000000000000000000000000000000000");
+        let b = address("0x2000000000000000000000000000000000000000");
+        let mut state = account_state();
+        state.commit(AccountCommitment::Full {
+            nonce: U256::one(),
+            address: b,
+            balance: U256::from(3u64),
+            code: Arc::new(vec![0x00]),
+        }).unwrap();
+        state.increase_balance(a, U256::from(5u64));
+
+        let mut accounts: Vec<(Address, U256, U256, Vec<u8>)> = state.accounts().map(|account| {
+            match account {
+                &AccountChange::Full { address, nonce, balance, ref code, .. } =>
+                    (address, nonce, balance, code.as_ref().clone()),
+                account => panic!("unexpected account {:?}", account),
+            }
+        }).collect();
+        accounts.sort_by_key(|account| account.0);
+        assert_eq!(accounts, vec![
+            (a, U256::zero(), U256::from(15u64), Vec::new()),
+            (b, U256::one(), U256::from(3u64), vec![0x00]),
+        ]);
+    }
+}
@@ -31,7 +31,7 @@ impl BlockhashState {
     /// running EVMs.
     pub fn commit(&mut self, number: U256, hash: H256) -> Result<(), CommitError> {
         if self.0.contains_key(&number) {
-            return Err(CommitError::AlreadyCommitted);
+            return Err(CommitError::BlockhashAlreadyCommitted(number));
         }
 
         self.0.insert(number, hash);
@@ -46,3 +46,17 @@ impl BlockhashState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recommit() {
+        let mut state = BlockhashState::default();
+        state.commit(U256::one(), H256::default()).unwrap();
+        assert_eq!(state.commit(U256::one(), H256::default()),
+                   Err(CommitError::BlockhashAlreadyCommitted(U256::one())));
+        assert!(state.require(U256::one()).is_ok());
+    }
+}
@@ -0,0 +1,466 @@
+//! Typed transaction envelopes of EIP-2718.
+
+use bigint::{U256, H256, Address, Gas};
+use rlp::{RlpStream, UntrustedRlp, DecoderError};
+use sha3::{Digest, Keccak256};
+
+use block_core::TransactionAction;
+use block::Transaction;
+
+use transaction::AccessListItem;
+
+use errors::PreExecutionError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Type of an enveloped transaction.
+pub enum TransactionType {
+    /// A legacy RLP encoded transaction, without an envelope.
+    Legacy,
+    /// An EIP-2930 access list transaction, with type byte `0x01`.
+    AccessList,
+    /// An EIP-1559 dynamic fee transaction, with type byte `0x02`.
+    DynamicFee,
+}
+
+impl TransactionType {
+    /// The type byte of the envelope, or `None` for legacy
+    /// transactions.
+    pub fn type_byte(&self) -> Option<u8> {
+        match *self {
+            TransactionType::Legacy => None,
+            TransactionType::AccessList => Some(0x01),
+            TransactionType::DynamicFee => Some(0x02),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A typed transaction, either an EIP-2930 access list transaction or
+/// an EIP-1559 dynamic fee transaction.
+pub struct TypedTransaction {
+    /// Type of the transaction. Never `TransactionType::Legacy`.
+    pub transaction_type: TransactionType,
+    /// Chain ID the transaction is signed for.
+    pub chain_id: u64,
+    /// Transaction nonce.
+    pub nonce: U256,
+    /// Maximum priority fee per gas. Only present for dynamic fee
+    /// transactions.
+    pub max_priority_fee_per_gas: Option<Gas>,
+    /// Gas price for access list transactions, or maximum fee per gas
+    /// for dynamic fee transactions.
+    pub gas_price: Gas,
+    /// Transaction gas limit.
+    pub gas_limit: Gas,
+    /// Action CALL/CREATE of the transaction.
+    pub action: TransactionAction,
+    /// Value sent with this transaction.
+    pub value: U256,
+    /// Transaction input.
+    pub input: Vec<u8>,
+    /// Access list of the transaction.
+    pub access_list: Vec<AccessListItem>,
+    /// Parity of the `y` value of the signature.
+    pub y_parity: u64,
+    /// `r` value of the signature.
+    pub r: U256,
+    /// `s` value of the signature.
+    pub s: U256,
+}
+
+impl TypedTransaction {
+    fn payload_rlp_append(&self, s: &mut RlpStream, signed: bool) {
+        let len = match self.transaction_type {
+            TransactionType::DynamicFee => 9,
+            _ => 8,
+        };
+        s.begin_list(if signed { len + 3 } else { len });
+        s.append(&self.chain_id);
+        s.append(&self.nonce);
+        if let Some(max_priority_fee_per_gas) = self.max_priority_fee_per_gas {
+            s.append(&max_priority_fee_per_gas);
+        }
+        s.append(&self.gas_price);
+        s.append(&self.gas_limit);
+        s.append(&self.action);
+        s.append(&self.value);
+        s.append(&self.input);
+        s.append_list(&self.access_list);
+        if signed {
+            s.append(&self.y_parity);
+            s.append(&self.r);
+            s.append(&self.s);
+        }
+    }
+
+    fn encode_payload(&self, signed: bool) -> Vec<u8> {
+        let mut s = RlpStream::new();
+        self.payload_rlp_append(&mut s, signed);
+
+        let mut ret = vec![self.transaction_type.type_byte().unwrap()];
+        ret.extend_from_slice(&s.out());
+        ret
+    }
+
+    /// The envelope encoding of the signed transaction, the type byte
+    /// followed by the RLP payload.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_payload(true)
+    }
+
+    /// The hash signed by the caller of this transaction.
+    pub fn signing_hash(&self) -> H256 {
+        H256::from(Keccak256::digest(&self.encode_payload(false)).as_slice())
+    }
+
+    /// Recover the caller of this transaction from its signature.
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    pub fn caller(&self) -> Result<Address, PreExecutionError> {
+        if self.y_parity > 1 {
+            return Err(PreExecutionError::InvalidCaller);
+        }
+
+        let mut data = [0u8; 128];
+        data[0..32].copy_from_slice(&self.signing_hash());
+        data[63] = 27 + self.y_parity as u8;
+        self.r.to_big_endian(&mut data[64..96]);
+        self.s.to_big_endian(&mut data[96..128]);
+
+        match ::patch::kececrec(&data) {
+            Ok(hash) => Ok(Address::from(H256::from(hash.as_ref()))),
+            Err(_) => Err(PreExecutionError::InvalidCaller),
+        }
+    }
+
+    fn decode_payload(transaction_type: TransactionType, rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let offset = match transaction_type {
+            TransactionType::DynamicFee => 1,
+            _ => 0,
+        };
+        if rlp.item_count()? != 11 + offset {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        Ok(TypedTransaction {
+            transaction_type,
+            chain_id: rlp.val_at(0)?,
+            nonce: rlp.val_at(1)?,
+            max_priority_fee_per_gas: if offset == 1 { Some(rlp.val_at(2)?) } else { None },
+            gas_price: rlp.val_at(2 + offset)?,
+            gas_limit: rlp.val_at(3 + offset)?,
+            action: rlp.val_at(4 + offset)?,
+            value: rlp.val_at(5 + offset)?,
+            input: rlp.val_at(6 + offset)?,
+            access_list: rlp.list_at(7 + offset)?,
+            y_parity: rlp.val_at(8 + offset)?,
+            r: rlp.val_at(9 + offset)?,
+            s: rlp.val_at(10 + offset)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A transaction decoded from the EIP-2718 envelope format.
+pub enum EnvelopedTransaction {
+    /// A legacy transaction.
+    Legacy(Transaction),
+    /// A typed transaction.
+    Typed(TypedTransaction),
+}
+
+impl EnvelopedTransaction {
+    /// Type of the transaction.
+    pub fn transaction_type(&self) -> TransactionType {
+        match *self {
+            EnvelopedTransaction::Legacy(_) => TransactionType::Legacy,
+            EnvelopedTransaction::Typed(ref transaction) => transaction.transaction_type,
+        }
+    }
+
+    /// Recover the caller of this transaction from its signature.
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    pub fn caller(&self) -> Result<Address, PreExecutionError> {
+        match *self {
+            EnvelopedTransaction::Legacy(ref transaction) =>
+                transaction.caller().map_err(|_| PreExecutionError::InvalidCaller),
+            EnvelopedTransaction::Typed(ref transaction) => transaction.caller(),
+        }
+    }
+}
+
+/// Wrap `bytes` holding exactly one RLP item, without trailing bytes.
+fn exact_rlp(bytes: &[u8]) -> Result<UntrustedRlp<'_>, DecoderError> {
+    let rlp = UntrustedRlp::new(bytes);
+    if rlp.payload_info()?.total() != bytes.len() {
+        return Err(DecoderError::RlpIsTooBig);
+    }
+    Ok(rlp)
+}
+
+/// Decode a transaction in the EIP-2718 envelope format. A first byte
+/// of `0xc0` or above is an RLP list, and is decoded as a legacy
+/// transaction. Otherwise, the first byte is the transaction type.
+/// Bytes after the transaction are an error.
+pub fn decode_transaction(bytes: &[u8]) -> Result<EnvelopedTransaction, DecoderError> {
+    if bytes.is_empty() {
+        return Err(DecoderError::RlpIsTooShort);
+    }
+
+    let transaction_type = match bytes[0] {
+        first if first >= 0xc0 => {
+            let rlp = exact_rlp(bytes)?;
+            if rlp.item_count()? != 9 {
+                return Err(DecoderError::RlpIncorrectListLen);
+            }
+            return Ok(EnvelopedTransaction::Legacy(rlp.as_val()?));
+        },
+        0x01 => TransactionType::AccessList,
+        0x02 => TransactionType::DynamicFee,
+        _ => return Err(DecoderError::Custom("unknown transaction type")),
+    };
+
+    TypedTransaction::decode_payload(transaction_type, &exact_rlp(&bytes[1..])?)
+        .map(EnvelopedTransaction::Typed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::*;
+    use bigint::M256;
+    use hexutil::*;
+    use testing::AccessListPatch;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn typed(transaction_type: TransactionType, max_priority_fee_per_gas: Option<Gas>) -> TypedTransaction {
+        TypedTransaction {
+            transaction_type,
+            chain_id: 1,
+            nonce: U256::from(9u64),
+            max_priority_fee_per_gas,
+            gas_price: Gas::from(20000000000u64),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(Address::from_str("0x3535353535353535353535353535353535353535").unwrap()),
+            value: U256::from(1000000000000000000u64),
+            input: vec![0xab, 0xcd],
+            access_list: vec![AccessListItem {
+                address: Address::from_str("0x3535353535353535353535353535353535353535").unwrap(),
+                storage_keys: vec![H256::default(), H256::from(U256::one())],
+            }],
+            y_parity: 1,
+            r: U256::from(2u64),
+            s: U256::from(3u64),
+        }
+    }
+
+    #[test]
+    fn decode_legacy() {
+        // The signed transaction example of EIP-155.
+        let bytes = read_hex("0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83").unwrap();
+        let transaction = decode_transaction(&bytes).unwrap();
+
+        assert_eq!(transaction.transaction_type(), TransactionType::Legacy);
+        match transaction {
+            EnvelopedTransaction::Legacy(legacy) => {
+                assert_eq!(legacy.nonce, U256::from(9u64));
+                assert_eq!(legacy.gas_price, Gas::from(20000000000u64));
+                assert_eq!(legacy.value, U256::from(1000000000000000000u64));
+                assert_eq!(legacy.signature.v, 37);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn decode_access_list() {
+        let transaction = typed(TransactionType::AccessList, None);
+        let bytes = transaction.encode();
+        assert_eq!(bytes[0], 0x01);
+
+        let decoded = decode_transaction(&bytes).unwrap();
+        assert_eq!(decoded.transaction_type(), TransactionType::AccessList);
+        match decoded {
+            EnvelopedTransaction::Typed(decoded) => assert_eq!(decoded, transaction),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn decode_dynamic_fee() {
+        let transaction = typed(TransactionType::DynamicFee, Some(Gas::from(2000000000u64)));
+        let bytes = transaction.encode();
+        assert_eq!(bytes[0], 0x02);
+
+        let decoded = decode_transaction(&bytes).unwrap();
+        assert_eq!(decoded.transaction_type(), TransactionType::DynamicFee);
+        match decoded {
+            EnvelopedTransaction::Typed(decoded) => assert_eq!(decoded, transaction),
+            _ => panic!(),
+        }
+    }
+
+    /// An EIP-2930 transaction signed by the key
+    /// 0x45a915e4d060149eb4365960e6a7a45f334393093061116b197e3240065ff2d8
+    /// for chain 1. Its `s` is 31 bytes long.
+    const SIGNED_ACCESS_LIST: &str = "0x01f8cb01098504a817c80082c350943535353535353535353535353535353535353535880de0b6b3a7640000\
+        82abcdf85bf859943535353535353535353535353535353535353535f842a000000000000000000000000000\
+        00000000000000000000000000000000000000a000000000000000000000000000000000000000000000000000\
+        0000000000000101a02b22efda32491a9e0294339ca3da761f7d36cfc8814c1b29ca731921025ff6959fb1bc\
+        dcf0c74abb1b866d91dd0de58547c2d332bc27eedc1ac9b005659faf0a";
+
+    /// An EIP-1559 transaction signed by the same key for chain 1,
+    /// with a maximum priority fee of 2 gwei and a maximum fee of 20
+    /// gwei. Its `s` is 31 bytes long.
+    const SIGNED_DYNAMIC_FEE: &str = "0x02f8d0010984773594008504a817c80082c350943535353535353535353535353535353535353535880de0\
+        b6b3a764000082abcdf85bf859943535353535353535353535353535353535353535f842a00000000000000000\
+        000000000000000000000000000000000000000000000000a00000000000000000000000000000000000000000\
+        00000000000000000000000180a06d2b085e9e382ed10b69fc311a03f8641ccfff21574de0927513a49d9a68\
+        8a009f62006b7b0f08140f03901821851e31f964ba44170d8288bbd3d1ccd4700a12";
+
+    fn sender() -> Address {
+        Address::from_str("0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b").unwrap()
+    }
+
+    /// Check a signed transaction against the fields it was signed
+    /// with.
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn check_signed(hex: &str, transaction_type: TransactionType, r: &str, s: &str) {
+        let bytes = read_hex(hex).unwrap();
+        let decoded = decode_transaction(&bytes).unwrap();
+        assert_eq!(decoded.transaction_type(), transaction_type);
+        assert_eq!(decoded.caller().unwrap(), sender());
+        match decoded {
+            EnvelopedTransaction::Typed(decoded) => {
+                assert_eq!(decoded.chain_id, 1);
+                assert_eq!(decoded.nonce, U256::from(9u64));
+                assert_eq!(decoded.access_list.len(), 1);
+                assert_eq!(decoded.access_list[0].storage_keys.len(), 2);
+                assert_eq!(decoded.r, U256::from_str(r).unwrap());
+                assert_eq!(decoded.s, U256::from_str(s).unwrap());
+                // The encoding is canonical.
+                assert_eq!(decoded.encode(), bytes);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn decode_signed_access_list() {
+        check_signed(SIGNED_ACCESS_LIST, TransactionType::AccessList,
+                     "0x2b22efda32491a9e0294339ca3da761f7d36cfc8814c1b29ca731921025ff695",
+                     "0xb1bcdcf0c74abb1b866d91dd0de58547c2d332bc27eedc1ac9b005659faf0a");
+    }
+
+    #[test]
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn decode_signed_dynamic_fee() {
+        check_signed(SIGNED_DYNAMIC_FEE, TransactionType::DynamicFee,
+                     "0x6d2b085e9e382ed10b69fc311a03f8641ccfff21574de0927513a49d9a688a00",
+                     "0x62006b7b0f08140f03901821851e31f964ba44170d8288bbd3d1ccd4700a12");
+    }
+
+    /// Validate a signed transaction in a block with the given base
+    /// fee, with the sender holding 10 ether.
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn validate<P: Patch>(hex: &str, base_fee: M256) -> Result<ValidTransaction, PreExecutionError> {
+        let transaction = decode_transaction(&read_hex(hex).unwrap()).unwrap();
+        let mut account_state = AccountState::<P::Account>::default();
+        account_state.commit(AccountCommitment::Full {
+            nonce: U256::from(9u64), address: sender(),
+            balance: U256::from(10000000000000000000u64), code: Arc::new(Vec::new()),
+        }).unwrap();
+        let header = HeaderParams { base_fee, ..HeaderParams::default() };
+        ValidTransaction::from_enveloped_transaction::<P>(&transaction, &header, &account_state).unwrap()
+    }
+
+    test_patch! {
+        /// Byzantium patch with the Berlin access lists and the London
+        /// base fee.
+        struct LondonPatch;
+        fn has_access_list() -> bool { true }
+        fn has_base_fee() -> bool { true }
+    }
+
+    #[test]
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn access_list_intrinsic_gas() {
+        let valid = validate::<AccessListPatch>(SIGNED_ACCESS_LIST, M256::zero()).unwrap();
+        assert_eq!(valid.caller, Some(sender()));
+        assert_eq!(valid.gas_price, Gas::from(20000000000u64));
+        assert_eq!(valid.access_list.len(), 1);
+        // The data bytes 0xab 0xcd, and one address with two storage
+        // keys.
+        assert_eq!(valid.intrinsic_gas::<AccessListPatch>(),
+                   Gas::from(21000 + 2 * 68 + 2400 + 2 * 1900usize));
+    }
+
+    #[test]
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn dynamic_fee_gas_price() {
+        // The base fee plus the priority fee.
+        let valid = validate::<LondonPatch>(SIGNED_DYNAMIC_FEE, M256::from(10000000000u64)).unwrap();
+        assert_eq!(valid.gas_price, Gas::from(12000000000u64));
+        // Capped by the maximum fee.
+        let valid = validate::<LondonPatch>(SIGNED_DYNAMIC_FEE, M256::from(19000000000u64)).unwrap();
+        assert_eq!(valid.gas_price, Gas::from(20000000000u64));
+        match validate::<LondonPatch>(SIGNED_DYNAMIC_FEE, M256::from(30000000000u64)) {
+            Err(PreExecutionError::InsufficientGasPrice) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    fn typed_transactions_need_fork() {
+        match validate::<EmbeddedByzantiumPatch>(SIGNED_ACCESS_LIST, M256::zero()) {
+            Err(PreExecutionError::UnsupportedTransactionType) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(validate::<LondonPatch>(SIGNED_ACCESS_LIST, M256::zero()).is_ok());
+
+        match validate::<AccessListPatch>(SIGNED_DYNAMIC_FEE, M256::from(10000000000u64)) {
+            Err(PreExecutionError::UnsupportedTransactionType) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(validate::<LondonPatch>(SIGNED_DYNAMIC_FEE, M256::from(10000000000u64)).is_ok());
+    }
+
+    #[test]
+    fn signing_hash_excludes_signature() {
+        let transaction = typed(TransactionType::DynamicFee, Some(Gas::from(2000000000u64)));
+        let mut other = transaction.clone();
+        other.y_parity = 0;
+        other.r = U256::zero();
+        assert_eq!(transaction.signing_hash(), other.signing_hash());
+
+        other.chain_id = 2;
+        assert!(transaction.signing_hash() != other.signing_hash());
+    }
+
+    #[test]
+    fn decode_malformed() {
+        let mut bytes = typed(TransactionType::DynamicFee, Some(Gas::zero())).encode();
+        bytes[0] = 0x03;
+        assert!(decode_transaction(&bytes).is_err());
+
+        // An access list payload under the dynamic fee type.
+        let mut bytes = typed(TransactionType::AccessList, None).encode();
+        bytes[0] = 0x02;
+        assert!(decode_transaction(&bytes).is_err());
+
+        assert!(decode_transaction(&[]).is_err());
+    }
+
+    #[test]
+    fn decode_trailing_bytes() {
+        let mut bytes = read_hex("0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83").unwrap();
+        bytes.push(0x00);
+        assert_eq!(decode_transaction(&bytes).err(), Some(DecoderError::RlpIsTooBig));
+
+        let mut bytes = typed(TransactionType::DynamicFee, Some(Gas::zero())).encode();
+        bytes.push(0x00);
+        assert_eq!(decode_transaction(&bytes).err(), Some(DecoderError::RlpIsTooBig));
+    }
+}
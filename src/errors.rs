@@ -1,5 +1,9 @@
 //! VM errors
 
+#[cfg(feature = "std")] use std::fmt;
+#[cfg(not(feature = "std"))] use core::fmt;
+#[cfg(feature = "std")] use std::error::Error;
+
 use bigint::{Address, U256};
 
 #[derive(Debug, Clone)]
@@ -13,19 +17,48 @@ pub enum PreExecutionError {
     InsufficientBalance,
     /// Gas limit is smaller than the intrinsic gas required.
     InsufficientGasLimit,
+    /// Maximum fee per gas is smaller than the base fee of the block
+    /// or the maximum priority fee per gas.
+    InsufficientGasPrice,
+    /// The transaction type is not enabled by the patch, such as an
+    /// access list transaction before Berlin.
+    UnsupportedTransactionType,
 }
 
+impl fmt::Display for PreExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PreExecutionError::InvalidCaller => write!(f, "invalid caller"),
+            PreExecutionError::InvalidNonce => write!(f, "invalid nonce"),
+            PreExecutionError::InsufficientBalance => write!(f, "insufficient balance"),
+            PreExecutionError::InsufficientGasLimit => write!(f, "gas limit is smaller than the intrinsic gas"),
+            PreExecutionError::InsufficientGasPrice => write!(f, "max fee per gas is smaller than the base fee or the priority fee"),
+            PreExecutionError::UnsupportedTransactionType => write!(f, "transaction type is not supported"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for PreExecutionError { }
+
 #[derive(Debug, Clone)]
 /// Errors that can be written on chain.
 pub enum OnChainError {
-    /// Stack is overflowed (pushed more than 1024 items to the
-    /// stack).
+    /// Stack is overflowed (pushed more items than the stack limit,
+    /// usually 1024, to the stack).
     StackOverflow,
     /// Stack is underflowed (poped an empty stack).
     StackUnderflow,
     /// The opcode is invalid and the PC is not able to convert it to
-    /// an instruction.
-    InvalidOpcode,
+    /// an instruction. It is either never defined, or the designated
+    /// INVALID opcode `0xfe`. Carries the offending byte.
+    InvalidOpcode(u8),
+    /// The opcode is defined, but not enabled by the patch, such as
+    /// REVERT before Byzantium. Carries the offending byte.
+    UnsupportedOpcode(u8),
+    /// The opcode is forbidden by `Patch::forbidden_opcodes`. Carries
+    /// the offending byte.
+    ForbiddenOpcode(u8),
     /// PC jumped to an invalid jump destination.
     BadJumpDest,
     /// PC overflowed (tries to read the next opcode which is already
@@ -41,6 +74,79 @@ pub enum OnChainError {
     NotStatic,
     /// Invoked by REVERT opcode.
     Revert,
+    /// The address to create already has code or a nonce (EIP-684).
+    CreateCollision,
+    /// A RETURN or REVERT in init code exceeds
+    /// `Patch::init_code_output_limit`.
+    OutputTooLarge,
+}
+
+impl fmt::Display for OnChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OnChainError::StackOverflow => write!(f, "stack overflow"),
+            OnChainError::StackUnderflow => write!(f, "stack underflow"),
+            OnChainError::InvalidOpcode(byte) => write!(f, "invalid opcode 0x{:02x}", byte),
+            OnChainError::UnsupportedOpcode(byte) => write!(f, "opcode 0x{:02x} is not enabled", byte),
+            OnChainError::ForbiddenOpcode(byte) => write!(f, "forbidden opcode 0x{:02x}", byte),
+            OnChainError::BadJumpDest => write!(f, "bad jump destination"),
+            OnChainError::PCOverflow => write!(f, "program counter overflow"),
+            OnChainError::EmptyGas => write!(f, "out of gas"),
+            OnChainError::InvalidRange => write!(f, "invalid range"),
+            OnChainError::NotStatic => write!(f, "state mutation in a static context"),
+            OnChainError::Revert => write!(f, "reverted"),
+            OnChainError::CreateCollision => write!(f, "contract address collision"),
+            OnChainError::OutputTooLarge => write!(f, "init code output too large"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for OnChainError { }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A coarse classification of on-chain errors, stable across the
+/// finer variants of `OnChainError`.
+pub enum ErrorKind {
+    /// Not enough gas.
+    OutOfGas,
+    /// Invoked by REVERT opcode.
+    Revert,
+    /// Invalid, not enabled or forbidden opcode.
+    InvalidOpcode,
+    /// Reading past the end of the code.
+    PCOverflow,
+    /// A memory or data range of an instruction is invalid.
+    InvalidRange,
+    /// Stack overflow or underflow.
+    StackError,
+    /// Jump to an invalid jump destination.
+    BadJumpDest,
+    /// State mutation in a static context.
+    WriteProtection,
+    /// Creation at an address that already has code or a nonce.
+    CreateCollision,
+    /// Init code output over the limit of the patch.
+    OutputTooLarge,
+}
+
+impl OnChainError {
+    /// Returns the kind of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            OnChainError::StackOverflow | OnChainError::StackUnderflow => ErrorKind::StackError,
+            OnChainError::InvalidOpcode(_) | OnChainError::UnsupportedOpcode(_) |
+            OnChainError::ForbiddenOpcode(_) => ErrorKind::InvalidOpcode,
+            OnChainError::PCOverflow => ErrorKind::PCOverflow,
+            OnChainError::BadJumpDest => ErrorKind::BadJumpDest,
+            OnChainError::EmptyGas => ErrorKind::OutOfGas,
+            OnChainError::InvalidRange => ErrorKind::InvalidRange,
+            OnChainError::NotStatic => ErrorKind::WriteProtection,
+            OnChainError::Revert => ErrorKind::Revert,
+            OnChainError::CreateCollision => ErrorKind::CreateCollision,
+            OnChainError::OutputTooLarge => ErrorKind::OutputTooLarge,
+        }
+    }
 }
 
 impl From<OnChainError> for RuntimeError {
@@ -70,8 +176,24 @@ pub enum NotSupportedError {
     MemoryIndexNotSupported,
     /// A particular precompiled contract is not supported.
     PrecompiledNotSupported,
+    /// The behavior is known to differ between implementations, and
+    /// the patch is strict.
+    AmbiguousBehavior,
 }
 
+impl fmt::Display for NotSupportedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotSupportedError::MemoryIndexNotSupported => write!(f, "memory index not supported"),
+            NotSupportedError::PrecompiledNotSupported => write!(f, "precompiled contract not supported"),
+            NotSupportedError::AmbiguousBehavior => write!(f, "behavior differs between implementations"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for NotSupportedError { }
+
 impl From<NotSupportedError> for RuntimeError {
     fn from(val: NotSupportedError) -> RuntimeError {
         RuntimeError::NotSupported(val)
@@ -94,6 +216,18 @@ pub enum RuntimeError {
     NotSupported(NotSupportedError),
 }
 
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::OnChain(ref err) => write!(f, "{}", err),
+            RuntimeError::NotSupported(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for RuntimeError { }
+
 impl From<RuntimeError> for EvalError {
     fn from(val: RuntimeError) -> EvalError {
         match val {
@@ -115,6 +249,18 @@ pub enum EvalOnChainError {
     Require(RequireError),
 }
 
+impl fmt::Display for EvalOnChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvalOnChainError::OnChain(ref err) => write!(f, "{}", err),
+            EvalOnChainError::Require(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for EvalOnChainError { }
+
 impl From<EvalOnChainError> for EvalError {
     fn from(val: EvalOnChainError) -> EvalError {
         match val {
@@ -137,7 +283,20 @@ pub enum EvalError {
     Require(RequireError),
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvalError::OnChain(ref err) => write!(f, "{}", err),
+            EvalError::NotSupported(ref err) => write!(f, "{}", err),
+            EvalError::Require(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for EvalError { }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// Errors stating that the VM requires additional information to
 /// continue running.
 pub enum RequireError {
@@ -159,6 +318,21 @@ pub enum RequireError {
     Blockhash(U256),
 }
 
+impl fmt::Display for RequireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequireError::Account(address) => write!(f, "requires account 0x{:x}", address),
+            RequireError::AccountCode(address) => write!(f, "requires code of account 0x{:x}", address),
+            RequireError::AccountStorage(address, index) =>
+                write!(f, "requires storage {} of account 0x{:x}", index, address),
+            RequireError::Blockhash(number) => write!(f, "requires hash of block {}", number),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for RequireError { }
+
 impl From<RequireError> for EvalError {
     fn from(val: RequireError) -> EvalError {
         EvalError::Require(val)
@@ -171,11 +345,204 @@ impl From<RequireError> for EvalOnChainError {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Reasons for a CALL, CALLCODE, DELEGATECALL, STATICCALL or CREATE
+/// to fail without an error. The instruction pushes zero and the
+/// caller continues running.
+pub enum CallFailure {
+    /// The call stack limit of the patch is reached.
+    CallStackTooDeep,
+    /// The caller balance is lower than the value to transfer.
+    InsufficientBalance,
+}
+
+impl fmt::Display for CallFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallFailure::CallStackTooDeep => write!(f, "call stack too deep"),
+            CallFailure::InsufficientBalance => write!(f, "insufficient balance"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// Errors returned when committing a new information.
 pub enum CommitError {
-    /// The commitment is invalid.
-    InvalidCommitment,
-    /// The commitment has already been committed.
-    AlreadyCommitted,
+    /// The commitment is invalid for the account, such as storage of
+    /// an account that is not committed or whose storage is complete.
+    InvalidCommitment(Address),
+    /// The account has already been committed, or was created or
+    /// found nonexistent.
+    AlreadyCommitted(Address),
+    /// The commitment conflicts with the committed data of the
+    /// account.
+    Conflict(Address),
+    /// The commitment conflicts with the committed value of a storage
+    /// index of the account.
+    StorageConflict(Address, U256),
+    /// The hash of the block number has already been committed.
+    BlockhashAlreadyCommitted(U256),
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommitError::InvalidCommitment(address) =>
+                write!(f, "invalid commitment for account 0x{:x}", address),
+            CommitError::AlreadyCommitted(address) =>
+                write!(f, "account 0x{:x} already committed", address),
+            CommitError::Conflict(address) =>
+                write!(f, "commitment conflicts with committed data of account 0x{:x}", address),
+            CommitError::StorageConflict(address, index) =>
+                write!(f, "commitment conflicts with committed storage {} of account 0x{:x}", index, address),
+            CommitError::BlockhashAlreadyCommitted(number) =>
+                write!(f, "hash of block {} already committed", number),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for CommitError { }
+
+#[derive(Debug, Clone)]
+/// Errors returned when subtracting from an account balance.
+pub enum BalanceError {
+    /// Requires the account to compute the new balance.
+    Require(RequireError),
+    /// The balance is lower than the amount subtracted.
+    Underflow,
+}
+
+impl fmt::Display for BalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalanceError::Require(ref err) => write!(f, "{}", err),
+            BalanceError::Underflow => write!(f, "balance underflow"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for BalanceError { }
+
+impl From<RequireError> for BalanceError {
+    fn from(val: RequireError) -> BalanceError {
+        BalanceError::Require(val)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Errors returned when parsing a hex string.
+pub enum ParseHexError {
+    /// The hex string has an odd number of digits.
+    OddLength,
+    /// The hex string contains a character that is not a hex digit.
+    InvalidCharacter,
+}
+
+impl fmt::Display for ParseHexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseHexError::OddLength => write!(f, "odd number of hex digits"),
+            ParseHexError::InvalidCharacter => write!(f, "invalid hex character"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for ParseHexError { }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Errors returned when stepping a runtime back.
+pub enum DebugError {
+    /// No instruction is recorded to undo, either because the
+    /// history is disabled or because it is used up.
+    EmptyHistory,
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DebugError::EmptyHistory => write!(f, "no recorded instruction to undo"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for DebugError { }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Errors returned by the static analysis of bytecode.
+pub enum AnalysisError {
+    /// The stack could exceed its limit at the instruction of the
+    /// given code offset.
+    StackOverflow(usize),
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AnalysisError::StackOverflow(position) =>
+                write!(f, "stack could overflow at offset {}", position),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for AnalysisError { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::{VM, SeqContextVM, AccountCommitment, EmbeddedByzantiumPatch};
+    use testing::{context, header};
+    use std::str::FromStr;
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", OnChainError::BadJumpDest), "bad jump destination");
+        assert_eq!(format!("{}", OnChainError::InvalidOpcode(0xfe)), "invalid opcode 0xfe");
+        assert_eq!(format!("{}", OnChainError::UnsupportedOpcode(0xfd)), "opcode 0xfd is not enabled");
+        assert_eq!(format!("{}", OnChainError::ForbiddenOpcode(0x55)), "forbidden opcode 0x55");
+        assert_eq!(format!("{}", EvalError::from(OnChainError::InvalidOpcode(0x0c))), "invalid opcode 0x0c");
+
+        let address = Address::from_str("0x1000000000000000000000000000000000000001").unwrap();
+        assert_eq!(format!("{}", RequireError::AccountStorage(address, U256::from(42u64))),
+                   "requires storage 42 of account 0x1000000000000000000000000000000000000001");
+        assert_eq!(format!("{}", BalanceError::Underflow), "balance underflow");
+    }
+
+    #[test]
+    fn error_kinds() {
+        let cases = [
+            ("00", false, None),
+            ("5b600056", false, Some(ErrorKind::OutOfGas)),
+            ("60006000fd", false, Some(ErrorKind::Revert)),
+            ("fe", false, Some(ErrorKind::InvalidOpcode)),
+            ("01", false, Some(ErrorKind::StackError)),
+            ("600356", false, Some(ErrorKind::BadJumpDest)),
+            ("60006000a0", true, Some(ErrorKind::WriteProtection)),
+        ];
+        for &(code, is_static, kind) in &cases {
+            let mut context = context(Address::default(), code);
+            context.is_static = is_static;
+            let code = context.code.clone();
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header());
+            loop {
+                match vm.fire() {
+                    Ok(()) => break,
+                    Err(RequireError::Account(address)) => vm.commit_account(AccountCommitment::Full {
+                        nonce: U256::zero(), address, balance: U256::zero(), code: code.clone(),
+                    }).unwrap(),
+                    Err(err) => panic!("unexpected require {:?}", err),
+                }
+            }
+            let status = vm.status();
+            assert_eq!(status.is_success(), kind.is_none());
+            assert_eq!(status.error_kind(), kind);
+        }
+
+        assert_eq!(OnChainError::PCOverflow.kind(), ErrorKind::PCOverflow);
+        assert_eq!(OnChainError::InvalidRange.kind(), ErrorKind::InvalidRange);
+    }
 }
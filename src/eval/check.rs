@@ -2,11 +2,27 @@
 
 use bigint::{U256, M256, Gas};
 
-use ::{Memory, Instruction, Patch};
+use ::{Memory, Instruction, Patch, Opcode};
 use errors::{OnChainError, NotSupportedError, EvalOnChainError};
 use eval::{State, Runtime, ControlCheck};
 
 use super::util::check_range;
+use super::cost::G_CALLSTIPEND;
+
+/// Largest memory size, in words, that implementations agree on. Geth
+/// fails larger sizes as a gas overflow even if the gas is there.
+const STRICT_MEMORY_WORDS: u64 = 0xffffffff;
+
+/// Check behavior known to differ between implementations, if the
+/// patch is strict. `memory_cost` is the memory size in words after
+/// the opcode.
+pub fn check_strict<P: Patch>(memory_cost: Gas) -> Result<(), NotSupportedError> {
+    if P::strict() && memory_cost > Gas::from(STRICT_MEMORY_WORDS) {
+        Err(NotSupportedError::AmbiguousBehavior)
+    } else {
+        Ok(())
+    }
+}
 
 #[allow(unused_variables)]
 pub fn extra_check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, state: &State<M, P>, stipend_gas: Gas, after_gas: Gas) -> Result<(), OnChainError> {
@@ -18,6 +34,15 @@ pub fn extra_check_opcode<M: Memory + Default, P: Patch>(instruction: Instructio
                 Ok(())
             }
         },
+        // Under EIP-2200, SSTORE fails if no more than the call
+        // stipend is left, so that the stipend cannot change storage.
+        Instruction::SSTORE if P::has_net_gas_metering() => {
+            if state.available_gas() <= Gas::from(G_CALLSTIPEND) {
+                Err(OnChainError::EmptyGas)
+            } else {
+                Ok(())
+            }
+        },
         _ => Ok(())
     }
 }
@@ -47,6 +72,11 @@ pub fn check_support<M: Memory + Default, P: Patch>(instruction: Instruction, st
                 state.stack.peek(1).unwrap().into(), state.stack.peek(3).unwrap().into())?;
             Ok(())
         },
+        Instruction::RETURNDATACOPY => {
+            state.memory.check_write_range(
+                state.stack.peek(0).unwrap().into(), state.stack.peek(2).unwrap().into())?;
+            Ok(())
+        },
         Instruction::CALL => {
             state.memory.check_write_range(
                 state.stack.peek(5).unwrap().into(), state.stack.peek(6).unwrap().into())?;
@@ -93,7 +123,10 @@ pub fn check_static<M: Memory + Default, P: Patch>(instruction: Instruction, sta
         Instruction::OR |
         Instruction::XOR |
         Instruction::NOT |
-        Instruction::BYTE => Ok(()),
+        Instruction::BYTE |
+        Instruction::SHL |
+        Instruction::SHR |
+        Instruction::SAR => Ok(()),
 
         Instruction::SHA3 => Ok(()),
 
@@ -111,14 +144,16 @@ pub fn check_static<M: Memory + Default, P: Patch>(instruction: Instruction, sta
         Instruction::EXTCODESIZE |
         Instruction::EXTCODECOPY |
         Instruction::RETURNDATASIZE |
-        Instruction::RETURNDATACOPY => Ok(()),
+        Instruction::RETURNDATACOPY |
+        Instruction::EXTCODEHASH => Ok(()),
 
         Instruction::BLOCKHASH |
         Instruction::COINBASE |
         Instruction::TIMESTAMP |
         Instruction::NUMBER |
         Instruction::DIFFICULTY |
-        Instruction::GASLIMIT => Ok(()),
+        Instruction::GASLIMIT |
+        Instruction::BASEFEE => Ok(()),
 
         Instruction::POP |
         Instruction::MLOAD |
@@ -162,6 +197,11 @@ pub fn check_static<M: Memory + Default, P: Patch>(instruction: Instruction, sta
 /// Check whether `run_opcode` would fail without mutating any of the
 /// machine state.
 pub fn check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, state: &State<M, P>, runtime: &Runtime) -> Result<Option<ControlCheck>, EvalOnChainError> {
+    let opcode = Opcode::from(state.context.code[state.position]);
+    if P::forbidden_opcodes().contains(&opcode) {
+        return Err(OnChainError::ForbiddenOpcode(opcode.into()).into());
+    }
+
     match instruction {
         Instruction::STOP => Ok(None),
         Instruction::ADD => { state.stack.check_pop_push(2, 1)?; Ok(None) },
@@ -187,6 +227,9 @@ pub fn check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, sta
         Instruction::XOR => { state.stack.check_pop_push(2, 1)?; Ok(None) },
         Instruction::NOT => { state.stack.check_pop_push(1, 1)?; Ok(None) },
         Instruction::BYTE => { state.stack.check_pop_push(2, 1)?; Ok(None) },
+        Instruction::SHL => { state.stack.check_pop_push(2, 1)?; Ok(None) },
+        Instruction::SHR => { state.stack.check_pop_push(2, 1)?; Ok(None) },
+        Instruction::SAR => { state.stack.check_pop_push(2, 1)?; Ok(None) },
 
         Instruction::SHA3 => {
             state.stack.check_pop_push(2, 1)?;
@@ -231,21 +274,26 @@ pub fn check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, sta
         Instruction::RETURNDATASIZE => { state.stack.check_pop_push(0, 1)?; Ok(None) },
         Instruction::RETURNDATACOPY => {
             state.stack.check_pop_push(3, 0)?;
-            let start = state.stack.peek(0).unwrap().into();
-            let end = state.stack.peek(2).unwrap().into();
-            check_range(start, end)?;
-            if start + end > U256::from(state.ret.len()) {
+            let data_index: U256 = state.stack.peek(1).unwrap().into();
+            let len: U256 = state.stack.peek(2).unwrap().into();
+            check_range(state.stack.peek(0).unwrap().into(), len)?;
+            if data_index.saturating_add(len) > U256::from(state.ret.len()) {
                 Err(EvalOnChainError::OnChain(OnChainError::InvalidRange))
             } else {
                 Ok(None)
             }
         },
+        Instruction::EXTCODEHASH => {
+            state.stack.check_pop_push(1, 1)?;
+            state.account_state.require(state.stack.peek(0).unwrap().into())?;
+            Ok(None)
+        },
 
         Instruction::BLOCKHASH => {
             state.stack.check_pop_push(1, 1)?;
             let current_number = runtime.block.number;
             let number: U256 = state.stack.peek(0).unwrap().into();
-            if !(number >= current_number || current_number - number > U256::from(256u64)) {
+            if !(number >= current_number || current_number - number > U256::from(P::blockhash_window())) {
                 runtime.blockhash_state.get(number)?;
             }
             Ok(None)
@@ -255,6 +303,7 @@ pub fn check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, sta
         Instruction::NUMBER => { state.stack.check_pop_push(0, 1)?; Ok(None) },
         Instruction::DIFFICULTY => { state.stack.check_pop_push(0, 1)?; Ok(None) },
         Instruction::GASLIMIT => { state.stack.check_pop_push(0, 1)?; Ok(None) },
+        Instruction::BASEFEE => { state.stack.check_pop_push(0, 1)?; Ok(None) },
 
         Instruction::POP => { state.stack.check_pop_push(1, 0)?; Ok(None) },
         Instruction::MLOAD => { state.stack.check_pop_push(1, 1)?; Ok(None) },
@@ -332,7 +381,7 @@ pub fn check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, sta
             check_range(state.stack.peek(3).unwrap().into(), state.stack.peek(4).unwrap().into())?;
             check_range(state.stack.peek(5).unwrap().into(), state.stack.peek(6).unwrap().into())?;
             state.account_state.require(state.context.address)?;
-            state.account_state.require(state.stack.peek(1).unwrap().into())?;
+            state.account_state.require_code(state.stack.peek(1).unwrap().into())?;
             Ok(None)
         },
         Instruction::RETURN => {
@@ -350,7 +399,7 @@ pub fn check_opcode<M: Memory + Default, P: Patch>(instruction: Instruction, sta
             check_range(state.stack.peek(2).unwrap().into(), state.stack.peek(3).unwrap().into())?;
             check_range(state.stack.peek(4).unwrap().into(), state.stack.peek(5).unwrap().into())?;
             state.account_state.require(state.context.address)?;
-            state.account_state.require(state.stack.peek(1).unwrap().into())?;
+            state.account_state.require_code(state.stack.peek(1).unwrap().into())?;
             Ok(None)
         },
         Instruction::SUICIDE => {
@@ -5,11 +5,11 @@ use bigint::{M256, U256, Gas, Address};
 #[cfg(feature = "std")] use std::cmp::max;
 #[cfg(not(feature = "std"))] use core::cmp::max;
 
-use ::{Memory, Instruction, Patch};
+use ::{Memory, Instruction, Patch, AccountPatch, M256Ext};
 use super::State;
 
 const G_ZERO: usize = 0;
-const G_BASE: usize = 2;
+pub const G_BASE: usize = 2;
 const G_VERYLOW: usize = 3;
 const G_LOW: usize = 5;
 const G_MID: usize = 8;
@@ -22,7 +22,7 @@ const R_SUICIDE: usize = 24000;
 const G_CREATE: usize = 32000;
 const G_CODEDEPOSIT: usize = 200;
 const G_CALLVALUE: usize = 9000;
-const G_CALLSTIPEND: usize = 2300;
+pub const G_CALLSTIPEND: usize = 2300;
 const G_NEWACCOUNT: usize = 25000;
 const G_EXP: usize = 10;
 const G_MEMORY: usize = 3;
@@ -33,21 +33,104 @@ const G_SHA3: usize = 30;
 const G_SHA3WORD: usize = 6;
 const G_COPY: usize = 3;
 const G_BLOCKHASH: usize = 20;
+const G_COLDACCOUNTACCESS: usize = 2600;
+const G_WARMACCOUNTACCESS: usize = 100;
+const G_COLDSLOAD: usize = 2100;
+const G_WARMSTORAGEREAD: usize = 100;
 
 fn sstore_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>) -> Gas {
     let index: U256 = machine.stack.peek(0).unwrap().into();
     let value = machine.stack.peek(1).unwrap();
     let address = machine.context.address;
+    let current = machine.account_state.storage_read(address, index).unwrap();
+    let (sload, reset) = sstore_gas::<P>();
+    // After EIP-2929, a cold slot costs extra.
+    let access = if P::has_access_list() {
+        storage_access_cost(machine, index, Gas::zero())
+    } else {
+        Gas::zero()
+    };
+
+    if P::has_net_gas_metering() {
+        let original = machine.account_state.original_storage(address, index).unwrap();
+        return access + if current == value || original != current {
+            sload
+        } else if original == M256::zero() {
+            G_SSET.into()
+        } else {
+            reset
+        };
+    }
 
-    if value != M256::zero() && machine.account_state.storage_read(address, index).unwrap() == M256::zero() {
+    access + if value != M256::zero() && current == M256::zero() {
         G_SSET.into()
     } else {
-        G_SRESET.into()
+        reset
+    }
+}
+
+/// Cost of SSTORE reading the slot, and of resetting a non-zero
+/// slot. After EIP-2929, these are the warm read cost, and the reset
+/// cost without the cold read cost, which is charged separately.
+fn sstore_gas<P: Patch>() -> (Gas, Gas) {
+    if P::has_access_list() {
+        (Gas::from(G_WARMSTORAGEREAD), Gas::from(G_SRESET - G_COLDSLOAD))
+    } else {
+        (P::gas_sload(), Gas::from(G_SRESET))
+    }
+}
+
+/// SSTORE refund under EIP-2200, as the refund added and the refund
+/// removed.
+fn net_sstore_refund<M: Memory + Default, P: Patch>(state: &State<M, P>) -> (Gas, Gas) {
+    let index: U256 = state.stack.peek(0).unwrap().into();
+    let value = state.stack.peek(1).unwrap();
+    let address = state.context.address;
+    let current = state.account_state.storage_read(address, index).unwrap();
+    let original = state.account_state.original_storage(address, index).unwrap();
+
+    if current == value {
+        return (Gas::zero(), Gas::zero());
+    }
+    if original == current {
+        return if original != M256::zero() && value == M256::zero() {
+            (Gas::from(R_SCLEAR), Gas::zero())
+        } else {
+            (Gas::zero(), Gas::zero())
+        };
     }
+
+    let mut refund = Gas::zero();
+    let mut removed = Gas::zero();
+    if original != M256::zero() {
+        if current == M256::zero() {
+            removed = Gas::from(R_SCLEAR);
+        } else if value == M256::zero() {
+            refund = Gas::from(R_SCLEAR);
+        }
+    }
+    if original == value {
+        let (sload, reset) = sstore_gas::<P>();
+        let restored = if original == M256::zero() { Gas::from(G_SSET) } else { reset };
+        refund = refund + restored - sload;
+    }
+    (refund, removed)
 }
 
+/// Cost of the CALL family, as the base cost, the value transfer cost
+/// and the new account cost. The gas given to the sub runtime is not
+/// included, so the L64 rule applies to the gas left after this cost.
 fn call_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, instruction: &Instruction) -> Gas {
-    Gas::from(P::gas_call()) + xfer_cost(machine, instruction) + new_cost(machine, instruction)
+    call_base_cost(machine) + xfer_cost(machine, instruction) + new_cost(machine, instruction)
+}
+
+fn call_base_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>) -> Gas {
+    let address: Address = machine.stack.peek(1).unwrap().into();
+    if P::has_access_list() {
+        access_cost(machine, address, Gas::from(G_WARMACCOUNTACCESS))
+    } else {
+        P::gas_call()
+    }
 }
 
 fn xfer_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, instruction: &Instruction) -> Gas {
@@ -65,7 +148,12 @@ fn xfer_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, instruction:
 
 fn new_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, instruction: &Instruction) -> Gas {
     let address: Address = machine.stack.peek(1).unwrap().into();
-    if (instruction == &Instruction::CALL || instruction == &Instruction::STATICCALL) && !machine.account_state.exists(address).unwrap() {
+    // After EIP-161, the cost only applies when value is transferred.
+    let transfers = P::Account::empty_considered_exists() ||
+        (instruction == &Instruction::CALL && machine.stack.peek(2).unwrap() != M256::zero());
+    if (instruction == &Instruction::CALL || instruction == &Instruction::STATICCALL) && transfers &&
+        !machine.account_state.exists(address).unwrap()
+    {
         Gas::from(G_NEWACCOUNT)
     } else {
         Gas::zero()
@@ -74,10 +162,61 @@ fn new_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, instruction: &
 
 fn suicide_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>) -> Gas {
     let address: Address = machine.stack.peek(0).unwrap().into();
-    Gas::from(P::gas_suicide()) + if !machine.account_state.exists(address).unwrap() {
+    // After EIP-161, the cost only applies when value is transferred.
+    let transfers = P::Account::empty_considered_exists() ||
+        machine.account_state.balance(machine.context.address).unwrap() != U256::zero();
+    let new_account = if transfers && !machine.account_state.exists(address).unwrap() {
         Gas::from(P::gas_suicide_new_account())
     } else {
         Gas::zero()
+    };
+    // After EIP-2929, a cold beneficiary costs extra.
+    let access = if P::has_access_list() {
+        access_cost(machine, address, Gas::zero())
+    } else {
+        Gas::zero()
+    };
+    P::gas_suicide() + new_account + access
+}
+
+/// Cost of accessing `address` after EIP-2929. The first access in
+/// the transaction has the cold cost, and later ones cost `warm`.
+fn access_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, address: Address, warm: Gas) -> Gas {
+    if machine.accessed.contains(&address) {
+        warm
+    } else {
+        Gas::from(G_COLDACCOUNTACCESS)
+    }
+}
+
+/// Cost of accessing the storage at `index` of the current address
+/// after EIP-2929, with the cold cost on the first access as for
+/// `access_cost`.
+fn storage_access_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, index: U256, warm: Gas) -> Gas {
+    if machine.accessed_storage.contains(&(machine.context.address, index)) {
+        warm
+    } else {
+        Gas::from(G_COLDSLOAD)
+    }
+}
+
+/// Cost of BALANCE and the EXTCODE family for the address on top of
+/// the stack, without the copy cost. This is `gas` before EIP-2929.
+fn account_read_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>, gas: Gas) -> Gas {
+    let address: Address = machine.stack.peek(0).unwrap().into();
+    if P::has_access_list() {
+        access_cost(machine, address, Gas::from(G_WARMACCOUNTACCESS))
+    } else {
+        gas
+    }
+}
+
+fn sload_cost<M: Memory + Default, P: Patch>(machine: &State<M, P>) -> Gas {
+    let index: U256 = machine.stack.peek(0).unwrap().into();
+    if P::has_access_list() {
+        storage_access_cost(machine, index, Gas::from(G_WARMSTORAGEREAD))
+    } else {
+        P::gas_sload()
     }
 }
 
@@ -95,6 +234,13 @@ fn memory_expand(current: Gas, from: Gas, len: Gas) -> Gas {
     max(current, new)
 }
 
+/// Calculate the per-word copy cost of the copy opcodes.
+fn copy_gas(len: M256) -> Gas {
+    let wordd = Gas::from(len) / Gas::from(32u64);
+    let wordr = Gas::from(len) % Gas::from(32u64);
+    Gas::from(G_COPY) * if wordr == Gas::zero() { wordd } else { wordd + Gas::from(1u64) }
+}
+
 /// Calculate code deposit cost for a ContractCreation transaction.
 pub fn code_deposit_gas(len: usize) -> Gas {
     Gas::from(G_CODEDEPOSIT) * Gas::from(len)
@@ -140,7 +286,9 @@ pub fn memory_cost<M: Memory + Default, P: Patch>(instruction: Instruction, stat
             let len: U256 = stack.peek(2).unwrap().into();
             memory_expand(current, Gas::from(from), Gas::from(len))
         },
-        Instruction::CALL => {
+        // The memory is expanded once, to the larger extent of the
+        // input and the output.
+        Instruction::CALL | Instruction::CALLCODE => {
             let in_from: U256 = stack.peek(3).unwrap().into();
             let in_len: U256 = stack.peek(4).unwrap().into();
             let out_from: U256 = stack.peek(5).unwrap().into();
@@ -148,6 +296,14 @@ pub fn memory_cost<M: Memory + Default, P: Patch>(instruction: Instruction, stat
             memory_expand(memory_expand(current, Gas::from(in_from), Gas::from(in_len)),
                           Gas::from(out_from), Gas::from(out_len))
         },
+        Instruction::DELEGATECALL | Instruction::STATICCALL => {
+            let in_from: U256 = stack.peek(2).unwrap().into();
+            let in_len: U256 = stack.peek(3).unwrap().into();
+            let out_from: U256 = stack.peek(4).unwrap().into();
+            let out_len: U256 = stack.peek(5).unwrap().into();
+            memory_expand(memory_expand(current, Gas::from(in_from), Gas::from(in_len)),
+                          Gas::from(out_from), Gas::from(out_len))
+        },
         _ => {
             current
         }
@@ -157,6 +313,10 @@ pub fn memory_cost<M: Memory + Default, P: Patch>(instruction: Instruction, stat
 
 /// Calculate the gas cost.
 pub fn gas_cost<M: Memory + Default, P: Patch>(instruction: Instruction, state: &State<M, P>) -> Gas {
+    if let Some(gas) = P::gas_override(state.context.code[state.position].into(), state) {
+        return gas;
+    }
+
     match instruction {
         Instruction::CALL => call_cost::<M, P>(state, &Instruction::CALL),
         Instruction::CALLCODE => call_cost::<M, P>(state, &Instruction::CALLCODE),
@@ -179,29 +339,21 @@ pub fn gas_cost<M: Memory + Default, P: Patch>(instruction: Instruction, state:
 
         Instruction::EXTCODECOPY => {
             let len = state.stack.peek(3).unwrap();
-            let wordd = Gas::from(len) / Gas::from(32u64);
-            let wordr = Gas::from(len) % Gas::from(32u64);
-            (Gas::from(P::gas_extcode()) + Gas::from(G_COPY) * if wordr == Gas::zero() { wordd } else { wordd + Gas::from(1u64) }).into()
+            account_read_cost(state, P::gas_extcode()) + copy_gas(len)
         },
 
         Instruction::CALLDATACOPY | Instruction::CODECOPY | Instruction::RETURNDATACOPY => {
             let len = state.stack.peek(2).unwrap();
-            let wordd = Gas::from(len) / Gas::from(32u64);
-            let wordr = Gas::from(len) % Gas::from(32u64);
-            (Gas::from(G_VERYLOW) + Gas::from(G_COPY) * if wordr == Gas::zero() { wordd } else { wordd + Gas::from(1u64) }).into()
+            Gas::from(G_VERYLOW) + copy_gas(len)
         },
 
         Instruction::EXP => {
-            if state.stack.peek(1).unwrap() == M256::zero() {
-                Gas::from(G_EXP)
-            } else {
-                Gas::from(G_EXP) + Gas::from(P::gas_expbyte()) * (Gas::from(1u64) + Gas::from(state.stack.peek(1).unwrap().log2floor()) / Gas::from(8u64))
-            }
+            Gas::from(G_EXP) + P::gas_expbyte() * Gas::from(state.stack.peek(1).unwrap().byte_len())
         }
 
         Instruction::CREATE => G_CREATE.into(),
         Instruction::JUMPDEST => G_JUMPDEST.into(),
-        Instruction::SLOAD => P::gas_sload(),
+        Instruction::SLOAD => sload_cost(state),
 
         // W_zero
         Instruction::STOP | Instruction::RETURN | Instruction::REVERT
@@ -212,7 +364,7 @@ pub fn gas_cost<M: Memory + Default, P: Patch>(instruction: Instruction, state:
         Instruction::CALLVALUE | Instruction::CALLDATASIZE | Instruction::RETURNDATASIZE |
         Instruction::CODESIZE | Instruction::GASPRICE | Instruction::COINBASE |
         Instruction::TIMESTAMP | Instruction::NUMBER | Instruction::DIFFICULTY |
-        Instruction::GASLIMIT | Instruction::POP | Instruction::PC |
+        Instruction::GASLIMIT | Instruction::BASEFEE | Instruction::POP | Instruction::PC |
         Instruction::MSIZE | Instruction::GAS
             => G_BASE.into(),
 
@@ -220,7 +372,8 @@ pub fn gas_cost<M: Memory + Default, P: Patch>(instruction: Instruction, state:
         Instruction::ADD | Instruction::SUB | Instruction::NOT | Instruction::LT |
         Instruction::GT | Instruction::SLT | Instruction::SGT | Instruction::EQ |
         Instruction::ISZERO | Instruction::AND | Instruction::OR | Instruction::XOR |
-        Instruction::BYTE | Instruction::CALLDATALOAD | Instruction::MLOAD |
+        Instruction::BYTE | Instruction::SHL | Instruction::SHR | Instruction::SAR |
+        Instruction::CALLDATALOAD | Instruction::MLOAD |
         Instruction::MSTORE | Instruction::MSTORE8 | Instruction::PUSH(_) |
         Instruction::DUP(_) | Instruction::SWAP(_)
             => G_VERYLOW.into(),
@@ -238,8 +391,9 @@ pub fn gas_cost<M: Memory + Default, P: Patch>(instruction: Instruction, state:
         Instruction::JUMPI => G_HIGH.into(),
 
         // W_extcode
-        Instruction::EXTCODESIZE => P::gas_extcode(),
-        Instruction::BALANCE => P::gas_balance(),
+        Instruction::EXTCODESIZE => account_read_cost(state, P::gas_extcode()),
+        Instruction::EXTCODEHASH => account_read_cost(state, P::gas_extcodehash()),
+        Instruction::BALANCE => account_read_cost(state, P::gas_balance()),
         Instruction::BLOCKHASH => G_BLOCKHASH.into(),
     }
 }
@@ -263,6 +417,7 @@ pub fn gas_stipend<M: Memory + Default, P: Patch>(instruction: Instruction, stat
 /// Calculate the refunded gas.
 pub fn gas_refund<M: Memory + Default, P: Patch>(instruction: Instruction, state: &State<M, P>) -> Gas {
     match instruction {
+        Instruction::SSTORE if P::has_net_gas_metering() => net_sstore_refund(state).0,
         Instruction::SSTORE => {
             let index: U256 = state.stack.peek(0).unwrap().into();
             let value = state.stack.peek(1).unwrap();
@@ -284,3 +439,140 @@ pub fn gas_refund<M: Memory + Default, P: Patch>(instruction: Instruction, state
         _ => Gas::zero()
     }
 }
+
+/// Calculate the refund removed by an instruction. Under EIP-2200,
+/// SSTORE can take back a refund given earlier in the transaction.
+pub fn gas_refund_removed<M: Memory + Default, P: Patch>(instruction: Instruction, state: &State<M, P>) -> Gas {
+    match instruction {
+        Instruction::SSTORE if P::has_net_gas_metering() => net_sstore_refund(state).1,
+        _ => Gas::zero(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::M256;
+    use super::{gas_cost, memory_cost, memory_gas};
+    use testing::AccessListPatch;
+    use std::collections::HashMap as Map;
+
+    #[test]
+    fn copy_opcodes_charge_per_word() {
+        let mut state = Machine::<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>::new(
+            Context::from_hex_code("00").unwrap(), 1).into_state();
+        // Copy 64 bytes from offset 0 to memory offset 0.
+        state.stack.push(M256::from(64u64)).unwrap();
+        state.stack.push(M256::zero()).unwrap();
+        state.stack.push(M256::zero()).unwrap();
+        for instruction in &[Instruction::CALLDATACOPY, Instruction::CODECOPY, Instruction::RETURNDATACOPY] {
+            assert_eq!(gas_cost(*instruction, &state), Gas::from(3 + 2 * 3u64));
+        }
+
+        // EXTCODECOPY takes the address on top.
+        state.stack.push(M256::zero()).unwrap();
+        assert_eq!(gas_cost(Instruction::EXTCODECOPY, &state), Gas::from(700 + 2 * 3u64));
+    }
+
+    #[test]
+    fn huge_memory_offset_is_not_truncated() {
+        let mut state = Machine::<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>::new(
+            Context::from_hex_code("00").unwrap(), 1).into_state();
+        // MSTORE at 2^64, which is one word when truncated to u64.
+        state.stack.push(M256::zero()).unwrap();
+        state.stack.push(M256::one() << 64).unwrap();
+        assert_eq!(memory_cost(Instruction::MSTORE, &state), Gas::from(1u64 << 59) + Gas::one());
+
+        // MSTORE at 2^256 - 1 saturates, and can never be paid for.
+        state.stack.pop().unwrap();
+        state.stack.push(M256::max_value()).unwrap();
+        let cost = memory_cost(Instruction::MSTORE, &state);
+        assert_eq!(cost, Gas::max_value() / Gas::from(32u64) + Gas::one());
+        assert!(memory_gas(cost) > Gas::from(u64::MAX));
+    }
+
+    #[test]
+    fn call_memory_covers_input_and_output() {
+        // Two words of output at 0x40, past one word of input at 0,
+        // with the gas, the address and for CALL and CALLCODE, the
+        // value on top.
+        let cost = |instruction, value: bool| {
+            let mut state = Machine::<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>::new(
+                Context::from_hex_code("00").unwrap(), 1).into_state();
+            for &item in &[0x40u64, 0x40, 0x20, 0] {
+                state.stack.push(M256::from(item)).unwrap();
+            }
+            if value {
+                state.stack.push(M256::zero()).unwrap();
+            }
+            state.stack.push(M256::zero()).unwrap();
+            state.stack.push(M256::zero()).unwrap();
+            memory_cost(instruction, &state)
+        };
+
+        assert_eq!(cost(Instruction::CALL, true), Gas::from(4u64));
+        assert_eq!(cost(Instruction::CALLCODE, true), Gas::from(4u64));
+        assert_eq!(cost(Instruction::DELEGATECALL, false), Gas::from(4u64));
+        assert_eq!(cost(Instruction::STATICCALL, false), Gas::from(4u64));
+        assert_eq!(memory_gas(Gas::from(4u64)), Gas::from(12u64));
+    }
+
+    test_patch! {
+        /// Byzantium patch where SLOAD costs 1.
+        struct CheapSloadPatch;
+        fn gas_override<M: Memory + Default>(opcode: Opcode, _state: &State<M, Self>) -> Option<Gas> {
+            if opcode == Opcode::SLOAD { Some(Gas::one()) } else { None }
+        }
+    }
+
+    #[test]
+    fn gas_override() {
+        let runtime = Runtime::new(HeaderParams::default());
+        // SLOAD slot 0, STOP.
+        let mut machine = Machine::<SeqMemory<CheapSloadPatch>, CheapSloadPatch>::new(
+            Context::from_hex_code("60005400").unwrap(), 1);
+        machine.commit_account(AccountCommitment::Complete {
+            nonce: U256::zero(), address: Address::default(), balance: U256::zero(),
+            code: machine.state().context.code.clone(), storage: Map::new(),
+        }).unwrap();
+        while let MachineStatus::Running = machine.status() {
+            machine.step(&runtime).unwrap();
+        }
+
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.state().total_used_gas(), Gas::from(3 + 1u64));
+    }
+
+    #[test]
+    fn cold_and_warm_accesses() {
+        let mut machine = Machine::<SeqMemory<AccessListPatch>, AccessListPatch>::new(
+            Context::from_hex_code("00").unwrap(), 1);
+        machine.commit_account(AccountCommitment::Complete {
+            nonce: U256::zero(), address: Address::default(), balance: U256::zero(),
+            code: machine.state().context.code.clone(), storage: Map::new(),
+        }).unwrap();
+        let mut state = machine.into_state();
+        let other = Address::from(M256::from(0x1000u64));
+
+        // BALANCE and EXTCODESIZE of another address.
+        state.stack.push(M256::from(other)).unwrap();
+        assert_eq!(gas_cost(Instruction::BALANCE, &state), Gas::from(2600u64));
+        state.accessed.insert(other);
+        assert_eq!(gas_cost(Instruction::BALANCE, &state), Gas::from(100u64));
+        assert_eq!(gas_cost(Instruction::EXTCODESIZE, &state), Gas::from(100u64));
+
+        // SLOAD slot 1, and SSTORE 1 to it.
+        state.stack.pop().unwrap();
+        state.stack.push(M256::one()).unwrap();
+        assert_eq!(gas_cost(Instruction::SLOAD, &state), Gas::from(2100u64));
+        state.stack.push(M256::one()).unwrap();
+        assert_eq!(gas_cost(Instruction::SSTORE, &state), Gas::from(2100 + 20000u64));
+        state.accessed_storage.insert((Address::default(), U256::one()));
+        assert_eq!(gas_cost(Instruction::SSTORE, &state), Gas::from(20000u64));
+        state.stack.pop().unwrap();
+        assert_eq!(gas_cost(Instruction::SLOAD, &state), Gas::from(100u64));
+    }
+}
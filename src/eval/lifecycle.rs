@@ -3,13 +3,13 @@
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
 use bigint::{U256, M256, Gas, Address};
-use errors::{RequireError, OnChainError};
+use errors::{RequireError, OnChainError, BalanceError};
 use commit::AccountState;
-use ::{Memory, Patch, AccountPatch};
+use ::{Memory, Patch, AccountPatch, AccessListItem};
 use super::{Machine, MachineStatus, GasUsage};
 use super::util::copy_into_memory_apply;
 use super::cost::code_deposit_gas;
@@ -36,26 +36,19 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
 
         if !self.state.context.is_system {
             self.state.account_state.decrease_balance(self.state.context.caller, preclaimed_value);
-            self.state.account_state.decrease_balance(self.state.context.caller, self.state.context.value);
         }
-        self.state.account_state.increase_balance(self.state.context.address, self.state.context.value);
-
-        Ok(())
+        self.transfer_value()
     }
 
-    /// Initialize the runtime as a call from a CALL or CALLCODE opcode.
-    ///
-    /// ### Panic
-    /// Requires caller of the CALL/CALLCODE opcode to be committed.
+    /// Initialize the runtime as a call from a CALL, CALLCODE,
+    /// DELEGATECALL or STATICCALL opcode. The caller is only required
+    /// when value moves, as the inherited caller of a DELEGATECALL may
+    /// never have been committed. The value is transferred within the
+    /// checkpoint of this runtime, so it is reverted together with its
+    /// other changes.
     pub fn invoke_call(&mut self) -> Result<(), RequireError> {
         self.state.account_state.require(self.state.context.address)?;
-
-        if !self.state.context.is_system {
-            self.state.account_state.decrease_balance(self.state.context.caller, self.state.context.value);
-        }
-        self.state.account_state.increase_balance(self.state.context.address, self.state.context.value);
-
-        Ok(())
+        self.transfer_value()
     }
 
     /// Initialize a ContractCreation transaction.
@@ -64,14 +57,30 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
     /// Requires caller of the transaction to be committed.
     pub fn initialize_create(&mut self, preclaimed_value: U256) -> Result<(), RequireError> {
         self.state.account_state.require(self.state.context.address)?;
+        self.state.is_create = true;
 
         if !self.state.context.is_system {
             self.state.account_state.decrease_balance(self.state.context.caller, preclaimed_value);
-            self.state.account_state.decrease_balance(self.state.context.caller, self.state.context.value);
         }
-        self.state.account_state.create(self.state.context.address, self.state.context.value).unwrap();
+        if self.create_collision()? {
+            reset_error_hard!(self, OnChainError::CreateCollision);
+            return Ok(());
+        }
+        self.state.account_state.create(self.state.context.address, U256::zero()).unwrap();
+        self.transfer_value()
+    }
 
-        Ok(())
+    /// Mark the addresses and storage keys of an EIP-2930 access list
+    /// as accessed before the transaction runs.
+    pub fn initialize_access_list(&mut self, access_list: &[AccessListItem]) {
+        if P::has_access_list() {
+            for item in access_list {
+                self.state.accessed.insert(item.address);
+                for key in &item.storage_keys {
+                    self.state.accessed_storage.insert((item.address, U256::from(key)));
+                }
+            }
+        }
     }
 
     /// Initialize the runtime as a call from a CREATE opcode.
@@ -80,19 +89,60 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
     /// Requires caller of the CREATE opcode to be committed.
     pub fn invoke_create(&mut self) -> Result<(), RequireError> {
         self.state.account_state.require(self.state.context.address)?;
+        self.state.is_create = true;
 
-        if !self.state.context.is_system {
-            self.state.account_state.decrease_balance(self.state.context.caller, self.state.context.value);
+        if self.create_collision()? {
+            reset_error_hard!(self, OnChainError::CreateCollision);
+            return Ok(());
         }
-        self.state.account_state.create(self.state.context.address, self.state.context.value).unwrap();
 
-        Ok(())
+        self.state.account_state.create(self.state.context.address, U256::zero()).unwrap();
+        self.transfer_value()
     }
 
-    /// Deposit code for a ContractCreation transaction or a CREATE opcode.
+    /// Move the value of the context from the caller to the context
+    /// address with `AccountState::transfer`. A system call has no
+    /// caller and only credits the value. A call without value only
+    /// touches the address, so the caller is not required, as the
+    /// inherited caller of a DELEGATECALL may never have been
+    /// committed.
+    ///
+    /// ### Panic
+    /// Requires the balance of the caller to cover the value, which
+    /// the transaction validation and the CALL and CREATE opcodes
+    /// check.
+    fn transfer_value(&mut self) -> Result<(), RequireError> {
+        let caller = self.state.context.caller;
+        let address = self.state.context.address;
+        let value = self.state.context.value;
+
+        if self.state.context.is_system || value == U256::zero() {
+            return self.state.account_state.add_balance(address, value);
+        }
+        match self.state.account_state.transfer(caller, address, value) {
+            Ok(()) => Ok(()),
+            Err(BalanceError::Require(err)) => Err(err),
+            Err(BalanceError::Underflow) => panic!(),
+        }
+    }
+
+    /// Returns `true` if the address to create already has code or a
+    /// nonce (EIP-684). Such a creation fails, using all its gas,
+    /// without changing the account. A balance alone is no collision.
+    fn create_collision(&self) -> Result<bool, RequireError> {
+        let address = self.state.context.address;
+        Ok(self.state.account_state.nonce(address)? != P::Account::initial_nonce() ||
+           !self.state.account_state.code(address)?.is_empty())
+    }
+
+    /// Deposit code for a ContractCreation transaction or a CREATE
+    /// opcode. The deposit cost is charged from the gas left to the
+    /// creation, and the creation fails if it is not enough. Nothing is
+    /// deposited if the creation exited with an error.
     pub fn code_deposit(&mut self) {
         match self.status() {
-            MachineStatus::ExitedOk | MachineStatus::ExitedErr(_) => (),
+            MachineStatus::ExitedOk => (),
+            MachineStatus::ExitedErr(_) => return,
             _ => panic!(),
         }
 
@@ -108,7 +158,7 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
             if !P::force_code_deposit() {
                 reset_error_hard!(self, OnChainError::EmptyGas);
             } else {
-                self.state.account_state.code_deposit(self.state.context.address, Rc::new(Vec::new()));
+                self.state.account_state.code_deposit(self.state.context.address, Arc::new(Vec::new()));
             }
         } else {
             self.state.used_gas += deposit_cost;
@@ -186,14 +236,21 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
     /// should have been created by the current runtime's `derive`
     /// function. Depending whether the current runtime is invoking a
     /// ContractCreation or MessageCall instruction, it will apply
-    /// various states back.
-    pub fn apply_sub(&mut self, sub: Machine<M, P>) {
+    /// various states back. If the sub runtime exited with an error,
+    /// its account changes, logs, removed accounts and refunds are discarded,
+    /// while its used gas is still charged.
+    pub fn apply_sub(&mut self, mut sub: Machine<M, P>) {
         #[cfg(feature = "std")]
         use std::mem::swap;
 
         #[cfg(not(feature = "std"))]
         use core::mem::swap;
 
+        self.commitments.append(&mut sub.commitments);
+        // The analyses stay valid whether or not the sub runtime
+        // succeeded.
+        swap(&mut self.state.valids_cache, &mut sub.state.valids_cache);
+
         let mut status = MachineStatus::Running;
         swap(&mut status, &mut self.status);
         match status {
@@ -212,19 +269,30 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
 
         let sub_total_used_gas = sub.state.total_used_gas();
 
-        self.state.logs.append(&mut sub.state.logs);
         self.state.used_gas += sub_total_used_gas;
-        self.state.refunded_gas = self.state.refunded_gas + sub.state.refunded_gas;
-        self.state.ret = sub.state.out.clone();
+        self.state.call_failures.append(&mut sub.state.call_failures);
 
         match sub.status() {
             MachineStatus::ExitedOk => {
+                sub.state.account_state.commit_checkpoint();
                 self.state.account_state = sub.state.account_state;
+                self.state.refunded_gas = sub.state.refunded_gas;
+                self.state.logs.append(&mut sub.state.logs);
                 self.state.removed = sub.state.removed;
+                self.state.accessed = sub.state.accessed;
+                self.state.accessed_storage = sub.state.accessed_storage;
+                self.state.clear_return_data();
             },
-            MachineStatus::ExitedErr(_) => {
+            MachineStatus::ExitedErr(err) => {
+                sub.state.account_state.revert_checkpoint();
+                self.state.account_state = sub.state.account_state;
                 self.state.stack.pop().unwrap();
                 self.state.stack.push(M256::zero()).unwrap();
+                // Only REVERT leaves return data.
+                self.state.ret = match err {
+                    OnChainError::Revert => sub.state.out.clone(),
+                    _ => Arc::new(Vec::new()),
+                };
             },
             _ => panic!(),
         }
@@ -233,25 +301,1285 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
     fn apply_call(&mut self, mut sub: Machine<M, P>, out_start: U256, out_len: U256) {
         let sub_total_used_gas = sub.state.total_used_gas();
 
-        self.state.logs.append(&mut sub.state.logs);
         self.state.used_gas += sub_total_used_gas;
-        self.state.refunded_gas = self.state.refunded_gas + sub.state.refunded_gas;
 
+        // Write at most the output region, without padding it when the
+        // output is shorter. The full output stays as return data.
+        // Only REVERT leaves output on failure, so other errors leave
+        // the region untouched.
         copy_into_memory_apply(&mut self.state.memory, sub.state.out.as_slice(),
                                out_start, out_len);
+        self.state.call_failures.append(&mut sub.state.call_failures);
 
         match sub.status() {
             MachineStatus::ExitedOk => {
+                sub.state.account_state.commit_checkpoint();
                 self.state.account_state = sub.state.account_state;
+                self.state.refunded_gas = sub.state.refunded_gas;
+                self.state.logs.append(&mut sub.state.logs);
                 self.state.removed = sub.state.removed;
-                self.state.ret = Rc::new(Vec::new());
+                self.state.accessed = sub.state.accessed;
+                self.state.accessed_storage = sub.state.accessed_storage;
+                self.state.ret = sub.state.out.clone();
             },
-            MachineStatus::ExitedErr(_) => {
+            MachineStatus::ExitedErr(err) => {
+                sub.state.account_state.revert_checkpoint();
+                self.state.account_state = sub.state.account_state;
                 self.state.stack.pop().unwrap();
                 self.state.stack.push(M256::zero()).unwrap();
-                self.state.ret = sub.state.out.clone();
+                // Only REVERT leaves return data.
+                self.state.ret = match err {
+                    OnChainError::Revert => sub.state.out.clone(),
+                    _ => Arc::new(Vec::new()),
+                };
             },
             _ => panic!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::*;
+    use hexutil::*;
+    use errors::RequireError;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use testing::{context, header, AccessListPatch};
+
+    /// Fire the VM, committing the given accounts (address, balance,
+    /// code) when required. Other accounts are committed as
+    /// nonexistent, and all storage as zero.
+    fn fire<P: Patch>(vm: &mut SeqContextVM<P>, accounts: &[(Address, U256, &str)]) {
+        loop {
+            let address = match vm.fire() {
+                Ok(()) => return,
+                Err(RequireError::Account(address)) => address,
+                Err(RequireError::AccountCode(address)) => address,
+                Err(RequireError::AccountStorage(address, index)) => {
+                    vm.commit_account(AccountCommitment::Storage {
+                        address, index, value: M256::zero(),
+                    }).unwrap();
+                    continue;
+                },
+                Err(RequireError::Blockhash(number)) => {
+                    vm.commit_blockhash(number, H256::default()).unwrap();
+                    continue;
+                },
+            };
+            match accounts.iter().find(|&&(a, _, _)| a == address) {
+                Some(&(address, balance, code)) => {
+                    vm.commit_account(AccountCommitment::Full {
+                        nonce: U256::zero(), address, balance,
+                        code: Arc::new(read_hex(code).unwrap()),
+                    }).unwrap();
+                },
+                None => {
+                    vm.commit_account(AccountCommitment::Nonexist(address)).unwrap();
+                },
+            }
+        }
+    }
+
+    fn caller() -> Address {
+        Address::from_str("0x1000000000000000000000000000000000000000").unwrap()
+    }
+
+    fn callee() -> Address {
+        Address::from_str("0x2000000000000000000000000000000000000000").unwrap()
+    }
+
+    /// CALL the callee with no value and no input or output, and store
+    /// the result in slot 0.
+    const CALL_CALLEE: &str = "60006000600060006000732000000000000000000000000000000000000000620ffffff1600055";
+
+    fn failed_sub_is_rolled_back(callee_code: &str) {
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), CALL_CALLEE), header());
+        fire(&mut vm, &[(caller(), U256::zero(), CALL_CALLEE),
+                        (callee(), U256::zero(), callee_code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::zero());
+        assert_eq!(state.account_state.storage_read(callee(), U256::zero()).unwrap(), M256::zero());
+        assert!(vm.logs().is_empty());
+    }
+
+    #[test]
+    fn invalid_sub_is_rolled_back() {
+        // SSTORE 1 into slot 0, LOG0, then INVALID.
+        failed_sub_is_rolled_back("600160005560006000a0fe");
+    }
+
+    #[test]
+    fn reverted_sub_is_rolled_back() {
+        // SSTORE 1 into slot 0, LOG0, then REVERT.
+        failed_sub_is_rolled_back("600160005560006000a060006000fd");
+    }
+
+    #[test]
+    fn logs_are_in_execution_order() {
+        // LOG0 the byte 1, CALL the callee, and LOG0 the byte 3.
+        let code = concat!("600160005360016000a0",
+                           "60006000600060006000732000000000000000000000000000000000000000620ffffff1",
+                           "600360005360016000a0");
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        // The callee logs the byte 2.
+        fire(&mut vm, &[(caller(), U256::zero(), code),
+                        (callee(), U256::zero(), "600260005360016000a0")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let logs: Vec<(Address, Vec<u8>)> = vm.logs().iter()
+            .map(|log| (log.address, log.data.clone())).collect();
+        assert_eq!(logs, vec![(caller(), vec![1]), (callee(), vec![2]), (caller(), vec![3])]);
+    }
+
+    /// Fill the output region with 0xeeee, CALL the callee, and store
+    /// the output region, the output region after clearing its first
+    /// byte and RETURNDATACOPY, and RETURNDATASIZE.
+    fn failed_call_output(callee_code: &str) -> (M256, M256, M256) {
+        let code = concat!("60ee60005360ee600153",
+                           "6002600060006000600073200000000000000000000000000000000000000061fffff150",
+                           "600051600055", "60006000533d600060003e", "600051600155", "3d60025500");
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code), (callee(), U256::zero(), callee_code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        (state.account_state.storage_read(caller(), U256::zero()).unwrap(),
+         state.account_state.storage_read(caller(), U256::one()).unwrap(),
+         state.account_state.storage_read(caller(), U256::from(2u64)).unwrap())
+    }
+
+    #[test]
+    fn failed_call_output_region() {
+        let word = |a: u8, b: u8| M256::from(a as u64) << 248 | M256::from(b as u64) << 240;
+
+        // A REVERT writes its payload into the output region, but only
+        // up to its length, and leaves it as return data.
+        assert_eq!(failed_call_output("602a60005360016000fd"),
+                   (word(0x2a, 0xee), word(0x2a, 0xee), M256::one()));
+        // Other errors leave the output region untouched, without
+        // return data.
+        assert_eq!(failed_call_output("fe"),
+                   (word(0xee, 0xee), word(0x00, 0xee), M256::zero()));
+    }
+
+    #[test]
+    fn call_with_insufficient_balance() {
+        // CALL the callee with value 1, store the result in slot 0,
+        // and then store 1 in slot 1.
+        let code = "60006000600060006001732000000000000000000000000000000000000000620ffffff16000556001600155";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code),
+                        (callee(), U256::zero(), "6001600055")]);
+
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::zero());
+        assert_eq!(state.account_state.storage_read(caller(), U256::one()).unwrap(), M256::one());
+        // The callee never ran, so its storage was never required.
+        assert!(state.account_state.storage_read(callee(), U256::zero()).is_err());
+        assert_eq!(state.account_state.balance(callee()).unwrap(), U256::zero());
+        // Pushes, the CALL base and value transfer cost minus the
+        // unused stipend, and the two SSTOREs.
+        assert_eq!(vm.used_gas(), Gas::from(21usize + 700 + 9000 - 2300 + 5003 + 20006));
+    }
+
+    fn origin() -> Address {
+        Address::from_str("0x3000000000000000000000000000000000000000").unwrap()
+    }
+
+    #[test]
+    fn delegate_call_preserves_caller_and_value() {
+        // DELEGATECALL the callee with no input or output.
+        let code = "6000600060006000732000000000000000000000000000000000000000620ffffff4";
+        // Store CALLER in slot 0, CALLVALUE in slot 1, and ADDRESS in
+        // slot 2.
+        let library = "33600055346001553060025500";
+        let mut context = context(caller(), code);
+        context.caller = origin();
+        context.value = U256::from(2u64);
+        context.apprent_value = U256::from(2u64);
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header());
+        fire(&mut vm, &[(origin(), U256::zero(), ""),
+                        (caller(), U256::zero(), code),
+                        (callee(), U256::zero(), library)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::from(origin()));
+        assert_eq!(state.account_state.storage_read(caller(), U256::from(1u64)).unwrap(), M256::from(2u64));
+        assert_eq!(state.account_state.storage_read(caller(), U256::from(2u64)).unwrap(), M256::from(caller()));
+        // The library code runs in the caller's storage.
+        assert!(state.account_state.storage_read(callee(), U256::from(2u64)).is_err());
+    }
+
+    #[test]
+    fn value_transfer_chain_conserves_balance() {
+        let third = Address::from_str("0x3000000000000000000000000000000000000000").unwrap();
+        // CALL the callee with value 3, which CALLs the third account
+        // with value 1.
+        let code = "60006000600060006003732000000000000000000000000000000000000000620ffffff100";
+        let callee_code = "60006000600060006001733000000000000000000000000000000000000000620ffffff100";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::from(10u64), code),
+                        (callee(), U256::from(5u64), callee_code),
+                        (third, U256::zero(), "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.balance(caller()).unwrap(), U256::from(7u64));
+        assert_eq!(state.account_state.balance(callee()).unwrap(), U256::from(7u64));
+        assert_eq!(state.account_state.balance(third).unwrap(), U256::one());
+        assert_eq!(state.total_balance(), U256::from(15u64));
+        assert_eq!(state.account_state.balance_tally(), U256::zero());
+    }
+
+    #[test]
+    fn suicide_balance_tally() {
+        // SUICIDE to the callee, then to the caller itself.
+        let tally = |beneficiary: Address| {
+            let code = format!("73{}ff", &to_hex(&beneficiary)[2..]);
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), &code), header());
+            fire(&mut vm, &[(caller(), U256::from(5u64), &code), (callee(), U256::one(), "")]);
+
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            let state = vm.current_state();
+            assert_eq!(state.account_state.balance(caller()).unwrap(), U256::zero());
+            (state.total_balance(), state.account_state.balance_tally())
+        };
+
+        // The balance moves to the beneficiary, unless it is the
+        // contract itself, which burns it.
+        assert_eq!(tally(callee()), (U256::from(6u64), U256::zero()));
+        let (total, burnt) = tally(caller());
+        assert_eq!(total, U256::zero());
+        assert_eq!(burnt.overflowing_add(U256::from(5u64)).0, U256::zero());
+    }
+
+    #[test]
+    fn refunds_of_failed_sub_calls_dropped() {
+        let static_call = "600060006000600073200000000000000000000000000000000000000062\
+                           0ffffffa600055";
+        // Store 1 and then 0 in slot 0, earning a refund, and then STOP
+        // or REVERT.
+        let refund = |code: &str, callee_code: &str| {
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+            fire(&mut vm, &[(caller(), U256::zero(), code),
+                            (callee(), U256::zero(), callee_code)]);
+
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            vm.current_state().refunded_gas
+        };
+
+        assert_eq!(refund(CALL_CALLEE, "6001600055600060005500"), Gas::from(15000usize));
+        assert_eq!(refund(CALL_CALLEE, "6001600055600060005560006000fd"), Gas::zero());
+        // A static frame fails on the SSTORE.
+        assert_eq!(refund(static_call, "6001600055600060005500"), Gas::zero());
+    }
+
+    #[test]
+    fn delegate_call_log_address() {
+        // DELEGATECALL the callee with no input or output.
+        let code = "6000600060006000732000000000000000000000000000000000000000620ffffff4";
+        // LOG0 with no data.
+        let library = "60006000a000";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(Address::default(), U256::zero(), ""),
+                        (caller(), U256::zero(), code),
+                        (callee(), U256::zero(), library)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        // The log belongs to the account whose storage is used.
+        assert_eq!(vm.logs().len(), 1);
+        assert_eq!(vm.logs()[0].address, caller());
+    }
+
+    #[test]
+    fn delegate_call_does_not_require_caller() {
+        // The same DELEGATECALL, into a library that only stops. The
+        // inherited caller moves no value, so it is never required.
+        let code = "6000600060006000732000000000000000000000000000000000000000620ffffff4";
+        let mut context = context(caller(), code);
+        context.caller = origin();
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header());
+        loop {
+            let address = match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) => address,
+                Err(err) => panic!("unexpected require {:?}", err),
+            };
+            assert!(address != origin(), "the inherited caller was required");
+            let code = if address == caller() { code } else { "00" };
+            vm.commit_account(AccountCommitment::Full {
+                nonce: U256::zero(), address, balance: U256::zero(),
+                code: Arc::new(read_hex(code).unwrap()),
+            }).unwrap();
+        }
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn delegate_call_not_enabled() {
+        let code = "6000600060006000732000000000000000000000000000000000000000620ffffff4";
+        let mut vm = SeqContextVM::<VMTestPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::UnsupportedOpcode(0xf4)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn call_code_uses_self_as_caller() {
+        // CALLCODE the callee with value 1 and no input or output.
+        let code = "60006000600060006001732000000000000000000000000000000000000000620ffffff2";
+        // Store CALLER in slot 0, CALLVALUE in slot 1, and 1 in slot 2.
+        let library = "33600055346001556001600255";
+        let mut context = context(caller(), code);
+        context.caller = origin();
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header());
+        fire(&mut vm, &[(origin(), U256::zero(), ""),
+                        (caller(), U256::from(5u64), code),
+                        (callee(), U256::zero(), library)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::from(caller()));
+        assert_eq!(state.account_state.storage_read(caller(), U256::from(1u64)).unwrap(), M256::one());
+        assert_eq!(state.account_state.storage_read(caller(), U256::from(2u64)).unwrap(), M256::one());
+        // The value is transferred to self.
+        assert_eq!(state.account_state.balance(caller()).unwrap(), U256::from(5u64));
+        assert_eq!(state.account_state.balance(callee()).unwrap(), U256::zero());
+        // Pushes, the CALLCODE base and value transfer cost minus the
+        // stipend, CALLER, CALLVALUE, the library pushes and its three
+        // SSTOREs.
+        assert_eq!(vm.used_gas(), Gas::from(21usize + 700 + 9000 - 2300 + 2 + 2 + 12 + 3 * 20000));
+    }
+
+    #[test]
+    fn call_requires_callee_account() {
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), CALL_CALLEE), header());
+        vm.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: caller(), balance: U256::zero(),
+            code: Arc::new(read_hex(CALL_CALLEE).unwrap()),
+        }).unwrap();
+
+        // The gas cost of CALL depends on whether the callee exists, so
+        // the full account is required, not only its code.
+        match vm.fire() {
+            Err(RequireError::Account(address)) => assert_eq!(address, callee()),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn delegate_call_requires_code() {
+        let code = "6000600060006000732000000000000000000000000000000000000000620ffffff4";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        vm.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: caller(), balance: U256::zero(),
+            code: Arc::new(read_hex(code).unwrap()),
+        }).unwrap();
+
+        match vm.fire() {
+            Err(RequireError::AccountCode(address)) => assert_eq!(address, callee()),
+            result => panic!("unexpected result {:?}", result),
+        }
+        vm.commit_account(AccountCommitment::Code {
+            address: callee(),
+            code: Arc::new(read_hex("6001600055").unwrap()),
+        }).unwrap();
+        fire(&mut vm, &[(Address::default(), U256::zero(), "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().account_state.storage_read(caller(), U256::zero()).unwrap(), M256::one());
+    }
+
+    #[test]
+    fn call_codeless_account() {
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), CALL_CALLEE), header());
+        fire(&mut vm, &[(caller(), U256::zero(), CALL_CALLEE),
+                        (callee(), U256::zero(), "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::one());
+        // Pushes, the CALL base cost and the SSTORE. All gas given to
+        // the callee is returned.
+        assert_eq!(vm.used_gas(), Gas::from(21usize + 700 + 20003));
+    }
+
+    #[test]
+    fn reverted_sibling_logs_are_dropped() {
+        let reverter = Address::from_str("0x4000000000000000000000000000000000000000").unwrap();
+        // CALL the callee, then CALL the reverter, with no value and no
+        // input or output.
+        let code = "60006000600060006000732000000000000000000000000000000000000000620ffffff1\
+                    60006000600060006000734000000000000000000000000000000000000000620ffffff1";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        // LOG0, and LOG0 then REVERT.
+        fire(&mut vm, &[(caller(), U256::zero(), code),
+                        (callee(), U256::zero(), "60006000a0"),
+                        (reverter, U256::zero(), "60006000a060006000fd")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.stack.peek(0).unwrap(), M256::zero());
+        assert_eq!(state.stack.peek(1).unwrap(), M256::one());
+        assert_eq!(state.logs().len(), 1);
+        assert_eq!(state.logs()[0].address, callee());
+    }
+
+    test_patch! {
+        /// Byzantium patch where empty accounts are considered
+        /// nonexistent, as in EIP-161.
+        struct EmptyNonexistPatch;
+        type Account = EmbeddedByzantiumAccountPatch;
+    }
+
+    /// CALL the callee with the given value and no input or output,
+    /// and return the used gas.
+    fn call_gas<P: Patch>(value: u8, callee_balance: Option<U256>) -> Gas {
+        let code = format!("600060006000600060{:02x}732000000000000000000000000000000000000000620ffffff1", value);
+        let mut accounts = vec![(caller(), U256::from(10u64), code.as_str())];
+        if let Some(balance) = callee_balance {
+            accounts.push((callee(), balance, ""));
+        }
+        let mut vm = SeqContextVM::<P>::new(context(caller(), &code), header());
+        fire(&mut vm, &accounts);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::one());
+        vm.used_gas()
+    }
+
+    #[test]
+    fn call_with_value_to_new_account() {
+        let existing = call_gas::<EmbeddedByzantiumPatch>(1, Some(U256::from(5u64)));
+        // Pushes, and the CALL base and value transfer cost minus the
+        // unused stipend.
+        assert_eq!(existing, Gas::from(21usize + 700 + 9000 - 2300));
+        assert_eq!(call_gas::<EmbeddedByzantiumPatch>(1, None) - existing, Gas::from(25000usize));
+        assert_eq!(call_gas::<EmbeddedByzantiumPatch>(1, Some(U256::zero())), existing);
+
+        // After EIP-161, empty accounts are new, but only when value is
+        // transferred.
+        assert_eq!(call_gas::<EmptyNonexistPatch>(1, Some(U256::from(5u64))), existing);
+        assert_eq!(call_gas::<EmptyNonexistPatch>(1, Some(U256::zero())) - existing, Gas::from(25000usize));
+        assert_eq!(call_gas::<EmptyNonexistPatch>(0, None), Gas::from(21usize + 700));
+    }
+
+    #[test]
+    fn call_to_dead_account_across_forks() {
+        let base = Gas::from(21usize + 700);
+
+        // Before EIP-161, calling a nonexistent account is charged the
+        // new account cost even without value, while an empty account
+        // exists.
+        assert_eq!(call_gas::<EmbeddedByzantiumPatch>(0, None), base + Gas::from(25000usize));
+        assert_eq!(call_gas::<EmbeddedByzantiumPatch>(0, Some(U256::zero())), base);
+
+        // After EIP-161, nonexistent and empty accounts are both dead,
+        // and are only charged when value is transferred.
+        assert_eq!(call_gas::<EmptyNonexistPatch>(0, None), base);
+        assert_eq!(call_gas::<EmptyNonexistPatch>(0, Some(U256::zero())), base);
+        assert_eq!(call_gas::<EmptyNonexistPatch>(1, None), call_gas::<EmptyNonexistPatch>(1, Some(U256::zero())));
+    }
+
+    /// SUICIDE to the beneficiary from the caller with the given
+    /// balance, and return the gas used before refunds.
+    fn suicide_gas<P: Patch>(beneficiary: Address, beneficiary_balance: Option<U256>, balance: u64) -> Gas {
+        let code = format!("73{}ff", &to_hex(&beneficiary)[2..]);
+        let mut accounts = vec![(caller(), U256::from(balance), code.as_str())];
+        if let Some(beneficiary_balance) = beneficiary_balance {
+            accounts.push((beneficiary, beneficiary_balance, ""));
+        }
+        let mut vm = SeqContextVM::<P>::new(context(caller(), &code), header());
+        fire(&mut vm, &accounts);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        vm.current_state().total_used_gas()
+    }
+
+    #[test]
+    fn suicide_cold_beneficiary() {
+        // The origin is warm from the start, the callee is cold.
+        let warm = Address::default();
+        let five = Some(U256::from(5u64));
+
+        assert_eq!(suicide_gas::<AccessListPatch>(warm, five, 10), Gas::from(3 + 5000usize));
+        assert_eq!(suicide_gas::<AccessListPatch>(callee(), five, 10), Gas::from(3 + 5000 + 2600usize));
+        assert_eq!(suicide_gas::<EmbeddedByzantiumPatch>(callee(), five, 10), Gas::from(3 + 5000usize));
+
+        // Empty beneficiaries are new only when value is transferred.
+        assert_eq!(suicide_gas::<AccessListPatch>(callee(), None, 10),
+                   Gas::from(3 + 5000 + 2600 + 25000usize));
+        assert_eq!(suicide_gas::<AccessListPatch>(callee(), Some(U256::zero()), 10),
+                   Gas::from(3 + 5000 + 2600 + 25000usize));
+        assert_eq!(suicide_gas::<AccessListPatch>(callee(), None, 0), Gas::from(3 + 5000 + 2600usize));
+        assert_eq!(suicide_gas::<EmbeddedByzantiumPatch>(callee(), None, 0),
+                   Gas::from(3 + 5000 + 25000usize));
+    }
+
+    /// CALL the callee the given number of times with 0x1000 gas, no
+    /// value and no input or output, and return the used gas.
+    fn repeated_call_gas<P: Patch>(calls: usize) -> Gas {
+        let code = "60006000600060006000732000000000000000000000000000000000000000611000f1".repeat(calls);
+        let five = U256::from(5u64);
+        let mut vm = SeqContextVM::<P>::new(context(caller(), &code), header());
+        fire(&mut vm, &[(caller(), five, code.as_str()), (callee(), five, "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        vm.used_gas()
+    }
+
+    #[test]
+    fn call_base_cost_across_forks() {
+        assert_eq!(repeated_call_gas::<VMTestPatch>(1), Gas::from(21 + 40usize));
+        assert_eq!(repeated_call_gas::<EmbeddedByzantiumPatch>(1), Gas::from(21 + 700usize));
+        // After EIP-2929, the first CALL is cold and the second warm.
+        assert_eq!(repeated_call_gas::<AccessListPatch>(1), Gas::from(21 + 2600usize));
+        assert_eq!(repeated_call_gas::<AccessListPatch>(2), Gas::from(2 * 21 + 2600 + 100usize));
+    }
+
+    #[test]
+    fn call_with_zero_gas() {
+        // CALL the callee with no gas and no value, and store the
+        // result in slot 0.
+        let code = "6000600060006000600073200000000000000000000000000000000000000060\
+                    00f1600055";
+        let result = |callee_code: &str| {
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+            fire(&mut vm, &[(caller(), U256::zero(), code), (callee(), U256::zero(), callee_code)]);
+
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            (vm.current_state().account_state.storage_read(caller(), U256::zero()).unwrap(), vm.used_gas())
+        };
+
+        // The callee can STOP, but anything costing gas runs out of it.
+        // Only the CALL base cost is charged either way.
+        assert_eq!(result("00"), (M256::one(), Gas::from(21 + 700 + 3 + 20000usize)));
+        assert_eq!(result("600100"), (M256::zero(), Gas::from(21 + 700 + 3 + 5000usize)));
+        assert_eq!(result("5b00"), (M256::zero(), Gas::from(21 + 700 + 3 + 5000usize)));
+    }
+
+    #[test]
+    fn call_gives_l64_of_gas_after_base_cost() {
+        // CALL the callee with all gas, and store GAS in the callee.
+        let code = "60006000600060006000732000000000000000000000000000000000000000620ffffff1";
+        let mut vm = SeqContextVM::<AccessListPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code), (callee(), U256::zero(), "5a600055")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let after_gas = 1000000 - 21 - 2600u64;
+        assert_eq!(vm.current_state().account_state.storage_read(callee(), U256::zero()).unwrap(),
+                   M256::from(after_gas - after_gas / 64 - 2));
+    }
+
+    /// CREATE with the given 6-byte init code, and return the created
+    /// address, the return data size and the used gas.
+    fn create_gas(init: &str) -> (M256, M256, Gas) {
+        let code = format!("65{}6000526006601a6000f03d", init);
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), &code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), &code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        (state.stack.peek(1).unwrap(), state.stack.peek(0).unwrap(), vm.used_gas())
+    }
+
+    #[test]
+    fn create_charges_code_deposit() {
+        let created = M256::from(create_address(caller(), U256::zero()));
+        // Pushes, MSTORE with one word of memory, and CREATE. The
+        // RETURNDATASIZE costs 2 more.
+        let base = 21 + 32000usize;
+
+        // RETURN 16 bytes, with one word of memory, and the deposit.
+        assert_eq!(create_gas("60106000f300"), (created, M256::zero(), Gas::from(base + 9 + 16 * 200 + 2)));
+
+        // RETURN 0x6000 bytes, whose deposit is more than the gas given
+        // to the creation. All that gas is used.
+        let after_gas = 1000000 - base;
+        assert_eq!(create_gas("6160006000f3"),
+                   (M256::zero(), M256::zero(), Gas::from(base + after_gas - after_gas / 64 + 2)));
+
+        // REVERT with 16 bytes, which are not deposited.
+        assert_eq!(create_gas("60106000fd00"), (M256::zero(), M256::from(16u64), Gas::from(base + 9 + 2)));
+    }
+
+    test_patch! {
+        /// Byzantium patch where init code can return at most 0x100 bytes.
+        struct OutputLimitPatch;
+        fn init_code_output_limit() -> Option<usize> { Some(0x100) }
+    }
+
+    #[test]
+    fn init_code_output_limit() {
+        // CREATE with the given 8-byte init code, with enough gas to
+        // expand the memory to 4 GiB.
+        let created = |init: &str| {
+            let code = format!("67{}600052600860186000f000", init);
+            let mut context = context(caller(), &code);
+            context.gas_limit = Gas::from(u64::MAX);
+            let mut vm = SeqContextVM::<OutputLimitPatch>::new(context, header());
+            fire(&mut vm, &[(caller(), U256::zero(), &code)]);
+
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            vm.current_state().stack.peek(0).unwrap()
+        };
+
+        // RETURN 0x100 bytes, then 0xffffffff bytes, then REVERT
+        // 0xffffffff bytes.
+        assert_eq!(created("6101006000f30000"),
+                   M256::from(create_address(caller(), U256::zero())));
+        assert_eq!(created("63ffffffff6000f3"), M256::zero());
+        assert_eq!(created("63ffffffff6000fd"), M256::zero());
+    }
+
+    #[test]
+    fn resume_create_frame() {
+        let created = create_address(caller(), U256::zero());
+        // Init code RETURNing 0x1000 bytes, more than the limit.
+        let mut context = context(created, "6110006000f3");
+        context.caller = caller();
+        let runtime = Runtime::new(header());
+        let mut machine = Machine::<SeqMemory<OutputLimitPatch>, OutputLimitPatch>::new(context, 1);
+        machine.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: caller(), balance: U256::zero(), code: Arc::new(Vec::new()),
+        }).unwrap();
+        machine.commit_account(AccountCommitment::Nonexist(created)).unwrap();
+        machine.initialize_create(U256::zero()).unwrap();
+        machine.step(&runtime).unwrap();
+        machine.step(&runtime).unwrap();
+
+        // The resumed machine still runs init code, so the limit
+        // applies to its RETURN.
+        let status = machine.status();
+        let state = machine.into_state();
+        let position = state.position;
+        let mut resumed = Machine::from_state(state, position, status).unwrap();
+        assert!(resumed.state().is_create);
+        while let MachineStatus::Running = resumed.status() {
+            resumed.step(&runtime).unwrap();
+        }
+        match resumed.status() {
+            MachineStatus::ExitedErr(OnChainError::OutputTooLarge) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    /// CREATE with init code returning 16 bytes, at an address
+    /// committed with the given nonce, balance and code. Return the
+    /// CREATE result, the used gas and the created account.
+    fn create_at(nonce: U256, balance: U256, code: &str) -> (M256, Gas, AccountChange) {
+        let created = create_address(caller(), U256::zero());
+        let init = "6560106000f3006000526006601a6000f0";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), init), header());
+        loop {
+            let address = match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) => address,
+                Err(err) => panic!("unexpected require {:?}", err),
+            };
+            let (nonce, balance, code) = if address == created {
+                (nonce, balance, code)
+            } else {
+                (U256::zero(), U256::zero(), init)
+            };
+            vm.commit_account(AccountCommitment::Full {
+                nonce, address, balance, code: Arc::new(read_hex(code).unwrap()),
+            }).unwrap();
+        }
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let account = vm.accounts().find(|account| account.address() == created).unwrap().clone();
+        (vm.current_state().stack.peek(0).unwrap(), vm.used_gas(), account)
+    }
+
+    #[test]
+    fn create_collision() {
+        let created = create_address(caller(), U256::zero());
+        let base = 21 + 32000usize;
+        let after_gas = 1000000 - base;
+
+        // An address with code or a nonce is a collision, which uses all
+        // the gas given to the creation and leaves the account as is.
+        for &(nonce, code) in &[(U256::zero(), "00"), (U256::one(), "")] {
+            let (result, used_gas, account) = create_at(nonce, U256::one(), code);
+            assert_eq!(result, M256::zero());
+            assert_eq!(used_gas, Gas::from(base + after_gas - after_gas / 64));
+            match account {
+                AccountChange::Full { nonce: n, balance, code: ref c, .. } => {
+                    assert_eq!(n, nonce);
+                    assert_eq!(balance, U256::one());
+                    assert_eq!(c.as_slice(), read_hex(code).unwrap().as_slice());
+                },
+                account => panic!("unexpected account {:?}", account),
+            }
+        }
+
+        // A balance alone is no collision.
+        let (result, used_gas, account) = create_at(U256::zero(), U256::one(), "");
+        assert_eq!(result, M256::from(created));
+        assert_eq!(used_gas, Gas::from(base + 9 + 16 * 200));
+        match account {
+            AccountChange::Create { balance, ref code, .. } => {
+                assert_eq!(balance, U256::one());
+                assert_eq!(code.len(), 16);
+            },
+            account => panic!("unexpected account {:?}", account),
+        }
+    }
+
+    #[test]
+    fn gas_read_before_call_and_in_callee() {
+        // GAS, then CALL the callee with all gas, and store GAS in the
+        // callee.
+        let code = "5a60006000600060006000732000000000000000000000000000000000000000620ffffff1";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code), (callee(), U256::zero(), "5a600055")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        let before = state.stack.peek(1).unwrap().low_u64();
+        let callee_gas = state.account_state.storage_read(callee(), U256::zero()).unwrap().low_u64();
+        // The pushes and the CALL base cost are charged first, the
+        // caller retains 1/64 of the rest, and the callee GAS costs 2.
+        let after_gas = before - 21 - 700;
+        assert_eq!(callee_gas, after_gas - after_gas / 64 - 2);
+    }
+
+    /// CALL the precompiled contract with the given gas and input
+    /// length of zeros, and a word of output. Return the CALL result,
+    /// the return data size and the used gas.
+    fn precompiled_call(precompiled: u8, gas: u32, in_len: u8) -> (M256, M256, Gas) {
+        let code = format!("6020600060{:02x}6000600073{:040x}62{:06x}f13d", in_len, precompiled, gas);
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), &code), header());
+        let address = Address::from(M256::from(precompiled as u64));
+        fire(&mut vm, &[(caller(), U256::zero(), &code), (address, U256::one(), "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        (state.stack.peek(1).unwrap(), state.stack.peek(0).unwrap(), vm.used_gas())
+    }
+
+    #[test]
+    fn failing_precompiled_call() {
+        // ECREC on an invalid signature succeeds with empty output,
+        // and charges its gas. The input is four words of memory.
+        assert_eq!(precompiled_call(0x01, 0x0fffff, 0x80),
+                   (M256::one(), M256::zero(), Gas::from(21 + 700 + 12 + 3000 + 2usize)));
+        // SHA256 with less gas than it needs fails, and consumes all
+        // the gas given to it. The output is one word of memory.
+        assert_eq!(precompiled_call(0x02, 0x10, 0),
+                   (M256::zero(), M256::zero(), Gas::from(21 + 700 + 3 + 0x10 + 2usize)));
+    }
+
+    #[test]
+    fn precompiled_gas_checked_before_running() {
+        // SHA256 of seven words costs 60 + 7 * 12 gas. With one gas
+        // less, it does not run and consumes all the gas given to it,
+        // without output.
+        assert_eq!(precompiled_call(0x02, 143, 0xe0),
+                   (M256::zero(), M256::zero(), Gas::from(21 + 700 + 21 + 143 + 2usize)));
+        assert_eq!(precompiled_call(0x02, 144, 0xe0),
+                   (M256::one(), M256::from(32u64), Gas::from(21 + 700 + 21 + 144 + 2usize)));
+    }
+
+    test_patch! {
+        /// Byzantium patch in strict mode.
+        struct StrictPatch;
+        fn strict() -> bool { true }
+    }
+
+    /// CALL the identity precompiled contract with one byte, and then
+    /// RETURNDATACOPY one byte from offset 1 of its output.
+    fn returndatacopy_past_end<P: Patch>() -> VMStatus {
+        let code = "602a6000536000600060016000600060046200fffff1506001600160003e00";
+        let mut vm = SeqContextVM::<P>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+        vm.status()
+    }
+
+    #[test]
+    fn identity_overlapping_regions() {
+        // Store 0x11223344 at bytes 28..32, CALL the identity
+        // precompiled contract with input at 28 and output at 30, both
+        // 4 bytes, and MLOAD the word at 2.
+        let code = "63112233446000526004601e6004601c600060046200fffff15060025100";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        // The output is the input as it was before the copy.
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(0x112211223344u64));
+    }
+
+    #[test]
+    fn returndatacopy_past_end_of_data() {
+        match returndatacopy_past_end::<EmbeddedByzantiumPatch>() {
+            VMStatus::ExitedErr(OnChainError::InvalidRange) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        // Reading past the end is not ambiguous.
+        match returndatacopy_past_end::<StrictPatch>() {
+            VMStatus::ExitedErr(OnChainError::InvalidRange) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn returndatacopy_to_memory_past_data_length() {
+        // The same CALL, and then RETURNDATACOPY its one byte output to
+        // memory offset 0x20. Only the return data offset and length
+        // are checked against the return data.
+        let code = "602a6000536000600060016000600060046200fffff1506001600060203e00";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().memory.read_raw(U256::from(0x20u64)), 0x2a);
+    }
+
+    #[test]
+    fn strict_memory_growth() {
+        // MSTORE 1 at offset 2^37, past 2^32 words of memory.
+        let code = "6001642000000000525b";
+        let mut rich = context(caller(), code);
+        rich.gas_limit = Gas::from(U256::from(2u64).pow(U256::from(60u64)));
+        let mut vm = SeqContextVM::<StrictPatch>::new(rich, header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedNotSupported(NotSupportedError::AmbiguousBehavior) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().memory.len(), 0);
+
+        // Without the gas to pay for it, every implementation runs out
+        // of gas.
+        let mut vm = SeqContextVM::<StrictPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::EmptyGas) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    test_patch! {
+        /// EIP-161 patch with net gas metering for SSTORE, as in
+        /// EIP-2200.
+        struct NetMeteringPatch;
+        type Account = EmbeddedByzantiumAccountPatch;
+        fn gas_sload() -> Gas { Gas::from(800usize) }
+        fn has_net_gas_metering() -> bool { true }
+    }
+
+    #[test]
+    fn net_metering_against_original_across_revert() {
+        // Store 1 in slot 0, DELEGATECALL the callee, then store 0 in
+        // slot 0.
+        let code = "6001600055\
+                    6000600060006000732000000000000000000000000000000000000000620ffffff450\
+                    6000600055";
+        let mut vm = SeqContextVM::<NetMeteringPatch>::new(context(caller(), code), header());
+        // Store 0 in slot 0, then REVERT. The callee runs with the
+        // transaction caller, which must exist.
+        fire(&mut vm, &[(caller(), U256::zero(), code), (callee(), U256::zero(), "600060005560006000fd"),
+                        (Address::default(), U256::one(), "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::zero());
+        assert_eq!(state.account_state.original_storage(caller(), U256::zero()).unwrap(), M256::zero());
+        // The first SSTORE sets a clean slot. Both later SSTOREs see a
+        // dirty slot, since the revert restores 1 rather than the
+        // original 0.
+        assert_eq!(state.total_used_gas(),
+                   Gas::from(6 + 20000 + 18 + 700 + (6 + 800 + 6) + 2 + 6 + 800usize));
+        // Restoring the original refunds the set cost minus the SLOAD
+        // cost, once. The refund in the reverted frame is dropped.
+        assert_eq!(state.refunded_gas, Gas::from(20000 - 800usize));
+    }
+
+    #[test]
+    fn net_metering_removes_parent_refund() {
+        // Clear slot 0, then DELEGATECALL the callee.
+        let code = "6000600055\
+                    6000600060006000732000000000000000000000000000000000000000620ffffff450";
+        let mut vm = SeqContextVM::<NetMeteringPatch>::new(context(caller(), code), header());
+        vm.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: caller(), balance: U256::zero(),
+            code: Arc::new(read_hex(code).unwrap()),
+        }).unwrap();
+        vm.commit_account(AccountCommitment::Storage {
+            address: caller(), index: U256::zero(), value: M256::one(),
+        }).unwrap();
+        // Store the original 1 back in slot 0.
+        fire(&mut vm, &[(callee(), U256::zero(), "6001600055"), (Address::default(), U256::one(), "")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        // The callee takes back the clear refund the caller was given,
+        // and is refunded the reset cost minus the SLOAD cost. This
+        // needs the callee to start from the caller's refund.
+        assert_eq!(vm.current_state().refunded_gas, Gas::from(5000 - 800usize));
+    }
+
+    #[test]
+    fn sstore_stipend_sentry() {
+        // Store 0 in the clean slot 0, which costs an SLOAD.
+        let code = "6000600055";
+        let run = |gas_limit: usize| {
+            let context = Context { gas_limit: Gas::from(gas_limit), ..context(caller(), code) };
+            let mut vm = SeqContextVM::<NetMeteringPatch>::new(context, header());
+            fire(&mut vm, &[(caller(), U256::zero(), code)]);
+            vm
+        };
+
+        // The pushes leave exactly the stipend.
+        match run(6 + 2300).status() {
+            VMStatus::ExitedErr(OnChainError::EmptyGas) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let vm = run(6 + 2301);
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(6 + 800usize));
+    }
+
+    #[test]
+    fn return_data_kept_until_next_call() {
+        // CALL the callee, ADD, store RETURNDATASIZE in slot 0, CALL an
+        // account without code, and store RETURNDATASIZE in slot 1.
+        let code = concat!("60006000600060006000732000000000000000000000000000000000000000620ffffff150",
+                           "6001600201503d600055",
+                           "60006000600060006000733000000000000000000000000000000000000000620ffffff150",
+                           "3d600155");
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        // RETURN 32 bytes.
+        fire(&mut vm, &[(caller(), U256::zero(), code), (callee(), U256::zero(), "60206000f3")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::from(32u64));
+        assert_eq!(state.account_state.storage_read(caller(), U256::one()).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn return_data_follows_last_call() {
+        // Store RETURNDATASIZE in slot 0, CALL the callee and store it
+        // in slot 1, CREATE with empty init code and store it in slot
+        // 2, CALL the callee again, then CALL it with a value above
+        // the balance and store it in slot 3.
+        let call = "60006000600060006000732000000000000000000000000000000000000000620ffffff150";
+        let code = "3d600055".to_string() + call + "3d600155" + "600060006000f0503d600255" + call +
+            "6000600060006000600173200000000000000000000000000000000000000062\
+            0ffffff1503d600355";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), &code), header());
+        // RETURN 32 bytes.
+        fire(&mut vm, &[(caller(), U256::zero(), &code), (callee(), U256::zero(), "60206000f3")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        let slot = |index: u64| state.account_state.storage_read(caller(), U256::from(index)).unwrap();
+        assert_eq!(slot(0), M256::zero());
+        assert_eq!(slot(1), M256::from(32u64));
+        assert_eq!(slot(2), M256::zero());
+        assert_eq!(slot(3), M256::zero());
+    }
+
+    #[test]
+    fn reverted_call_restores_value() {
+        // CALL the callee with value 3 and no input or output.
+        let code = "600060006000600060037320000000000000000000000000000000000000006\
+                    20ffffff1";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        // Store 1 in slot 0, then REVERT.
+        fire(&mut vm, &[(caller(), U256::from(10u64), code),
+                        (callee(), U256::from(5u64), "600160005560006000fd")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.stack.peek(0).unwrap(), M256::zero());
+        assert_eq!(state.account_state.balance(caller()).unwrap(), U256::from(10u64));
+        assert_eq!(state.account_state.balance(callee()).unwrap(), U256::from(5u64));
+        assert_eq!(state.account_state.storage_read(callee(), U256::zero()).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn value_transferred_once() {
+        // CALL the callee with value 3 and no input or output, POP, and
+        // CREATE with value 2 and empty init code.
+        let code = "60006000600060006003732000000000000000000000000000000000000000620ffffff150\
+                    600060006002f0";
+        let created = create_address(caller(), U256::zero());
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::from(10u64), code),
+                        (callee(), U256::from(5u64), "00")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.stack.peek(0).unwrap(), M256::from(created));
+        assert_eq!(state.account_state.balance(caller()).unwrap(), U256::from(5u64));
+        assert_eq!(state.account_state.balance(callee()).unwrap(), U256::from(8u64));
+        assert_eq!(state.account_state.balance(created).unwrap(), U256::from(2u64));
+    }
+
+    #[test]
+    fn call_to_self_shares_storage() {
+        // With call data, store 0x2a in slot 0. Without, CALL itself
+        // with value 1 and one byte of call data, POP, and copy slot 0
+        // into slot 1.
+        let code = "3615600b57602a600055005b\
+                    60006000600160006001731000000000000000000000000000000000000000620ffffff150\
+                    600054600155";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::from(10u64), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::from(0x2au64));
+        assert_eq!(state.account_state.storage_read(caller(), U256::one()).unwrap(), M256::from(0x2au64));
+        // Value sent to itself does not change the balance.
+        assert_eq!(state.account_state.balance(caller()).unwrap(), U256::from(10u64));
+    }
+
+    #[test]
+    fn call_output_is_clamped() {
+        let call = |prefill: &str, callee_code: &str| {
+            // MSTORE the prefill at 0 and 0x99 at 32, CALL the callee
+            // into 32 bytes of output at 0, POP, RETURNDATASIZE,
+            // STOP.
+            let code = format!("{}600052609960205260206000600060006000\
+                                732000000000000000000000000000000000000000620ffffff1503d00", prefill);
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), &code), header());
+            fire(&mut vm, &[(caller(), U256::zero(), &code), (callee(), U256::zero(), callee_code)]);
+
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            let state = vm.current_state();
+            (state.memory_word(U256::zero()), state.memory_word(U256::from(32u64)), state.stack.peek(0).unwrap())
+        };
+
+        // MSTORE 0x11 at 0 and 0x22 at 32, RETURN 64 bytes. Only 32
+        // bytes are written, but all are return data.
+        let (first, second, size) = call("6000", "6011600052602260205260406000f3");
+        assert_eq!(first, M256::from(0x11u64));
+        assert_eq!(second, M256::from(0x99u64));
+        assert_eq!(size, M256::from(64u64));
+
+        // MSTORE 0x11 at 0, RETURN the last 16 bytes. The rest of the
+        // output region is not padded.
+        let ones = "7f".to_string() + &"ff".repeat(32);
+        let (first, second, size) = call(&ones, "601160005260106010f3");
+        assert_eq!(first, (M256::from(0x11u64) << 128) + (M256::max_value() >> 128));
+        assert_eq!(second, M256::from(0x99u64));
+        assert_eq!(size, M256::from(16u64));
+    }
+
+    #[test]
+    fn call_value_stipend() {
+        // CALL the callee with no gas and value 1 into 32 bytes of
+        // output at 0, POP, and store the output in slot 0.
+        let code = "6020600060006000600173200000000000000000000000000000000000000060\
+                    00f150600051600055";
+        // GAS, MSTORE it at 0, RETURN 32 bytes.
+        let callee_code = "5a60005260206000f3";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::from(10u64), code), (callee(), U256::zero(), callee_code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        // The callee runs with the 2300 stipend, less the GAS cost.
+        let state = vm.current_state();
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::from(2298u64));
+        // Pushes and the output memory, then the CALL base and value
+        // transfer cost minus the stipend, the callee's 17 gas, and
+        // POP, PUSH1, MLOAD, PUSH1 and SSTORE.
+        assert_eq!(state.total_used_gas(),
+                   Gas::from(7 * 3 + 3 + 700 + 9000 - 2300 + 17 + 2 + 3 + 3 + 3 + 20000usize));
+    }
+
+    #[test]
+    fn reverted_middle_frame_is_discarded() {
+        let inner = Address::from_str("0x4000000000000000000000000000000000000000").unwrap();
+        // SSTORE 1 into slot 0, CALL the callee, and store the result
+        // in slot 1.
+        let code = "6001600055\
+                    60006000600060006000732000000000000000000000000000000000000000620ffffff1600155";
+        // SSTORE 2 into slot 0, CALL the inner account, then REVERT.
+        let middle = "6002600055\
+                      60006000600060006000734000000000000000000000000000000000000000620ffffff1\
+                      60006000fd";
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(caller(), code), header());
+        // SSTORE 3 into slot 0.
+        fire(&mut vm, &[(caller(), U256::zero(), code),
+                        (callee(), U256::zero(), middle),
+                        (inner, U256::zero(), "6003600055")]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let state = vm.current_state();
+        assert_eq!(state.account_state.checkpoint_depth(), 0);
+        assert_eq!(state.account_state.storage_read(caller(), U256::zero()).unwrap(), M256::one());
+        assert_eq!(state.account_state.storage_read(caller(), U256::one()).unwrap(), M256::zero());
+        // The storage commitments made by the reverted frames are kept.
+        assert_eq!(state.account_state.storage_read(callee(), U256::zero()).unwrap(), M256::zero());
+        assert_eq!(state.account_state.storage_read(inner, U256::zero()).unwrap(), M256::zero());
+    }
+
+    test_patch! {
+        /// Byzantium patch where calling ECREC costs 100.
+        struct CheapEcrecPatch;
+        fn precompiled_gas_override(address: Address, _data: &[u8]) -> Option<Gas> {
+            if address == EMBEDDED_PRECOMPILEDS[0].0 { Some(Gas::from(100usize)) } else { None }
+        }
+    }
+
+    /// CALL ECREC with no input or output, and return the used gas.
+    fn ecrec_gas<P: Patch>() -> Gas {
+        let code = "600060006000600060007300000000000000000000000000000000000000016200fffff100";
+        let mut vm = SeqContextVM::<P>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::one());
+        vm.used_gas()
+    }
+
+    #[test]
+    fn precompiled_gas_override() {
+        assert_eq!(ecrec_gas::<EmbeddedByzantiumPatch>() - ecrec_gas::<CheapEcrecPatch>(),
+                   Gas::from(3000usize - 100));
+    }
+
+    #[test]
+    fn precompiled_gas_override_keeps_limit() {
+        // CALL ECREC with 2999 gas, less than its own cost of 3000.
+        let code = "60006000600060006000730000000000000000000000000000000000000001610bb7f100";
+        let mut vm = SeqContextVM::<CheapEcrecPatch>::new(context(caller(), code), header());
+        fire(&mut vm, &[(caller(), U256::zero(), code)]);
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::zero());
+    }
+}
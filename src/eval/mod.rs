@@ -2,24 +2,34 @@
 
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
 #[cfg(not(feature = "std"))] use core::ops::AddAssign;
 #[cfg(feature = "std")] use std::ops::AddAssign;
+#[cfg(not(feature = "std"))] use core::cmp::min;
+#[cfg(feature = "std")] use std::cmp::min;
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::collections::HashSet as Set;
+#[cfg(not(feature = "std"))] use alloc::BTreeSet as Set;
+#[cfg(feature = "std")] use std::collections::VecDeque;
+#[cfg(not(feature = "std"))] use alloc::VecDeque;
 
 use bigint::{M256, U256, Gas, Address};
 use super::pc::Instruction;
 use super::commit::{AccountState, BlockhashState};
 use super::errors::{RequireError, RuntimeError, CommitError, EvalOnChainError,
-                    OnChainError, NotSupportedError};
-use super::{Stack, Context, HeaderParams, Patch, PC, PCMut, Valids, Memory,
-            AccountCommitment, Log, Opcode};
+                    OnChainError, NotSupportedError, CallFailure, DebugError};
+use super::{Stack, Context, HeaderParams, Patch, PC, PCMut, Valids, ValidsCache, Memory,
+            AccountCommitment, Log, Opcode, M256Ext, GasExt};
 
-use self::check::{check_opcode, check_static, check_support, extra_check_opcode};
+use self::check::{check_opcode, check_static, check_support, check_strict, extra_check_opcode};
 use self::run::run_opcode;
-use self::cost::{gas_refund, gas_stipend, gas_cost, memory_cost, memory_gas};
+use self::cost::{gas_refund, gas_refund_removed, gas_stipend, gas_cost, memory_cost, memory_gas, G_BASE};
 
 macro_rules! reset_error_hard {
     ($self: expr, $err: expr) => {
@@ -27,7 +37,7 @@ macro_rules! reset_error_hard {
         $self.state.used_gas = GasUsage::All;
         $self.state.refunded_gas = Gas::zero();
         $self.state.logs = Vec::new();
-        $self.state.out = Rc::new(Vec::new());
+        $self.state.out = Arc::new(Vec::new());
     }
 }
 
@@ -43,7 +53,7 @@ macro_rules! reset_error_not_supported {
         $self.state.used_gas = GasUsage::Some(Gas::zero());
         $self.state.refunded_gas = Gas::zero();
         $self.state.logs = Vec::new();
-        $self.state.out = Rc::new(Vec::new());
+        $self.state.out = Arc::new(Vec::new());
     }
 }
 
@@ -81,16 +91,26 @@ pub struct State<M, P: Patch> {
     pub context: Context,
 
     /// The current out value.
-    pub out: Rc<Vec<u8>>,
-    /// Return data buffer.
-    pub ret: Rc<Vec<u8>>,
-
-    /// The current memory cost. Note that this is different from
-    /// memory gas.
+    pub out: Arc<Vec<u8>>,
+    /// Return data buffer. It is empty when the runtime starts, and
+    /// only changes when a CALL or CREATE instruction finishes: a
+    /// call sets it to the output of the sub runtime, and a call or
+    /// create without return data clears it. Other instructions leave
+    /// it untouched.
+    pub ret: Arc<Vec<u8>>,
+
+    /// The current memory cost, as the number of active memory
+    /// words. Note that this is different from memory gas, which is
+    /// computed from it for the whole memory, so it is never charged
+    /// twice.
     pub memory_cost: Gas,
     /// Used gas excluding memory gas.
     pub used_gas: GasUsage,
-    /// Refunded gas.
+    /// Refunded gas so far in the transaction, including the refunds
+    /// of successful parent and sub runtimes. A sub runtime starts
+    /// from its parent's refund, since net gas metering can take back
+    /// a refund the parent was given, and the parent takes the sub
+    /// runtime's refund only if it succeeds.
     pub refunded_gas: Gas,
 
     /// The current account commitment states.
@@ -99,12 +119,30 @@ pub struct State<M, P: Patch> {
     pub logs: Vec<Log>,
     /// All removed accounts using the SUICIDE opcode.
     pub removed: Vec<Address>,
+    /// Calls and creates that failed without an error, with the
+    /// depth of the runtime invoking them. Failures in sub runtimes
+    /// are kept even if those runtimes exit with an error.
+    pub call_failures: Vec<(usize, CallFailure)>,
+    /// Addresses accessed in the current transaction, as defined in
+    /// EIP-2929. Accesses in sub runtimes exiting with an error are
+    /// discarded.
+    pub accessed: Set<Address>,
+    /// Storage slots accessed in the current transaction, by address
+    /// and index, as defined in EIP-2929. Discarded the same way as
+    /// `accessed`.
+    pub accessed_storage: Set<(Address, U256)>,
 
     /// Depth of this runtime.
     pub depth: usize,
+    /// Whether this runtime runs the init code of a contract
+    /// creation.
+    pub is_create: bool,
 
     /// Code valid maps.
     pub valids: Valids,
+    /// Valid maps of the codes run by this runtime, its parents and
+    /// its applied sub runtimes, keyed by code hash.
+    pub valids_cache: ValidsCache,
     /// PC position.
     pub position: usize,
 }
@@ -115,18 +153,86 @@ impl<M, P: Patch> State<M, P> {
         memory_gas(self.memory_cost)
     }
 
-    /// Available gas at this moment.
+    /// Clear the return data buffer, for a CALL or CREATE instruction
+    /// that leaves no return data.
+    pub fn clear_return_data(&mut self) {
+        self.ret = Arc::new(Vec::new());
+    }
+
+    /// Logs appended so far. Logs of failed sub calls are not
+    /// included.
+    pub fn logs(&self) -> &[Log] {
+        self.logs.as_slice()
+    }
+
+    /// Available gas at this moment. This is the value the GAS opcode
+    /// pushes, after its own cost is charged.
     pub fn available_gas(&self) -> Gas {
-        self.context.gas_limit - self.total_used_gas()
+        self.gas_remaining(self.memory_cost, Gas::zero()).unwrap_or(Gas::zero())
     }
 
-    /// Total used gas including the memory gas.
+    /// Gas left after growing the memory to `memory_cost` words and
+    /// paying `gas_cost`, or `None` if that exceeds the gas limit or
+    /// all gas is used. Before an instruction runs, this is the gas
+    /// GAS pushes and CALL and CREATE forward from, and once it is
+    /// charged it equals `available_gas`.
+    pub fn gas_remaining(&self, memory_cost: Gas, gas_cost: Gas) -> Option<Gas> {
+        let used_gas = match self.used_gas {
+            GasUsage::Some(gas) => gas,
+            GasUsage::All => return None,
+        };
+        memory_gas(memory_cost).checked_add(used_gas)
+            .and_then(|gas| gas.checked_add(gas_cost))
+            .and_then(|gas| self.context.gas_limit.checked_sub(gas))
+    }
+
+    /// Total used gas including the memory gas. Refunds are not
+    /// subtracted, see `used_gas_after_refund`.
     pub fn total_used_gas(&self) -> Gas {
         match self.used_gas {
             GasUsage::All => self.context.gas_limit,
             GasUsage::Some(gas) => self.memory_gas() + gas,
         }
     }
+
+    /// Refund applied to the total used gas. This is the refunded gas
+    /// capped at the total used gas divided by
+    /// `Patch::max_refund_quotient`.
+    pub fn applied_refund(&self) -> Gas {
+        self.applied_refund_on(self.total_used_gas())
+    }
+
+    /// Refund applied when `used_gas` is charged in total, such as the
+    /// total used gas plus the intrinsic gas of a transaction.
+    pub fn applied_refund_on(&self, used_gas: Gas) -> Gas {
+        let refund_cap = used_gas / Gas::from(P::max_refund_quotient());
+        min(refund_cap, self.refunded_gas)
+    }
+
+    /// Total used gas with the applied refund subtracted.
+    pub fn used_gas_after_refund(&self) -> Gas {
+        self.total_used_gas() - self.applied_refund()
+    }
+
+    /// Sum of the balances in the account state, see
+    /// `AccountState::total_balance`.
+    pub fn total_balance(&self) -> U256 {
+        self.account_state.total_balance()
+    }
+}
+
+impl<M: Memory, P: Patch> State<M, P> {
+    /// The current memory contents, zero-filled up to the memory size
+    /// already paid for.
+    pub fn memory_dump(&self) -> Vec<u8> {
+        let len = self.memory_cost.as_usize() * 32;
+        (0..len).map(|i| self.memory.read_raw(U256::from(i))).collect()
+    }
+
+    /// Read a memory word at the offset, without expanding memory.
+    pub fn memory_word(&self, offset: U256) -> M256 {
+        self.memory.read(offset)
+    }
 }
 
 /// A VM runtime. Only available in eval.
@@ -138,6 +244,38 @@ pub struct Runtime {
 
     /// Hooks for context history.
     pub context_history_hooks: Vec<Box<Fn(&Context)>>,
+    /// Hooks called after each executed instruction.
+    pub step_hooks: Vec<StepHook>,
+    /// Whether step hooks get the stack before each instruction.
+    pub stack_snapshots: bool,
+    /// Whether step hooks get the memory before each instruction.
+    pub memory_snapshots: bool,
+}
+
+/// A hook called with each executed instruction.
+pub type StepHook = Box<dyn Fn(&StepInfo)>;
+
+#[derive(Debug, Clone)]
+/// Information about an executed instruction, passed to step hooks.
+pub struct StepInfo {
+    /// Position of the instruction in the code.
+    pub position: usize,
+    /// Opcode of the instruction.
+    pub opcode: Opcode,
+    /// Depth of the runtime that executed the instruction.
+    pub depth: usize,
+    /// Available gas before the instruction is executed.
+    pub gas: Gas,
+    /// Gas charged for the instruction, including memory gas. For
+    /// CALL and CREATE opcodes, this does not include the gas used
+    /// by the sub runtime.
+    pub gas_cost: Gas,
+    /// Stack before the instruction is executed, from the bottom, if
+    /// `Runtime::stack_snapshots` is set.
+    pub stack: Option<Vec<M256>>,
+    /// Memory before the instruction is executed, if
+    /// `Runtime::memory_snapshots` is set.
+    pub memory: Option<Vec<u8>>,
 }
 
 impl Runtime {
@@ -152,14 +290,60 @@ impl Runtime {
             block, blockhash_state,
 
             context_history_hooks: Vec::new(),
+            step_hooks: Vec::new(),
+            stack_snapshots: false,
+            memory_snapshots: false,
         }
     }
 }
 
-/// A VM state with PC.
+/// A VM state with PC. A machine can be moved between threads
+/// (it is `Send`) as long as its memory `M` is, which is the case for
+/// `SeqMemory`. The `Runtime` holding hooks is not.
 pub struct Machine<M, P: Patch> {
     state: State<M, P>,
     status: MachineStatus,
+    history: VecDeque<Snapshot<P>>,
+    history_limit: usize,
+    /// Accounts committed while instructions are recorded, committed
+    /// again when `step_back` restores an older account state.
+    commitments: Vec<AccountCommitment>,
+}
+
+/// Account state and removed accounts of a runtime.
+type AccountSnapshot<P> = (AccountState<<P as Patch>::Account>, Vec<Address>);
+
+/// Accessed addresses and storage slots of a runtime.
+type AccessedSnapshot = (Set<Address>, Set<(Address, U256)>);
+
+/// The parts of a runtime an instruction changes, recorded to undo it
+/// with `Machine::step_back`.
+struct Snapshot<P: Patch> {
+    status: MachineStatus,
+    /// Stack length before the instruction, and the items it pops,
+    /// from bottom to top.
+    stack_len: usize,
+    stack: Vec<M256>,
+    /// Offset and old bytes of the memory the instruction writes
+    /// within the old memory size. Memory past it was zero.
+    memory: (usize, Vec<u8>),
+    out: Arc<Vec<u8>>,
+    ret: Arc<Vec<u8>>,
+    memory_cost: Gas,
+    used_gas: GasUsage,
+    refunded_gas: Gas,
+    /// Logs and call failures are only appended to, except that a
+    /// failing instruction clears the logs.
+    logs_len: usize,
+    cleared_logs: Option<Vec<Log>>,
+    call_failures_len: usize,
+    /// Account state and removed accounts, only for the instructions
+    /// that can change them.
+    accounts: Option<AccountSnapshot<P>>,
+    /// Accessed addresses and storage slots, only for the
+    /// instructions that can access new ones.
+    accessed: Option<AccessedSnapshot>,
+    position: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -209,17 +393,37 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
                           AccountState::default())
     }
 
+    /// Addresses accessed before a transaction of the context runs.
+    fn initial_accessed(context: &Context) -> Set<Address> {
+        let mut accessed = Set::new();
+        if P::has_access_list() {
+            // The origin, the called address and the precompiled
+            // contracts are accessed from the start.
+            accessed.insert(context.origin);
+            accessed.insert(context.caller);
+            accessed.insert(context.address);
+            for &(address, _, _) in P::precompileds() {
+                accessed.insert(address);
+            }
+        }
+        accessed
+    }
+
     /// Create a new runtime with the given states.
     pub fn with_states(context: Context,
                        depth: usize, account_state: AccountState<P::Account>) -> Self {
+        let accessed = Self::initial_accessed(&context);
+        let mut valids_cache = ValidsCache::new();
+        let valids = valids_cache.get(context.code.as_slice());
+
         Machine {
             status: MachineStatus::Running,
             state: State {
                 memory: M::default(),
-                stack: Stack::default(),
+                stack: Stack::new(P::stack_limit()),
 
-                out: Rc::new(Vec::new()),
-                ret: Rc::new(Vec::new()),
+                out: Arc::new(Vec::new()),
+                ret: Arc::new(Vec::new()),
 
                 memory_cost: Gas::zero(),
                 used_gas: GasUsage::Some(Gas::zero()),
@@ -228,63 +432,177 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
                 account_state,
                 logs: Vec::new(),
                 removed: Vec::new(),
+                call_failures: Vec::new(),
+                accessed,
+                accessed_storage: Set::new(),
 
                 depth,
+                is_create: false,
                 position: 0,
-                valids: Valids::new(context.code.as_slice()),
+                valids,
+                valids_cache,
 
                 context,
             },
+            history: VecDeque::new(),
+            history_limit: 0,
+            commitments: Vec::new(),
         }
     }
 
+    /// Reset the runtime to run a new context from the start, as a
+    /// new runtime at the same depth would. The account state is kept,
+    /// including changes made by the previous run, so committed
+    /// accounts need not be committed again. The memory and stack are
+    /// cleared but keep their allocations, and the jump destination
+    /// analyses are kept, which is cheaper when running many contexts.
+    pub fn reset(&mut self, context: Context) {
+        self.status = MachineStatus::Running;
+
+        self.state.memory.clear();
+        self.state.stack.clear();
+        self.state.out = Arc::new(Vec::new());
+        self.state.clear_return_data();
+
+        self.state.memory_cost = Gas::zero();
+        self.state.used_gas = GasUsage::Some(Gas::zero());
+        self.state.refunded_gas = Gas::zero();
+
+        self.state.logs.clear();
+        self.state.removed.clear();
+        self.state.call_failures.clear();
+        self.state.accessed = Self::initial_accessed(&context);
+        self.state.accessed_storage.clear();
+
+        self.state.position = 0;
+        self.state.valids = self.state.valids_cache.get(context.code.as_slice());
+        self.state.context = context;
+        self.history.clear();
+        self.commitments.clear();
+        self.state.is_create = false;
+    }
+
+    /// Reconstruct a runtime from a previously captured state, PC
+    /// position and status, so that it can be stepped again. Whether
+    /// it runs init code is carried by the state. Returns `None` if
+    /// the PC position is out of the code bounds.
+    pub fn from_state(mut state: State<M, P>, position: usize, status: MachineStatus) -> Option<Self> {
+        if position > state.context.code.len() {
+            return None;
+        }
+        state.position = position;
+
+        Some(Machine { state, status, history: VecDeque::new(), history_limit: 0, commitments: Vec::new() })
+    }
+
     /// Derive this runtime to create a sub runtime. This will not
     /// modify the current runtime, and it will have a chance to
     /// review whether it wants to accept the result of this sub
-    /// runtime afterwards.
+    /// runtime afterwards. The sub runtime starts with the refunded
+    /// gas of this runtime, see `State::refunded_gas`.
     pub fn derive(&self, context: Context) -> Self {
+        let mut account_state = self.state.account_state.clone();
+        account_state.push_checkpoint();
+        // Calls into code run before, such as recursive or sibling
+        // calls, reuse the jump destination analysis.
+        let mut valids_cache = self.state.valids_cache.clone();
+        let valids = valids_cache.get(context.code.as_slice());
+
         Machine {
             status: MachineStatus::Running,
             state: State {
                 memory: M::default(),
-                stack: Stack::default(),
+                stack: Stack::new(P::stack_limit()),
 
-                out: Rc::new(Vec::new()),
-                ret: Rc::new(Vec::new()),
+                out: Arc::new(Vec::new()),
+                ret: Arc::new(Vec::new()),
 
                 memory_cost: Gas::zero(),
                 used_gas: GasUsage::Some(Gas::zero()),
-                refunded_gas: Gas::zero(),
+                refunded_gas: self.state.refunded_gas,
 
-                account_state: self.state.account_state.clone(),
+                account_state,
                 logs: Vec::new(),
                 removed: self.state.removed.clone(),
+                call_failures: Vec::new(),
+                accessed: self.state.accessed.clone(),
+                accessed_storage: self.state.accessed_storage.clone(),
 
                 depth: self.state.depth + 1,
+                is_create: false,
 
                 position: 0,
-                valids: Valids::new(context.code.as_slice()),
+                valids,
+                valids_cache,
 
                 context,
             },
+            history: VecDeque::new(),
+            history_limit: self.history_limit,
+            commitments: Vec::new(),
         }
     }
 
     /// Commit a new account into this runtime.
     pub fn commit_account(&mut self, commitment: AccountCommitment) -> Result<(), CommitError> {
-        self.state.account_state.commit(commitment)
+        if self.history_limit == 0 {
+            return self.state.account_state.commit(commitment);
+        }
+        self.state.account_state.commit(commitment.clone())?;
+        self.commitments.push(commitment);
+        Ok(())
+    }
+
+    /// Commit only the code of an account into this runtime, the same
+    /// as committing `AccountCommitment::Code`. This is enough for a
+    /// `RequireError::AccountCode`.
+    pub fn commit_code(&mut self, address: Address, code: Vec<u8>) -> Result<(), CommitError> {
+        self.commit_account(AccountCommitment::Code {
+            address,
+            code: Arc::new(code),
+        })
+    }
+
+    /// Commit several accounts into this runtime in order, the same
+    /// as calling `commit_account` for each of them. Stops at the
+    /// first failed commitment, and returns its index with the
+    /// error. Commitments before it are kept.
+    pub fn commit_accounts<I: IntoIterator<Item=AccountCommitment>>(
+        &mut self, commitments: I
+    ) -> Result<(), (usize, CommitError)> {
+        for (index, commitment) in commitments.into_iter().enumerate() {
+            self.commit_account(commitment).map_err(|err| (index, err))?;
+        }
+        Ok(())
     }
 
     /// Step a precompiled runtime. This function returns true if the
     /// runtime is indeed a precompiled address. Otherwise return
     /// false with state unchanged.
+    ///
+    /// A precompiled contract failing with an on-chain error, such as
+    /// running out of gas, consumes all the gas given to it and leaves
+    /// no return data, so the CALL pushes zero. Contracts defining an
+    /// empty output for invalid input, like ECREC on a bad signature,
+    /// succeed with that output and charge their usual gas. The patch
+    /// can replace the cost with `Patch::precompiled_gas_override`.
     pub fn step_precompiled(&mut self) -> bool {
         for precompiled in P::precompileds() {
             if self.state.context.address == precompiled.0 &&
                 (precompiled.1.is_none() || precompiled.1.unwrap() == self.state.context.code.as_slice())
             {
                 let data = &self.state.context.data;
-                match precompiled.2.gas_and_step(data, self.state.context.gas_limit) {
+                let result = match P::precompiled_gas_override(precompiled.0, data) {
+                    Some(gas) if gas > self.state.context.gas_limit =>
+                        Err(RuntimeError::OnChain(OnChainError::EmptyGas)),
+                    // The overridden cost replaces the contract's own
+                    // cost, but the contract still runs within the gas
+                    // limit, which bounds the work it does.
+                    Some(gas) => precompiled.2.gas_and_step(data, self.state.context.gas_limit)
+                        .map(|(_, ret)| (gas, ret)),
+                    None => precompiled.2.gas_and_step(data, self.state.context.gas_limit),
+                };
+                match result {
                     Err(RuntimeError::OnChain(err)) => {
                         reset_error_hard!(self, err);
                     },
@@ -324,18 +642,213 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
         }
     }
 
+    /// Skip an undefined opcode for its base gas, as allowed by
+    /// `Patch::undefined_as_invalid`.
+    fn skip_undefined(&mut self) {
+        if self.state.available_gas() < Gas::from(G_BASE) {
+            reset_error_hard!(self, OnChainError::EmptyGas);
+            return;
+        }
+        self.state.used_gas += Gas::from(G_BASE);
+        self.state.position += 1;
+    }
+
     /// Step an instruction in the PC. The eval result is refected by
     /// the runtime status, and it will only return an error if
     /// there're accounts or blockhashes to be committed to this
     /// runtime for it to run. In that case, the state of the current
     /// runtime will not be affected.
     pub fn step(&mut self, runtime: &Runtime) -> Result<(), RequireError> {
+        if self.history_limit == 0 {
+            return self.step_unrecorded(runtime);
+        }
+
+        let mut snapshot = self.snapshot();
+        // The logs are moved out during the instruction instead of
+        // copied, and the new ones appended back afterwards.
+        let mut logs = mem::take(&mut self.state.logs);
+        if let Err(err) = self.step_unrecorded(runtime) {
+            self.state.logs = logs;
+            return Err(err);
+        }
+        match self.status {
+            MachineStatus::ExitedErr(OnChainError::Revert) => (),
+            MachineStatus::ExitedErr(_) | MachineStatus::ExitedNotSupported(_) => {
+                snapshot.cleared_logs = Some(logs);
+                logs = Vec::new();
+            },
+            _ => (),
+        }
+        logs.append(&mut self.state.logs);
+        self.state.logs = logs;
+
+        if self.history.len() >= self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(snapshot);
+        Ok(())
+    }
+
+    /// Set how many of the last instructions can be undone with
+    /// `step_back`. It is `0` by default, so nothing is recorded. Each
+    /// recorded instruction keeps the stack items and memory it
+    /// changes, and a copy of the account state if it can change it,
+    /// so this is only meant for debugging. Sub runtimes derived
+    /// afterwards get the same limit.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// Undo the last recorded instruction, restoring the state and
+    /// status before it. The effects of a sub runtime applied since
+    /// are undone with the instruction invoking it. Returns
+    /// `DebugError::EmptyHistory` if no instruction is recorded.
+    pub fn step_back(&mut self) -> Result<(), DebugError> {
+        let snapshot = match self.history.pop_back() {
+            Some(snapshot) => snapshot,
+            None => return Err(DebugError::EmptyHistory),
+        };
+
+        self.status = snapshot.status;
+        // The memory had at least this size before, so it fits.
+        let size = snapshot.memory_cost.as_usize() * 32;
+        for i in size..(self.state.memory_cost.as_usize() * 32) {
+            self.state.memory.write_raw(U256::from(i), 0).unwrap();
+        }
+        self.state.memory.copy_from_slice(U256::from(snapshot.memory.0), &snapshot.memory.1).unwrap();
+        while self.state.stack.len() > snapshot.stack_len - snapshot.stack.len() {
+            self.state.stack.pop().unwrap();
+        }
+        for value in snapshot.stack {
+            self.state.stack.push(value).unwrap();
+        }
+        self.state.out = snapshot.out;
+        self.state.ret = snapshot.ret;
+        self.state.memory_cost = snapshot.memory_cost;
+        self.state.used_gas = snapshot.used_gas;
+        self.state.refunded_gas = snapshot.refunded_gas;
+        match snapshot.cleared_logs {
+            Some(logs) => self.state.logs = logs,
+            None => self.state.logs.truncate(snapshot.logs_len),
+        }
+        self.state.call_failures.truncate(snapshot.call_failures_len);
+        if let Some((account_state, removed)) = snapshot.accounts {
+            self.state.account_state = account_state;
+            self.state.removed = removed;
+            // Accounts committed since stay committed. Committing the
+            // same data again succeeds, so only commitments the older
+            // state already has with other data fail, and that data is
+            // kept.
+            for commitment in &self.commitments {
+                let _ = self.state.account_state.commit(commitment.clone());
+            }
+        }
+        if let Some((accessed, accessed_storage)) = snapshot.accessed {
+            self.state.accessed = accessed;
+            self.state.accessed_storage = accessed_storage;
+        }
+        self.state.position = snapshot.position;
+        Ok(())
+    }
+
+    /// Replace the gas limit of the current context, for example to
+    /// drive a running machine into an out-of-gas exit. The gas
+    /// already used is kept, so the available gas becomes `gas` minus
+    /// the used gas. Only for tests, behind the `fault-injection`
+    /// feature.
+    ///
+    /// Panics if `gas` is less than the gas already used.
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn set_gas_limit(&mut self, gas: Gas) {
+        assert!(gas >= self.state.total_used_gas());
+        self.state.context.gas_limit = gas;
+    }
+
+    fn snapshot(&self) -> Snapshot<P> {
+        let opcode = self.peek_opcode();
+        let stack = self.state.stack.as_slice();
+        let popped = match opcode {
+            Some(opcode) => min(opcode.stack_io().0, stack.len()),
+            None => 0,
+        };
+
+        let size = self.state.memory_cost.as_usize() * 32;
+        let memory = match self.written_range(opcode) {
+            Some((start, len)) if start < U256::from(size) => {
+                let end = min(start.saturating_add(len), U256::from(size)).as_usize();
+                let start = start.as_usize();
+                (start, (start..end).map(|i| self.state.memory.read_raw(U256::from(i))).collect())
+            },
+            _ => (0, Vec::new()),
+        };
+
+        let accounts = match opcode {
+            Some(Opcode::SSTORE) | Some(Opcode::SUICIDE) | Some(Opcode::CREATE) |
+            Some(Opcode::CALL) | Some(Opcode::CALLCODE) | Some(Opcode::DELEGATECALL) |
+            Some(Opcode::STATICCALL) => Some((self.state.account_state.clone(),
+                                              self.state.removed.clone())),
+            _ => None,
+        };
+        let accesses = accounts.is_some() || matches!(opcode,
+            Some(Opcode::BALANCE) | Some(Opcode::EXTCODESIZE) | Some(Opcode::EXTCODECOPY) |
+            Some(Opcode::EXTCODEHASH) | Some(Opcode::SLOAD));
+        let accessed = if accesses {
+            Some((self.state.accessed.clone(), self.state.accessed_storage.clone()))
+        } else {
+            None
+        };
+
+        Snapshot {
+            status: self.status.clone(),
+            stack_len: stack.len(),
+            stack: stack[(stack.len() - popped)..].to_vec(),
+            memory,
+            out: self.state.out.clone(),
+            ret: self.state.ret.clone(),
+            memory_cost: self.state.memory_cost,
+            used_gas: self.state.used_gas,
+            refunded_gas: self.state.refunded_gas,
+            logs_len: self.state.logs.len(),
+            cleared_logs: None,
+            call_failures_len: self.state.call_failures.len(),
+            accounts,
+            accessed,
+            position: self.state.position,
+        }
+    }
+
+    /// Offset and length of the memory the opcode writes, including
+    /// the output of a sub runtime it invokes.
+    fn written_range(&self, opcode: Option<Opcode>) -> Option<(U256, U256)> {
+        let stack = &self.state.stack;
+        let range = |start: usize, len: usize| match (stack.peek(start), stack.peek(len)) {
+            (Ok(start), Ok(len)) => Some((start.into(), len.into())),
+            _ => None,
+        };
+        match opcode {
+            Some(Opcode::MSTORE) => stack.peek(0).ok().map(|start| (start.into(), U256::from(32))),
+            Some(Opcode::MSTORE8) => stack.peek(0).ok().map(|start| (start.into(), U256::one())),
+            Some(Opcode::CALLDATACOPY) | Some(Opcode::CODECOPY) |
+            Some(Opcode::RETURNDATACOPY) => range(0, 2),
+            Some(Opcode::EXTCODECOPY) => range(1, 3),
+            Some(Opcode::CALL) | Some(Opcode::CALLCODE) => range(5, 6),
+            Some(Opcode::DELEGATECALL) | Some(Opcode::STATICCALL) => range(4, 5),
+            _ => None,
+        }
+    }
+
+    /// Step an instruction without recording it for `step_back`.
+    fn step_unrecorded(&mut self, runtime: &Runtime) -> Result<(), RequireError> {
         struct Precheck {
             position: usize,
             memory_cost: Gas,
             gas_cost: Gas,
             gas_stipend: Gas,
             gas_refund: Gas,
+            gas_refund_removed: Gas,
             after_gas: Gas,
         }
 
@@ -348,9 +861,11 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
             return Ok(());
         }
 
+        let gas_before = self.state.available_gas();
+
         let Precheck {
             position, memory_cost,
-            gas_cost, gas_stipend, gas_refund, after_gas
+            gas_cost, gas_stipend, gas_refund, gas_refund_removed, after_gas
         } = {
             let pc = PC::<P>::new(&self.state.context.code,
                                   &self.state.valids, &self.state.position);
@@ -362,6 +877,14 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
 
             let instruction = match pc.peek() {
                 Ok(val) => val,
+                Err(OnChainError::InvalidOpcode(byte)) if byte != 0xfe && !P::undefined_as_invalid() => {
+                    self.skip_undefined();
+                    return Ok(())
+                },
+                Err(OnChainError::UnsupportedOpcode(_)) if !P::undefined_as_invalid() => {
+                    self.skip_undefined();
+                    return Ok(())
+                },
                 Err(err) => {
                     reset_error_hard!(self, err);
                     return Ok(())
@@ -372,10 +895,9 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
                 match v {
                     None => Ok(()),
                     Some(ControlCheck::Jump(dest)) => {
-                        if dest <= M256::from(usize::max_value()) && pc.is_valid(dest.as_usize()) {
-                            Ok(())
-                        } else {
-                            Err(OnChainError::BadJumpDest.into())
+                        match dest.to_usize() {
+                            Some(dest) if pc.is_valid(dest) => Ok(()),
+                            _ => Err(OnChainError::BadJumpDest.into()),
                         }
                     }
                 }
@@ -403,28 +925,35 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
                 }
             }
 
-            let used_gas = match self.state.used_gas {
-                GasUsage::Some(gas) => gas,
-                GasUsage::All => {
-                    reset_error_hard!(self, OnChainError::EmptyGas);
-                    return Ok(());
+            match (instruction, P::init_code_output_limit()) {
+                (Instruction::RETURN, Some(limit)) | (Instruction::REVERT, Some(limit))
+                    if self.state.is_create =>
+                {
+                    let len: U256 = self.state.stack.peek(1).unwrap().into();
+                    if len > U256::from(limit) {
+                        reset_error_hard!(self, OnChainError::OutputTooLarge);
+                        return Ok(());
+                    }
                 },
-            };
+                _ => (),
+            }
 
             let position = pc.position();
             let memory_cost = memory_cost(instruction, &self.state);
-            let memory_gas = memory_gas(memory_cost);
             let gas_cost = gas_cost::<M, P>(instruction, &self.state);
             let gas_stipend = gas_stipend(instruction, &self.state);
             let gas_refund = gas_refund(instruction, &self.state);
+            let gas_refund_removed = gas_refund_removed(instruction, &self.state);
 
-            let all_gas_cost = memory_gas + used_gas + gas_cost;
-            if self.state.context.gas_limit < all_gas_cost {
-                reset_error_hard!(self, OnChainError::EmptyGas);
-                return Ok(());
-            }
+            let after_gas = match self.state.gas_remaining(memory_cost, gas_cost) {
+                Some(gas) => gas,
+                None => {
+                    reset_error_hard!(self, OnChainError::EmptyGas);
+                    return Ok(());
+                },
+            };
 
-            match check_support(instruction, &self.state) {
+            match check_strict::<P>(memory_cost).and_then(|()| check_support(instruction, &self.state)) {
                 Ok(()) => (),
                 Err(err) => {
                     reset_error_not_supported!(self, err);
@@ -432,8 +961,6 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
                 },
             };
 
-            let after_gas = self.state.context.gas_limit - all_gas_cost;
-
             match extra_check_opcode::<M, P>(instruction, &self.state, gas_stipend, after_gas) {
                 Ok(()) => (),
                 Err(err) => {
@@ -444,26 +971,69 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
 
             Precheck {
                 position, memory_cost,
-                gas_cost, gas_stipend, gas_refund, after_gas
+                gas_cost, gas_stipend, gas_refund, gas_refund_removed, after_gas
             }
         };
 
-        let instruction = PCMut::<P>::new(&self.state.context.code,
-                                          &self.state.valids, &mut self.state.position)
-            .read().unwrap();
+        let hooked = !runtime.step_hooks.is_empty();
+        let stack = if hooked && runtime.stack_snapshots {
+            Some(self.state.stack.as_slice().to_vec())
+        } else {
+            None
+        };
+        let memory = if hooked && runtime.memory_snapshots {
+            Some(self.state.memory_dump())
+        } else {
+            None
+        };
+
+        // The instruction was peeked above, so reading it should not
+        // fail, but an out of bounds read is still an error rather than
+        // a panic.
+        let instruction = match PCMut::<P>::new(&self.state.context.code,
+                                                &self.state.valids, &mut self.state.position)
+            .read()
+        {
+            Ok(val) => val,
+            Err(err) => {
+                reset_error_hard!(self, err);
+                return Ok(());
+            },
+        };
         let result = run_opcode::<M, P>((instruction, position),
                                         &mut self.state, runtime, gas_stipend, after_gas);
 
         self.state.used_gas += gas_cost - gas_stipend;
         self.state.memory_cost = memory_cost;
-        self.state.refunded_gas = self.state.refunded_gas + gas_refund;
+        self.state.refunded_gas = (self.state.refunded_gas + gas_refund).saturating_sub(gas_refund_removed);
+
+        if hooked {
+            let info = StepInfo {
+                position,
+                opcode: self.state.context.code[position].into(),
+                depth: self.state.depth,
+                gas: gas_before,
+                gas_cost: gas_before - self.state.available_gas(),
+                stack,
+                memory,
+            };
+            for hook in &runtime.step_hooks {
+                hook(&info);
+            }
+        }
 
         match result {
             None => Ok(()),
             Some(Control::Jump(dest)) => {
-                PCMut::<P>::new(&self.state.context.code,
-                                &self.state.valids, &mut self.state.position)
-                    .jump(dest.as_usize()).unwrap();
+                match PCMut::<P>::new(&self.state.context.code,
+                                      &self.state.valids, &mut self.state.position)
+                    .jump(dest.as_usize())
+                {
+                    Ok(()) => (),
+                    Err(err) => {
+                        reset_error_hard!(self, err);
+                    },
+                }
                 Ok(())
             },
             Some(Control::InvokeCall(context, (from, len))) => {
@@ -485,18 +1055,962 @@ impl<M: Memory + Default, P: Patch> Machine<M, P> {
         }
     }
 
+    /// Step the runtime up to `n` times, and return the number of
+    /// steps taken. It stops early if the runtime is no longer
+    /// running, for example when it exits or invokes a sub runtime. A
+    /// `RequireError` is returned as in `step`, and the steps taken
+    /// before it are kept.
+    pub fn steps(&mut self, n: usize, runtime: &Runtime) -> Result<usize, RequireError> {
+        let mut taken = 0;
+        while taken < n {
+            match self.status {
+                MachineStatus::Running => (),
+                _ => break,
+            }
+            self.step(runtime)?;
+            taken += 1;
+        }
+        Ok(taken)
+    }
+
     /// Get the runtime state.
     pub fn state(&self) -> &State<M, P> {
         &self.state
     }
 
+    /// Consume the runtime and return its state.
+    pub fn into_state(self) -> State<M, P> {
+        self.state
+    }
+
     /// Get the runtime PC.
     pub fn pc(&self) -> PC<P> {
         PC::new(&self.state.context.code, &self.state.valids, &self.state.position)
     }
 
+    /// Get the code being executed.
+    pub fn code(&self) -> &[u8] {
+        &self.state.context.code
+    }
+
+    /// Get the code from the current position to the end, empty once
+    /// the position is past the end.
+    pub fn remaining_code(&self) -> &[u8] {
+        let code = self.code();
+        &code[min(self.state.position, code.len())..]
+    }
+
+    /// Predict the accounts, storage and block hashes that the
+    /// upcoming instructions will require, so that they can be
+    /// fetched together. The code from the current position is
+    /// scanned for BALANCE, EXTCODESIZE, EXTCODECOPY, EXTCODEHASH,
+    /// SLOAD and BLOCKHASH whose argument is pushed right before
+    /// them, or is on the stack for the next instruction. Requires
+    /// already satisfied are left out, except block hashes, which are
+    /// kept by the `Runtime`. This is only a hint: control flow is not
+    /// followed, so the list is neither exhaustive nor certain.
+    pub fn pending_requires(&self) -> Vec<RequireError> {
+        let account_state = &self.state.account_state;
+        let mut requires = Vec::new();
+        let mut position = self.state.position;
+        let mut argument = self.state.stack.peek(0).ok();
+
+        while let Ok(instruction) = PCMut::<P>::new(&self.state.context.code,
+                                                    &self.state.valids, &mut position).read() {
+            let require = match (instruction, argument) {
+                (Instruction::PUSH(value), _) => {
+                    argument = Some(value);
+                    continue;
+                },
+                (Instruction::BALANCE, Some(address)) =>
+                    account_state.balance(address.into()).err(),
+                (Instruction::EXTCODESIZE, Some(address)) | (Instruction::EXTCODECOPY, Some(address)) =>
+                    account_state.code(address.into()).err(),
+                (Instruction::EXTCODEHASH, Some(address)) =>
+                    account_state.exists(address.into()).and_then(|_| account_state.code(address.into())).err(),
+                (Instruction::SLOAD, Some(index)) =>
+                    account_state.storage_read(self.state.context.address, index.into()).err(),
+                (Instruction::BLOCKHASH, Some(number)) =>
+                    Some(RequireError::Blockhash(number.into())),
+                _ => None,
+            };
+            argument = None;
+            if let Some(require) = require {
+                if !requires.contains(&require) {
+                    requires.push(require);
+                }
+            }
+        }
+        requires
+    }
+
     /// Get the current runtime status.
     pub fn status(&self) -> MachineStatus {
         self.status.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::M256;
+    use std::sync::Arc;
+
+    fn assert_send<T: Send>() { }
+
+    type TestMachine = Machine<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>;
+
+    fn finish(machine: &mut TestMachine, runtime: &Runtime) {
+        while let MachineStatus::Running = machine.status() {
+            machine.step(runtime).unwrap();
+        }
+    }
+
+    #[test]
+    fn remaining_code() {
+        // PUSH1 1, PUSH2 2, ADD.
+        let context = Context::from_hex_code("600161000201").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let mut machine = TestMachine::new(context, 1);
+        assert_eq!(machine.remaining_code(), machine.code());
+
+        machine.steps(2, &runtime).unwrap();
+        assert_eq!(machine.code().len(), 6);
+        assert_eq!(machine.remaining_code(), &[0x01]);
+        finish(&mut machine, &runtime);
+        assert!(machine.remaining_code().is_empty());
+    }
+
+    #[test]
+    fn set_gas_limit() {
+        // PUSH1 0, PUSH1 0, SHA3, STOP. The SHA3 of no data costs 30.
+        let context = Context::from_hex_code("600060002000").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        for &(gas, success) in &[(6 + 30, true), (6 + 29, false)] {
+            let mut machine = TestMachine::new(context.clone(), 1);
+            machine.steps(2, &runtime).unwrap();
+            machine.set_gas_limit(Gas::from(gas as u64));
+            assert_eq!(machine.state().available_gas(), Gas::from(gas as u64 - 6));
+            machine.step(&runtime).unwrap();
+
+            match machine.status() {
+                MachineStatus::Running if success => (),
+                MachineStatus::ExitedErr(OnChainError::EmptyGas) if !success => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+        }
+    }
+
+    #[test]
+    fn step_back() {
+        // MSTORE 0x2a at 0, SSTORE 1 at 0, STOP.
+        let context = Context::from_hex_code("602a6000526001600055").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let commitments = vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::zero(),
+                code: context.code.clone(),
+            },
+            AccountCommitment::Storage { address: context.address, index: U256::zero(), value: M256::zero() },
+        ];
+
+        let mut once = TestMachine::new(context.clone(), 1);
+        once.commit_accounts(commitments.clone()).unwrap();
+        once.step(&runtime).unwrap();
+
+        let mut machine = TestMachine::new(context, 1);
+        machine.commit_accounts(commitments).unwrap();
+        machine.set_history_limit(5);
+        assert_eq!(machine.steps(6, &runtime).unwrap(), 6);
+        assert_eq!(machine.state().account_state.storage_read(machine.state().context.address, U256::zero())
+                   .unwrap(), M256::one());
+
+        for _ in 0..5 {
+            machine.step_back().unwrap();
+        }
+        assert_eq!(machine.step_back(), Err(DebugError::EmptyHistory));
+
+        match machine.status() {
+            MachineStatus::Running => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.state().stack.as_slice(), once.state().stack.as_slice());
+        assert_eq!(machine.state().memory_dump(), once.state().memory_dump());
+        assert_eq!(machine.state().total_used_gas(), once.state().total_used_gas());
+        assert_eq!(machine.state().position, once.state().position);
+        assert_eq!(machine.state().account_state.storage_read(machine.state().context.address, U256::zero())
+                   .unwrap(), M256::zero());
+
+        // Stepping forward again gives the same result.
+        finish(&mut machine, &runtime);
+        assert_eq!(machine.state().memory_word(U256::zero()), M256::from(0x2au64));
+        assert_eq!(machine.state().total_used_gas(), Gas::from(4 * 3 + 3 + 3 + 20000usize));
+    }
+
+    #[test]
+    fn step_back_keeps_commitments() {
+        // SSTORE 1 at 0, SLOAD 1.
+        let context = Context::from_hex_code("600160005560015400").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let mut machine = TestMachine::new(context.clone(), 1);
+        machine.commit_accounts(vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::zero(),
+                code: context.code.clone(),
+            },
+            AccountCommitment::Storage { address: context.address, index: U256::zero(), value: M256::zero() },
+        ]).unwrap();
+        machine.set_history_limit(10);
+
+        let mut required = 0;
+        while let MachineStatus::Running = machine.status() {
+            match machine.step(&runtime) {
+                Ok(()) => (),
+                Err(RequireError::AccountStorage(address, index)) => {
+                    required += 1;
+                    machine.commit_account(AccountCommitment::Storage {
+                        address, index, value: M256::from(42u64),
+                    }).unwrap();
+                },
+                Err(err) => panic!("unexpected require error {:?}", err),
+            }
+        }
+        assert_eq!(required, 1);
+
+        // Undoing the SSTORE restores the account state from before
+        // the slot read by SLOAD was committed.
+        for _ in 0..6 {
+            machine.step_back().unwrap();
+        }
+        finish(&mut machine, &runtime);
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::from(42u64));
+    }
+
+    #[test]
+    fn step_back_memory_and_logs() {
+        // MSTORE 0x2a at 0, MSTORE 0x63 at 1, LOG0 no data, INVALID.
+        let context = Context::from_hex_code("602a600052606360015260006000a0fe").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut once = TestMachine::new(context.clone(), 1);
+        once.steps(3, &runtime).unwrap();
+
+        let mut machine = TestMachine::new(context, 1);
+        machine.set_history_limit(10);
+        finish(&mut machine, &runtime);
+        match machine.status() {
+            MachineStatus::ExitedErr(OnChainError::InvalidOpcode(0xfe)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(machine.state().logs().is_empty());
+
+        // Undoing the failing instruction brings back the logs it
+        // cleared.
+        machine.step_back().unwrap();
+        assert_eq!(machine.state().logs().len(), 1);
+        for _ in 0..3 {
+            machine.step_back().unwrap();
+        }
+        assert!(machine.state().logs().is_empty());
+
+        // Undoing the second MSTORE restores the overwritten bytes and
+        // clears the memory grown by it.
+        for _ in 0..3 {
+            machine.step_back().unwrap();
+        }
+        assert_eq!(machine.state().stack.as_slice(), once.state().stack.as_slice());
+        assert_eq!(machine.state().memory_dump(), once.state().memory_dump());
+        assert_eq!(machine.state().memory_word(U256::from(32)), M256::zero());
+        assert_eq!(machine.state().total_used_gas(), once.state().total_used_gas());
+
+        finish(&mut machine, &runtime);
+        assert_eq!(machine.state().memory_word(U256::one()), M256::from(0x63u64));
+        match machine.status() {
+            MachineStatus::ExitedErr(OnChainError::InvalidOpcode(0xfe)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn resume_from_state() {
+        // Count down from 3 in a loop.
+        let context = Context::from_hex_code("60035b600190038060025700").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut uninterrupted = TestMachine::new(context.clone(), 1);
+        finish(&mut uninterrupted, &runtime);
+
+        let mut machine = TestMachine::new(context.clone(), 1);
+        for _ in 0..7 {
+            machine.step(&runtime).unwrap();
+        }
+        let status = machine.status();
+        let state = machine.into_state();
+        let position = state.position;
+        let mut resumed = Machine::from_state(state, position, status).unwrap();
+        finish(&mut resumed, &runtime);
+
+        match resumed.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(resumed.state().stack.len(), uninterrupted.state().stack.len());
+        assert_eq!(resumed.state().stack.peek(0).unwrap(), uninterrupted.state().stack.peek(0).unwrap());
+        assert_eq!(resumed.state().total_used_gas(), uninterrupted.state().total_used_gas());
+        assert_eq!(resumed.state().position, uninterrupted.state().position);
+    }
+
+    #[test]
+    fn reset_clears_output() {
+        // MSTORE 1 at 0, LOG0 no data, and RETURN the word at 0.
+        let context = Context::from_hex_code("600160005260006000a060206000f3").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let mut machine = TestMachine::new(context, 1);
+        finish(&mut machine, &runtime);
+        assert_eq!(machine.state().out.len(), 32);
+        assert_eq!(machine.state().logs().len(), 1);
+
+        // STOP.
+        machine.reset(Context::from_hex_code("00").unwrap());
+        finish(&mut machine, &runtime);
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(machine.state().out.is_empty());
+        assert!(machine.state().ret.is_empty());
+        assert!(machine.state().logs().is_empty());
+        assert!(machine.state().removed.is_empty());
+        assert_eq!(machine.state().refunded_gas, Gas::zero());
+    }
+
+    #[test]
+    fn reset_runs_like_new() {
+        // MSTORE 42 at 0x20 and PUSH1 0xff, and count down from 3 in a
+        // loop.
+        let contexts = [Context::from_hex_code("602a60205260ff00").unwrap(),
+                        Context::from_hex_code("60035b600190038060025700").unwrap()];
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(contexts[0].clone(), 1);
+        finish(&mut machine, &runtime);
+        for context in contexts.iter().rev().chain(contexts.iter()) {
+            machine.reset(context.clone());
+            finish(&mut machine, &runtime);
+
+            let mut fresh = TestMachine::new(context.clone(), 1);
+            finish(&mut fresh, &runtime);
+
+            match machine.status() {
+                MachineStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(machine.state().stack.as_slice(), fresh.state().stack.as_slice());
+            assert_eq!(machine.state().memory_dump(), fresh.state().memory_dump());
+            assert_eq!(machine.state().total_used_gas(), fresh.state().total_used_gas());
+            assert_eq!(machine.state().position, fresh.state().position);
+        }
+    }
+
+    #[test]
+    fn bounded_steps() {
+        // Count down from 3 in a loop.
+        let context = Context::from_hex_code("60035b600190038060025700").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut manual = TestMachine::new(context.clone(), 1);
+        for _ in 0..3 {
+            manual.step(&runtime).unwrap();
+        }
+        let mut machine = TestMachine::new(context.clone(), 1);
+        assert_eq!(machine.steps(3, &runtime).unwrap(), 3);
+        assert_eq!(machine.state().position, manual.state().position);
+        assert_eq!(machine.state().stack.len(), manual.state().stack.len());
+        assert_eq!(machine.state().stack.peek(0).unwrap(), manual.state().stack.peek(0).unwrap());
+
+        // The loop runs 23 instructions, and the step past the STOP is
+        // not taken.
+        let mut machine = TestMachine::new(context, 1);
+        assert_eq!(machine.steps(100, &runtime).unwrap(), 23);
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.steps(1, &runtime).unwrap(), 0);
+    }
+
+    #[test]
+    fn from_state_out_of_bounds() {
+        let state = TestMachine::new(Context::from_hex_code("6001").unwrap(), 1).into_state();
+        assert!(Machine::from_state(state, 3, MachineStatus::Running).is_none());
+    }
+
+    #[test]
+    fn total_used_gas_includes_memory_gas() {
+        // PUSH1 0x2a, PUSH1 0x40, MSTORE, STOP.
+        let context = Context::from_hex_code("602a60405200").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(context, 1);
+        machine.step(&runtime).unwrap();
+        machine.step(&runtime).unwrap();
+        assert_eq!(machine.state().memory_gas(), Gas::zero());
+        assert_eq!(machine.state().total_used_gas(), Gas::from(6u64));
+
+        machine.step(&runtime).unwrap();
+        // MSTORE at 0x40 expands the memory to three words.
+        assert_eq!(machine.state().memory_gas(), Gas::from(9u64));
+        assert_eq!(machine.state().total_used_gas(), Gas::from(6u64 + 3 + 9));
+        assert_eq!(machine.state().used_gas_after_refund(), machine.state().total_used_gas());
+    }
+
+    #[test]
+    fn memory_gas_is_kept_across_require() {
+        // MSTORE at 0x400, then SLOAD slot 0 and POP it.
+        let context = Context::from_hex_code("6001610400526000545000").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(context, 1);
+        for _ in 0..4 {
+            machine.step(&runtime).unwrap();
+        }
+        // The memory is expanded to 33 words.
+        let memory_gas = Gas::from(3 * 33 + 33 * 33 / 512u64);
+        assert_eq!(machine.state().memory_gas(), memory_gas);
+
+        // The memory cost is absolute, and a step stopped by a
+        // require does not change it.
+        let mut requires = 0;
+        loop {
+            match machine.step(&runtime) {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) => {
+                    machine.commit_account(AccountCommitment::Full {
+                        nonce: U256::zero(), address, balance: U256::zero(),
+                        code: Arc::new(Vec::new()),
+                    }).unwrap();
+                },
+                Err(RequireError::AccountStorage(address, index)) => {
+                    machine.commit_account(AccountCommitment::Storage {
+                        address, index, value: M256::zero(),
+                    }).unwrap();
+                },
+                Err(err) => panic!("unexpected require {:?}", err),
+            }
+            requires += 1;
+            assert_eq!(machine.state().memory_gas(), memory_gas);
+        }
+        assert!(requires > 0);
+        finish(&mut machine, &runtime);
+
+        assert_eq!(machine.state().memory_gas(), memory_gas);
+        assert_eq!(machine.state().total_used_gas(), Gas::from(3 + 3 + 3 + 3 + 200 + 2u64) + memory_gas);
+    }
+
+    #[test]
+    fn sload_requires_uncommitted_slot() {
+        // PUSH1 5, SLOAD, STOP.
+        let context = Context::from_hex_code("60055400").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(context, 1);
+        machine.step(&runtime).unwrap();
+        machine.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: Address::default(), balance: U256::zero(),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+
+        // A fully committed account still has unknown storage, which is
+        // loaded one slot at a time.
+        match machine.step(&runtime) {
+            Err(RequireError::AccountStorage(address, index)) => {
+                assert_eq!(address, Address::default());
+                assert_eq!(index, U256::from(5u64));
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+        machine.commit_account(AccountCommitment::Storage {
+            address: Address::default(), index: U256::from(5u64), value: M256::from(7u64),
+        }).unwrap();
+        machine.step(&runtime).unwrap();
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::from(7u64));
+    }
+
+    #[test]
+    fn costs_exceeding_gas_limit() {
+        let runtime = Runtime::new(HeaderParams::default());
+        let max = "7f".to_string() + &"ff".repeat(32);
+        // PUSH1 0, PUSH1 0, MSTORE with one gas short of the 12 needed,
+        // and MSTORE at 2^256 - 1 with the largest u64 gas limit.
+        for &(ref code, gas_limit) in &[("6000600052".to_string(), Gas::from(11u64)),
+                                        (format!("6000{}52", max), Gas::from(u64::MAX))] {
+            let mut context = Context::from_hex_code(code).unwrap();
+            context.gas_limit = gas_limit;
+            let mut machine = TestMachine::new(context, 1);
+            finish(&mut machine, &runtime);
+
+            match machine.status() {
+                MachineStatus::ExitedErr(OnChainError::EmptyGas) => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(machine.state().total_used_gas(), gas_limit);
+            assert_eq!(machine.state().available_gas(), Gas::zero());
+        }
+    }
+
+    #[test]
+    fn memory_dump() {
+        // MSTORE 0x2a at 0x00, MSTORE 0x63 at 0x40, STOP.
+        let context = Context::from_hex_code("602a6000526063604052").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(context, 1);
+        finish(&mut machine, &runtime);
+
+        let dump = machine.state().memory_dump();
+        assert_eq!(dump.len(), 96);
+        assert_eq!(dump[31], 0x2a);
+        assert_eq!(dump[95], 0x63);
+        assert_eq!(dump.iter().filter(|&&b| b != 0).count(), 2);
+        assert_eq!(machine.state().memory_word(U256::zero()), M256::from(0x2au64));
+        assert_eq!(machine.state().memory_word(U256::from(0x40u64)), M256::from(0x63u64));
+        assert_eq!(machine.state().memory_word(U256::from(0x20u64)), M256::zero());
+    }
+
+    #[test]
+    fn code_without_stop_exits_ok() {
+        // PUSH1 1, PUSH1 2, ADD, with no STOP at the end.
+        let context = Context::from_hex_code("6001600201").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let mut machine = TestMachine::new(context, 1);
+        finish(&mut machine, &runtime);
+
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::from(3u64));
+        assert_eq!(machine.state().total_used_gas(), Gas::from(9usize));
+        // Reading past the end of code is an error, not a panic.
+        assert!(machine.pc().is_end());
+        match machine.pc().peek() {
+            Err(OnChainError::PCOverflow) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn push_at_end_of_code() {
+        // PUSH1 without its immediate byte, and PUSH32 with only one.
+        let runtime = Runtime::new(HeaderParams::default());
+        for &(code, value) in &[("60", M256::zero()), ("7fff", M256::from(0xffu64) << 248)] {
+            let mut machine = TestMachine::new(Context::from_hex_code(code).unwrap(), 1);
+            finish(&mut machine, &runtime);
+
+            match machine.status() {
+                MachineStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(machine.state().stack.len(), 1);
+            assert_eq!(machine.state().stack.peek(0).unwrap(), value);
+            assert_eq!(machine.state().total_used_gas(), Gas::from(3usize));
+        }
+    }
+
+    test_patch! {
+        /// Byzantium patch with a stack of only 16 items.
+        struct SmallStackPatch;
+        fn stack_limit() -> usize { 16 }
+    }
+
+    test_patch! {
+        /// Byzantium patch skipping undefined opcodes.
+        struct UndefinedAsNoopPatch;
+        fn undefined_as_invalid() -> bool { false }
+    }
+
+    #[test]
+    fn patch_undefined_as_invalid() {
+        let runtime = Runtime::new(HeaderParams::default());
+        // The undefined opcode 0x0c, PUSH1 1, STOP.
+        let context = Context::from_hex_code("0c600100").unwrap();
+
+        let mut machine = TestMachine::new(context.clone(), 1);
+        finish(&mut machine, &runtime);
+        match machine.status() {
+            MachineStatus::ExitedErr(OnChainError::InvalidOpcode(0x0c)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+
+        let mut machine = Machine::<SeqMemory<UndefinedAsNoopPatch>, UndefinedAsNoopPatch>::new(context, 1);
+        while let MachineStatus::Running = machine.status() {
+            machine.step(&runtime).unwrap();
+        }
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.state().stack.as_slice(), &[M256::one()][..]);
+        assert_eq!(machine.state().total_used_gas(), Gas::from(2 + 3usize));
+
+        // BASEFEE is not enabled before London, and is skipped too.
+        let context = Context::from_hex_code("4800").unwrap();
+        let mut machine = Machine::<SeqMemory<UndefinedAsNoopPatch>, UndefinedAsNoopPatch>::new(context, 1);
+        machine.step(&runtime).unwrap();
+        assert_eq!(machine.state().position, 1);
+
+        // The designated INVALID opcode still fails.
+        let context = Context::from_hex_code("fe00").unwrap();
+        let mut machine = Machine::<SeqMemory<UndefinedAsNoopPatch>, UndefinedAsNoopPatch>::new(context, 1);
+        machine.step(&runtime).unwrap();
+        match machine.status() {
+            MachineStatus::ExitedErr(OnChainError::InvalidOpcode(0xfe)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn patch_stack_limit() {
+        let runtime = Runtime::new(HeaderParams::default());
+        let run = |pushes: usize| {
+            // PUSH1 1 the given number of times, STOP.
+            let context = Context::from_hex_code(&("6001".repeat(pushes) + "00")).unwrap();
+            let mut machine = Machine::<SeqMemory<SmallStackPatch>, SmallStackPatch>::new(context, 1);
+            while let MachineStatus::Running = machine.status() {
+                machine.step(&runtime).unwrap();
+            }
+            machine.status()
+        };
+
+        match run(16) {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        match run(17) {
+            MachineStatus::ExitedErr(OnChainError::StackOverflow) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn call_stack_too_deep() {
+        // CALL the target with no gas and no value, PUSH1 7, STOP.
+        let target = Address::from(M256::from(0x2000u64));
+        let code = "6000600060006000600073".to_string() +
+            "0000000000000000000000000000000000002000" + "6000f1600700";
+        let runtime = Runtime::new(HeaderParams::default());
+        let machine = |depth| {
+            let context = Context::from_hex_code(&code).unwrap();
+            let mut machine = TestMachine::new(context.clone(), depth);
+            machine.commit_accounts(vec![
+                AccountCommitment::Full {
+                    nonce: U256::zero(),
+                    address: context.address,
+                    balance: U256::zero(),
+                    code: context.code.clone(),
+                },
+                AccountCommitment::Nonexist(target),
+            ]).unwrap();
+            machine
+        };
+
+        // The runtime at the call stack limit still invokes the call.
+        let mut last = machine(1024);
+        finish(&mut last, &runtime);
+        match last.status() {
+            MachineStatus::InvokeCall(_, _) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(last.state().call_failures.is_empty());
+
+        // Past the limit the call pushes zero and the runtime
+        // continues.
+        let mut past = machine(1025);
+        finish(&mut past, &runtime);
+        match past.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(past.state().stack.peek(1).unwrap(), M256::zero());
+        assert_eq!(past.state().stack.peek(0).unwrap(), M256::from(7u64));
+        assert_eq!(past.state().call_failures, vec![(1025, CallFailure::CallStackTooDeep)]);
+    }
+
+    #[test]
+    fn call_stack_too_deep_gas() {
+        // CALL the target with 0xffff gas and value 1, PUSH1 7, STOP.
+        let target = Address::from(M256::from(0x2000u64));
+        let code = "6000600060006000600173".to_string() +
+            "0000000000000000000000000000000000002000" + "61fffff1600700";
+        let runtime = Runtime::new(HeaderParams::default());
+        let context = Context::from_hex_code(&code).unwrap();
+        let mut machine = TestMachine::new(context.clone(), 1025);
+        machine.commit_accounts(vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::one(),
+                code: context.code.clone(),
+            },
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: target, balance: U256::zero(),
+                code: Arc::new(vec![0x00]),
+            },
+        ]).unwrap();
+        finish(&mut machine, &runtime);
+
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.state().stack.peek(1).unwrap(), M256::zero());
+        // The pushes, and the CALL base and value transfer cost. The
+        // forwarded gas and the stipend are not used.
+        assert_eq!(machine.state().total_used_gas(), Gas::from(24usize + 700 + 9000 - 2300));
+        assert_eq!(machine.state().account_state.balance(context.address).unwrap(), U256::one());
+    }
+
+    #[test]
+    fn call_without_code_resolves_inline() {
+        // CALL an account without code with no gas and no value, STOP.
+        let target = Address::from(M256::from(0x2000u64));
+        let code = "6000600060006000600073".to_string() +
+            "0000000000000000000000000000000000002000" + "6000f100";
+        let runtime = Runtime::new(HeaderParams::default());
+        let context = Context::from_hex_code(&code).unwrap();
+        let mut machine = TestMachine::new(context.clone(), 1);
+        machine.commit_accounts(vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::zero(),
+                code: context.code.clone(),
+            },
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: target, balance: U256::zero(),
+                code: Arc::new(Vec::new()),
+            },
+        ]).unwrap();
+
+        // The runtime never invokes a sub runtime, and is only charged
+        // the pushes and the CALL base cost.
+        finish(&mut machine, &runtime);
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::one());
+        assert_eq!(machine.state().total_used_gas(), Gas::from(21usize + 700));
+        assert!(machine.state().ret.is_empty());
+    }
+
+    #[test]
+    fn call_without_code_fires_context_hooks() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        // CALL an account without code with no gas and no value, STOP.
+        let target = Address::from(M256::from(0x2000u64));
+        let code = "6000600060006000600073".to_string() +
+            "0000000000000000000000000000000000002000" + "6000f100";
+        let mut runtime = Runtime::new(HeaderParams::default());
+        let called = Rc::new(RefCell::new(Vec::new()));
+        let hooked = called.clone();
+        runtime.context_history_hooks.push(Box::new(move |context: &Context| {
+            hooked.borrow_mut().push(context.address);
+        }));
+        let context = Context::from_hex_code(&code).unwrap();
+        let mut machine = TestMachine::new(context.clone(), 1);
+        machine.commit_accounts(vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::zero(),
+                code: context.code.clone(),
+            },
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: target, balance: U256::zero(),
+                code: Arc::new(Vec::new()),
+            },
+        ]).unwrap();
+
+        finish(&mut machine, &runtime);
+        match machine.status() {
+            MachineStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(*called.borrow(), vec![target]);
+    }
+
+    #[test]
+    fn create_and_call_statuses() {
+        // CREATE with init code returning the byte 0x01, then CALL the
+        // created address.
+        let init = "600160005360016000f3";
+        let code = "69".to_string() + init + "600052600a60166000f0" +
+            "60006000600060006000856203fffff1";
+        let runtime = Runtime::new(HeaderParams::default());
+        let context = Context::from_hex_code(&code).unwrap();
+        let created = create_address(context.address, U256::zero());
+        let mut machine = TestMachine::new(context.clone(), 1);
+        machine.commit_accounts(vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::zero(),
+                code: context.code.clone(),
+            },
+            AccountCommitment::Nonexist(created),
+        ]).unwrap();
+
+        finish(&mut machine, &runtime);
+        let sub_context = match machine.status() {
+            MachineStatus::InvokeCreate(context) => context,
+            status => panic!("unexpected status {:?}", status),
+        };
+        assert_eq!(sub_context.address, created);
+        let mut sub = machine.derive(sub_context);
+        sub.invoke_create().unwrap();
+        finish(&mut sub, &runtime);
+        machine.apply_sub(sub);
+
+        // The executor deploys the code and pushes the address.
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::from(created));
+        assert_eq!(machine.state().account_state.code(created).unwrap().as_slice(), &[0x01]);
+
+        finish(&mut machine, &runtime);
+        match machine.status() {
+            MachineStatus::InvokeCall(context, _) => assert_eq!(context.address, created),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn commit_code_for_extcodesize() {
+        // EXTCODESIZE of the target, STOP.
+        let target = Address::from(M256::from(0x2000u64));
+        let context = Context::from_hex_code("7300000000000000000000000000000000000020003b00").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let mut machine = TestMachine::new(context, 1);
+        machine.step(&runtime).unwrap();
+        match machine.step(&runtime) {
+            Err(RequireError::AccountCode(address)) => assert_eq!(address, target),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        machine.commit_code(target, vec![0x60, 0x01, 0x00]).unwrap();
+        finish(&mut machine, &runtime);
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::from(3u64));
+    }
+
+    #[test]
+    fn pending_requires() {
+        // BALANCE of a, SLOAD 5, BALANCE of the context address,
+        // EXTCODESIZE of b, BLOCKHASH 1, STOP.
+        let a = Address::from(M256::from(0x2000u64));
+        let b = Address::from(M256::from(0x3000u64));
+        let code = concat!("7300000000000000000000000000000000000020003150", "60055450",
+                           "7300000000000000000000000000000000000000003150",
+                           "7300000000000000000000000000000000000030003b50", "60014000");
+        let context = Context::from_hex_code(code).unwrap();
+        let mut machine = TestMachine::new(context.clone(), 1);
+        machine.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: context.address, balance: U256::zero(),
+            code: context.code.clone(),
+        }).unwrap();
+
+        assert_eq!(machine.pending_requires(), vec![
+            RequireError::Account(a),
+            RequireError::AccountStorage(context.address, U256::from(5u64)),
+            RequireError::AccountCode(b),
+            RequireError::Blockhash(U256::one()),
+        ]);
+    }
+
+    #[test]
+    fn commit_accounts() {
+        // SLOAD slot 1, STOP.
+        let context = Context::from_hex_code("60015400").unwrap();
+        let runtime = Runtime::new(HeaderParams::default());
+        let account = AccountCommitment::Full {
+            nonce: U256::zero(),
+            address: context.address,
+            balance: U256::from(5u64),
+            code: context.code.clone(),
+        };
+        let storage = AccountCommitment::Storage {
+            address: context.address,
+            index: U256::one(),
+            value: M256::from(9u64),
+        };
+
+        let mut machine = TestMachine::new(context, 1);
+        machine.step(&runtime).unwrap();
+        match machine.step(&runtime) {
+            Err(RequireError::Account(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        machine.commit_accounts(vec![account, storage.clone()]).unwrap();
+        finish(&mut machine, &runtime);
+
+        assert_eq!(machine.state().stack.peek(0).unwrap(), M256::from(9u64));
+        assert_eq!(machine.state().account_state.balance(machine.state().context.address).unwrap(),
+                   U256::from(5u64));
+        let conflict = AccountCommitment::Storage {
+            address: machine.state().context.address,
+            index: U256::one(),
+            value: M256::from(8u64),
+        };
+        match machine.commit_accounts(vec![storage, conflict]) {
+            Err((1, CommitError::StorageConflict(_, index))) if index == U256::one() => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn gas_opcode_pushes_available_gas() {
+        // MSTORE 1 at offset 0, GAS, STOP.
+        let mut context = Context::from_hex_code("60016000525a00").unwrap();
+        context.gas_limit = Gas::from(100000u64);
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(context, 1);
+        for _ in 0..4 {
+            machine.step(&runtime).unwrap();
+        }
+        let available = machine.state().available_gas();
+        let pushed: M256 = available.into();
+        assert_eq!(machine.state().stack.peek(0).unwrap(), pushed);
+        // Two pushes, MSTORE with one word of memory, and GAS.
+        assert_eq!(available, Gas::from(100000u64 - (3 + 3 + 3 + 3 + 2)));
+    }
+
+    #[test]
+    fn call_forwards_from_available_gas() {
+        // CALL the target with more gas than available, and no value.
+        let target = Address::from(M256::from(0x2000u64));
+        let mut context = Context::from_hex_code(&("6000600060006000600073".to_string() +
+            "0000000000000000000000000000000000002000" + "62fffffff1")).unwrap();
+        context.gas_limit = Gas::from(100000u64);
+        let runtime = Runtime::new(HeaderParams::default());
+
+        let mut machine = TestMachine::new(context.clone(), 1);
+        machine.commit_accounts(vec![
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: context.address, balance: U256::zero(), code: context.code.clone(),
+            },
+            AccountCommitment::Full {
+                nonce: U256::zero(), address: target, balance: U256::zero(), code: Arc::new(vec![0x00]),
+            },
+        ]).unwrap();
+        finish(&mut machine, &runtime);
+
+        // The CALL is charged its base cost, and the sub call gets all
+        // but one 64th of what is left, as GAS would push.
+        let sub_context = match machine.status() {
+            MachineStatus::InvokeCall(context, _) => context,
+            status => panic!("unexpected status {:?}", status),
+        };
+        let available = machine.state().available_gas();
+        assert_eq!(sub_context.gas_limit, available - available / Gas::from(64u64));
+    }
+
+    #[test]
+    fn machine_is_send() {
+        assert_send::<State<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>>();
+        assert_send::<Machine<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>>();
+    }
+}
@@ -2,7 +2,7 @@
 
 use bigint::M256;
 
-use ::Memory;
+use ::{Memory, M256Ext};
 use super::State;
 use patch::Patch;
 
@@ -38,3 +38,36 @@ pub fn byte<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
 
     push!(state, ret);
 }
+
+pub fn shl<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
+    pop!(state, shift, value);
+
+    let shifted = match shift.to_usize() {
+        Some(shift) if shift < 256 => value << shift,
+        _ => M256::zero(),
+    };
+    push!(state, shifted);
+}
+
+pub fn shr<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
+    pop!(state, shift, value);
+
+    let shifted = match shift.to_usize() {
+        Some(shift) if shift < 256 => value >> shift,
+        _ => M256::zero(),
+    };
+    push!(state, shifted);
+}
+
+pub fn sar<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
+    pop!(state, shift, value);
+
+    // A negative value is shifted as its complement, so that the
+    // vacated bits are filled with ones.
+    let negative = value.is_negative();
+    let shifted = match shift.to_usize() {
+        Some(shift) if shift < 256 => if negative { !((!value) >> shift) } else { value >> shift },
+        _ => if negative { M256::max_value() } else { M256::zero() },
+    };
+    push!(state, shifted);
+}
@@ -1,16 +1,15 @@
 //! Environment instructions
 
-use ::Memory;
+use bigint::{Address, M256};
+use ::{Memory, M256Ext};
 use super::State;
 use patch::Patch;
 
+use sha3::{Digest, Keccak256};
+
 pub fn calldataload<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
     pop!(state, index);
-    let index: Option<usize> = if index > usize::max_value().into() {
-        None
-    } else {
-        Some(index.as_usize())
-    };
+    let index: Option<usize> = index.to_usize();
     let data = state.context.data.as_slice();
     let mut load: [u8; 32] = [0u8; 32];
     for i in 0..32 {
@@ -20,3 +19,19 @@ pub fn calldataload<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
     }
     push!(state, load.as_ref().into());
 }
+
+pub fn extcodehash<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
+    pop!(state, address: Address);
+    state.accessed.insert(address);
+
+    // Nonexistent and, after EIP-161, empty accounts hash to zero.
+    // Precompiled contracts are treated as having no code.
+    let hash = if !state.account_state.exists(address).unwrap() {
+        M256::zero()
+    } else if P::precompileds().iter().any(|&(precompiled, _, _)| precompiled == address) {
+        M256::from(Keccak256::digest(&[]).as_slice())
+    } else {
+        state.account_state.code_hash(address).unwrap()
+    };
+    push!(state, hash);
+}
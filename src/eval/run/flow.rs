@@ -7,12 +7,14 @@ use patch::Patch;
 
 pub fn sload<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
     pop!(state, index: U256);
+    state.accessed_storage.insert((state.context.address, index));
     let value = state.account_state.storage_read(state.context.address, index).unwrap();
     push!(state, value);
 }
 
 pub fn sstore<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
     pop!(state, index: U256, value: M256);
+    state.accessed_storage.insert((state.context.address, index));
     state.account_state.storage_write(state.context.address, index, value).unwrap();
 }
 
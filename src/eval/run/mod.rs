@@ -49,8 +49,8 @@ mod flow;
 mod environment;
 mod system;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
 use bigint::{M256, MI256, U256, Address, Gas};
 #[cfg(feature = "std")] use std::ops::{Add, Sub, Mul, Div, Rem, BitAnd, BitOr, BitXor};
@@ -87,11 +87,15 @@ pub fn run_opcode<M: Memory + Default, P: Patch>(pc: (Instruction, usize), state
         Instruction::XOR => { op2!(state, bitxor); None },
         Instruction::NOT => { bitwise::not(state); None },
         Instruction::BYTE => { bitwise::byte(state); None },
+        Instruction::SHL => { bitwise::shl(state); None },
+        Instruction::SHR => { bitwise::shr(state); None },
+        Instruction::SAR => { bitwise::sar(state); None },
 
         Instruction::SHA3 => { system::sha3(state); None },
 
         Instruction::ADDRESS => { push!(state, state.context.address.into()); None },
         Instruction::BALANCE => { pop!(state, address: Address);
+                                  state.accessed.insert(address);
                                   push!(state, state.account_state.balance(address).unwrap().into());
                                   None },
         Instruction::ORIGIN => { push!(state, state.context.origin.into()); None },
@@ -112,10 +116,12 @@ pub fn run_opcode<M: Memory + Default, P: Patch>(pc: (Instruction, usize), state
                                    None },
         Instruction::GASPRICE => { push!(state, state.context.gas_price.into()); None },
         Instruction::EXTCODESIZE => { pop!(state, address: Address);
+                                      state.accessed.insert(address);
                                       push!(state,
                                             state.account_state.code(address).unwrap().len().into());
                                       None },
         Instruction::EXTCODECOPY => { pop!(state, address: Address);
+                                      state.accessed.insert(address);
                                       pop!(state, memory_index: U256, code_index: U256, len: U256);
                                       copy_into_memory(&mut state.memory,
                                                        &state.account_state.code(address).unwrap(),
@@ -127,10 +133,11 @@ pub fn run_opcode<M: Memory + Default, P: Patch>(pc: (Instruction, usize), state
                                                           state.ret.as_slice(),
                                                           memory_index, data_index, len);
                                          None },
+        Instruction::EXTCODEHASH => { environment::extcodehash(state); None },
 
         Instruction::BLOCKHASH => { pop!(state, number: U256);
                                     let current_number = runtime.block.number;
-                                    if !(number >= current_number || current_number - number > U256::from(256u64)) {
+                                    if !(number >= current_number || current_number - number > U256::from(P::blockhash_window())) {
                                         push!(state, M256::from(runtime.blockhash_state.get(number).unwrap()));
                                     } else {
                                         push!(state, M256::zero());
@@ -139,8 +146,16 @@ pub fn run_opcode<M: Memory + Default, P: Patch>(pc: (Instruction, usize), state
         Instruction::COINBASE => { push!(state, M256::from(runtime.block.beneficiary)); None },
         Instruction::TIMESTAMP => { push!(state, M256::from(runtime.block.timestamp)); None },
         Instruction::NUMBER => { push!(state, M256::from(runtime.block.number)); None },
-        Instruction::DIFFICULTY => { push!(state, M256::from(runtime.block.difficulty)); None },
+        Instruction::DIFFICULTY => {
+            if P::has_prev_randao() {
+                push!(state, M256::from(runtime.block.prev_randao));
+            } else {
+                push!(state, M256::from(runtime.block.difficulty));
+            }
+            None
+        },
         Instruction::GASLIMIT => { push!(state, runtime.block.gas_limit.into()); None },
+        Instruction::BASEFEE => { push!(state, runtime.block.base_fee); None },
 
         Instruction::POP => { state.stack.pop().unwrap(); None },
         Instruction::MLOAD => { flow::mload(state); None },
@@ -173,16 +188,689 @@ pub fn run_opcode<M: Memory + Default, P: Patch>(pc: (Instruction, usize), state
         Instruction::LOG(v) => { system::log(state, v); None },
 
         Instruction::CREATE => { system::create::<M, P>(state, after_gas) },
-        Instruction::CALL => { system::call::<M, P>(state, stipend_gas, after_gas, false) },
-        Instruction::CALLCODE => { system::call::<M, P>(state, stipend_gas, after_gas, true) },
+        Instruction::CALL => { system::call::<M, P>(state, runtime, stipend_gas, after_gas, false) },
+        Instruction::CALLCODE => { system::call::<M, P>(state, runtime, stipend_gas, after_gas, true) },
         Instruction::DELEGATECALL => { system::delegate_call::<M, P>(state, after_gas) },
         Instruction::STATICCALL => { system::static_call::<M, P>(state, stipend_gas, after_gas) },
         Instruction::RETURN => { pop!(state, start: U256, len: U256);
-                                 state.out = Rc::new(copy_from_memory(&mut state.memory, start, len));
+                                 state.out = Arc::new(copy_from_memory(&state.memory, start, len));
                                  Some(Control::Stop) },
         Instruction::REVERT => { pop!(state, start: U256, len: U256);
-                                 state.out = Rc::new(copy_from_memory(&mut state.memory, start, len));
+                                 state.out = Arc::new(copy_from_memory(&state.memory, start, len));
                                  Some(Control::Revert) },
         Instruction::SUICIDE => { system::suicide(state); Some(Control::Stop) },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::*;
+    use hexutil::*;
+    use errors::RequireError;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::collections::HashMap as Map;
+    use sha3::{Digest, Keccak256};
+    use testing::header;
+
+    fn context(code: &str, data: Vec<u8>) -> Context {
+        Context {
+            address: Address::from_str("0x1000000000000000000000000000000000000000").unwrap(),
+            caller: Address::from_str("0x2000000000000000000000000000000000000000").unwrap(),
+            code: Arc::new(read_hex(code).unwrap()),
+            data: Arc::new(data),
+            gas_limit: Gas::from(100000usize),
+            gas_price: Gas::zero(),
+            origin: Address::from_str("0x3000000000000000000000000000000000000000").unwrap(),
+            value: U256::from(7u64),
+            apprent_value: U256::from(7u64),
+            is_system: false,
+            is_static: false,
+        }
+    }
+
+    /// Run code that does not require any account, and return the VM
+    /// after it exits.
+    fn run(context: Context) -> SeqContextVM<EmbeddedByzantiumPatch> {
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header());
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        vm
+    }
+
+    #[test]
+    fn address_caller_callvalue() {
+        // ADDRESS, CALLER, CALLVALUE, STOP.
+        let context = context("30333400", Vec::new());
+        let vm = run(context.clone());
+        let stack = &vm.current_state().stack;
+
+        assert_eq!(stack.len(), 3);
+        assert_eq!(stack.peek(2).unwrap(), M256::from(context.address));
+        assert_eq!(stack.peek(1).unwrap(), M256::from(context.caller));
+        assert_eq!(stack.peek(0).unwrap(), M256::from(context.apprent_value));
+    }
+
+    #[test]
+    fn calldatasize() {
+        // CALLDATASIZE, STOP.
+        let vm = run(context("3600", vec![0xff; 37]));
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(37u64));
+
+        let vm = run(context("3600", Vec::new()));
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn codesize() {
+        // PUSH1 0, POP, CODESIZE, STOP.
+        let vm = run(context("6000503800", Vec::new()));
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(5u64));
+    }
+
+    #[test]
+    fn codecopy_past_code_end() {
+        // Copy 32 bytes of the 11 bytes long code into memory at 0,
+        // then MLOAD it.
+        let code = "6020600060003960005100";
+        let vm = run(context(code, Vec::new()));
+
+        let mut expected = read_hex(code).unwrap();
+        expected.resize(32, 0);
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(expected.as_slice()));
+        // Four pushes, CODECOPY with one word copied and one word of
+        // memory, and MLOAD.
+        assert_eq!(vm.used_gas(), Gas::from(4 * 3 + (3 + 3 + 3) + 3usize));
+    }
+
+    #[test]
+    fn extcodesize_requires_code() {
+        let target = Address::from_str("0x4000000000000000000000000000000000000000").unwrap();
+        // EXTCODESIZE of the target, STOP.
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+            context("7340000000000000000000000000000000000000003b00", Vec::new()), header());
+
+        match vm.fire() {
+            Err(RequireError::AccountCode(address)) => assert_eq!(address, target),
+            result => panic!("unexpected result {:?}", result),
+        }
+        vm.commit_account(AccountCommitment::Code {
+            address: target,
+            code: Arc::new(vec![0x60, 0x00, 0x00]),
+        }).unwrap();
+        vm.fire().unwrap();
+
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(3u64));
+    }
+
+    #[test]
+    fn complete_account_requires_nothing_more() {
+        // SLOAD 1, SLOAD 2, BALANCE and EXTCODESIZE of ADDRESS, STOP.
+        let context = context("6001546002543031303b00", Vec::new());
+        let mut storage = Map::new();
+        storage.insert(U256::one(), M256::from(42u64));
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context.clone(), header());
+        vm.commit_account(AccountCommitment::Complete {
+            nonce: U256::zero(),
+            address: context.address,
+            balance: U256::from(100u64),
+            code: context.code.clone(),
+            storage,
+        }).unwrap();
+        vm.fire().unwrap();
+
+        let stack = &vm.current_state().stack;
+        assert_eq!(stack.peek(3).unwrap(), M256::from(42u64));
+        assert_eq!(stack.peek(2).unwrap(), M256::zero());
+        assert_eq!(stack.peek(1).unwrap(), M256::from(100u64));
+        assert_eq!(stack.peek(0).unwrap(), M256::from(11u64));
+    }
+
+    #[test]
+    fn signed_comparison() {
+        let minus_one = "7f".to_string() + &"ff".repeat(32);
+        let int_min = "7f80".to_string() + &"00".repeat(31);
+        let int_max = "7f7f".to_string() + &"ff".repeat(31);
+        let compare = |a: &str, b: &str, opcode: &str| {
+            // Push b, push a, compare, STOP.
+            let vm = run(context(&format!("{}{}{}00", b, a, opcode), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+
+        // SLT(-1, 0)
+        assert_eq!(compare(&minus_one, "6000", "12"), M256::from(1u64));
+        // SGT(0, -1)
+        assert_eq!(compare("6000", &minus_one, "13"), M256::from(1u64));
+        // SLT(INT_MIN, INT_MAX)
+        assert_eq!(compare(&int_min, &int_max, "12"), M256::from(1u64));
+        assert_eq!(compare(&int_max, &int_min, "12"), M256::zero());
+        // LT(INT_MIN, INT_MAX) compares without sign.
+        assert_eq!(compare(&int_min, &int_max, "10"), M256::zero());
+    }
+
+    #[test]
+    fn unsigned_comparison() {
+        let max = "7f".to_string() + &"ff".repeat(32);
+        let max_minus_one = "7f".to_string() + &"ff".repeat(31) + "fe";
+        let compare = |a: &str, b: &str, opcode: &str| {
+            // Push b, push a, compare, STOP.
+            let vm = run(context(&format!("{}{}{}00", b, a, opcode), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+
+        // LT and GT at the 2^256 - 1 boundary.
+        assert_eq!(compare(&max_minus_one, &max, "10"), M256::one());
+        assert_eq!(compare(&max, &max_minus_one, "10"), M256::zero());
+        assert_eq!(compare(&max, &max, "10"), M256::zero());
+        assert_eq!(compare(&max, "6000", "11"), M256::one());
+        assert_eq!(compare("6000", &max, "11"), M256::zero());
+        assert_eq!(compare(&max, &max, "11"), M256::zero());
+        // EQ
+        assert_eq!(compare(&max, &max, "14"), M256::one());
+        assert_eq!(compare(&max, &max_minus_one, "14"), M256::zero());
+
+        let iszero = |a: &str| {
+            // Push a, ISZERO, STOP.
+            let vm = run(context(&format!("{}1500", a), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        assert_eq!(iszero("6000"), M256::one());
+        assert_eq!(iszero("6007"), M256::zero());
+        assert_eq!(iszero(&max), M256::zero());
+    }
+
+    #[test]
+    fn log_gas_scales_with_topics_and_data() {
+        // LOG0 of 0 bytes at offset 0, STOP.
+        let log0 = run(context("60006000a000", Vec::new()));
+        // Four topics, LOG4 of 64 bytes at offset 0, STOP.
+        let log4 = run(context("600460036002600160406000a400", Vec::new()));
+
+        assert_eq!(log0.used_gas(), Gas::from(2 * 3 + 375u64));
+        assert_eq!(log4.current_state().logs[0].topics.len(), 4);
+        assert_eq!(log4.current_state().logs[0].data.len(), 64);
+        // Four more pushes, then 375 per topic, 8 per byte and the
+        // memory expansion to two words.
+        assert_eq!(log4.used_gas() - log0.used_gas(),
+                   Gas::from(4 * 3 + 4 * 375 + 8 * 64 + 2 * 3u64));
+    }
+
+    #[test]
+    fn blockhash_requires_recent_hash() {
+        let mut header = header();
+        header.number = U256::from(1000u64);
+        let hash = H256::from(0x42u64);
+
+        // BLOCKHASH of block 744, STOP.
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+            context("6102e84000", Vec::new()), header.clone());
+        match vm.fire() {
+            Err(RequireError::Blockhash(number)) => assert_eq!(number, U256::from(744u64)),
+            result => panic!("unexpected result {:?}", result),
+        }
+        vm.commit_blockhash(U256::from(744u64), hash).unwrap();
+        vm.fire().unwrap();
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(hash));
+
+        // BLOCKHASH of blocks 743 and 1000 are out of range.
+        for code in &["6102e74000", "6103e84000"] {
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+                context(code, Vec::new()), header.clone());
+            vm.fire().unwrap();
+            assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::zero());
+        }
+    }
+
+    test_patch! {
+        /// Byzantium patch with the hashes of only 8 blocks available.
+        struct ShortBlockhashPatch;
+        fn blockhash_window() -> usize { 8 }
+    }
+
+    #[test]
+    fn patch_blockhash_window() {
+        let mut header = header();
+        header.number = U256::from(1000u64);
+        let hash = H256::from(0x42u64);
+
+        // BLOCKHASH of block 992, STOP.
+        let mut vm = SeqContextVM::<ShortBlockhashPatch>::new(
+            context("6103e04000", Vec::new()), header.clone());
+        match vm.fire() {
+            Err(RequireError::Blockhash(number)) => assert_eq!(number, U256::from(992u64)),
+            result => panic!("unexpected result {:?}", result),
+        }
+        vm.commit_blockhash(U256::from(992u64), hash).unwrap();
+        vm.fire().unwrap();
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(hash));
+
+        // BLOCKHASH of block 991 is out of range.
+        let mut vm = SeqContextVM::<ShortBlockhashPatch>::new(
+            context("6103df4000", Vec::new()), header);
+        vm.fire().unwrap();
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::zero());
+    }
+
+    #[test]
+    fn bad_jump_consumes_all_gas() {
+        // JUMP to 0x10 past the end of code; JUMP to 0x01 in PUSH
+        // data; JUMPI to 0x10 with a non-zero condition.
+        for code in &["601056", "60015600", "600160105700"] {
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+                context(code, Vec::new()), header());
+            vm.fire().unwrap();
+
+            match vm.status() {
+                VMStatus::ExitedErr(OnChainError::BadJumpDest) => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(vm.used_gas(), Gas::from(100000usize));
+        }
+
+        // JUMPI to 0x10 with a zero condition does not jump.
+        let vm = run(context("600060105700", Vec::new()));
+        assert_eq!(vm.used_gas(), Gas::from(3 + 3 + 10usize));
+    }
+
+    #[test]
+    fn jump_destination_range() {
+        // JUMP to 2^256 - 1, and to 2^64, which do not fit in the
+        // code or in usize.
+        let max = "7f".to_string() + &"ff".repeat(32);
+        for code in &[format!("{}56", max), "6801000000000000000056".to_string()] {
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+                context(code, Vec::new()), header());
+            vm.fire().unwrap();
+
+            match vm.status() {
+                VMStatus::ExitedErr(OnChainError::BadJumpDest) => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+        }
+
+        // JUMP to a JUMPDEST at 0x1000 past a run of STOPs, PUSH1 2,
+        // STOP.
+        let code = "61100056".to_string() + &"00".repeat(0x1000 - 4) + "5b600200";
+        let vm = run(context(&code, Vec::new()));
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(2u64));
+    }
+
+    #[test]
+    fn jumpi_checks_destination_only_when_taken() {
+        // JUMPI to 0xffff with a zero condition, PUSH1 1, STOP.
+        let vm = run(context("600061ffff57600100", Vec::new()));
+        assert_eq!(vm.current_state().stack.len(), 1);
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::one());
+
+        // JUMPI over INVALID to a JUMPDEST, PUSH1 2, STOP.
+        let vm = run(context("6001600657fe5b600200", Vec::new()));
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(2u64));
+
+        // JUMPI to 0xffff with a non-zero condition.
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+            context("600161ffff57600100", Vec::new()), header());
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::BadJumpDest) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn memory_expansion_out_of_gas() {
+        // MSTORE 1 at offset 0xffffffff; MSTORE 1 at offset 2^256 - 1.
+        let max = "7f".to_string() + &"ff".repeat(32);
+        for code in &["600163ffffffff5200".to_string(), format!("6001{}5200", max)] {
+            let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(
+                context(code, Vec::new()), header());
+            vm.fire().unwrap();
+
+            match vm.status() {
+                VMStatus::ExitedErr(OnChainError::EmptyGas) => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(vm.used_gas(), Gas::from(100000usize));
+            assert_eq!(vm.current_state().memory.len(), 0);
+        }
+    }
+
+    #[test]
+    fn exp() {
+        let exp = |base: &str, exponent: &str| {
+            // Push the exponent, push the base, EXP, STOP.
+            let vm = run(context(&format!("{}{}0a00", exponent, base), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let minus_one = "7f".to_string() + &"ff".repeat(32);
+
+        assert_eq!(exp("6000", "6000"), M256::one());
+        assert_eq!(exp("6005", "6000"), M256::one());
+        assert_eq!(exp("6000", "6007"), M256::zero());
+        // 2^256 wraps to zero, and 2^255 is the sign bit.
+        assert_eq!(exp("6002", "610100"), M256::zero());
+        assert_eq!(exp("6002", "60ff"), M256::one() << 255);
+        // (2^128 + 1)^2 = 2^129 + 1 modulo 2^256.
+        assert_eq!(exp("700100000000000000000000000000000001", "6002"),
+                   (M256::one() << 129) + M256::one());
+        // (-1)^(2^256 - 1) = -1, and (-1)^(2^256 - 2) = 1.
+        assert_eq!(exp(&minus_one, &minus_one), M256::max_value());
+        assert_eq!(exp(&minus_one, &("7f".to_string() + &"ff".repeat(31) + "fe")), M256::one());
+    }
+
+    #[test]
+    fn bitwise() {
+        let not = |x: &str| {
+            // Push x, NOT, NOT, STOP.
+            let vm = run(context(&format!("{}191900", x), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let and_not = |x: &str| {
+            // Push x, DUP1, NOT, AND, STOP.
+            let vm = run(context(&format!("{}80191600", x), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let or_not = |x: &str| {
+            // Push x, DUP1, NOT, OR, STOP.
+            let vm = run(context(&format!("{}80191700", x), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let xor_self = |x: &str| {
+            // Push x, DUP1, XOR, STOP.
+            let vm = run(context(&format!("{}801800", x), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+
+        // All-zeros, all-ones, and pseudo-random 256-bit values from
+        // a fixed linear congruential sequence.
+        let mut values = vec![vec![0x00u8; 32], vec![0xffu8; 32]];
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for _ in 0..16 {
+            values.push((0..32).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (seed >> 56) as u8
+            }).collect());
+        }
+
+        for value in &values {
+            let x = M256::from(value.as_slice());
+            let push = "7f".to_string() + &to_hex(value)[2..];
+            assert_eq!(not(&push), x);
+            assert_eq!(and_not(&push), M256::zero());
+            assert_eq!(or_not(&push), M256::max_value());
+            assert_eq!(xor_self(&push), M256::zero());
+        }
+
+        // NOT of zero is all ones; AND, OR and XOR of zero and all
+        // ones.
+        let max = "7f".to_string() + &"ff".repeat(32);
+        let vm = run(context("60001900", Vec::new()));
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::max_value());
+        let binary = |opcode: &str| {
+            let vm = run(context(&format!("6000{}{}00", max, opcode), Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        assert_eq!(binary("16"), M256::zero());
+        assert_eq!(binary("17"), M256::max_value());
+        assert_eq!(binary("18"), M256::max_value());
+    }
+
+    #[test]
+    fn add_mul_sub_wrap() {
+        let arithmetic = |opcode: &str, a: &[u8], b: &[u8]| {
+            // Push b, push a, the opcode, STOP.
+            let code = format!("7f{}7f{}{}00", &to_hex(b)[2..], &to_hex(a)[2..], opcode);
+            let vm = run(context(&code, Vec::new()));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let word = |value: u128| {
+            let mut bytes = [0u8; 32];
+            for i in 0..16 {
+                bytes[31 - i] = (value >> (8 * i)) as u8;
+            }
+            bytes
+        };
+
+        // Pseudo-random 64-bit operands, checked against 128-bit
+        // arithmetic, which cannot overflow for them.
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for _ in 0..16 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let a = seed as u128;
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let b = seed as u128;
+            assert_eq!(arithmetic("01", &word(a), &word(b)), M256::from(&word(a + b)[..]));
+            assert_eq!(arithmetic("02", &word(a), &word(b)), M256::from(&word(a * b)[..]));
+            let (large, small) = if a > b { (a, b) } else { (b, a) };
+            assert_eq!(arithmetic("03", &word(large), &word(small)), M256::from(&word(large - small)[..]));
+        }
+
+        // Results wrap around 2^256.
+        let max = [0xffu8; 32];
+        let one = word(1);
+        let zero = word(0);
+        assert_eq!(arithmetic("01", &max, &one), M256::zero());
+        assert_eq!(arithmetic("02", &max, &word(2)), M256::max_value() - M256::one());
+        assert_eq!(arithmetic("02", &max, &max), M256::one());
+        assert_eq!(arithmetic("03", &zero, &one), M256::max_value());
+        assert_eq!(arithmetic("03", &one, &max), M256::from(2u64));
+    }
+
+    test_patch! {
+        /// Byzantium patch with the London BASEFEE opcode enabled.
+        struct BaseFeePatch;
+        fn has_base_fee() -> bool { true }
+    }
+
+    #[test]
+    fn basefee() {
+        let mut header = header();
+        header.base_fee = M256::from(1000000007u64);
+        // BASEFEE, STOP.
+        let mut vm = SeqContextVM::<BaseFeePatch>::new(context("4800", Vec::new()), header.clone());
+        vm.fire().unwrap();
+
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(1000000007u64));
+        assert_eq!(vm.used_gas(), Gas::from(2u64));
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context("4800", Vec::new()), header);
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::UnsupportedOpcode(0x48)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    test_patch! {
+        /// Byzantium patch with the London BASEFEE opcode and the Merge
+        /// PREVRANDAO opcode enabled.
+        struct MergePatch;
+        fn has_base_fee() -> bool { true }
+        fn has_prev_randao() -> bool { true }
+    }
+
+    #[test]
+    fn prev_randao() {
+        let mut header = header();
+        header.difficulty = U256::from(131072u64);
+        header.prev_randao = H256::from(M256::from(0x5eedu64));
+        // DIFFICULTY, STOP.
+        let mut vm = SeqContextVM::<BaseFeePatch>::new(context("4400", Vec::new()), header.clone());
+        vm.fire().unwrap();
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(131072u64));
+
+        let mut vm = SeqContextVM::<MergePatch>::new(context("4400", Vec::new()), header);
+        vm.fire().unwrap();
+        assert_eq!(vm.current_state().stack.peek(0).unwrap(), M256::from(0x5eedu64));
+    }
+
+    test_patch! {
+        /// Byzantium patch with the Constantinople shift opcodes enabled.
+        struct ShiftPatch;
+        fn has_bitwise_shift() -> bool { true }
+    }
+
+    #[test]
+    fn shift() {
+        let shift = |opcode: &str, shift: &str, value: &str| {
+            // Push the value, push the shift, shift, STOP.
+            let code = format!("7f{}{}{}00", value, shift, opcode);
+            let mut vm = SeqContextVM::<ShiftPatch>::new(context(&code, Vec::new()), header());
+            vm.fire().unwrap();
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(vm.used_gas(), Gas::from(3 + 3 + 3u64));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let one = "00".repeat(31) + "01";
+        let minus_one = "ff".repeat(32);
+        let int_min = "80".to_string() + &"00".repeat(31);
+        let positive = "7f".to_string() + &"ff".repeat(31);
+        let max = "7f".to_string() + &"ff".repeat(32);
+
+        assert_eq!(shift("1b", "6001", &one), M256::from(2u64));
+        assert_eq!(shift("1b", "60ff", &one), M256::one() << 255);
+        assert_eq!(shift("1b", "610100", &one), M256::zero());
+        assert_eq!(shift("1c", "6001", &int_min), M256::one() << 254);
+        assert_eq!(shift("1c", "60ff", &minus_one), M256::one());
+        assert_eq!(shift("1c", "610100", &minus_one), M256::zero());
+
+        // SAR fills with the sign bit.
+        assert_eq!(shift("1d", "610100", &minus_one), M256::max_value());
+        assert_eq!(shift("1d", "610100", &int_min), M256::max_value());
+        assert_eq!(shift("1d", "610100", &positive), M256::zero());
+        assert_eq!(shift("1d", &max, &int_min), M256::max_value());
+        assert_eq!(shift("1d", &max, &positive), M256::zero());
+        assert_eq!(shift("1d", "60ff", &int_min), M256::max_value());
+        assert_eq!(shift("1d", "60fe", &int_min), !M256::one());
+        assert_eq!(shift("1d", "60fe", &positive), M256::one());
+        assert_eq!(shift("1d", "6001", &int_min), M256::from_big_endian(&[0xc0]) << 248);
+        assert_eq!(shift("1d", "6000", &int_min), M256::one() << 255);
+        assert_eq!(shift("1d", "6000", &positive), M256::max_value() >> 1);
+
+        // The opcodes are invalid before Constantinople.
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context("600160011d00", Vec::new()), header());
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::UnsupportedOpcode(0x1d)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    test_patch! {
+        /// Byzantium patch forbidding SSTORE.
+        struct NoSstorePatch;
+        fn forbidden_opcodes() -> &'static [Opcode] { &[Opcode::SSTORE] }
+    }
+
+    #[test]
+    fn forbidden_opcode() {
+        // Store 1 in slot 0, STOP.
+        let context = context("600160005500", Vec::new());
+        let account = AccountCommitment::Complete {
+            nonce: U256::zero(),
+            address: context.address,
+            balance: U256::zero(),
+            code: context.code.clone(),
+            storage: Map::new(),
+        };
+
+        let mut vm = SeqContextVM::<NoSstorePatch>::new(context.clone(), header());
+        vm.commit_account(account.clone()).unwrap();
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::ForbiddenOpcode(0x55)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(100000usize));
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context.clone(), header());
+        vm.commit_account(account).unwrap();
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().account_state.storage_read(context.address, U256::zero()).unwrap(),
+                   M256::one());
+    }
+
+    test_patch! {
+        /// Byzantium patch with the Constantinople EXTCODEHASH opcode
+        /// enabled.
+        struct ExtcodehashPatch;
+        type Account = EmbeddedByzantiumAccountPatch;
+        fn has_extcodehash() -> bool { true }
+    }
+
+    #[test]
+    fn extcodehash() {
+        let hash = |target: &str, account: Option<(U256, &str)>| {
+            // EXTCODEHASH of the target, STOP.
+            let target = Address::from_str(target).unwrap();
+            let mut vm = SeqContextVM::<ExtcodehashPatch>::new(
+                context(&format!("73{}3f00", &to_hex(&target)[2..]), Vec::new()), header());
+            vm.commit_account(match account {
+                Some((balance, code)) => AccountCommitment::Full {
+                    nonce: U256::zero(), address: target, balance,
+                    code: Arc::new(read_hex(code).unwrap()),
+                },
+                None => AccountCommitment::Nonexist(target),
+            }).unwrap();
+            vm.fire().unwrap();
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(vm.used_gas(), Gas::from(3 + 400usize));
+            vm.current_state().stack.peek(0).unwrap()
+        };
+        let empty = M256::from(read_hex("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470")
+                               .unwrap().as_slice());
+        let eoa = "0x2000000000000000000000000000000000000000";
+        let ecrecover = "0x0000000000000000000000000000000000000001";
+
+        assert_eq!(hash(eoa, None), M256::zero());
+        assert_eq!(hash(eoa, Some((U256::zero(), ""))), M256::zero());
+        assert_eq!(hash(eoa, Some((U256::one(), ""))), empty);
+        // PUSH1 0, STOP.
+        assert_eq!(hash(eoa, Some((U256::zero(), "600000"))),
+                   M256::from(Keccak256::digest(&read_hex("600000").unwrap()).as_slice()));
+
+        assert_eq!(hash(ecrecover, None), M256::zero());
+        assert_eq!(hash(ecrecover, Some((U256::one(), "600000"))), empty);
+
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context("303f00", Vec::new()), header());
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::UnsupportedOpcode(0x3f)) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn return_expands_memory() {
+        // RETURN 32 bytes at offset 0x100 of an empty memory, expanding
+        // it to nine words for 3 * 9 gas.
+        let vm = run(context("6020610100f3", Vec::new()));
+        assert_eq!(vm.used_gas(), Gas::from(3 + 3 + 3 * 9usize));
+        assert_eq!(vm.out(), &[0u8; 32][..]);
+        assert_eq!(vm.current_state().memory_cost, Gas::from(9usize));
+
+        // The same region with REVERT.
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context("6020610100fd", Vec::new()), header());
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::Revert) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(3 + 3 + 3 * 9usize));
+        assert_eq!(vm.out(), &[0u8; 32][..]);
+    }
+}
@@ -3,14 +3,13 @@
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
 use bigint::{U256, M256, H256, Address, Gas};
-use ::{Memory, Log, ValidTransaction, Patch};
+use ::{Memory, Log, Patch, CallFailure, create_address};
 use eval::util::{l64, copy_from_memory};
-use block_core::TransactionAction;
-use super::{Control, State};
+use super::{Control, State, Runtime};
 
 #[cfg(feature = "std")] use std::cmp::min;
 #[cfg(not(feature = "std"))] use core::cmp::min;
@@ -19,14 +18,15 @@ use sha3::{Digest, Keccak256};
 
 pub fn suicide<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
     pop!(state, address: Address);
+    state.accessed.insert(address);
     let balance = state.account_state.balance(state.context.address).unwrap();
     if !state.removed.contains(&state.context.address) {
         state.removed.push(state.context.address);
     }
-    state.account_state.increase_balance(address, balance);
-
-    let balance = state.account_state.balance(state.context.address).unwrap();
-    state.account_state.decrease_balance(state.context.address, balance);
+    state.account_state.transfer(state.context.address, address, balance).unwrap();
+    // The balance left is only nonzero when the contract names itself
+    // as the beneficiary, and is then burnt.
+    state.account_state.set_balance(state.context.address, U256::zero()).unwrap();
 }
 
 pub fn log<M: Memory + Default, P: Patch>(state: &mut State<M, P>, topic_len: usize) {
@@ -54,6 +54,8 @@ pub fn sha3<M: Memory + Default, P: Patch>(state: &mut State<M, P>) {
 macro_rules! try_callstack_limit {
     ( $state:expr, $patch:tt ) => {
         if $state.depth > $patch::callstack_limit() {
+            $state.call_failures.push(($state.depth, CallFailure::CallStackTooDeep));
+            $state.clear_return_data();
             push!($state, M256::zero());
             return None;
         }
@@ -63,6 +65,8 @@ macro_rules! try_callstack_limit {
 macro_rules! try_balance {
     ( $state:expr, $value:expr, $gas:expr ) => {
         if $state.account_state.balance($state.context.address).unwrap() < $value {
+            $state.call_failures.push(($state.depth, CallFailure::InsufficientBalance));
+            $state.clear_return_data();
             push!($state, M256::zero());
             return None;
         }
@@ -78,52 +82,53 @@ pub fn create<M: Memory + Default, P: Patch>(state: &mut State<M, P>, after_gas:
     try_callstack_limit!(state, P);
     try_balance!(state, value, Gas::zero());
 
-    let init = Rc::new(copy_from_memory(&state.memory, init_start, init_len));
-    let transaction = ValidTransaction {
-        caller: Some(state.context.address),
-        gas_price: state.context.gas_price,
-        gas_limit: l64_after_gas,
-        value: value,
-        input: init,
-        action: TransactionAction::Create,
-        nonce: state.account_state.nonce(state.context.address).unwrap(),
-    };
-    let context = transaction.into_context::<P>(
-        Gas::zero(), Some(state.context.origin), &mut state.account_state, true,
-        state.context.is_static,
-    ).unwrap();
+    let init = Arc::new(copy_from_memory(&state.memory, init_start, init_len));
+    let nonce = state.account_state.nonce(state.context.address).unwrap();
+    let address = create_address(state.context.address, nonce);
+    state.account_state.set_nonce(state.context.address, nonce + U256::from(1u64)).unwrap();
+    let context = state.context.create(address, init, value, l64_after_gas);
 
     push!(state, context.address.into());
     Some(Control::InvokeCreate(context))
 }
 
-pub fn call<M: Memory + Default, P: Patch>(state: &mut State<M, P>, stipend_gas: Gas, after_gas: Gas, as_self: bool) -> Option<Control> {
+pub fn call<M: Memory + Default, P: Patch>(state: &mut State<M, P>, runtime: &Runtime, stipend_gas: Gas, after_gas: Gas, as_self: bool) -> Option<Control> {
     let l64_after_gas = if P::call_create_l64_after_gas() { l64(after_gas) } else { after_gas };
 
     pop!(state, gas: Gas, to: Address, value: U256);
     pop!(state, in_start: U256, in_len: U256, out_start: U256, out_len: U256);
+    state.accessed.insert(to);
     let gas_limit = min(gas, l64_after_gas) + stipend_gas;
 
     try_callstack_limit!(state, P);
     try_balance!(state, value, gas_limit);
 
-    let input = Rc::new(copy_from_memory(&state.memory, in_start, in_len));
-    let transaction = ValidTransaction {
-        caller: Some(state.context.address),
-        gas_price: state.context.gas_price,
-        gas_limit: gas_limit,
-        value: value,
-        input: input,
-        action: TransactionAction::Call(to),
-        nonce: state.account_state.nonce(state.context.address).unwrap(),
+    let code = state.account_state.code(to).unwrap();
+    let input = Arc::new(copy_from_memory(&state.memory, in_start, in_len));
+    let context = if as_self {
+        state.context.call_code(code, input, value, gas_limit)
+    } else {
+        state.context.call(to, code, input, value, gas_limit)
     };
 
-    let mut context = transaction.into_context::<P>(
-        Gas::zero(), Some(state.context.origin), &mut state.account_state, true,
-        state.context.is_static,
-    ).unwrap();
-    if as_self {
-        context.address = state.context.address;
+    // A call without value into an existing account without code,
+    // other than a precompiled contract, cannot run anything, so it
+    // is resolved here without invoking a sub runtime. Calls into
+    // accounts that do not exist still invoke one, which creates or
+    // touches the account as the patch requires.
+    if value == U256::zero() && context.code.is_empty() &&
+        state.account_state.exists(to).unwrap() &&
+        !P::precompileds().iter().any(|&(address, _, _)| address == to)
+    {
+        for hook in &runtime.context_history_hooks {
+            hook(&context)
+        }
+        if !as_self {
+            state.account_state.increase_balance(to, U256::zero());
+        }
+        state.clear_return_data();
+        push!(state, M256::from(1u64));
+        return None;
     }
 
     push!(state, M256::from(1u64));
@@ -135,25 +140,14 @@ pub fn static_call<M: Memory + Default, P: Patch>(state: &mut State<M, P>, stipe
 
     pop!(state, gas: Gas, to: Address);
     pop!(state, in_start: U256, in_len: U256, out_start: U256, out_len: U256);
+    state.accessed.insert(to);
     let gas_limit = min(gas, l64_after_gas) + stipend_gas;
 
     try_callstack_limit!(state, P);
 
-    let input = Rc::new(copy_from_memory(&state.memory, in_start, in_len));
-    let transaction = ValidTransaction {
-        caller: Some(state.context.address),
-        gas_price: state.context.gas_price,
-        gas_limit: gas_limit,
-        value: U256::zero(),
-        input: input,
-        action: TransactionAction::Call(to),
-        nonce: state.account_state.nonce(state.context.address).unwrap(),
-    };
-
-    let context = transaction.into_context::<P>(
-        Gas::zero(), Some(state.context.origin), &mut state.account_state, true,
-        true,
-    ).unwrap();
+    let input = Arc::new(copy_from_memory(&state.memory, in_start, in_len));
+    let code = state.account_state.code(to).unwrap();
+    let context = state.context.static_call(to, code, input, gas_limit);
 
     push!(state, M256::from(1u64));
     Some(Control::InvokeCall(context, (out_start, out_len)))
@@ -164,27 +158,14 @@ pub fn delegate_call<M: Memory + Default, P: Patch>(state: &mut State<M, P>, aft
 
     pop!(state, gas: Gas, to: Address);
     pop!(state, in_start: U256, in_len: U256, out_start: U256, out_len: U256);
+    state.accessed.insert(to);
     let gas_limit = min(gas, l64_after_gas);
 
     try_callstack_limit!(state, P);
 
-    let input = Rc::new(copy_from_memory(&state.memory, in_start, in_len));
-    let transaction = ValidTransaction {
-        caller: Some(state.context.caller),
-        gas_price: state.context.gas_price,
-        gas_limit: gas_limit,
-        value: state.context.value,
-        input: input,
-        action: TransactionAction::Call(to),
-        nonce: state.account_state.nonce(state.context.address).unwrap(),
-    };
-
-    let mut context = transaction.into_context::<P>(
-        Gas::zero(), Some(state.context.origin), &mut state.account_state, true,
-        state.context.is_static,
-    ).unwrap();
-    context.value = U256::zero();
-    context.address = state.context.address;
+    let input = Arc::new(copy_from_memory(&state.memory, in_start, in_len));
+    let code = state.account_state.code(to).unwrap();
+    let context = state.context.delegate(code, input, gas_limit);
 
     push!(state, M256::from(1u64));
     Some(Control::InvokeCall(context, (out_start, out_len)))
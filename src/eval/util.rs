@@ -33,30 +33,26 @@ pub fn copy_from_memory<M: Memory>(memory: &M, start: U256, len: U256) -> Vec<u8
     result
 }
 
+/// Copy `len` bytes of the values from `value_start` into memory,
+/// padding with zeros past the end of the values.
 pub fn copy_into_memory<M: Memory>(memory: &mut M, values: &[u8], start: U256, value_start: U256, len: U256) {
+    if len == U256::zero() {
+        return;
+    }
+
     let value_len = U256::from(values.len());
-    let mut i = start;
-    let mut j = value_start;
-    while i < start + len {
-        if j < value_len {
-            let ju: usize = j.as_usize();
-            memory.write_raw(i, values[ju]).unwrap();
-            j = j + U256::from(1u64);
-        } else {
-            memory.write_raw(i, 0u8).unwrap();
-        }
-        i = i + U256::from(1u64);
+    let mut data: Vec<u8> = Vec::with_capacity(len.as_usize());
+    if value_start < value_len {
+        let from = value_start.as_usize();
+        let to = from + min(len, value_len - value_start).as_usize();
+        data.extend_from_slice(&values[from..to]);
     }
+    data.resize(len.as_usize(), 0u8);
+    memory.copy_from_slice(start, &data).unwrap();
 }
 
 pub fn copy_into_memory_apply<M: Memory>(memory: &mut M, values: &[u8], start: U256, len: U256) {
     let value_len = U256::from(values.len());
     let actual_len = min(len, value_len);
-    let mut i = start;
-    let mut j = 0;
-    while i < start + actual_len {
-        memory.write_raw(i, values[j]).unwrap();
-        i = i + U256::from(1u64);
-        j = j + 1;
-    }
+    memory.copy_from_slice(start, &values[..actual_len.as_usize()]).unwrap();
 }
@@ -33,8 +33,8 @@
 //! use sputnikvm::{EmbeddedByzantiumPatch, VMTestPatch,
 //!                 HeaderParams, ValidTransaction, TransactionAction,
 //!                 VM, SeqTransactionVM};
-//! use bigint::{Gas, U256, Address};
-//! use std::rc::Rc;
+//! use bigint::{Gas, U256, M256, H256, Address};
+//! use std::sync::Arc;
 //!
 //! fn main() {
 //!   let block_number = 1000;
@@ -44,15 +44,18 @@
 //!     gas_limit: Gas::max_value(),
 //!     action: TransactionAction::Create,
 //!     value: U256::zero(),
-//!     input: Rc::new(Vec::new()),
-//!     nonce: U256::zero()
+//!     input: Arc::new(Vec::new()),
+//!     nonce: U256::zero(),
+//!     access_list: Vec::new(),
 //!   };
 //!   let header = HeaderParams {
 //!     beneficiary: Address::default(),
 //!     timestamp: 0,
 //!     number: U256::zero(),
 //!     difficulty: U256::zero(),
-//!     gas_limit: Gas::zero()
+//!     gas_limit: Gas::zero(),
+//!     base_fee: M256::zero(),
+//!     prev_randao: H256::default()
 //!   };
 //!   let vm = if block_number < 500 {
 //!     SeqTransactionVM::<VMTestPatch>::new(
@@ -115,6 +118,9 @@ extern crate block;
 #[cfg(test)]
 extern crate hexutil;
 
+#[cfg(test)]
+#[macro_use]
+mod testing;
 mod util;
 mod memory;
 mod stack;
@@ -124,27 +130,44 @@ mod eval;
 mod commit;
 mod patch;
 mod transaction;
+mod trace;
+#[cfg(feature = "std")]
+mod envelope;
 pub mod errors;
 
 pub use self::memory::{Memory, SeqMemory};
 pub use self::stack::Stack;
-pub use self::pc::{PC, PCMut, Instruction, Valids};
+pub use self::pc::{PC, PCMut, Instruction, Valids, ValidsCache};
 pub use self::params::*;
 pub use self::patch::*;
-pub use self::eval::{State, Machine, Runtime, MachineStatus};
+pub use self::eval::{State, Machine, Runtime, MachineStatus, StepInfo, StepHook};
 pub use self::commit::{AccountCommitment, AccountChange, AccountState, BlockhashState, Storage};
-pub use self::transaction::{ValidTransaction, TransactionVM, UntrustedTransaction};
-pub use self::errors::{OnChainError, NotSupportedError, RequireError, CommitError, PreExecutionError};
+pub use self::transaction::{ValidTransaction, TransactionVM, UntrustedTransaction, AccessListItem,
+                            intrinsic_gas};
+pub use self::trace::{GasProfiler, OffsetProfiler, Coverage, StructLog, StructLogger};
+#[cfg(feature = "std")]
+pub use self::envelope::{TransactionType, TypedTransaction, EnvelopedTransaction,
+                         decode_transaction};
+pub use self::errors::{OnChainError, NotSupportedError, RequireError, CommitError, PreExecutionError,
+                       ParseHexError, CallFailure, BalanceError, ErrorKind, DebugError,
+                       AnalysisError};
 pub use self::util::opcode::Opcode;
+pub use self::util::address::{create_address, create2_address};
+pub use self::util::hex::{hex_to_bytes, bytes_to_hex};
+pub use self::util::m256::M256Ext;
+pub use self::util::gas::GasExt;
+pub use self::util::revert::revert_reason;
+pub use self::util::calldata::encode_call;
+pub use self::util::analysis::max_stack_depth;
 pub use block_core::TransactionAction;
 
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
 
 #[cfg(feature = "std")] use std::collections::{HashSet as Set, hash_map as map};
 #[cfg(not(feature = "std"))] use alloc::{BTreeSet as Set, btree_map as map};
-#[cfg(feature = "std")] use std::cmp::min;
-#[cfg(not(feature = "std"))] use core::cmp::min;
 use bigint::{U256, H256, Gas, Address};
 
 #[derive(Debug, Clone)]
@@ -163,6 +186,28 @@ pub enum VMStatus {
     /// agrees upon on a really large number of gas limit, so it
     /// usually can be safely ignored.
     ExitedNotSupported(NotSupportedError),
+    /// The transaction failed the checks on its caller before
+    /// running, such as a nonce mismatch or a balance not covering
+    /// `gas_limit * gas_price + value`. No code is run and no account
+    /// is changed.
+    Rejected(PreExecutionError),
+}
+
+impl VMStatus {
+    /// Returns `true` if the VM exited without errors.
+    pub fn is_success(&self) -> bool {
+        matches!(*self, VMStatus::ExitedOk)
+    }
+
+    /// Returns the kind of the on-chain error the VM exited with, or
+    /// `None` if it is running, succeeded, exited as not supported or
+    /// was rejected.
+    pub fn error_kind(&self) -> Option<ErrorKind> {
+        match *self {
+            VMStatus::ExitedErr(ref err) => Some(err.kind()),
+            _ => None,
+        }
+    }
 }
 
 /// Represents an EVM. This is usually the main interface for clients
@@ -187,19 +232,26 @@ pub trait VM {
     fn step(&mut self) -> Result<(), RequireError>;
     /// Run instructions until it reaches a `RequireError` or
     /// exits. If this function succeeds, the VM status can only be
-    /// either `ExitedOk` or `ExitedErr`.
+    /// either `ExitedOk`, `ExitedErr`, `ExitedNotSupported` or, for a
+    /// transaction, `Rejected`.
     fn fire(&mut self) -> Result<(), RequireError> {
         loop {
             match self.status() {
                 VMStatus::Running => self.step()?,
                 VMStatus::ExitedOk | VMStatus::ExitedErr(_) |
-                VMStatus::ExitedNotSupported(_) => return Ok(()),
+                VMStatus::ExitedNotSupported(_) | VMStatus::Rejected(_) => return Ok(()),
             }
         }
     }
     /// Returns the changed or committed accounts information up to
     /// current execution status.
     fn accounts(&self) -> map::Values<Address, AccountChange>;
+    /// Returns the same accounts as `accounts`, sorted by address.
+    fn sorted_accounts(&self) -> Vec<&AccountChange> {
+        let mut accounts: Vec<&AccountChange> = self.accounts().collect();
+        accounts.sort_by_key(|account| account.address());
+        accounts
+    }
     /// Returns all fetched or modified addresses.
     fn used_addresses(&self) -> Set<Address>;
     /// Returns the out value, if any.
@@ -208,11 +260,19 @@ pub trait VM {
     fn available_gas(&self) -> Gas;
     /// Returns the refunded gas of this VM.
     fn refunded_gas(&self) -> Gas;
+    /// Returns the refund subtracted from the used gas. This is the
+    /// refunded gas, capped at a fraction of the used gas.
+    fn applied_refund(&self) -> Gas;
     /// Returns logs to be appended to the current block if the user
-    /// decided to accept the running status of this VM.
+    /// decided to accept the running status of this VM. Logs of sub
+    /// runtimes are interleaved in execution order, so the position
+    /// of a log is its index in the transaction.
     fn logs(&self) -> &[Log];
     /// Returns all removed account addresses as for current VM execution.
     fn removed(&self) -> &[Address];
+    /// Returns calls and creates that failed without an error, with
+    /// the depth of the runtime invoking them.
+    fn call_failures(&self) -> &[(usize, CallFailure)];
     /// Returns the real used gas by the transaction or the VM
     /// context. Only available when the status of the VM is
     /// exited. Otherwise returns zero.
@@ -290,6 +350,19 @@ impl<M: Memory + Default, P: Patch> ContextVM<M, P> {
     pub fn add_context_history_hook<F: 'static + Fn(&Context)>(&mut self, f: F) {
         self.runtime.context_history_hooks.push(Box::new(f));
     }
+
+    /// Add a new step hook, called after each executed instruction.
+    pub fn add_step_hook<F: 'static + Fn(&StepInfo)>(&mut self, f: F) {
+        self.runtime.step_hooks.push(Box::new(f));
+    }
+
+    /// Set whether step hooks get the stack and the memory before
+    /// each instruction. Both are off by default, since they are
+    /// copied for every step.
+    pub fn set_step_snapshots(&mut self, stack: bool, memory: bool) {
+        self.runtime.stack_snapshots = stack;
+        self.runtime.memory_snapshots = memory;
+    }
 }
 
 impl<M: Memory + Default, P: Patch> VM for ContextVM<M, P> {
@@ -391,7 +464,7 @@ impl<M: Memory + Default, P: Patch> VM for ContextVM<M, P> {
             match self.status() {
                 VMStatus::Running => self.step()?,
                 VMStatus::ExitedOk | VMStatus::ExitedErr(_) |
-                VMStatus::ExitedNotSupported(_) => return Ok(()),
+                VMStatus::ExitedNotSupported(_) | VMStatus::Rejected(_) => return Ok(()),
             }
         }
     }
@@ -416,6 +489,10 @@ impl<M: Memory + Default, P: Patch> VM for ContextVM<M, P> {
         self.machines[0].state().refunded_gas
     }
 
+    fn applied_refund(&self) -> Gas {
+        self.machines[0].state().applied_refund()
+    }
+
     fn logs(&self) -> &[Log] {
         self.machines[0].state().logs.as_slice()
     }
@@ -424,10 +501,11 @@ impl<M: Memory + Default, P: Patch> VM for ContextVM<M, P> {
         self.machines[0].state().removed.as_slice()
     }
 
+    fn call_failures(&self) -> &[(usize, CallFailure)] {
+        self.machines[0].state().call_failures.as_slice()
+    }
+
     fn used_gas(&self) -> Gas {
-        let total_used = self.machines[0].state().total_used_gas();
-        let refund_cap = total_used / Gas::from(2u64);
-        let refunded = min(refund_cap, self.machines[0].state().refunded_gas);
-        total_used - refunded
+        self.machines[0].state().used_gas_after_refund()
     }
 }
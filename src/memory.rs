@@ -27,10 +27,24 @@ pub trait Memory {
     fn write(&mut self, index: U256, value: M256) -> Result<(), NotSupportedError>;
     /// Write only one byte value into the index.
     fn write_raw(&mut self, index: U256, value: u8) -> Result<(), NotSupportedError>;
+    /// Write the bytes starting at the index. The default writes
+    /// them one by one.
+    fn copy_from_slice(&mut self, index: U256, data: &[u8]) -> Result<(), NotSupportedError> {
+        self.check_write_range(index, U256::from(data.len()))?;
+        for (i, value) in data.iter().enumerate() {
+            self.write_raw(index + U256::from(i), *value)?;
+        }
+        Ok(())
+    }
     /// Read value from the index.
     fn read(&self, index: U256) -> M256;
     /// Read only one byte value from the index.
     fn read_raw(&self, index: U256) -> u8;
+    /// Clear the memory, so that every index reads zero. The default
+    /// replaces it with a new memory.
+    fn clear(&mut self) where Self: Sized + Default {
+        *self = Self::default();
+    }
 }
 
 /// A sequencial memory. It uses Rust's `Vec` for internal
@@ -54,11 +68,17 @@ impl<P: Patch> SeqMemory<P> {
     pub fn len(&self) -> usize {
         self.memory.len()
     }
+
+    /// The allocated memory region, starting at index zero. Reads
+    /// past its end return zero.
+    pub fn as_slice(&self) -> &[u8] {
+        self.memory.as_slice()
+    }
 }
 
 impl<P: Patch> Memory for SeqMemory<P> {
     fn check_write(&self, index: U256) -> Result<(), NotSupportedError> {
-        let end = index + 32.into();
+        let end = index.saturating_add(32.into());
         if end > U256::from(P::memory_limit()) {
             Err(NotSupportedError::MemoryIndexNotSupported)
         } else {
@@ -79,8 +99,8 @@ impl<P: Patch> Memory for SeqMemory<P> {
     }
 
     fn write(&mut self, index: U256, value: M256) -> Result<(), NotSupportedError> {
-        let end = M256::from(index) + 32.into();
-        if end > M256::from(P::memory_limit()) {
+        let end = index.saturating_add(32.into());
+        if end > U256::from(P::memory_limit()) {
             return Err(NotSupportedError::MemoryIndexNotSupported);
         }
 
@@ -105,6 +125,29 @@ impl<P: Patch> Memory for SeqMemory<P> {
         Ok(())
     }
 
+    fn clear(&mut self) {
+        self.memory.clear();
+    }
+
+    fn copy_from_slice(&mut self, index: U256, data: &[u8]) -> Result<(), NotSupportedError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if index.saturating_add(U256::from(data.len())) > U256::from(P::memory_limit()) {
+            return Err(NotSupportedError::MemoryIndexNotSupported);
+        }
+
+        let index: usize = index.as_usize();
+        let end = index + data.len();
+
+        if self.memory.len() < end {
+            self.memory.resize(end, 0u8);
+        }
+
+        self.memory[index..end].copy_from_slice(data);
+        Ok(())
+    }
+
     fn read(&self, index: U256) -> M256 {
         let mut a: [u8; 32] = [0u8; 32];
 
@@ -128,3 +171,85 @@ impl<P: Patch> Memory for SeqMemory<P> {
         self.memory[index]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::*;
+    use hexutil::*;
+    use std::sync::Arc;
+
+    test_patch! {
+        /// Byzantium patch with only 64 bytes of memory.
+        struct TinyMemoryPatch;
+        fn memory_limit() -> usize { 64 }
+    }
+
+    fn run(code: &str) -> SeqContextVM<TinyMemoryPatch> {
+        let context = Context {
+            address: Address::default(),
+            caller: Address::default(),
+            code: Arc::new(read_hex(code).unwrap()),
+            data: Arc::new(Vec::new()),
+            gas_limit: Gas::from(100000000usize),
+            gas_price: Gas::zero(),
+            origin: Address::default(),
+            value: U256::zero(),
+            apprent_value: U256::zero(),
+            is_system: false,
+            is_static: false,
+        };
+        let header = HeaderParams::default();
+
+        let mut vm = SeqContextVM::<TinyMemoryPatch>::new(context, header);
+        vm.fire().unwrap();
+        vm
+    }
+
+    #[test]
+    fn mstore_within_memory_limit() {
+        // MSTORE 1 at offset 32.
+        let vm = run("600160205200");
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.current_state().memory.len(), 64);
+    }
+
+    #[test]
+    fn mstore_past_memory_limit() {
+        // MSTORE 1 at offset 0x10000.
+        let vm = run("60016201000052");
+        match vm.status() {
+            VMStatus::ExitedNotSupported(NotSupportedError::MemoryIndexNotSupported) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        // The limit is checked before the memory grows.
+        assert_eq!(vm.current_state().memory.len(), 0);
+    }
+
+    #[test]
+    fn copy_from_slice_matches_byte_writes() {
+        let data: Vec<u8> = (0..4096).map(|i| (i * 7) as u8).collect();
+        let mut memory = SeqMemory::<EmbeddedByzantiumPatch>::default();
+        let mut reference = SeqMemory::<EmbeddedByzantiumPatch>::default();
+        // Write over the end of a shorter memory.
+        memory.write_raw(U256::from(10u64), 0xff).unwrap();
+        reference.write_raw(U256::from(10u64), 0xff).unwrap();
+
+        memory.copy_from_slice(U256::from(5u64), &data).unwrap();
+        for (i, value) in data.iter().enumerate() {
+            reference.write_raw(U256::from(5 + i), *value).unwrap();
+        }
+        assert_eq!(memory.as_slice(), reference.as_slice());
+        assert_eq!(memory.len(), 5 + 4096);
+
+        let mut tiny = SeqMemory::<TinyMemoryPatch>::default();
+        match tiny.copy_from_slice(U256::from(32u64), &data[0..33]) {
+            Err(NotSupportedError::MemoryIndexNotSupported) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(tiny.len(), 0);
+    }
+}
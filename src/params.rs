@@ -3,15 +3,18 @@
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
-use bigint::{U256, Address, Gas};
+use bigint::{U256, M256, H256, Address, Gas};
+use errors::ParseHexError;
+use util::hex::hex_to_bytes;
 #[cfg(feature = "std")]
 use block::Header;
 
-#[derive(Debug, Clone)]
-/// Block header.
+#[derive(Debug, Clone, Default)]
+/// Block header. The default is an all-zero header, which has no
+/// base fee as before London.
 pub struct HeaderParams {
     /// Block coinbase, the address that mines the block.
     pub beneficiary: Address,
@@ -22,7 +25,14 @@ pub struct HeaderParams {
     /// Difficulty of the block.
     pub difficulty: U256,
     /// Total block gas limit.
-    pub gas_limit: Gas
+    pub gas_limit: Gas,
+    /// Base fee per gas of the block, as defined in EIP-1559. Zero
+    /// for blocks before London.
+    pub base_fee: M256,
+    /// Mix hash field of the header, which holds the randomness of
+    /// the previous block since the Merge, as defined in EIP-4399.
+    /// Before the Merge it is the proof-of-work mix hash.
+    pub prev_randao: H256,
 }
 
 #[cfg(feature = "std")]
@@ -34,6 +44,8 @@ impl<'a> From<&'a Header> for HeaderParams {
             number: val.number,
             difficulty: val.difficulty,
             gas_limit: val.gas_limit,
+            base_fee: M256::zero(),
+            prev_randao: val.mix_hash,
         }
     }
 }
@@ -46,9 +58,9 @@ pub struct Context {
     /// Caller of the runtime.
     pub caller: Address,
     /// Code to be executed.
-    pub code: Rc<Vec<u8>>,
+    pub code: Arc<Vec<u8>>,
     /// Data associated with this execution.
-    pub data: Rc<Vec<u8>>,
+    pub data: Arc<Vec<u8>>,
     /// Gas limit.
     pub gas_limit: Gas,
     /// Gas price.
@@ -66,4 +78,265 @@ pub struct Context {
     pub is_static: bool,
 }
 
+impl Context {
+    /// Create a context running the given hex encoded code. All other
+    /// fields are zero, except the gas limit which is set to
+    /// `u64::MAX`. This is mostly useful for testing.
+    pub fn from_hex_code(code: &str) -> Result<Context, ParseHexError> {
+        Ok(Context {
+            address: Address::default(),
+            caller: Address::default(),
+            code: Arc::new(hex_to_bytes(code)?),
+            data: Arc::new(Vec::new()),
+            gas_limit: Gas::from(u64::MAX),
+            gas_price: Gas::zero(),
+            origin: Address::default(),
+            value: U256::zero(),
+            apprent_value: U256::zero(),
+            is_system: false,
+            is_static: false,
+        })
+    }
+
+    /// Finish a sub context of this context. The sub context may add
+    /// the static flag, but never remove it, so a context derived
+    /// from a static context is always static.
+    fn derive(&self, child: Context) -> Context {
+        let is_static = self.is_static || child.is_static;
+        Context { is_static, ..child }
+    }
+
+    /// Create the sub context for a CALL opcode. The code of `to` is
+    /// executed at `to`, with the current address as the caller and
+    /// `value` transferred.
+    pub fn call(&self, to: Address, code: Arc<Vec<u8>>, data: Arc<Vec<u8>>,
+                value: U256, gas_limit: Gas) -> Context {
+        self.derive(Context {
+            address: to,
+            caller: self.address,
+            code, data, gas_limit,
+            gas_price: self.gas_price,
+            origin: self.origin,
+            value,
+            apprent_value: value,
+            is_system: false,
+            is_static: false,
+        })
+    }
+
+    /// Create the sub context for a CALLCODE opcode. The code of the
+    /// target is executed at the current address, with the current
+    /// address as the caller and `value` transferred to itself.
+    pub fn call_code(&self, code: Arc<Vec<u8>>, data: Arc<Vec<u8>>,
+                     value: U256, gas_limit: Gas) -> Context {
+        self.derive(Context {
+            address: self.address,
+            caller: self.address,
+            code, data, gas_limit,
+            gas_price: self.gas_price,
+            origin: self.origin,
+            value,
+            apprent_value: value,
+            is_system: false,
+            is_static: false,
+        })
+    }
+
+    /// Create the sub context for a DELEGATECALL opcode. The code of
+    /// the target is executed at the current address, preserving the
+    /// current caller and apprent value. No value is transferred.
+    pub fn delegate(&self, code: Arc<Vec<u8>>, data: Arc<Vec<u8>>,
+                    gas_limit: Gas) -> Context {
+        self.derive(Context {
+            address: self.address,
+            caller: self.caller,
+            code, data, gas_limit,
+            gas_price: self.gas_price,
+            origin: self.origin,
+            value: U256::zero(),
+            apprent_value: self.apprent_value,
+            is_system: false,
+            is_static: false,
+        })
+    }
+
+    /// Create the sub context for a STATICCALL opcode. This is the
+    /// same as CALL without value, except that the sub context is
+    /// always static.
+    pub fn static_call(&self, to: Address, code: Arc<Vec<u8>>, data: Arc<Vec<u8>>,
+                       gas_limit: Gas) -> Context {
+        let context = self.call(to, code, data, U256::zero(), gas_limit);
+        self.derive(Context { is_static: true, ..context })
+    }
+
+    /// Create the sub context for a CREATE opcode. The init code is
+    /// executed at the newly created `address`, with the current
+    /// address as the caller and `value` transferred.
+    pub fn create(&self, address: Address, init: Arc<Vec<u8>>,
+                  value: U256, gas_limit: Gas) -> Context {
+        self.derive(Context {
+            address,
+            caller: self.address,
+            code: init,
+            data: Arc::new(Vec::new()),
+            gas_limit,
+            gas_price: self.gas_price,
+            origin: self.origin,
+            value,
+            apprent_value: value,
+            is_system: false,
+            is_static: false,
+        })
+    }
+}
+
 pub use block_core::Log;
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::*;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn parent() -> Context {
+        Context {
+            address: Address::from_str("0x1000000000000000000000000000000000000000").unwrap(),
+            caller: Address::from_str("0x2000000000000000000000000000000000000000").unwrap(),
+            code: Arc::new(vec![0x00]),
+            data: Arc::new(Vec::new()),
+            gas_limit: Gas::from(100000usize),
+            gas_price: Gas::from(2usize),
+            origin: Address::from_str("0x3000000000000000000000000000000000000000").unwrap(),
+            value: U256::from(5usize),
+            apprent_value: U256::from(5usize),
+            is_system: false,
+            is_static: false,
+        }
+    }
+
+    #[test]
+    fn call_context() {
+        let parent = parent();
+        let to = Address::from_str("0x4000000000000000000000000000000000000000").unwrap();
+        let code = Arc::new(vec![0x01]);
+        let child = parent.call(to, code.clone(), Arc::new(vec![0x02]), U256::from(3usize), Gas::from(100usize));
+
+        assert_eq!(child.address, to);
+        assert_eq!(child.caller, parent.address);
+        assert_eq!(child.origin, parent.origin);
+        assert_eq!(child.value, U256::from(3usize));
+        assert_eq!(child.apprent_value, U256::from(3usize));
+        assert_eq!(child.code, code);
+        assert_eq!(child.data, Arc::new(vec![0x02]));
+        assert_eq!(child.gas_price, parent.gas_price);
+    }
+
+    #[test]
+    fn create_context() {
+        let parent = parent();
+        let created = create_address(parent.address, U256::zero());
+        let init = Arc::new(vec![0x01]);
+        let child = parent.create(created, init.clone(), U256::from(3usize), Gas::from(100usize));
+
+        assert_eq!(child.address, created);
+        assert_eq!(child.caller, parent.address);
+        assert_eq!(child.origin, parent.origin);
+        assert_eq!(child.value, U256::from(3usize));
+        assert_eq!(child.apprent_value, U256::from(3usize));
+        assert_eq!(child.code, init);
+        assert!(child.data.is_empty());
+    }
+
+    #[test]
+    fn delegate_context() {
+        let parent = parent();
+        let code = Arc::new(vec![0x01]);
+        let child = parent.delegate(code.clone(), Arc::new(vec![0x02]), Gas::from(100usize));
+
+        assert_eq!(child.address, parent.address);
+        assert_eq!(child.caller, parent.caller);
+        assert_eq!(child.origin, parent.origin);
+        assert_eq!(child.value, U256::zero());
+        assert_eq!(child.apprent_value, parent.apprent_value);
+        assert_eq!(child.code, code);
+        assert_eq!(child.gas_limit, Gas::from(100usize));
+    }
+
+    #[test]
+    fn data_not_duplicated() {
+        let mut parent = parent();
+        parent.data = Arc::new(vec![0xff; 1024 * 1024]);
+
+        let copy = parent.clone();
+        assert!(Arc::ptr_eq(&copy.data, &parent.data));
+
+        // A sub context only holds the input passed to it.
+        let child = parent.delegate(Arc::new(vec![0x01]), Arc::new(vec![0x02]), Gas::from(100usize));
+        assert_eq!(child.data, Arc::new(vec![0x02]));
+        assert_eq!(Arc::strong_count(&parent.data), 2);
+    }
+
+    #[test]
+    fn call_code_context() {
+        let parent = parent();
+        let code = Arc::new(vec![0x01]);
+        let child = parent.call_code(code.clone(), Arc::new(vec![0x02]), U256::from(3usize), Gas::from(100usize));
+
+        assert_eq!(child.address, parent.address);
+        assert_eq!(child.caller, parent.address);
+        assert_eq!(child.origin, parent.origin);
+        assert_eq!(child.value, U256::from(3usize));
+        assert_eq!(child.apprent_value, U256::from(3usize));
+        assert_eq!(child.code, code);
+    }
+
+    #[test]
+    fn static_call_context() {
+        let parent = parent();
+        let to = Address::from_str("0x4000000000000000000000000000000000000000").unwrap();
+        let child = parent.static_call(to, Arc::new(Vec::new()), Arc::new(Vec::new()), Gas::from(100usize));
+
+        assert_eq!(child.address, to);
+        assert_eq!(child.caller, parent.address);
+        assert_eq!(child.value, U256::zero());
+        assert!(child.is_static);
+    }
+
+    #[test]
+    fn static_parent_yields_static_children() {
+        let mut parent = parent();
+        parent.is_static = true;
+        let to = Address::from_str("0x4000000000000000000000000000000000000000").unwrap();
+        let code = Arc::new(vec![0x01]);
+        let data = Arc::new(Vec::new());
+        let gas = Gas::from(100usize);
+
+        assert!(parent.call(to, code.clone(), data.clone(), U256::zero(), gas).is_static);
+        assert!(parent.call_code(code.clone(), data.clone(), U256::zero(), gas).is_static);
+        assert!(parent.delegate(code.clone(), data.clone(), gas).is_static);
+        assert!(parent.static_call(to, code.clone(), data.clone(), gas).is_static);
+        assert!(parent.create(to, code.clone(), U256::zero(), gas).is_static);
+
+        parent.is_static = false;
+        assert!(!parent.call(to, code.clone(), data.clone(), U256::zero(), gas).is_static);
+        assert!(parent.static_call(to, code, data, gas).is_static);
+    }
+
+    #[test]
+    fn default_header() {
+        let header = HeaderParams::default();
+        assert_eq!(header.base_fee, M256::zero());
+        assert_eq!(header.number, U256::zero());
+        assert_eq!(header.beneficiary, Address::default());
+    }
+
+    #[test]
+    fn from_hex_code() {
+        let context = Context::from_hex_code("0x6001600055").unwrap();
+        assert_eq!(context.code, Arc::new(vec![0x60, 0x01, 0x60, 0x00, 0x55]));
+        assert!(context.data.is_empty());
+
+        assert_eq!(Context::from_hex_code("0x600").err(), Some(ParseHexError::OddLength));
+    }
+}
@@ -6,6 +6,7 @@ mod precompiled;
 pub use self::precompiled::*;
 
 use bigint::{Address, Gas, U256, H160};
+use ::{Memory, Opcode, State};
 
 /// Account patch for account related variables.
 pub trait AccountPatch {
@@ -44,6 +45,15 @@ pub trait Patch {
 
     /// Maximum contract size.
     fn code_deposit_limit() -> Option<usize>;
+    /// Maximum size of the output of a RETURN or REVERT in init code,
+    /// or `None` for no limit. A larger output fails the creation with
+    /// `OnChainError::OutputTooLarge` before any memory is copied, to
+    /// bound allocation under adversarial init code. Unlike
+    /// `code_deposit_limit`, this is not a consensus rule: EIP-170
+    /// only limits the deposited code, after init code ran, and does
+    /// not apply to REVERT. It still applies to outputs within this
+    /// limit.
+    fn init_code_output_limit() -> Option<usize> { None }
     /// Limit of the call stack.
     fn callstack_limit() -> usize;
     /// Gas paid for extcode.
@@ -58,10 +68,18 @@ pub trait Patch {
     fn gas_suicide_new_account() -> Gas;
     /// Gas paid for CALL opcode.
     fn gas_call() -> Gas;
+    /// Gas paid for EXTCODEHASH opcode.
+    fn gas_extcodehash() -> Gas { Gas::from(400usize) }
     /// Gas paid for EXP opcode for every byte.
     fn gas_expbyte() -> Gas;
     /// Gas paid for a contract creation transaction.
     fn gas_transaction_create() -> Gas;
+    /// Gas paid for every non-zero byte of transaction data. This is
+    /// lowered to 16 in Istanbul by EIP-2028.
+    fn gas_transaction_data_nonzero() -> Gas { Gas::from(68usize) }
+    /// Divisor of the used gas giving the maximum refund of a
+    /// transaction. This is raised to 5 in London by EIP-3529.
+    fn max_refund_quotient() -> usize { 2 }
     /// Whether to force code deposit even if it does not have enough
     /// gas.
     fn force_code_deposit() -> bool;
@@ -73,6 +91,25 @@ pub trait Patch {
     fn has_revert() -> bool;
     /// Whether the EVM has RETURNDATASIZE and RETURNDATACOPY opcode.
     fn has_return_data() -> bool;
+    /// Whether the EVM has EXTCODEHASH opcode, as defined in
+    /// EIP-1052.
+    fn has_extcodehash() -> bool { false }
+    /// Whether the EVM has BASEFEE opcode.
+    fn has_base_fee() -> bool { false }
+    /// Whether the DIFFICULTY opcode pushes the previous block's
+    /// randomness instead, as PREVRANDAO defined in EIP-4399.
+    fn has_prev_randao() -> bool { false }
+    /// Whether the EVM has SHL, SHR and SAR opcodes, as defined in
+    /// EIP-145.
+    fn has_bitwise_shift() -> bool { false }
+    /// Whether SSTORE gas and refunds are computed against the
+    /// original value of the storage in the transaction, and SSTORE
+    /// fails with no more gas left than the call stipend, as defined
+    /// in EIP-2200.
+    fn has_net_gas_metering() -> bool { false }
+    /// Whether the EVM charges more for the first access of an
+    /// address in a transaction, as defined in EIP-2929.
+    fn has_access_list() -> bool { false }
     /// Whether to throw out of gas error when
     /// CALL/CALLCODE/DELEGATECALL requires more than maximum amount
     /// of gas.
@@ -82,9 +119,46 @@ pub trait Patch {
     fn call_create_l64_after_gas() -> bool;
     /// Maximum size of the memory, in bytes.
     fn memory_limit() -> usize;
+    /// Maximum number of items on the stack.
+    fn stack_limit() -> usize { 1024 }
+    /// Number of most recent blocks whose hashes are available to
+    /// BLOCKHASH. Older blocks push zero.
+    fn blockhash_window() -> usize { 256 }
     /// Precompiled contracts at given address, with required code,
     /// and its definition.
     fn precompileds() -> &'static [(Address, Option<&'static [u8]>, &'static Precompiled)];
+    /// Opcodes that fail with `OnChainError::ForbiddenOpcode`
+    /// regardless of the hard fork, to run untrusted code in a
+    /// sandbox.
+    fn forbidden_opcodes() -> &'static [Opcode] { &[] }
+    /// Whether to exit with `NotSupportedError::AmbiguousBehavior`
+    /// instead of choosing one interpretation when implementations
+    /// are known to differ, for conformance testing. This currently
+    /// guards memory growing past 2^32 words with enough gas to pay
+    /// for it, which some implementations fail as a gas overflow.
+    fn strict() -> bool { false }
+    /// Whether opcodes undefined in the patch fail with
+    /// `OnChainError::InvalidOpcode`, or `UnsupportedOpcode` for the
+    /// ones it does not enable. If not, they cost the base gas and
+    /// are skipped, to fuzz against tolerant implementations. The
+    /// designated INVALID opcode `0xfe` always fails.
+    fn undefined_as_invalid() -> bool { true }
+    /// Gas cost of an opcode replacing the default schedule, or
+    /// `None` to use the default. The state is the one before the
+    /// opcode runs. Memory expansion is still charged on top. For
+    /// CALL and CREATE opcodes, only the base cost is replaced, and
+    /// the gas given to the sub runtime is still taken from the gas
+    /// left after it.
+    fn gas_override<M: Memory + Default>(_opcode: Opcode, _state: &State<M, Self>) -> Option<Gas>
+        where Self: Sized { None }
+    /// Gas cost of calling the precompiled contract at `address` with
+    /// the given input, replacing the cost defined by the contract, or
+    /// `None` to use it. The contract still computes the output, and
+    /// fails if its own cost exceeds the gas limit of the call, so the
+    /// override must be an upper bound on the work the contract does.
+    /// A lower override only lowers the charged cost, and does not let
+    /// a call run a contract that its gas limit cannot pay for.
+    fn precompiled_gas_override(_address: Address, _data: &[u8]) -> Option<Gas> { None }
 }
 
 /// Default precompiled collections.
@@ -183,3 +257,25 @@ impl Patch for EmbeddedByzantiumPatch {
     fn precompileds() -> &'static [(Address, Option<&'static [u8]>, &'static Precompiled)] {
         &EMBEDDED_PRECOMPILEDS }
 }
+
+/// The patch to use when no specific hard fork is needed, such as in
+/// tests. It is the latest embedded mainnet patch, currently
+/// Byzantium, and only changes in a breaking release.
+pub type DefaultPatch = EmbeddedByzantiumPatch;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_patch_is_byzantium() {
+        assert!(DefaultPatch::has_delegate_call());
+        assert!(DefaultPatch::has_static_call());
+        assert!(DefaultPatch::has_revert());
+        assert!(DefaultPatch::has_return_data());
+        assert!(!DefaultPatch::has_base_fee());
+        assert!(DefaultPatch::call_create_l64_after_gas());
+        assert_eq!(DefaultPatch::code_deposit_limit(), Some(0x6000));
+        assert_eq!(DefaultPatch::gas_call(), Gas::from(700usize));
+    }
+}
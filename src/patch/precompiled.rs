@@ -1,8 +1,8 @@
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
 use bigint::Gas;
 #[cfg(all(feature = "std", any(feature = "rust-secp256k1", feature = "c-secp256k1")))] use std::cmp::min;
@@ -17,13 +17,15 @@ use digest::{Digest, FixedOutput};
 
 #[cfg(feature = "c-secp256k1")]
 use secp256k1::{SECP256K1, RecoverableSignature, Message, RecoveryId, Error};
+#[cfg(all(feature = "std", feature = "c-secp256k1"))] use std::mem;
+#[cfg(all(not(feature = "std"), feature = "c-secp256k1"))] use core::mem;
 #[cfg(feature = "rust-secp256k1")]
 use secp256k1::{recover, Message, RecoveryId, Signature, Error};
 
 /// Represent a precompiled contract.
 pub trait Precompiled: Sync {
     /// Step a precompiled contract based on the gas required.
-    fn step(&self, _: &[u8]) -> Rc<Vec<u8>> {
+    fn step(&self, _: &[u8]) -> Arc<Vec<u8>> {
         unimplemented!()
     }
     /// Gas needed for a given computation.
@@ -31,7 +33,7 @@ pub trait Precompiled: Sync {
         unimplemented!()
     }
     /// Combine step and gas together, given the gas limit.
-    fn gas_and_step(&self, data: &[u8], gas_limit: Gas) -> Result<(Gas, Rc<Vec<u8>>), RuntimeError> {
+    fn gas_and_step(&self, data: &[u8], gas_limit: Gas) -> Result<(Gas, Arc<Vec<u8>>), RuntimeError> {
         let gas = self.gas(data);
         if gas > gas_limit {
             Err(RuntimeError::OnChain(OnChainError::EmptyGas))
@@ -49,8 +51,8 @@ impl Precompiled for IDPrecompiled {
             Gas::from(3u64) * gas_div_ceil(Gas::from(data.len()), Gas::from(32u64))
     }
 
-    fn step(&self, data: &[u8]) -> Rc<Vec<u8>> {
-        Rc::new(data.into())
+    fn step(&self, data: &[u8]) -> Arc<Vec<u8>> {
+        Arc::new(data.into())
     }
 }
 /// Static value of ID precompiled contract.
@@ -64,7 +66,7 @@ impl Precompiled for RIP160Precompiled {
             Gas::from(120u64) * gas_div_ceil(Gas::from(data.len()), Gas::from(32u64))
     }
 
-    fn step(&self, data: &[u8]) -> Rc<Vec<u8>> {
+    fn step(&self, data: &[u8]) -> Arc<Vec<u8>> {
         let mut ripemd = Ripemd160::default();
         ripemd.input(data);
         let fixed = ripemd.fixed_result();
@@ -72,7 +74,7 @@ impl Precompiled for RIP160Precompiled {
         for i in 0..20 {
             result[i + 12] = fixed[i];
         }
-        Rc::new(result.as_ref().into())
+        Arc::new(result.as_ref().into())
     }
 }
 /// Static value of RIP160 precompiled contract.
@@ -87,7 +89,7 @@ impl Precompiled for SHA256Precompiled {
                                             Gas::from(32u64))
     }
 
-    fn step(&self, data: &[u8]) -> Rc<Vec<u8>> {
+    fn step(&self, data: &[u8]) -> Arc<Vec<u8>> {
         let mut sha2 = Sha256::default();
         sha2.input(data);
         let fixed = sha2.fixed_result();
@@ -95,7 +97,7 @@ impl Precompiled for SHA256Precompiled {
         for i in 0..32 {
             result[i] = fixed[i];
         }
-        Rc::new(result.as_ref().into())
+        Arc::new(result.as_ref().into())
     }
 }
 /// Static value of SHA256 precompiled contract.
@@ -109,7 +111,7 @@ impl Precompiled for ECRECPrecompiled {
         Gas::from(3000u64)
     }
 
-    fn step(&self, datao: &[u8]) -> Rc<Vec<u8>> {
+    fn step(&self, datao: &[u8]) -> Arc<Vec<u8>> {
         let mut data = [0u8; 128];
         for i in 0..min(datao.len(), 128) {
             data[i] = datao[i];
@@ -119,15 +121,15 @@ impl Precompiled for ECRECPrecompiled {
                 for i in 0..12 {
                     ret[i] = 0u8;
                 }
-                Rc::new(ret.as_ref().into())
+                Arc::new(ret.as_ref().into())
             },
-            Err(_) => Rc::new(Vec::new()),
+            Err(_) => Arc::new(Vec::new()),
         }
     }
 }
 #[cfg(all(not(feature = "c-secp256k1"), not(feature = "rust-secp256k1")))]
 impl Precompiled for ECRECPrecompiled {
-    fn gas_and_step(&self, _: &[u8], _: Gas) -> Result<(Gas, Rc<Vec<u8>>), RuntimeError> {
+    fn gas_and_step(&self, _: &[u8], _: Gas) -> Result<(Gas, Arc<Vec<u8>>), RuntimeError> {
         use errors::NotSupportedError;
 
         Err(RuntimeError::NotSupported(NotSupportedError::PrecompiledNotSupported))
@@ -145,7 +147,7 @@ fn gas_div_ceil(a: Gas, b: Gas) -> Gas {
 }
 
 #[cfg(feature = "c-secp256k1")]
-fn kececrec(data: &[u8]) -> Result<[u8; 32], Error> {
+pub(crate) fn kececrec(data: &[u8]) -> Result<[u8; 32], Error> {
     let message = Message::from_slice(&data[0..32])?;
     let recid_raw = match data[63] {
         27 | 28 if data[32..63] == [0; 31] => data[63] - 27,
@@ -157,6 +159,9 @@ fn kececrec(data: &[u8]) -> Result<[u8; 32], Error> {
     let key = recovered.serialize_vec(&SECP256K1, false);
 
     let ret_generic = Keccak256::digest(&key[1..65]);
+    // The array vector of secp256k1 reads past its length when dropped,
+    // and it holds no heap memory, so it is forgotten instead.
+    mem::forget(key);
     let mut ret = [0u8; 32];
 
     for i in 0..32 {
@@ -167,7 +172,7 @@ fn kececrec(data: &[u8]) -> Result<[u8; 32], Error> {
 }
 
 #[cfg(feature = "rust-secp256k1")]
-fn kececrec(data: &[u8]) -> Result<[u8; 32], Error> {
+pub(crate) fn kececrec(data: &[u8]) -> Result<[u8; 32], Error> {
     let mut message_raw = [0u8; 32];
     for i in 0..32 {
         message_raw[i] = data[i];
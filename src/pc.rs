@@ -2,8 +2,14 @@
 
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
-use bigint::M256;
+#[cfg(feature = "std")] use std::collections::HashMap as Map;
+#[cfg(not(feature = "std"))] use alloc::BTreeMap as Map;
+
+use bigint::{M256, H256};
+use sha3::{Digest, Keccak256};
 use util::opcode::Opcode;
 #[cfg(feature = "std")] use std::cmp::min;
 #[cfg(feature = "std")] use std::marker::PhantomData;
@@ -20,12 +26,14 @@ use super::errors::OnChainError;
 pub enum Instruction {
     STOP, ADD, MUL, SUB, DIV, SDIV, MOD, SMOD, ADDMOD, MULMOD, EXP,
     SIGNEXTEND, LT, GT, SLT, SGT, EQ, ISZERO, AND, OR, XOR, NOT, BYTE,
+    SHL, SHR, SAR,
     SHA3, ADDRESS, BALANCE, ORIGIN, CALLER, CALLVALUE, CALLDATALOAD,
     CALLDATASIZE, CALLDATACOPY, CODESIZE, CODECOPY, GASPRICE,
     EXTCODESIZE, EXTCODECOPY, BLOCKHASH, COINBASE, TIMESTAMP, NUMBER,
-    DIFFICULTY, GASLIMIT, POP, MLOAD, MSTORE, MSTORE8, SLOAD, SSTORE,
+    DIFFICULTY, GASLIMIT, BASEFEE, POP, MLOAD, MSTORE, MSTORE8, SLOAD, SSTORE,
     JUMP, JUMPI, PC, MSIZE, GAS, JUMPDEST, CREATE, CALL, CALLCODE,
     RETURN, DELEGATECALL, SUICIDE, STATICCALL, REVERT, RETURNDATASIZE, RETURNDATACOPY,
+    EXTCODEHASH,
 
     PUSH(M256),
     DUP(usize),
@@ -33,11 +41,15 @@ pub enum Instruction {
     LOG(usize),
 }
 
-/// Mapping of valid jump destination from code.
-pub struct Valids(Vec<bool>);
+#[derive(Clone)]
+/// Mapping of valid jump destination from code. Clones share the
+/// same analysis.
+pub struct Valids(Arc<Vec<bool>>);
 
 impl Valids {
-    /// Create a new valid mapping from given code bytes.
+    /// Create a new valid mapping from given code bytes. This is a
+    /// single pass over the code, and the mapping takes one entry per
+    /// code byte.
     pub fn new(code: &[u8]) -> Self {
         let mut valids: Vec<bool> = Vec::with_capacity(code.len());
         valids.resize(code.len(), false);
@@ -59,7 +71,7 @@ impl Valids {
             }
         }
 
-        Valids(valids)
+        Valids(Arc::new(valids))
     }
 
     /// Get the length of the valid mapping. This is the same as the
@@ -81,6 +93,53 @@ impl Valids {
     }
 }
 
+#[derive(Clone, Default)]
+/// Valid mappings keyed by code hash, so that runtimes running the
+/// same code share one analysis even if the code bytes are separate
+/// copies. Sub runtimes start from a clone of their parent's cache,
+/// and hand it back when they are applied.
+pub struct ValidsCache(Map<H256, Valids>);
+
+impl ValidsCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        ValidsCache(Map::new())
+    }
+
+    /// Get the valid mapping of the given code bytes, analysing them
+    /// only if no code with the same hash was seen before.
+    pub fn get(&mut self, code: &[u8]) -> Valids {
+        let hash = H256::from(Keccak256::digest(code).as_slice());
+        self.0.entry(hash).or_insert_with(|| Valids::new(code)).clone()
+    }
+
+    /// Number of distinct codes analysed.
+    pub fn len(&self) -> usize { self.0.len() }
+
+    /// Returns `true` if no code was analysed yet.
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+/// Whether the patch has the opcode. Opcodes added after Frontier
+/// are gated here on the patch flag of the fork that added them, so a
+/// new opcode is one arm in this table.
+fn is_enabled<P: Patch>(opcode: Opcode) -> bool {
+    match opcode {
+        // Homestead, EIP-7.
+        Opcode::DELEGATECALL => P::has_delegate_call(),
+        // Byzantium, EIP-214, EIP-140 and EIP-211.
+        Opcode::STATICCALL => P::has_static_call(),
+        Opcode::REVERT => P::has_revert(),
+        Opcode::RETURNDATASIZE | Opcode::RETURNDATACOPY => P::has_return_data(),
+        // Constantinople, EIP-145 and EIP-1052.
+        Opcode::SHL | Opcode::SHR | Opcode::SAR => P::has_bitwise_shift(),
+        Opcode::EXTCODEHASH => P::has_extcodehash(),
+        // London, EIP-3198.
+        Opcode::BASEFEE => P::has_base_fee(),
+        _ => true,
+    }
+}
+
 /// Represents a program counter in EVM.
 pub struct PC<'a, P: Patch> {
     position: &'a usize,
@@ -108,7 +167,11 @@ macro_rules! impl_pc {
                 }
                 let position = from_position;
                 let max = min(position.saturating_add(byte_count), self.code.len());
-                Ok(M256::from(&self.code[position..max]))
+                // Bytes past the end of code read as zero, so a truncated
+                // immediate is padded on the right.
+                let mut bytes = [0u8; 32];
+                bytes[..(max - position)].copy_from_slice(&self.code[position..max]);
+                Ok(M256::from(&bytes[..byte_count]))
             }
 
             /// Get the code bytearray.
@@ -147,7 +210,7 @@ macro_rules! impl_pc {
             }
 
             /// Check whether the PC is ended. Next `read` on this PC would
-            /// result in `PCError::PCOverflow`.
+            /// result in `OnChainError::PCOverflow`.
             pub fn is_end(&self) -> bool {
                 *self.position == self.code.len()
             }
@@ -163,6 +226,10 @@ macro_rules! impl_pc {
             /// Peek the next instruction.
             pub fn peek(&self) -> Result<Instruction, OnChainError> {
                 let opcode: Opcode = self.peek_opcode()?;
+                let byte = self.code[*self.position];
+                if !is_enabled::<P>(opcode) {
+                    return Err(OnChainError::UnsupportedOpcode(byte));
+                }
                 Ok(match opcode {
                     Opcode::STOP => Instruction::STOP,
                     Opcode::ADD => Instruction::ADD,
@@ -188,6 +255,9 @@ macro_rules! impl_pc {
                     Opcode::XOR => Instruction::XOR,
                     Opcode::NOT => Instruction::NOT,
                     Opcode::BYTE => Instruction::BYTE,
+                    Opcode::SHL => Instruction::SHL,
+                    Opcode::SHR => Instruction::SHR,
+                    Opcode::SAR => Instruction::SAR,
 
                     Opcode::SHA3 => Instruction::SHA3,
 
@@ -211,6 +281,7 @@ macro_rules! impl_pc {
                     Opcode::NUMBER => Instruction::NUMBER,
                     Opcode::DIFFICULTY => Instruction::DIFFICULTY,
                     Opcode::GASLIMIT => Instruction::GASLIMIT,
+                    Opcode::BASEFEE => Instruction::BASEFEE,
 
                     Opcode::POP => Instruction::POP,
                     Opcode::MLOAD => Instruction::MLOAD,
@@ -238,44 +309,15 @@ macro_rules! impl_pc {
                     Opcode::CALL => Instruction::CALL,
                     Opcode::CALLCODE => Instruction::CALLCODE,
                     Opcode::RETURN => Instruction::RETURN,
-                    Opcode::DELEGATECALL => {
-                        if P::has_delegate_call() {
-                            Instruction::DELEGATECALL
-                        } else {
-                            return Err(OnChainError::InvalidOpcode);
-                        }
-                    },
-                    Opcode::STATICCALL => {
-                        if P::has_static_call() {
-                            Instruction::STATICCALL
-                        } else {
-                            return Err(OnChainError::InvalidOpcode);
-                        }
-                    },
-                    Opcode::REVERT => {
-                        if P::has_revert() {
-                            Instruction::REVERT
-                        } else {
-                            return Err(OnChainError::InvalidOpcode);
-                        }
-                    },
-                    Opcode::RETURNDATASIZE => {
-                        if P::has_return_data() {
-                            Instruction::RETURNDATASIZE
-                        } else {
-                            return Err(OnChainError::InvalidOpcode);
-                        }
-                    },
-                    Opcode::RETURNDATACOPY => {
-                        if P::has_return_data() {
-                            Instruction::RETURNDATACOPY
-                        } else {
-                            return Err(OnChainError::InvalidOpcode);
-                        }
-                    },
+                    Opcode::DELEGATECALL => Instruction::DELEGATECALL,
+                    Opcode::STATICCALL => Instruction::STATICCALL,
+                    Opcode::REVERT => Instruction::REVERT,
+                    Opcode::RETURNDATASIZE => Instruction::RETURNDATASIZE,
+                    Opcode::RETURNDATACOPY => Instruction::RETURNDATACOPY,
+                    Opcode::EXTCODEHASH => Instruction::EXTCODEHASH,
 
                     Opcode::INVALID => {
-                        return Err(OnChainError::InvalidOpcode);
+                        return Err(OnChainError::InvalidOpcode(byte));
                     },
                     Opcode::SUICIDE => Instruction::SUICIDE,
                 })
@@ -336,3 +378,131 @@ impl<'a, P: Patch> PCMut<'a, P> {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use hexutil::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn position_advances_past_push_data() {
+        // PUSH1 0x01, PUSH2 0x0203, ADD, PC, PUSH3 truncated.
+        let code = read_hex("600161020301586204").unwrap();
+        let valids = Valids::new(&code);
+        let mut position = 0;
+
+        {
+            let mut pc = PCMut::<EmbeddedPatch>::new(&code, &valids, &mut position);
+            assert_eq!(pc.code(), code.as_slice());
+            let mut positions = Vec::new();
+            while !pc.is_end() {
+                pc.read().unwrap();
+                positions.push(pc.position());
+            }
+            // The truncated PUSH3 immediate stops at the end of code.
+            assert_eq!(positions, vec![2, 5, 6, 7, 9]);
+        }
+
+        let pc = PC::<EmbeddedPatch>::new(&code, &valids, &position);
+        assert_eq!(pc.position(), code.len());
+        assert_eq!(pc.code(), code.as_slice());
+    }
+
+    fn peek<P: Patch>(code: &[u8]) -> Result<Instruction, OnChainError> {
+        let valids = Valids::new(code);
+        let position = 0;
+        PC::<P>::new(code, &valids, &position).peek()
+    }
+
+    #[test]
+    fn invalid_opcode_carries_byte() {
+        // Never defined.
+        match peek::<EmbeddedByzantiumPatch>(&[0x0c]) {
+            Err(OnChainError::InvalidOpcode(0x0c)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match peek::<EmbeddedByzantiumPatch>(&[0xfe]) {
+            Err(OnChainError::InvalidOpcode(0xfe)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // STATICCALL is only enabled from Byzantium.
+        match peek::<EmbeddedPatch>(&[0xfa]) {
+            Err(OnChainError::UnsupportedOpcode(0xfa)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(peek::<EmbeddedByzantiumPatch>(&[0xfa]).unwrap(), Instruction::STATICCALL);
+    }
+
+    #[test]
+    fn byzantium_opcodes_are_gated() {
+        for &(byte, instruction) in &[(0xfd, Instruction::REVERT), (0x3d, Instruction::RETURNDATASIZE),
+                                      (0x3e, Instruction::RETURNDATACOPY), (0xfa, Instruction::STATICCALL)] {
+            match peek::<EmbeddedPatch>(&[byte]) {
+                Err(OnChainError::UnsupportedOpcode(unsupported)) => assert_eq!(unsupported, byte),
+                result => panic!("unexpected result {:?}", result),
+            }
+            assert_eq!(peek::<EmbeddedByzantiumPatch>(&[byte]).unwrap(), instruction);
+        }
+    }
+
+    #[test]
+    fn gating_goes_through_the_table() {
+        for byte in 0..256usize {
+            let byte = byte as u8;
+            let unsupported = matches!(peek::<EmbeddedPatch>(&[byte]), Err(OnChainError::UnsupportedOpcode(_)));
+            assert_eq!(unsupported, !super::is_enabled::<EmbeddedPatch>(byte.into()));
+        }
+    }
+
+    #[test]
+    fn derive_reuses_valids_for_same_code() {
+        let context = Context::from_hex_code("5b600056").unwrap();
+        let machine = Machine::<SeqMemory<EmbeddedByzantiumPatch>, EmbeddedByzantiumPatch>::new(
+            context.clone(), 1);
+
+        let same = machine.derive(context.clone());
+        assert!(Arc::ptr_eq(&machine.state().valids.0, &same.state().valids.0));
+
+        // The same bytes in a separate allocation hit the cache.
+        let copy = machine.derive(Context::from_hex_code("5b600056").unwrap());
+        assert!(Arc::ptr_eq(&machine.state().valids.0, &copy.state().valids.0));
+
+        let other = machine.derive(Context::from_hex_code("5b5b600056").unwrap());
+        assert!(!Arc::ptr_eq(&machine.state().valids.0, &other.state().valids.0));
+        assert!(other.state().valids.is_valid(1));
+    }
+
+    #[test]
+    fn valids_cache_keys_by_code_hash() {
+        let code = read_hex("5b600056").unwrap();
+        let mut cache = ValidsCache::new();
+        assert!(cache.is_empty());
+        let first = cache.get(&code);
+        let second = cache.get(&code.clone());
+        assert!(Arc::ptr_eq(&first.0, &second.0));
+        assert_eq!(cache.len(), 1);
+
+        cache.get(&read_hex("5b").unwrap());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn push_data_is_never_a_jump_destination() {
+        // 64KB of PUSH32, and of PUSH32 with JUMPDEST bytes as data.
+        let pushes = vec![0x7fu8; 0x10000];
+        let mut jumpdests = Vec::with_capacity(0x10000);
+        while jumpdests.len() < 0x10000 {
+            jumpdests.push(0x7fu8);
+            jumpdests.extend_from_slice(&[0x5b; 32]);
+        }
+        jumpdests.truncate(0x10000);
+
+        for code in &[pushes, jumpdests] {
+            let valids = Valids::new(code);
+            assert_eq!(valids.len(), code.len());
+            assert!((0..code.len()).all(|position| !valids.is_valid(position)));
+        }
+    }
+}
@@ -9,33 +9,41 @@ use super::errors::OnChainError;
 /// Represents an EVM stack.
 pub struct Stack {
     stack: Vec<M256>,
+    limit: usize,
 }
 
 impl Default for Stack {
     fn default() -> Stack {
+        Stack::new(1024)
+    }
+}
+
+impl Stack {
+    /// Create a new stack holding at most `limit` items.
+    pub fn new(limit: usize) -> Stack {
         Stack {
             stack: Vec::new(),
+            limit,
         }
     }
-}
 
-impl Stack {
     /// Check a pop-push cycle. If the check succeeded, `push`, `pop`,
     /// `set`, `peek` within the limit should not fail.
     pub fn check_pop_push(&self, pop: usize, push: usize) -> Result<(), OnChainError> {
         if self.len() < pop {
             return Err(OnChainError::StackUnderflow);
         }
-        if self.len() - pop + push > 1024 {
+        if self.len() - pop + push > self.limit {
             return Err(OnChainError::StackOverflow);
         }
         Ok(())
     }
 
-    /// Push a new value to the stack.
+    /// Push a new value to the stack. If the stack is already at its
+    /// limit, `OnChainError::StackOverflow` is returned.
     pub fn push(&mut self, elem: M256) -> Result<(), OnChainError> {
         self.stack.push(elem);
-        if self.len() > 1024 {
+        if self.len() > self.limit {
             self.stack.pop();
             Err(OnChainError::StackOverflow)
         } else {
@@ -43,7 +51,8 @@ impl Stack {
         }
     }
 
-    /// Pop a value from the stack.
+    /// Pop a value from the stack. If the stack is empty,
+    /// `OnChainError::StackUnderflow` is returned.
     pub fn pop(&mut self) -> Result<M256, OnChainError> {
         match self.stack.pop() {
             Some(x) => Ok(x),
@@ -53,7 +62,7 @@ impl Stack {
 
     /// Set a value at given index for the stack, where the top of the
     /// stack is at index `0`. If the index is too large,
-    /// `StackError::Underflow` is returned.
+    /// `OnChainError::StackUnderflow` is returned.
     pub fn set(&mut self, no_from_top: usize, val: M256) -> Result<(), OnChainError> {
         if self.stack.len() > no_from_top {
             let len = self.stack.len();
@@ -66,7 +75,7 @@ impl Stack {
 
     /// Peek a value at given index for the stack, where the top of
     /// the stack is at index `0`. If the index is too large,
-    /// `StackError::Underflow` is returned.
+    /// `OnChainError::StackUnderflow` is returned.
     pub fn peek(&self, no_from_top: usize) -> Result<M256, OnChainError> {
         if self.stack.len() > no_from_top {
             Ok(self.stack[self.stack.len() - no_from_top - 1])
@@ -75,8 +84,82 @@ impl Stack {
         }
     }
 
+    /// Remove all values from the stack.
+    pub fn clear(&mut self) {
+        self.stack.clear();
+    }
+
     /// Get the current stack length.
     pub fn len(&self) -> usize {
         self.stack.len()
     }
+
+    /// View all values of the stack, from the bottom, so the top of
+    /// the stack is the last item.
+    pub fn as_slice(&self) -> &[M256] {
+        &self.stack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_overflow() {
+        let mut stack = Stack::default();
+        for i in 0..1024u64 {
+            stack.push(M256::from(i)).unwrap();
+        }
+        match stack.push(M256::zero()) {
+            Err(OnChainError::StackOverflow) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(stack.len(), 1024);
+        assert_eq!(stack.peek(0).unwrap(), M256::from(1023u64));
+        assert!(stack.check_pop_push(0, 1).is_err());
+        assert!(stack.check_pop_push(1, 1).is_ok());
+    }
+
+    #[test]
+    fn pop_underflow() {
+        let mut stack = Stack::default();
+        match stack.pop() {
+            Err(OnChainError::StackUnderflow) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        stack.push(M256::one()).unwrap();
+        assert_eq!(stack.pop().unwrap(), M256::one());
+        assert!(stack.pop().is_err());
+        assert!(stack.peek(0).is_err());
+        assert!(stack.set(0, M256::one()).is_err());
+        assert!(stack.check_pop_push(1, 0).is_err());
+    }
+
+    #[test]
+    fn peek_from_top() {
+        let mut stack = Stack::default();
+        for i in 1..4u64 {
+            stack.push(M256::from(i)).unwrap();
+        }
+        assert_eq!(stack.peek(0).unwrap(), M256::from(3u64));
+        assert_eq!(stack.peek(2).unwrap(), M256::one());
+        match stack.peek(3) {
+            Err(OnChainError::StackUnderflow) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(stack.len(), 3);
+    }
+
+    #[test]
+    fn as_slice_from_bottom() {
+        let mut stack = Stack::default();
+        for i in 1..4u64 {
+            stack.push(M256::from(i)).unwrap();
+        }
+        assert_eq!(stack.as_slice(), &[M256::from(1u64), M256::from(2u64), M256::from(3u64)][..]);
+        assert_eq!(stack.as_slice().len(), stack.len());
+        assert_eq!(*stack.as_slice().last().unwrap(), stack.peek(0).unwrap());
+    }
 }
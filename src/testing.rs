@@ -0,0 +1,189 @@
+//! Patches and parameters for unit tests. Each patch changes a few
+//! methods of the Byzantium patch.
+
+use bigint::{Address, Gas, U256};
+use hexutil::read_hex;
+use ::{Patch, Precompiled, EmbeddedByzantiumPatch, Context, HeaderParams};
+use std::sync::Arc;
+
+/// Context running the hex encoded `code` at `address`, with no
+/// value or input, and a gas limit of 1000000.
+pub fn context(address: Address, code: &str) -> Context {
+    Context {
+        address,
+        caller: Address::default(),
+        code: Arc::new(read_hex(code).unwrap()),
+        data: Arc::new(Vec::new()),
+        gas_limit: Gas::from(1000000usize),
+        gas_price: Gas::zero(),
+        origin: Address::default(),
+        value: U256::zero(),
+        apprent_value: U256::zero(),
+        is_system: false,
+        is_static: false,
+    }
+}
+
+/// Header of the genesis block, with no gas limit.
+pub fn header() -> HeaderParams {
+    HeaderParams::default()
+}
+
+/// Precompiled contracts of a patch, as returned by
+/// `Patch::precompileds`.
+pub type Precompileds = &'static [(Address, Option<&'static [u8]>, &'static dyn Precompiled)];
+
+/// Required methods of `Patch`, by default the ones of
+/// `EmbeddedByzantiumPatch`. A patch declared with `test_patch!`
+/// forwards them here, and overrides them in its implementation of
+/// this trait. Provided methods of `Patch` already default to the
+/// Byzantium values, so they are not listed.
+pub trait ByzantiumBase {
+    fn code_deposit_limit() -> Option<usize> { EmbeddedByzantiumPatch::code_deposit_limit() }
+    fn callstack_limit() -> usize { EmbeddedByzantiumPatch::callstack_limit() }
+    fn gas_extcode() -> Gas { EmbeddedByzantiumPatch::gas_extcode() }
+    fn gas_balance() -> Gas { EmbeddedByzantiumPatch::gas_balance() }
+    fn gas_sload() -> Gas { EmbeddedByzantiumPatch::gas_sload() }
+    fn gas_suicide() -> Gas { EmbeddedByzantiumPatch::gas_suicide() }
+    fn gas_suicide_new_account() -> Gas { EmbeddedByzantiumPatch::gas_suicide_new_account() }
+    fn gas_call() -> Gas { EmbeddedByzantiumPatch::gas_call() }
+    fn gas_expbyte() -> Gas { EmbeddedByzantiumPatch::gas_expbyte() }
+    fn gas_transaction_create() -> Gas { EmbeddedByzantiumPatch::gas_transaction_create() }
+    fn force_code_deposit() -> bool { EmbeddedByzantiumPatch::force_code_deposit() }
+    fn has_delegate_call() -> bool { EmbeddedByzantiumPatch::has_delegate_call() }
+    fn has_static_call() -> bool { EmbeddedByzantiumPatch::has_static_call() }
+    fn has_revert() -> bool { EmbeddedByzantiumPatch::has_revert() }
+    fn has_return_data() -> bool { EmbeddedByzantiumPatch::has_return_data() }
+    fn err_on_call_with_more_gas() -> bool { EmbeddedByzantiumPatch::err_on_call_with_more_gas() }
+    fn call_create_l64_after_gas() -> bool { EmbeddedByzantiumPatch::call_create_l64_after_gas() }
+    fn memory_limit() -> usize { EmbeddedByzantiumPatch::memory_limit() }
+    fn precompileds() -> Precompileds { EmbeddedByzantiumPatch::precompileds() }
+}
+
+/// Declare a patch for tests, overriding the given methods of the
+/// Byzantium patch. The account patch is `EmbeddedAccountPatch`
+/// unless a `type Account` follows the struct.
+///
+/// Overrides of provided `Patch` methods go into the `Patch`
+/// implementation as written, so a new provided method needs no
+/// change here. Overrides of required methods are routed to
+/// `ByzantiumBase` by name, which is why each of them has a rule
+/// below.
+///
+/// ```ignore
+/// test_patch! {
+///     /// Byzantium patch with only 64 bytes of memory.
+///     struct TinyMemoryPatch;
+///     fn memory_limit() -> usize { 64 }
+/// }
+/// ```
+macro_rules! test_patch {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident; type Account = $account:ty; $($item:tt)*) => {
+        test_patch! { @items [$(#[$attr])* $vis struct $name; type Account = $account;] [] [] $($item)* }
+    };
+    ($(#[$attr:meta])* $vis:vis struct $name:ident; $($item:tt)*) => {
+        test_patch! {
+            $(#[$attr])*
+            $vis struct $name;
+            type Account = ::EmbeddedAccountPatch;
+            $($item)*
+        }
+    };
+
+    // Route each item to the base trait or to the patch, by name.
+    (@items $decl:tt $base:tt $patch:tt fn code_deposit_limit $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn code_deposit_limit] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn callstack_limit $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn callstack_limit] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_extcode $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_extcode] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_balance $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_balance] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_sload $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_sload] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_suicide $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_suicide] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_suicide_new_account $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_suicide_new_account] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_call $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_call] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_expbyte $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_expbyte] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn gas_transaction_create $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn gas_transaction_create] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn force_code_deposit $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn force_code_deposit] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn has_delegate_call $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn has_delegate_call] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn has_static_call $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn has_static_call] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn has_revert $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn has_revert] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn has_return_data $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn has_return_data] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn err_on_call_with_more_gas $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn err_on_call_with_more_gas] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn call_create_l64_after_gas $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn call_create_l64_after_gas] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn memory_limit $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn memory_limit] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn precompileds $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [fn precompileds] $($rest)* } };
+    (@items $decl:tt $base:tt $patch:tt fn $($rest:tt)*) => {
+        test_patch! { @patch $decl $base $patch [fn] $($rest)* } };
+    (@items [$(#[$attr:meta])* $vis:vis struct $name:ident; type Account = $account:ty;]
+     [$($base:tt)*] [$($patch:tt)*]) => {
+        $(#[$attr])*
+        $vis struct $name;
+        impl ::testing::ByzantiumBase for $name { $($base)* }
+        impl ::Patch for $name {
+            type Account = $account;
+
+            fn code_deposit_limit() -> Option<usize> {
+                <Self as ::testing::ByzantiumBase>::code_deposit_limit() }
+            fn callstack_limit() -> usize { <Self as ::testing::ByzantiumBase>::callstack_limit() }
+            fn gas_extcode() -> ::bigint::Gas { <Self as ::testing::ByzantiumBase>::gas_extcode() }
+            fn gas_balance() -> ::bigint::Gas { <Self as ::testing::ByzantiumBase>::gas_balance() }
+            fn gas_sload() -> ::bigint::Gas { <Self as ::testing::ByzantiumBase>::gas_sload() }
+            fn gas_suicide() -> ::bigint::Gas { <Self as ::testing::ByzantiumBase>::gas_suicide() }
+            fn gas_suicide_new_account() -> ::bigint::Gas {
+                <Self as ::testing::ByzantiumBase>::gas_suicide_new_account() }
+            fn gas_call() -> ::bigint::Gas { <Self as ::testing::ByzantiumBase>::gas_call() }
+            fn gas_expbyte() -> ::bigint::Gas { <Self as ::testing::ByzantiumBase>::gas_expbyte() }
+            fn gas_transaction_create() -> ::bigint::Gas {
+                <Self as ::testing::ByzantiumBase>::gas_transaction_create() }
+            fn force_code_deposit() -> bool { <Self as ::testing::ByzantiumBase>::force_code_deposit() }
+            fn has_delegate_call() -> bool { <Self as ::testing::ByzantiumBase>::has_delegate_call() }
+            fn has_static_call() -> bool { <Self as ::testing::ByzantiumBase>::has_static_call() }
+            fn has_revert() -> bool { <Self as ::testing::ByzantiumBase>::has_revert() }
+            fn has_return_data() -> bool { <Self as ::testing::ByzantiumBase>::has_return_data() }
+            fn err_on_call_with_more_gas() -> bool {
+                <Self as ::testing::ByzantiumBase>::err_on_call_with_more_gas() }
+            fn call_create_l64_after_gas() -> bool {
+                <Self as ::testing::ByzantiumBase>::call_create_l64_after_gas() }
+            fn memory_limit() -> usize { <Self as ::testing::ByzantiumBase>::memory_limit() }
+            fn precompileds() -> ::testing::Precompileds { <Self as ::testing::ByzantiumBase>::precompileds() }
+
+            $($patch)*
+        }
+    };
+
+    // Move the tokens of one function, up to and including its body,
+    // to the base trait or to the patch.
+    (@base $decl:tt [$($base:tt)*] $patch:tt [$($item:tt)*] { $($body:tt)* } $($rest:tt)*) => {
+        test_patch! { @items $decl [$($base)* $($item)* { $($body)* }] $patch $($rest)* } };
+    (@base $decl:tt $base:tt $patch:tt [$($item:tt)*] $token:tt $($rest:tt)*) => {
+        test_patch! { @base $decl $base $patch [$($item)* $token] $($rest)* } };
+    (@patch $decl:tt $base:tt [$($patch:tt)*] [$($item:tt)*] { $($body:tt)* } $($rest:tt)*) => {
+        test_patch! { @items $decl $base [$($patch)* $($item)* { $($body)* }] $($rest)* } };
+    (@patch $decl:tt $base:tt $patch:tt [$($item:tt)*] $token:tt $($rest:tt)*) => {
+        test_patch! { @patch $decl $base $patch [$($item)* $token] $($rest)* } };
+}
+
+test_patch! {
+    /// EIP-161 patch charging cold account and storage accesses, as
+    /// in EIP-2929.
+    pub struct AccessListPatch;
+    type Account = ::EmbeddedByzantiumAccountPatch;
+    fn has_access_list() -> bool { true }
+}
@@ -0,0 +1,311 @@
+//! Execution tracing built on top of step hooks.
+
+#[cfg(not(feature = "std"))]
+use alloc::Vec;
+
+#[cfg(not(feature = "std"))] use alloc::rc::Rc;
+#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use core::cell::RefCell;
+#[cfg(feature = "std")] use std::cell::RefCell;
+#[cfg(feature = "std")] use std::collections::{BTreeMap as Map, BTreeSet as Set};
+#[cfg(not(feature = "std"))] use alloc::{BTreeMap as Map, BTreeSet as Set};
+
+use bigint::{M256, Gas};
+use super::{Opcode, StepInfo};
+
+#[derive(Debug, Clone, Default)]
+/// A gas profiler that accumulates the number of executions and the
+/// total gas charged for each opcode. Attach it to a VM through
+/// `hook`. The profiler only observes the execution, so it does not
+/// change results or gas usage.
+pub struct GasProfiler(Rc<RefCell<Map<u8, (u64, Gas)>>>);
+
+impl GasProfiler {
+    /// Returns a step hook feeding this profiler. It can be added to
+    /// a VM with `add_step_hook`.
+    pub fn hook(&self) -> impl Fn(&StepInfo) {
+        let profile = self.0.clone();
+        move |info| {
+            let mut profile = profile.borrow_mut();
+            let entry = profile.entry(info.opcode.into()).or_insert((0, Gas::zero()));
+            entry.0 += 1;
+            entry.1 = entry.1 + info.gas_cost;
+        }
+    }
+
+    /// Returns the opcode, the execution count and the total gas
+    /// charged for each executed opcode, ordered by opcode.
+    pub fn report(&self) -> Vec<(u8, u64, Gas)> {
+        self.0.borrow().iter().map(|(opcode, &(count, gas))| (*opcode, count, gas)).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A gas profiler that accumulates the total gas charged at each code
+/// offset of the runtime at a given depth, across all executions of
+/// that offset. Attach it to a VM through `hook`; without it nothing
+/// is recorded.
+pub struct OffsetProfiler {
+    depth: usize,
+    profile: Rc<RefCell<Map<usize, Gas>>>,
+}
+
+impl OffsetProfiler {
+    /// Create a new offset profiler for runtimes at the given
+    /// depth. The top-level runtime has depth `1`.
+    pub fn new(depth: usize) -> Self {
+        OffsetProfiler {
+            depth,
+            profile: Rc::new(RefCell::new(Map::new())),
+        }
+    }
+
+    /// Returns a step hook feeding this profiler. It can be added to
+    /// a VM with `add_step_hook`.
+    pub fn hook(&self) -> impl Fn(&StepInfo) {
+        let depth = self.depth;
+        let profile = self.profile.clone();
+        move |info| {
+            if info.depth != depth {
+                return;
+            }
+            let mut profile = profile.borrow_mut();
+            let entry = profile.entry(info.position).or_insert(Gas::zero());
+            *entry = *entry + info.gas_cost;
+        }
+    }
+
+    /// Returns the total gas charged at each executed code offset.
+    pub fn gas_by_offset(&self) -> Map<usize, Gas> {
+        self.profile.borrow().clone()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CoverageData {
+    offsets: Vec<bool>,
+    opcodes: Set<u8>,
+}
+
+#[derive(Debug, Clone)]
+/// A coverage tracker that records which code offsets and which
+/// distinct opcodes were executed by the runtime at a given depth.
+/// Attach it to a VM through `hook`.
+pub struct Coverage {
+    depth: usize,
+    data: Rc<RefCell<CoverageData>>,
+}
+
+impl Coverage {
+    /// Create a new coverage tracker for runtimes at the given
+    /// depth. The top-level runtime has depth `1`.
+    pub fn new(depth: usize) -> Self {
+        Coverage {
+            depth,
+            data: Rc::new(RefCell::new(CoverageData::default())),
+        }
+    }
+
+    /// Returns a step hook feeding this coverage tracker. It can be
+    /// added to a VM with `add_step_hook`.
+    pub fn hook(&self) -> impl Fn(&StepInfo) {
+        let depth = self.depth;
+        let data = self.data.clone();
+        move |info| {
+            if info.depth != depth {
+                return;
+            }
+            let mut data = data.borrow_mut();
+            if data.offsets.len() <= info.position {
+                data.offsets.resize(info.position + 1, false);
+            }
+            data.offsets[info.position] = true;
+            data.opcodes.insert(info.opcode.into());
+        }
+    }
+
+    /// Returns `true` if the instruction at the position was
+    /// executed.
+    pub fn is_executed(&self, position: usize) -> bool {
+        let data = self.data.borrow();
+        position < data.offsets.len() && data.offsets[position]
+    }
+
+    /// Returns all executed code offsets, in ascending order.
+    pub fn executed_offsets(&self) -> Vec<usize> {
+        self.data.borrow().offsets.iter().enumerate()
+            .filter(|&(_, executed)| *executed).map(|(position, _)| position).collect()
+    }
+
+    /// Returns all distinct executed opcodes.
+    pub fn executed_opcodes(&self) -> Set<u8> {
+        self.data.borrow().opcodes.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A structured log of one executed instruction, in the style of the
+/// `structLogs` of geth and parity traces.
+pub struct StructLog {
+    /// Position of the instruction in the code.
+    pub pc: usize,
+    /// Opcode of the instruction.
+    pub op: Opcode,
+    /// Available gas before the instruction is executed.
+    pub gas: Gas,
+    /// Gas charged for the instruction.
+    pub gas_cost: Gas,
+    /// Depth of the runtime that executed the instruction.
+    pub depth: usize,
+    /// Stack before the instruction, from the bottom, if stack
+    /// snapshots are enabled.
+    pub stack: Option<Vec<M256>>,
+    /// Memory before the instruction, if memory snapshots are
+    /// enabled.
+    pub memory: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+/// A tracer collecting a `StructLog` for each executed instruction.
+/// Attach it to a VM through `hook`, and enable snapshots with
+/// `set_step_snapshots` to get the stack and memory.
+pub struct StructLogger(Rc<RefCell<Vec<StructLog>>>);
+
+impl StructLogger {
+    /// Returns a step hook feeding this tracer. It can be added to a
+    /// VM with `add_step_hook`.
+    pub fn hook(&self) -> impl Fn(&StepInfo) {
+        let logs = self.0.clone();
+        move |info| {
+            logs.borrow_mut().push(StructLog {
+                pc: info.position,
+                op: info.opcode,
+                gas: info.gas,
+                gas_cost: info.gas_cost,
+                depth: info.depth,
+                stack: info.stack.clone(),
+                memory: info.memory.clone(),
+            });
+        }
+    }
+
+    /// Returns the structured logs of all executed instructions, in
+    /// execution order.
+    pub fn struct_logs(&self) -> Vec<StructLog> {
+        self.0.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::*;
+    use bigint::*;
+    use hexutil::*;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use testing::{context, header};
+
+    #[test]
+    fn gas_profiler_loop() {
+        // Count down from 3 in a loop.
+        let code = "60035b600190038060025700";
+        let profiler = GasProfiler::default();
+        let mut vm = SeqContextVM::<EmbeddedPatch>::new(context(Address::default(), code), header());
+        vm.add_step_hook(profiler.hook());
+        vm.fire().unwrap();
+
+        let report = profiler.report();
+        let hottest = report.iter().max_by_key(|&&(_, count, _)| count).unwrap();
+        assert_eq!(hottest.0, 0x60);
+        assert_eq!(hottest.1, 7);
+
+        let jumpdest = report.iter().find(|&&(opcode, _, _)| opcode == 0x5b).unwrap();
+        assert_eq!(jumpdest.1, 3);
+        assert_eq!(jumpdest.2, Gas::from(3usize));
+
+        let total = report.iter().fold(Gas::zero(), |acc, &(_, _, gas)| acc + gas);
+        assert_eq!(total, vm.used_gas());
+    }
+
+    #[test]
+    fn offset_profiler_loop() {
+        // Count down from 3 in a loop.
+        let code = "60035b600190038060025700";
+        let profiler = OffsetProfiler::new(1);
+        let mut vm = SeqContextVM::<EmbeddedPatch>::new(context(Address::default(), code), header());
+        vm.add_step_hook(profiler.hook());
+        vm.fire().unwrap();
+
+        let profile = profiler.gas_by_offset();
+        assert_eq!(profile[&0], Gas::from(3usize));
+        // The loop body runs three times.
+        assert_eq!(profile[&2], Gas::from(3usize));
+        assert_eq!(profile[&6], Gas::from(3 * 3usize));
+        assert_eq!(profile[&10], Gas::from(3 * 10usize));
+        assert_eq!(profile[&11], Gas::zero());
+        assert!(!profile.contains_key(&1));
+
+        let total = profile.values().fold(Gas::zero(), |acc, &gas| acc + gas);
+        assert_eq!(total, vm.used_gas());
+    }
+
+    #[test]
+    fn coverage_untaken_branch() {
+        // Jump over a PUSH1/POP pair, which is never executed.
+        let code = "60016008576002505b00";
+        let coverage = Coverage::new(1);
+        let mut vm = SeqContextVM::<EmbeddedPatch>::new(context(Address::default(), code), header());
+        vm.add_step_hook(coverage.hook());
+        vm.fire().unwrap();
+
+        assert_eq!(coverage.executed_offsets(), vec![0, 2, 4, 8, 9]);
+        assert!(!coverage.is_executed(5));
+        assert!(!coverage.is_executed(7));
+        assert!(coverage.executed_opcodes().contains(&0x57));
+        assert!(!coverage.executed_opcodes().contains(&0x50));
+    }
+
+    #[test]
+    fn struct_logs_call() {
+        let callee = Address::from_str("0x2000000000000000000000000000000000000000").unwrap();
+        // MSTORE8 1 at 0, then CALL the callee with no value and no
+        // input or output, which runs PUSH1 1, STOP.
+        let code = concat!("6001600053", "6000600060006000600073",
+                           "2000000000000000000000000000000000000000", "61fffff100");
+        let logger = StructLogger::default();
+        let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context(Address::default(), code), header());
+        vm.add_step_hook(logger.hook());
+        vm.set_step_snapshots(true, true);
+        loop {
+            let address = match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) => address,
+                Err(err) => panic!("unexpected require {:?}", err),
+            };
+            let code = read_hex(if address == callee { "600100" } else { code }).unwrap();
+            vm.commit_account(AccountCommitment::Full {
+                nonce: U256::zero(), address, balance: U256::one(), code: Arc::new(code),
+            }).unwrap();
+        }
+
+        let logs = logger.struct_logs();
+        let ops: Vec<(Opcode, usize)> = logs.iter().map(|log| (log.op, log.depth)).collect();
+        let mut expected = vec![(Opcode::PUSH(1), 1); 2];
+        expected.push((Opcode::MSTORE8, 1));
+        expected.extend(vec![(Opcode::PUSH(1), 1); 5]);
+        expected.extend(vec![(Opcode::PUSH(20), 1), (Opcode::PUSH(2), 1), (Opcode::CALL, 1),
+                             (Opcode::PUSH(1), 2), (Opcode::STOP, 2), (Opcode::STOP, 1)]);
+        assert_eq!(ops, expected);
+
+        assert_eq!(logs[0].pc, 0);
+        assert_eq!(logs[0].gas, Gas::from(1000000usize));
+        assert_eq!(logs[0].gas_cost, Gas::from(3usize));
+        assert_eq!(logs[0].stack, Some(Vec::new()));
+        assert_eq!(logs[0].memory, Some(Vec::new()));
+        // The CALL sees its seven arguments, and the memory written by
+        // the MSTORE8.
+        assert_eq!(logs[10].stack.as_ref().unwrap().len(), 7);
+        assert_eq!(logs[10].memory.as_ref().unwrap()[0], 1);
+        assert_eq!(logs[12].stack, Some(vec![M256::one()]));
+    }
+}
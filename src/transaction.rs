@@ -3,8 +3,8 @@
 #[cfg(not(feature = "std"))]
 use alloc::Vec;
 
-#[cfg(not(feature = "std"))] use alloc::rc::Rc;
-#[cfg(feature = "std")] use std::rc::Rc;
+#[cfg(not(feature = "std"))] use alloc::arc::Arc;
+#[cfg(feature = "std")] use std::sync::Arc;
 
 #[cfg(feature = "std")] use std::collections::{HashSet as Set, hash_map as map};
 #[cfg(feature = "std")] use std::cmp::min;
@@ -14,19 +14,23 @@ use alloc::Vec;
 #[cfg(not(feature = "std"))] use core::ops::Deref;
 use bigint::{U256, H256, Address, Gas};
 
-use super::errors::{RequireError, CommitError, PreExecutionError};
+use super::errors::{RequireError, CommitError, PreExecutionError, CallFailure};
 use super::{State, Machine, Context, ContextVM, VM, AccountState,
             BlockhashState, Patch, HeaderParams, Memory, VMStatus,
             AccountCommitment, Log, AccountChange,
             Instruction, Opcode};
 
+use rlp::{Encodable, Decodable, RlpStream, UntrustedRlp, DecoderError};
 use block_core::TransactionAction;
 #[cfg(feature = "std")]
 use block::Transaction;
+#[cfg(feature = "std")]
+use envelope::{EnvelopedTransaction, TransactionType};
 
 const G_TXDATAZERO: usize = 4;
-const G_TXDATANONZERO: usize = 68;
 const G_TRANSACTION: usize = 21000;
+const G_ACCESSLISTADDRESS: usize = 2400;
+const G_ACCESSLISTSTORAGEKEY: usize = 1900;
 
 static SYSTEM_ADDRESS: [u8; 20] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
                                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
@@ -38,6 +42,32 @@ macro_rules! system_address {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An entry of an EIP-2930 access list.
+pub struct AccessListItem {
+    /// Accessed address.
+    pub address: Address,
+    /// Accessed storage keys of the address.
+    pub storage_keys: Vec<H256>,
+}
+
+impl Encodable for AccessListItem {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append(&self.address);
+        s.append_list(&self.storage_keys);
+    }
+}
+
+impl Decodable for AccessListItem {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        Ok(AccessListItem {
+            address: rlp.val_at(0)?,
+            storage_keys: rlp.list_at(1)?,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Represents an untrusted Ethereum transaction.
 pub struct UntrustedTransaction {
@@ -52,7 +82,7 @@ pub struct UntrustedTransaction {
     /// Value sent with this transaction.
     pub value: U256,
     /// Transaction input.
-    pub input: Rc<Vec<u8>>,
+    pub input: Arc<Vec<u8>>,
 }
 
 impl UntrustedTransaction {
@@ -88,6 +118,7 @@ impl UntrustedTransaction {
                 value: self.value,
                 input: self.input.clone(),
                 nonce: nonce,
+                access_list: Vec::new(),
             }
         };
 
@@ -127,9 +158,12 @@ pub struct ValidTransaction {
     /// Value of this transaction.
     pub value: U256,
     /// Data or init associated with this transaction.
-    pub input: Rc<Vec<u8>>,
+    pub input: Arc<Vec<u8>>,
     /// Nonce of the transaction.
     pub nonce: U256,
+    /// EIP-2930 access list of the transaction. Empty for legacy
+    /// transactions.
+    pub access_list: Vec<AccessListItem>,
 }
 
 #[cfg(feature = "std")]
@@ -144,44 +178,106 @@ impl ValidTransaction {
             Err(_) => return Ok(Err(PreExecutionError::InvalidCaller)),
         };
 
-        let nonce = account_state.nonce(caller)?;
-        if nonce != transaction.nonce {
-            return Ok(Err(PreExecutionError::InvalidNonce));
-        }
-
-        let valid = ValidTransaction {
+        Self::validate::<P>(ValidTransaction {
             caller: Some(caller),
             gas_price: transaction.gas_price,
             gas_limit: transaction.gas_limit,
             action: transaction.action.clone(),
             value: transaction.value,
-            input: Rc::new(transaction.input.clone()),
-            nonce: nonce,
+            input: Arc::new(transaction.input.clone()),
+            nonce: transaction.nonce,
+            access_list: Vec::new(),
+        }, account_state)
+    }
+
+    /// Create a valid transaction from a transaction decoded from the
+    /// EIP-2718 envelope format, to be included in the given block.
+    /// For dynamic fee transactions, the balance must cover the
+    /// maximum fee per gas, and the gas price is the effective one of
+    /// EIP-1559. Access list transactions need a patch with access
+    /// lists, and dynamic fee ones also need the base fee. Caller is
+    /// always Some.
+    #[cfg(any(feature = "rust-secp256k1", feature = "c-secp256k1"))]
+    pub fn from_enveloped_transaction<P: Patch>(
+        transaction: &EnvelopedTransaction, block: &HeaderParams,
+        account_state: &AccountState<P::Account>
+    ) -> Result<Result<ValidTransaction, PreExecutionError>, RequireError> {
+        let transaction = match *transaction {
+            EnvelopedTransaction::Legacy(ref transaction) =>
+                return Self::from_transaction::<P>(transaction, account_state),
+            EnvelopedTransaction::Typed(ref transaction) => transaction,
         };
 
-        if valid.gas_limit < valid.intrinsic_gas::<P>() {
-            return Ok(Err(PreExecutionError::InsufficientGasLimit));
+        let supported = match transaction.transaction_type {
+            TransactionType::Legacy => true,
+            TransactionType::AccessList => P::has_access_list(),
+            TransactionType::DynamicFee => P::has_access_list() && P::has_base_fee(),
+        };
+        if !supported {
+            return Ok(Err(PreExecutionError::UnsupportedTransactionType));
         }
 
-        let balance = account_state.balance(caller)?;
+        let gas_price = match transaction.max_priority_fee_per_gas {
+            Some(max_priority_fee_per_gas) => {
+                let base_fee = Gas::from(block.base_fee);
+                if transaction.gas_price < base_fee ||
+                    transaction.gas_price < max_priority_fee_per_gas
+                {
+                    return Ok(Err(PreExecutionError::InsufficientGasPrice));
+                }
+                // Same as the minimum of the maximum fee and the base
+                // fee plus the tip, without overflowing the sum.
+                base_fee + min(transaction.gas_price - base_fee, max_priority_fee_per_gas)
+            },
+            None => transaction.gas_price,
+        };
 
-        let gas_limit: U256 = valid.gas_limit.into();
-        let gas_price: U256 = valid.gas_price.into();
+        let caller = match transaction.caller() {
+            Ok(val) => val,
+            Err(err) => return Ok(Err(err)),
+        };
 
-        let (preclaimed_value, overflowed1) = gas_limit.overflowing_mul(gas_price);
-        let (total, overflowed2) = preclaimed_value.overflowing_add(valid.value);
-        if overflowed1 || overflowed2 {
-            return Ok(Err(PreExecutionError::InsufficientBalance));
-        }
+        let valid = Self::validate::<P>(ValidTransaction {
+            caller: Some(caller),
+            gas_price: transaction.gas_price,
+            gas_limit: transaction.gas_limit,
+            action: transaction.action,
+            value: transaction.value,
+            input: Arc::new(transaction.input.clone()),
+            nonce: transaction.nonce,
+            access_list: transaction.access_list.clone(),
+        }, account_state)?;
 
-        if balance < total {
-            return Ok(Err(PreExecutionError::InsufficientBalance));
-        }
+        Ok(valid.map(|valid| ValidTransaction { gas_price, ..valid }))
+    }
 
-        Ok(Ok(valid))
+    /// Check the nonce, intrinsic gas and balance of a signed
+    /// transaction against the caller's account.
+    fn validate<P: Patch>(
+        valid: ValidTransaction, account_state: &AccountState<P::Account>
+    ) -> Result<Result<ValidTransaction, PreExecutionError>, RequireError> {
+        Ok(valid.check::<P>(account_state)?.map(|()| valid))
     }
 }
 
+/// Intrinsic gas of a transaction with the given data. This is the
+/// transaction base cost, the cost of every zero and non-zero byte of
+/// the data, and the creation cost if `is_create` is set.
+pub fn intrinsic_gas<P: Patch>(data: &[u8], is_create: bool) -> Gas {
+    let mut gas = Gas::from(G_TRANSACTION);
+    if is_create {
+        gas = gas + P::gas_transaction_create();
+    }
+    for d in data {
+        if *d == 0 {
+            gas = gas + Gas::from(G_TXDATAZERO);
+        } else {
+            gas = gas + P::gas_transaction_data_nonzero();
+        }
+    }
+    gas
+}
+
 impl ValidTransaction {
     /// To address of the transaction.
     pub fn address(&self) -> Address {
@@ -189,20 +285,15 @@ impl ValidTransaction {
     }
 
     /// Intrinsic gas to be paid in prior to this transaction
-    /// execution.
+    /// execution, including the cost of the addresses and storage
+    /// keys in its access list.
     pub fn intrinsic_gas<P: Patch>(&self) -> Gas {
-        let mut gas = Gas::from(G_TRANSACTION);
-        if self.action == TransactionAction::Create {
-            gas = gas + Gas::from(P::gas_transaction_create());
-        }
-        for d in self.input.deref() {
-            if *d == 0 {
-                gas = gas + Gas::from(G_TXDATAZERO);
-            } else {
-                gas = gas + Gas::from(G_TXDATANONZERO);
-            }
+        let mut gas = intrinsic_gas::<P>(self.input.deref(), self.action == TransactionAction::Create);
+        for item in &self.access_list {
+            gas = gas + Gas::from(G_ACCESSLISTADDRESS) +
+                Gas::from(G_ACCESSLISTSTORAGEKEY) * Gas::from(item.storage_keys.len());
         }
-        return gas;
+        gas
     }
 
     /// Convert this transaction into a context. Note that this will
@@ -252,7 +343,7 @@ impl ValidTransaction {
                     gas_price: self.gas_price,
                     value: self.value,
                     gas_limit: self.gas_limit - upfront,
-                    data: Rc::new(Vec::new()),
+                    data: Arc::new(Vec::new()),
                     code: self.input,
                     origin: origin.unwrap_or(self.caller.unwrap_or(system_address!())),
                     apprent_value: self.value,
@@ -268,6 +359,46 @@ impl ValidTransaction {
     pub fn preclaimed_value(&self) -> U256 {
         (self.gas_limit * self.gas_price).into()
     }
+
+    /// Check, without running it, that the caller's nonce matches
+    /// the transaction, that the gas limit covers the intrinsic gas,
+    /// and that the caller's balance covers `gas_limit * gas_price +
+    /// value`. A `RequireError` is returned if the caller is not
+    /// committed. System transactions always pass.
+    pub fn check<P: Patch>(
+        &self, account_state: &AccountState<P::Account>
+    ) -> Result<Result<(), PreExecutionError>, RequireError> {
+        let caller = match self.caller {
+            Some(caller) => caller,
+            None => return Ok(Ok(())),
+        };
+
+        let nonce = account_state.nonce(caller)?;
+        if nonce != self.nonce {
+            return Ok(Err(PreExecutionError::InvalidNonce));
+        }
+
+        if self.gas_limit < self.intrinsic_gas::<P>() {
+            return Ok(Err(PreExecutionError::InsufficientGasLimit));
+        }
+
+        let balance = account_state.balance(caller)?;
+
+        let gas_limit: U256 = self.gas_limit.into();
+        let gas_price: U256 = self.gas_price.into();
+
+        let (preclaimed_value, overflowed1) = gas_limit.overflowing_mul(gas_price);
+        let (total, overflowed2) = preclaimed_value.overflowing_add(self.value);
+        if overflowed1 || overflowed2 {
+            return Ok(Err(PreExecutionError::InsufficientBalance));
+        }
+
+        if balance < total {
+            return Ok(Err(PreExecutionError::InsufficientBalance));
+        }
+
+        Ok(Ok(()))
+    }
 }
 
 enum TransactionVMState<M, P: Patch> {
@@ -277,6 +408,7 @@ enum TransactionVMState<M, P: Patch> {
         preclaimed_value: U256,
         finalized: bool,
         code_deposit: bool,
+        create: bool,
         fresh_account_state: AccountState<P::Account>,
     },
     Constructing {
@@ -285,6 +417,9 @@ enum TransactionVMState<M, P: Patch> {
 
         account_state: AccountState<P::Account>,
         blockhash_state: BlockhashState,
+        /// Error of the caller prechecks, if the transaction failed
+        /// them and will not run.
+        rejected: Option<PreExecutionError>,
     },
 }
 
@@ -302,13 +437,16 @@ impl<M: Memory + Default, P: Patch> TransactionVM<M, P> {
 
             account_state: AccountState::default(),
             blockhash_state: BlockhashState::default(),
+            rejected: None,
         });
         vm.commit_account(transaction.caller).unwrap();
         Ok(vm)
     }
 
     /// Create a new VM using the given transaction, block header and
-    /// patch. This VM runs at the transaction level.
+    /// patch. This VM runs at the transaction level. The first step
+    /// checks the caller with `ValidTransaction::check`, and a
+    /// transaction failing it stops with `VMStatus::Rejected`.
     pub fn new(transaction: ValidTransaction, block: HeaderParams) -> Self {
         TransactionVM(TransactionVMState::Constructing {
             transaction: transaction,
@@ -316,6 +454,7 @@ impl<M: Memory + Default, P: Patch> TransactionVM<M, P> {
 
             account_state: AccountState::default(),
             blockhash_state: BlockhashState::default(),
+            rejected: None,
         })
     }
 
@@ -340,6 +479,7 @@ impl<M: Memory + Default, P: Patch> TransactionVM<M, P> {
                 TransactionVMState::Running { ref vm, .. } =>
                     vm.runtime.blockhash_state.clone(),
             },
+            rejected: None,
         })
     }
 
@@ -357,6 +497,22 @@ impl<M: Memory + Default, P: Patch> TransactionVM<M, P> {
             TransactionVMState::Constructing { .. } => None,
         }
     }
+
+    /// Returns the address of the contract deployed by a contract
+    /// creation transaction, derived from the caller and its nonce.
+    /// This is `None` for message calls, and until the creation has
+    /// exited successfully.
+    pub fn created_address(&self) -> Option<Address> {
+        match self.0 {
+            TransactionVMState::Running { ref vm, create: true, .. } => {
+                match self.status() {
+                    VMStatus::ExitedOk => Some(vm.machines[0].state().context.address),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
 }
 
 impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
@@ -383,6 +539,7 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
                     vm.status()
                 }
             },
+            TransactionVMState::Constructing { rejected: Some(ref err), .. } => VMStatus::Rejected(err.clone()),
             TransactionVMState::Constructing { .. } => VMStatus::Running,
         }
     }
@@ -409,6 +566,7 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
         let cblockhash_state: BlockhashState;
         let ccode_deposit: bool;
         let cpreclaimed_value: U256;
+        let caccess_list: Vec<AccessListItem>;
 
         let real_used_gas = self.used_gas();
 
@@ -449,7 +607,15 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
             }
             TransactionVMState::Constructing {
                 ref transaction, ref block,
-                ref mut account_state, ref blockhash_state } => {
+                ref mut account_state, ref blockhash_state, ref mut rejected } => {
+
+                if rejected.is_some() {
+                    return Ok(());
+                }
+                if let Err(err) = transaction.check::<P>(account_state)? {
+                    *rejected = Some(err);
+                    return Ok(());
+                }
 
                 let address = transaction.address();
                 account_state.require(address)?;
@@ -460,6 +626,7 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
                 };
                 cgas = transaction.intrinsic_gas::<P>();
                 cpreclaimed_value = transaction.preclaimed_value();
+                caccess_list = transaction.access_list.clone();
                 ccontext = transaction.clone().into_context::<P>(cgas, None, account_state, false, false)?;
                 cblock = block.clone();
                 caccount_state = account_state.clone();
@@ -478,6 +645,7 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
                 } else {
                     vm.machines[0].initialize_call(cpreclaimed_value).unwrap();
                 }
+                vm.machines[0].initialize_access_list(&caccess_list);
             });
 
         self.0 = TransactionVMState::Running {
@@ -486,6 +654,7 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
             intrinsic_gas: cgas,
             finalized: false,
             code_deposit: ccode_deposit,
+            create: ccode_deposit,
             preclaimed_value: cpreclaimed_value,
         };
 
@@ -527,6 +696,16 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
         }
     }
 
+    fn applied_refund(&self) -> Gas {
+        match self.0 {
+            TransactionVMState::Running { ref vm, intrinsic_gas, .. } => {
+                let state = vm.machines[0].state();
+                state.applied_refund_on(state.total_used_gas() + intrinsic_gas)
+            }
+            TransactionVMState::Constructing { .. } => Gas::zero(),
+        }
+    }
+
     fn logs(&self) -> &[Log] {
         match self.0 {
             TransactionVMState::Running { ref vm, .. } => vm.logs(),
@@ -541,13 +720,17 @@ impl<M: Memory + Default, P: Patch> VM for TransactionVM<M, P> {
         }
     }
 
+    fn call_failures(&self) -> &[(usize, CallFailure)] {
+        match self.0 {
+            TransactionVMState::Running { ref vm, .. } => vm.call_failures(),
+            TransactionVMState::Constructing { .. } => &[],
+        }
+    }
+
     fn used_gas(&self) -> Gas {
         match self.0 {
             TransactionVMState::Running { ref vm, intrinsic_gas, .. } => {
-                let total_used = vm.machines[0].state().total_used_gas() + intrinsic_gas;
-                let refund_cap = total_used / Gas::from(2u64);
-                let refunded = min(refund_cap, vm.machines[0].state().refunded_gas);
-                total_used - refunded
+                vm.machines[0].state().total_used_gas() + intrinsic_gas - self.applied_refund()
             }
             TransactionVMState::Constructing { .. } => Gas::zero(),
         }
@@ -560,8 +743,9 @@ mod tests {
     use bigint::*;
     use hexutil::*;
     use block::TransactionAction;
+    use testing::AccessListPatch;
     use std::str::FromStr;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn system_transaction() {
@@ -571,18 +755,14 @@ mod tests {
             gas_limit: Gas::from_str("0xffffffffffffffff").unwrap(),
             action: TransactionAction::Call(Address::default()),
             value: U256::from_str("0xffffffffffffffff").unwrap(),
-            input: Rc::new(Vec::new()),
+            input: Arc::new(Vec::new()),
             nonce: U256::zero(),
+            access_list: Vec::new(),
         };
-        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(transaction, HeaderParams {
-            beneficiary: Address::default(),
-            timestamp: 0,
-            number: U256::zero(),
-            difficulty: U256::zero(),
-            gas_limit: Gas::zero(),
-        });
+        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(transaction, HeaderParams::default());
         vm.commit_account(AccountCommitment::Nonexist(Address::default())).unwrap();
         vm.fire().unwrap();
+        assert_eq!(vm.created_address(), None);
 
         let mut accounts: Vec<AccountChange> = Vec::new();
         for account in vm.accounts() {
@@ -608,16 +788,11 @@ mod tests {
             gas_limit: Gas::from_str("0xffffffffffffffff").unwrap(),
             action: TransactionAction::Call(Address::default()),
             value: U256::from_str("0xffffffffffffffff").unwrap(),
-            input: Rc::new(Vec::new()),
+            input: Arc::new(Vec::new()),
             nonce: U256::zero(),
+            access_list: Vec::new(),
         };
-        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(transaction, HeaderParams {
-            beneficiary: Address::default(),
-            timestamp: 0,
-            number: U256::zero(),
-            difficulty: U256::zero(),
-            gas_limit: Gas::zero(),
-        });
+        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(transaction, HeaderParams::default());
         vm.commit_account(AccountCommitment::Nonexist(Address::default())).unwrap();
         vm.fire().unwrap();
 
@@ -642,8 +817,8 @@ mod tests {
         let context = Context {
             address: Address::default(),
             caller: Address::default(),
-            code: Rc::new(read_hex("6c726576657274656420646174616000557f726576657274206d657373616765000000000000000000000000000000000000600052600e6000fd").unwrap()),
-            data: Rc::new(Vec::new()),
+            code: Arc::new(read_hex("6c726576657274656420646174616000557f726576657274206d657373616765000000000000000000000000000000000000600052600e6000fd").unwrap()),
+            data: Arc::new(Vec::new()),
             gas_limit: Gas::from(100000usize),
             gas_price: Gas::from(0usize),
             origin: Address::default(),
@@ -654,11 +829,8 @@ mod tests {
         };
 
         let header = HeaderParams {
-            beneficiary: Address::default(),
-            timestamp: 0,
-            number: U256::zero(),
-            difficulty: U256::zero(),
             gas_limit: Gas::from(100000usize),
+            ..HeaderParams::default()
         };
 
         let mut vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header);
@@ -671,4 +843,446 @@ mod tests {
         println!("accounts: {:?}", vm.accounts());
         assert_eq!(vm.accounts().len(), 0);
     }
+
+    #[test]
+    fn refund_is_capped() {
+        let transaction = ValidTransaction {
+            caller: None,
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000usize),
+            action: TransactionAction::Call(Address::default()),
+            value: U256::zero(),
+            input: Arc::new(Vec::new()),
+            nonce: U256::zero(),
+            access_list: Vec::new(),
+        };
+        let mut vm = SeqTransactionVM::<EmbeddedByzantiumPatch>::new(transaction, HeaderParams::default());
+        // Clear slot 0, which was 1.
+        vm.commit_account(AccountCommitment::Full {
+            nonce: U256::zero(), address: Address::default(), balance: U256::zero(),
+            code: Arc::new(read_hex("6000600055").unwrap()),
+        }).unwrap();
+        vm.commit_account(AccountCommitment::Storage {
+            address: Address::default(), index: U256::zero(), value: M256::one(),
+        }).unwrap();
+        vm.fire().unwrap();
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.refunded_gas(), Gas::from(15000usize));
+        assert!(vm.refunded_gas() > vm.applied_refund());
+        assert_eq!(vm.applied_refund(), (vm.used_gas() + vm.applied_refund()) / Gas::from(2usize));
+    }
+
+    /// Send 5 wei with gas price 2 from an account holding 10^6 wei
+    /// to a contract with the given code, and return the VM and the
+    /// balances of the sender, the contract and the beneficiary.
+    fn pay_for_gas(code: &str) -> (SeqTransactionVM<EmbeddedByzantiumPatch>, U256, U256, U256) {
+        let sender = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+        let contract = Address::from_str("0x2000000000000000000000000000000000000000").unwrap();
+        let beneficiary = Address::from_str("0x3000000000000000000000000000000000000000").unwrap();
+        let transaction = ValidTransaction {
+            caller: Some(sender),
+            gas_price: Gas::from(2usize),
+            gas_limit: Gas::from(50000usize),
+            action: TransactionAction::Call(contract),
+            value: U256::from(5usize),
+            input: Arc::new(Vec::new()),
+            nonce: U256::zero(),
+            access_list: Vec::new(),
+        };
+        let mut vm = SeqTransactionVM::<EmbeddedByzantiumPatch>::new(transaction, HeaderParams {
+            beneficiary, ..HeaderParams::default()
+        });
+        loop {
+            let address = match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) => address,
+                Err(err) => panic!("unexpected require {:?}", err),
+            };
+            let (balance, code) = match address {
+                a if a == sender => (U256::from(1000000usize), ""),
+                a if a == contract => (U256::zero(), code),
+                _ => (U256::zero(), ""),
+            };
+            vm.commit_account(AccountCommitment::Full {
+                nonce: U256::zero(), address, balance, code: Arc::new(read_hex(code).unwrap()),
+            }).unwrap();
+        }
+
+        let balance = |address| vm.accounts().find(|account| account.address() == address)
+            .map(|account| match account {
+                &AccountChange::Full { balance, .. } => balance,
+                &AccountChange::IncreaseBalance(_, balance) => balance,
+                account => panic!("unexpected account {:?}", account),
+            }).unwrap_or(U256::zero());
+        let balances = (balance(sender), balance(contract), balance(beneficiary));
+        (vm, balances.0, balances.1, balances.2)
+    }
+
+    #[test]
+    fn failed_transaction_pays_for_gas() {
+        // Loop until out of gas. All the gas limit is paid, and the
+        // value goes back to the sender.
+        let (vm, sender, contract, beneficiary) = pay_for_gas("5b600056");
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::EmptyGas) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(50000usize));
+        assert_eq!(sender, U256::from(1000000 - 2 * 50000usize));
+        assert_eq!(contract, U256::zero());
+        assert_eq!(beneficiary, U256::from(2 * 50000usize));
+
+        // REVERT only pays for the intrinsic gas and the gas used.
+        let (vm, sender, contract, beneficiary) = pay_for_gas("60006000fd");
+        match vm.status() {
+            VMStatus::ExitedErr(OnChainError::Revert) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(21000 + 6usize));
+        assert_eq!(sender, U256::from(1000000 - 2 * 21006usize));
+        assert_eq!(contract, U256::zero());
+        assert_eq!(beneficiary, U256::from(2 * 21006usize));
+    }
+
+    #[test]
+    fn check_caller() {
+        let sender = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+        let transaction = ValidTransaction {
+            caller: Some(sender),
+            gas_price: Gas::from(2usize),
+            gas_limit: Gas::from(21000usize),
+            action: TransactionAction::Call(Address::default()),
+            value: U256::from(5usize),
+            input: Arc::new(Vec::new()),
+            nonce: U256::one(),
+            access_list: Vec::new(),
+        };
+        let mut account_state = AccountState::<EmbeddedAccountPatch>::default();
+        assert_eq!(transaction.check::<EmbeddedPatch>(&account_state).err(),
+                   Some(RequireError::Account(sender)));
+
+        account_state.commit(AccountCommitment::Full {
+            nonce: U256::one(), address: sender, balance: U256::from(2 * 21000 + 5usize),
+            code: Arc::new(Vec::new()),
+        }).unwrap();
+        match transaction.check::<EmbeddedPatch>(&account_state) {
+            Ok(Ok(())) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let stale = ValidTransaction { nonce: U256::zero(), ..transaction.clone() };
+        match stale.check::<EmbeddedPatch>(&account_state) {
+            Ok(Err(PreExecutionError::InvalidNonce)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let expensive = ValidTransaction { gas_price: Gas::from(3usize), ..transaction };
+        match expensive.check::<EmbeddedPatch>(&account_state) {
+            Ok(Err(PreExecutionError::InsufficientBalance)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_failed_caller_checks() {
+        let sender = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+        let transaction = ValidTransaction {
+            caller: Some(sender),
+            gas_price: Gas::from(2usize),
+            gas_limit: Gas::from(21000usize),
+            action: TransactionAction::Call(Address::default()),
+            value: U256::zero(),
+            input: Arc::new(Vec::new()),
+            nonce: U256::one(),
+            access_list: Vec::new(),
+        };
+        let sender_account = AccountCommitment::Full {
+            nonce: U256::one(), address: sender, balance: U256::from(2 * 21000usize),
+            code: Arc::new(Vec::new()),
+        };
+
+        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(
+            ValidTransaction { nonce: U256::zero(), ..transaction.clone() }, HeaderParams::default());
+        assert_eq!(vm.fire().err(), Some(RequireError::Account(sender)));
+        vm.commit_account(sender_account.clone()).unwrap();
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::Rejected(PreExecutionError::InvalidNonce) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::zero());
+        for account in vm.accounts() {
+            match account {
+                &AccountChange::Full { nonce, balance, .. } => {
+                    assert_eq!(nonce, U256::one());
+                    assert_eq!(balance, U256::from(2 * 21000usize));
+                },
+                account => panic!("unexpected account {:?}", account),
+            }
+        }
+
+        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(
+            ValidTransaction { gas_price: Gas::from(3usize), ..transaction.clone() }, HeaderParams::default());
+        vm.commit_account(sender_account.clone()).unwrap();
+        vm.fire().unwrap();
+        match vm.status() {
+            VMStatus::Rejected(PreExecutionError::InsufficientBalance) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+
+        let mut vm = SeqTransactionVM::<EmbeddedPatch>::new(transaction, HeaderParams::default());
+        vm.commit_account(sender_account).unwrap();
+        loop {
+            match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) => {
+                    vm.commit_account(AccountCommitment::Nonexist(address)).unwrap();
+                },
+                Err(err) => panic!("unexpected require error {:?}", err),
+            }
+        }
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(21000usize));
+    }
+
+    #[test]
+    fn creation_reports_created_address() {
+        let sender = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+        let transaction = ValidTransaction {
+            caller: Some(sender),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000usize),
+            action: TransactionAction::Create,
+            value: U256::zero(),
+            // Deploy the byte 0x01.
+            input: Arc::new(read_hex("600160005360016000f3").unwrap()),
+            nonce: U256::from(3u64),
+            access_list: Vec::new(),
+        };
+        let mut vm = SeqTransactionVM::<EmbeddedByzantiumPatch>::new(transaction, HeaderParams::default());
+        vm.commit_account(AccountCommitment::Full {
+            nonce: U256::from(3u64), address: sender, balance: U256::zero(), code: Arc::new(Vec::new()),
+        }).unwrap();
+        assert_eq!(vm.created_address(), None);
+        loop {
+            match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) =>
+                    vm.commit_account(AccountCommitment::Nonexist(address)).unwrap(),
+                Err(err) => panic!("unexpected require {:?}", err),
+            }
+        }
+
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let created = create_address(sender, U256::from(3u64));
+        assert_eq!(vm.created_address(), Some(created));
+        assert_eq!(vm.current_state().unwrap().account_state.code(created).unwrap().as_slice(), &[0x01]);
+    }
+
+    /// Drive any VM to completion, committing every required account
+    /// with the given code, and return its status and output.
+    fn drive<V: VM>(vm: &mut V, code: &[u8]) -> (VMStatus, Vec<u8>) {
+        loop {
+            match vm.fire() {
+                Ok(()) => return (vm.status(), vm.out().into()),
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) =>
+                    vm.commit_account(AccountCommitment::Full {
+                        nonce: U256::zero(), address, balance: U256::zero(), code: Arc::new(code.into()),
+                    }).unwrap(),
+                Err(err) => panic!("unexpected require {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn generic_vm() {
+        // RETURN the byte 0x2a.
+        let code = read_hex("602a60005360016000f3").unwrap();
+        let header = HeaderParams::default();
+        let address = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+
+        let context = Context {
+            address,
+            caller: Address::default(),
+            code: Arc::new(code.clone()),
+            data: Arc::new(Vec::new()),
+            gas_limit: Gas::from(100000usize),
+            gas_price: Gas::zero(),
+            origin: Address::default(),
+            value: U256::zero(),
+            apprent_value: U256::zero(),
+            is_system: false,
+            is_static: false,
+        };
+        let mut context_vm = SeqContextVM::<EmbeddedByzantiumPatch>::new(context, header.clone());
+
+        let transaction = ValidTransaction {
+            caller: Some(Address::default()),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000usize),
+            action: TransactionAction::Call(address),
+            value: U256::zero(),
+            input: Arc::new(Vec::new()),
+            nonce: U256::zero(),
+            access_list: Vec::new(),
+        };
+        let mut transaction_vm = SeqTransactionVM::<EmbeddedByzantiumPatch>::new(transaction, header);
+
+        for (status, out) in &[drive(&mut context_vm, &code), drive(&mut transaction_vm, &code)] {
+            match *status {
+                VMStatus::ExitedOk => (),
+                ref status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(out, &vec![0x2a]);
+        }
+    }
+
+    #[test]
+    fn accessed_reset_between_transactions() {
+        let sender = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+        let contract = Address::from_str("0x2000000000000000000000000000000000000000").unwrap();
+        // CALL the target twice with no gas, value, input or output,
+        // then SLOAD slot 1 twice.
+        let call = "600060006000600060007330000000000000000000000000000000000000006000f150";
+        let sload = "60015450";
+        let code = read_hex(&(call.to_string() + call + sload + sload + "00")).unwrap();
+        let header = HeaderParams::default();
+        let transaction = |nonce: usize| ValidTransaction {
+            caller: Some(sender),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000usize),
+            action: TransactionAction::Call(contract),
+            value: U256::zero(),
+            input: Arc::new(Vec::new()),
+            nonce: U256::from(nonce),
+            access_list: Vec::new(),
+        };
+
+        let mut first = SeqTransactionVM::<AccessListPatch>::new(transaction(0), header.clone());
+        loop {
+            let address = match first.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) => address,
+                Err(RequireError::AccountStorage(address, index)) => {
+                    first.commit_account(AccountCommitment::Storage {
+                        address, index, value: M256::zero(),
+                    }).unwrap();
+                    continue;
+                },
+                Err(err) => panic!("unexpected require {:?}", err),
+            };
+            let code = if address == contract { code.clone() } else { Vec::new() };
+            first.commit_account(AccountCommitment::Full {
+                nonce: U256::zero(), address, balance: U256::zero(), code: Arc::new(code),
+            }).unwrap();
+        }
+        let mut second = SeqTransactionVM::with_previous(transaction(1), header, &first);
+        second.fire().unwrap();
+
+        // The first CALL and SLOAD of each transaction are cold, and
+        // the second ones warm.
+        for vm in &[first, second] {
+            match vm.status() {
+                VMStatus::ExitedOk => (),
+                status => panic!("unexpected status {:?}", status),
+            }
+            assert_eq!(vm.used_gas(), Gas::from(21000 + 2 * (21 + 2) + 2600 + 100 +
+                                                2 * (3 + 2) + 2100 + 100usize));
+        }
+    }
+
+    #[test]
+    fn access_list_warms_addresses() {
+        let sender = Address::from_str("0x1000000000000000000000000000000000000000").unwrap();
+        let contract = Address::from_str("0x2000000000000000000000000000000000000000").unwrap();
+        let target = Address::from_str("0x3000000000000000000000000000000000000000").unwrap();
+        // SLOAD slot 1, then CALL the target with no gas, value, input
+        // or output.
+        let code = read_hex("6001545060006000600060006000733000000000000000000000000000000000\
+                             0000006000f15000").unwrap();
+        let transaction = ValidTransaction {
+            caller: Some(sender),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000usize),
+            action: TransactionAction::Call(contract),
+            value: U256::zero(),
+            input: Arc::new(Vec::new()),
+            nonce: U256::zero(),
+            access_list: vec![
+                AccessListItem { address: target, storage_keys: Vec::new() },
+                AccessListItem { address: contract, storage_keys: vec![H256::from(U256::one())] },
+            ],
+        };
+
+        let mut vm = SeqTransactionVM::<AccessListPatch>::new(transaction, HeaderParams::default());
+        loop {
+            let address = match vm.fire() {
+                Ok(()) => break,
+                Err(RequireError::Account(address)) | Err(RequireError::AccountCode(address)) => address,
+                Err(RequireError::AccountStorage(address, index)) => {
+                    vm.commit_account(AccountCommitment::Storage {
+                        address, index, value: M256::zero(),
+                    }).unwrap();
+                    continue;
+                },
+                Err(err) => panic!("unexpected require {:?}", err),
+            };
+            let code = if address == contract { code.clone() } else { Vec::new() };
+            vm.commit_account(AccountCommitment::Full {
+                nonce: U256::zero(), address, balance: U256::zero(), code: Arc::new(code),
+            }).unwrap();
+        }
+
+        // The listed addresses and storage key are paid for in the
+        // intrinsic gas, and the SLOAD and the CALL are warm.
+        match vm.status() {
+            VMStatus::ExitedOk => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(vm.used_gas(), Gas::from(21000 + 2 * 2400 + 1900 + (3 + 100 + 2) +
+                                            (21 + 2) + 100usize));
+    }
+
+    test_patch! {
+        /// Byzantium patch with the Istanbul calldata cost of EIP-2028.
+        struct CalldataPatch;
+        fn gas_transaction_data_nonzero() -> Gas { Gas::from(16usize) }
+    }
+
+    #[test]
+    fn intrinsic_gas_of_calldata() {
+        // Three zero bytes and two non-zero bytes.
+        let data = [0x00, 0x01, 0x00, 0xff, 0x00];
+
+        assert_eq!(intrinsic_gas::<EmbeddedByzantiumPatch>(&[], false), Gas::from(21000u64));
+        assert_eq!(intrinsic_gas::<EmbeddedByzantiumPatch>(&data, false),
+                   Gas::from(21000 + 3 * 4 + 2 * 68u64));
+        assert_eq!(intrinsic_gas::<CalldataPatch>(&data, false),
+                   Gas::from(21000 + 3 * 4 + 2 * 16u64));
+        assert_eq!(intrinsic_gas::<CalldataPatch>(&data, true),
+                   Gas::from(21000 + 32000 + 3 * 4 + 2 * 16u64));
+
+        let transaction = ValidTransaction {
+            caller: Some(Address::default()),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000u64),
+            action: TransactionAction::Create,
+            value: U256::zero(),
+            input: Arc::new(data.to_vec()),
+            nonce: U256::zero(),
+            access_list: Vec::new(),
+        };
+        assert_eq!(transaction.intrinsic_gas::<CalldataPatch>(),
+                   intrinsic_gas::<CalldataPatch>(&data, true));
+    }
 }
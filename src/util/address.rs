@@ -0,0 +1,70 @@
+//! Contract address derivation
+
+#[cfg(not(feature = "std"))]
+use alloc::Vec;
+
+use bigint::{U256, H256, Address};
+use rlp::RlpStream;
+use sha3::{Digest, Keccak256};
+
+/// Address of a contract created by `caller` through `CREATE` or a
+/// contract creation transaction, where `nonce` is the nonce of the
+/// caller before the creation. This is the last 20 bytes of the
+/// Keccak hash of the RLP list `[caller, nonce]`.
+pub fn create_address(caller: Address, nonce: U256) -> Address {
+    let mut rlp = RlpStream::new_list(2);
+    rlp.append(&caller);
+    rlp.append(&nonce);
+
+    Address::from(H256::from(Keccak256::digest(rlp.out().as_slice()).as_slice()))
+}
+
+/// Address of a contract created by `caller` through `CREATE2`, as
+/// defined in EIP-1014. This is the last 20 bytes of the Keccak hash
+/// of `0xff ++ caller ++ salt ++ init_code_hash`.
+pub fn create2_address(caller: Address, salt: H256, init_code_hash: H256) -> Address {
+    let mut data: Vec<u8> = Vec::with_capacity(1 + 20 + 32 + 32);
+    data.push(0xff);
+    data.extend_from_slice(&caller);
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&init_code_hash);
+
+    Address::from(H256::from(Keccak256::digest(data.as_slice()).as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha3::{Digest, Keccak256};
+    use std::str::FromStr;
+
+    fn hash(data: &[u8]) -> H256 {
+        H256::from(Keccak256::digest(data).as_slice())
+    }
+
+    #[test]
+    fn create_address_vectors() {
+        let caller = Address::from_str("0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0").unwrap();
+
+        assert_eq!(create_address(caller, U256::from(0u64)),
+                   Address::from_str("0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d").unwrap());
+        assert_eq!(create_address(caller, U256::from(1u64)),
+                   Address::from_str("0x343c43a37d37dff08ae8c4a11544c718abb4fcf8").unwrap());
+        assert_eq!(create_address(caller, U256::from(2u64)),
+                   Address::from_str("0xf778b86fa74e846c4f0a1fbd1335fe81c00a0c91").unwrap());
+    }
+
+    #[test]
+    fn create2_address_vectors() {
+        // Examples 0, 1 and 5 from EIP-1014.
+        assert_eq!(create2_address(Address::default(), H256::default(), hash(&[0x00])),
+                   Address::from_str("0x4d1a2e2bb4f88f0250f26ffff098b0b30b26bf38").unwrap());
+        assert_eq!(create2_address(Address::from_str("0xdeadbeef00000000000000000000000000000000").unwrap(),
+                                   H256::default(), hash(&[0x00])),
+                   Address::from_str("0xb928f69bb1d91cd65274e3c79d8986362984fda3").unwrap());
+        assert_eq!(create2_address(Address::from_str("0x00000000000000000000000000000000deadbeef").unwrap(),
+                                   H256::from_str("0x00000000000000000000000000000000000000000000000000000000cafebabe").unwrap(),
+                                   hash(&[0xde, 0xad, 0xbe, 0xef])),
+                   Address::from_str("0x60f3f640a8508fc6a86d45df051962668e1e8ac7").unwrap());
+    }
+}
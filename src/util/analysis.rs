@@ -0,0 +1,154 @@
+//! Static analysis of bytecode
+
+#[cfg(not(feature = "std"))]
+use alloc::Vec;
+
+use errors::AnalysisError;
+use pc::Valids;
+use super::opcode::Opcode;
+
+/// Bound the maximum stack depth reachable by executing `code` from
+/// an empty stack, without running it.
+///
+/// The analysis follows both branches of `JUMPI`, and the target of a
+/// jump whose destination is pushed right before it. Any other jump
+/// is assumed to reach every `JUMPDEST`, so the bound is conservative.
+/// Stack underflows are not reported. Returns
+/// `AnalysisError::StackOverflow` if the stack could exceed
+/// `stack_limit` items, usually `Patch::stack_limit()`.
+pub fn max_stack_depth(code: &[u8], stack_limit: usize) -> Result<usize, AnalysisError> {
+    let valids = Valids::new(code);
+    let jumpdests: Vec<usize> = (0..code.len()).filter(|&i| valids.is_valid(i)).collect();
+    let targets = jump_targets(code);
+
+    let mut visited: Vec<Option<usize>> = Vec::with_capacity(code.len());
+    visited.resize(code.len(), None);
+    let mut pending: Vec<(usize, usize)> = Vec::new();
+    pending.push((0, 0));
+    let mut max = 0;
+
+    while let Some((position, depth)) = pending.pop() {
+        // Running past the end of the code is an implicit STOP.
+        if position >= code.len() {
+            continue;
+        }
+        match visited[position] {
+            Some(seen) if seen >= depth => continue,
+            _ => visited[position] = Some(depth),
+        }
+
+        let opcode: Opcode = code[position].into();
+        let (pop, push) = opcode.stack_io();
+        let depth = depth.saturating_sub(pop) + push;
+        if depth > stack_limit {
+            return Err(AnalysisError::StackOverflow(position));
+        }
+        if depth > max {
+            max = depth;
+        }
+
+        let next = match opcode {
+            Opcode::PUSH(v) => position + v + 1,
+            _ => position + 1,
+        };
+        match opcode {
+            Opcode::STOP | Opcode::RETURN | Opcode::REVERT |
+            Opcode::INVALID | Opcode::SUICIDE => (),
+            Opcode::JUMP | Opcode::JUMPI => {
+                match targets[position] {
+                    Some(target) => {
+                        if valids.is_valid(target) {
+                            pending.push((target, depth));
+                        }
+                    },
+                    None => {
+                        for &target in &jumpdests {
+                            pending.push((target, depth));
+                        }
+                    },
+                }
+                if opcode == Opcode::JUMPI {
+                    pending.push((next, depth));
+                }
+            },
+            _ => pending.push((next, depth)),
+        }
+    }
+
+    Ok(max)
+}
+
+/// Map each jump that directly follows a push to the pushed
+/// destination. A destination too large for `usize` is mapped to
+/// `usize::MAX`, which is never valid.
+fn jump_targets(code: &[u8]) -> Vec<Option<usize>> {
+    let mut targets: Vec<Option<usize>> = Vec::with_capacity(code.len());
+    targets.resize(code.len(), None);
+    let mut pushed: Option<usize> = None;
+
+    let mut i = 0;
+    while i < code.len() {
+        let opcode: Opcode = code[i].into();
+        match opcode {
+            Opcode::PUSH(v) => {
+                let mut value: usize = 0;
+                for j in (i + 1)..(i + 1 + v) {
+                    let byte = if j < code.len() { code[j] } else { 0 };
+                    value = value.checked_mul(256)
+                        .and_then(|value| value.checked_add(byte as usize))
+                        .unwrap_or(usize::MAX);
+                }
+                pushed = Some(value);
+                i += v + 1;
+            },
+            Opcode::JUMP | Opcode::JUMPI => {
+                targets[i] = pushed;
+                pushed = None;
+                i += 1;
+            },
+            _ => {
+                pushed = None;
+                i += 1;
+            },
+        }
+    }
+
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_push_sequence() {
+        let mut code = Vec::new();
+        for _ in 0..100 {
+            code.extend_from_slice(&[0x60, 0x01]);
+        }
+        // Pop two of them with ADD, then STOP.
+        code.extend_from_slice(&[0x01, 0x00]);
+        assert_eq!(max_stack_depth(&code, 1024), Ok(100));
+        assert_eq!(max_stack_depth(&code, 16), Err(AnalysisError::StackOverflow(32)));
+
+        code.clear();
+        for _ in 0..1025 {
+            code.extend_from_slice(&[0x60, 0x01]);
+        }
+        assert_eq!(max_stack_depth(&code, 1024), Err(AnalysisError::StackOverflow(2048)));
+    }
+
+    #[test]
+    fn jumps() {
+        // PUSH1 7, JUMP, PUSH1 0, PUSH1 0, JUMPDEST, PUSH1 0, STOP: the
+        // skipped pushes are not counted.
+        assert_eq!(max_stack_depth(&[0x60, 0x07, 0x56, 0x60, 0x00, 0x60,
+                                     0x00, 0x5b, 0x60, 0x00, 0x00], 1024), Ok(1));
+        // JUMPDEST, PUSH1 0, PUSH1 0, JUMP: every iteration leaves one
+        // more item on the stack.
+        match max_stack_depth(&[0x5b, 0x60, 0x00, 0x60, 0x00, 0x56], 1024) {
+            Err(AnalysisError::StackOverflow(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Encoding of simple call data
+
+#[cfg(not(feature = "std"))]
+use alloc::Vec;
+
+use bigint::M256;
+use super::m256::M256Ext;
+
+/// Encode call data as the 4-byte function selector followed by each
+/// argument as a 32-byte big-endian word. This covers functions whose
+/// arguments are all static words, such as addresses and integers,
+/// and is not a full ABI encoder.
+pub fn encode_call(selector: [u8; 4], args: &[M256]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + 32 * args.len());
+    data.extend_from_slice(&selector);
+    for arg in args {
+        data.extend_from_slice(&arg.to_big_endian());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::Address;
+    use sha3::{Digest, Keccak256};
+    use std::str::FromStr;
+    use util::hex::hex_to_bytes;
+
+    #[test]
+    fn transfer() {
+        let mut selector = [0u8; 4];
+        selector.copy_from_slice(&Keccak256::digest(b"transfer(address,uint256)")[0..4]);
+        assert_eq!(selector, [0xa9, 0x05, 0x9c, 0xbb]);
+
+        let to = Address::from_str("0x1000000000000000000000000000000000000001").unwrap();
+        let data = encode_call(selector, &[M256::from(to), M256::from(1000u64)]);
+        assert_eq!(data.len(), 68);
+        assert_eq!(data, hex_to_bytes(&("a9059cbb".to_string() +
+            "0000000000000000000000001000000000000000000000000000000000000001" +
+            "00000000000000000000000000000000000000000000000000000000000003e8")).unwrap());
+    }
+}
@@ -0,0 +1,74 @@
+//! Checked gas arithmetic
+
+use bigint::{Gas, U256};
+
+/// Checked and saturating arithmetic on `Gas`. The `Gas` operators
+/// saturate on addition and multiplication, but panic when a
+/// subtraction underflows.
+pub trait GasExt: Sized {
+    /// Add, or `None` if the result overflows.
+    fn checked_add(self, other: Self) -> Option<Self>;
+    /// Subtract, or `None` if the result underflows.
+    fn checked_sub(self, other: Self) -> Option<Self>;
+    /// Subtract, or zero if the result underflows.
+    fn saturating_sub(self, other: Self) -> Self;
+    /// Convert into `u64`, or `None` if the value does not fit.
+    fn to_u64(&self) -> Option<u64>;
+    /// Convert from `u64`.
+    fn from_u64(value: u64) -> Self;
+}
+
+impl GasExt for Gas {
+    fn checked_add(self, other: Gas) -> Option<Gas> {
+        let a: U256 = self.into();
+        match a.overflowing_add(other.into()) {
+            (_, true) => None,
+            (value, false) => Some(value.into()),
+        }
+    }
+
+    fn checked_sub(self, other: Gas) -> Option<Gas> {
+        if self < other {
+            None
+        } else {
+            Some(self - other)
+        }
+    }
+
+    fn saturating_sub(self, other: Gas) -> Gas {
+        self.checked_sub(other).unwrap_or(Gas::zero())
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        if *self > Gas::from(u64::MAX) {
+            None
+        } else {
+            Some(self.as_u64())
+        }
+    }
+
+    fn from_u64(value: u64) -> Gas {
+        Gas::from(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked() {
+        assert_eq!(Gas::from(2u64).checked_add(Gas::from(3u64)), Some(Gas::from(5u64)));
+        assert_eq!(Gas::max_value().checked_add(Gas::one()), None);
+        assert_eq!(Gas::from(5u64).checked_sub(Gas::from(3u64)), Some(Gas::from(2u64)));
+        assert_eq!(Gas::from(3u64).checked_sub(Gas::from(5u64)), None);
+        assert_eq!(Gas::from(3u64).saturating_sub(Gas::from(5u64)), Gas::zero());
+    }
+
+    #[test]
+    fn u64_conversion() {
+        assert_eq!(Gas::from_u64(21000).to_u64(), Some(21000));
+        assert_eq!(Gas::from_u64(u64::MAX).to_u64(), Some(u64::MAX));
+        assert_eq!((Gas::from(u64::MAX) + Gas::one()).to_u64(), None);
+    }
+}
@@ -0,0 +1,62 @@
+//! Hex encoding of bytecode and calldata
+
+#[cfg(not(feature = "std"))]
+use alloc::{Vec, String};
+
+use errors::ParseHexError;
+
+const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Parse a hex string into bytes. The string can optionally start
+/// with `0x`, and must have an even number of digits.
+pub fn hex_to_bytes(s: &str) -> Result<Vec<u8>, ParseHexError> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.len() & 1 == 1 {
+        return Err(ParseHexError::OddLength);
+    }
+
+    let digits = s.as_bytes();
+    let mut ret = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        ret.push(digit(pair[0])? << 4 | digit(pair[1])?);
+    }
+    Ok(ret)
+}
+
+/// Encode bytes as a `0x` prefixed lowercase hex string.
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    let mut ret = String::with_capacity(2 + bytes.len() * 2);
+    ret.push_str("0x");
+    for byte in bytes {
+        ret.push(DIGITS[(byte >> 4) as usize] as char);
+        ret.push(DIGITS[(byte & 0x0f) as usize] as char);
+    }
+    ret
+}
+
+fn digit(c: u8) -> Result<u8, ParseHexError> {
+    (c as char).to_digit(16).map(|v| v as u8).ok_or(ParseHexError::InvalidCharacter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let code = "0x60035b600190038060025700";
+        let bytes = hex_to_bytes(code).unwrap();
+        assert_eq!(bytes, vec![0x60, 0x03, 0x5b, 0x60, 0x01, 0x90, 0x03, 0x80, 0x60, 0x02, 0x57, 0x00]);
+        assert_eq!(bytes_to_hex(&bytes), code);
+        assert_eq!(hex_to_bytes("60035B6001").unwrap(), hex_to_bytes("0x60035b6001").unwrap());
+        assert_eq!(hex_to_bytes("0x").unwrap(), Vec::<u8>::new());
+        assert_eq!(bytes_to_hex(&[]), "0x");
+    }
+
+    #[test]
+    fn malformed_hex() {
+        assert_eq!(hex_to_bytes("0x600"), Err(ParseHexError::OddLength));
+        assert_eq!(hex_to_bytes("60zz"), Err(ParseHexError::InvalidCharacter));
+        assert_eq!(hex_to_bytes("0x0x00"), Err(ParseHexError::InvalidCharacter));
+    }
+}
@@ -0,0 +1,198 @@
+//! Conversions of stack values
+
+#[cfg(not(feature = "std"))]
+use alloc::String;
+
+#[cfg(feature = "std")] use std::fmt::Write;
+#[cfg(not(feature = "std"))] use core::fmt::Write;
+
+use bigint::{M256, U256};
+
+/// Conversions of `M256` stack values into lengths, offsets and
+/// bytes, as used by the opcode implementations.
+pub trait M256Ext: Sized {
+    /// Convert into `usize`, or `None` if the value does not fit.
+    fn to_usize(&self) -> Option<usize>;
+    /// The lowest 64 bits of the value.
+    fn low_u64(&self) -> u64;
+    /// The number of significant bytes, which is `0` for zero. The
+    /// number of significant bits is given by `M256::bits`.
+    fn byte_len(&self) -> usize;
+    /// Read a value from big-endian bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is longer than 32 bytes.
+    fn from_big_endian(bytes: &[u8]) -> Self;
+    /// Write the value as 32 big-endian bytes.
+    fn to_big_endian(&self) -> [u8; 32];
+    /// Whether the most significant bit is set, which means the value
+    /// is negative as a two's complement signed integer.
+    fn is_negative(&self) -> bool;
+    /// Negate the value in two's complement. The most negative value
+    /// has no positive counterpart, so it negates to itself.
+    fn neg(&self) -> Self;
+    /// The magnitude of the value as a two's complement signed
+    /// integer, and whether it is negative. The magnitude of the most
+    /// negative value is `1 << 255`, read as unsigned.
+    fn abs_signed(&self) -> (Self, bool);
+    /// Render the value as a two's complement signed decimal.
+    fn to_signed_string(&self) -> String;
+    /// Convert from a signed integer, in two's complement.
+    fn from_signed_i64(value: i64) -> Self;
+}
+
+impl M256Ext for M256 {
+    fn to_usize(&self) -> Option<usize> {
+        if *self > M256::from(usize::MAX) {
+            None
+        } else {
+            Some(self.as_usize())
+        }
+    }
+
+    fn low_u64(&self) -> u64 {
+        let value: U256 = (*self).into();
+        value.low_u64()
+    }
+
+    fn byte_len(&self) -> usize {
+        self.bits().div_ceil(8)
+    }
+
+    fn from_big_endian(bytes: &[u8]) -> M256 {
+        M256::from(bytes)
+    }
+
+    fn to_big_endian(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        let value: U256 = (*self).into();
+        value.to_big_endian(&mut bytes);
+        bytes
+    }
+
+    fn is_negative(&self) -> bool {
+        *self & (M256::one() << 255) != M256::zero()
+    }
+
+    fn neg(&self) -> M256 {
+        // The addition wraps, so the most negative value is kept.
+        !*self + M256::one()
+    }
+
+    fn abs_signed(&self) -> (M256, bool) {
+        if self.is_negative() {
+            (self.neg(), true)
+        } else {
+            (*self, false)
+        }
+    }
+
+    fn to_signed_string(&self) -> String {
+        let mut string = String::new();
+        let (magnitude, negative) = self.abs_signed();
+        if negative {
+            string.push('-');
+        }
+        let magnitude: U256 = magnitude.into();
+        write!(string, "{}", magnitude).unwrap();
+        string
+    }
+
+    fn from_signed_i64(value: i64) -> M256 {
+        if value < 0 {
+            // -n is !(n - 1), which also fits i64::MIN.
+            !M256::from((-(value + 1)) as u64)
+        } else {
+            M256::from(value as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_usize_overflow() {
+        assert_eq!(M256::from(42u64).to_usize(), Some(42));
+        assert_eq!(M256::from(usize::MAX).to_usize(), Some(usize::MAX));
+        assert_eq!((M256::from(usize::MAX) + M256::one()).to_usize(), None);
+        assert_eq!(M256::max_value().to_usize(), None);
+    }
+
+    #[test]
+    fn low_u64_truncates() {
+        assert_eq!(M256::from(7u64).low_u64(), 7);
+        assert_eq!(((M256::one() << 64) + M256::from(3u64)).low_u64(), 3);
+        assert_eq!(M256::max_value().low_u64(), u64::MAX);
+    }
+
+    #[test]
+    fn significant_length() {
+        assert_eq!((M256::zero().bits(), M256::zero().byte_len()), (0, 0));
+        assert_eq!((M256::one().bits(), M256::one().byte_len()), (1, 1));
+        assert_eq!((M256::from(0xffu64).bits(), M256::from(0xffu64).byte_len()), (8, 1));
+        assert_eq!((M256::from(0x100u64).bits(), M256::from(0x100u64).byte_len()), (9, 2));
+        assert_eq!((M256::max_value().bits(), M256::max_value().byte_len()), (256, 32));
+    }
+
+    #[test]
+    fn big_endian_round_trip() {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let value = M256::from_big_endian(&bytes);
+        assert_eq!(value.to_big_endian(), bytes);
+        assert_eq!(value.low_u64(), 0x18191a1b1c1d1e1f);
+
+        assert_eq!(M256::from_big_endian(&[0x01, 0x00]), M256::from(256u64));
+        assert_eq!(M256::from(256u64).to_big_endian()[30..], [0x01, 0x00]);
+    }
+
+    #[test]
+    fn is_negative() {
+        assert!(!M256::zero().is_negative());
+        assert!(!(M256::max_value() >> 1).is_negative());
+        assert!((M256::one() << 255).is_negative());
+        assert!(M256::max_value().is_negative());
+    }
+
+    #[test]
+    fn signed_conversions() {
+        assert_eq!(M256::from_signed_i64(-1), M256::max_value());
+        assert_eq!(M256::from_signed_i64(-1).to_signed_string(), "-1");
+        assert_eq!(M256::from_signed_i64(42).to_signed_string(), "42");
+        assert_eq!(M256::from_signed_i64(i64::MIN).to_signed_string(), "-9223372036854775808");
+        assert_eq!((M256::one() << 255).to_signed_string(),
+                   "-57896044618658097711785492504343953926634992332820282019728792003956564819968");
+        assert_eq!(M256::zero().to_signed_string(), "0");
+    }
+
+    #[test]
+    fn negation() {
+        let min = M256::one() << 255;
+        assert_eq!(min.neg(), min);
+        assert_eq!(min.abs_signed(), (min, true));
+        assert_eq!(M256::zero().neg(), M256::zero());
+        assert_eq!(M256::one().neg(), M256::max_value());
+        assert_eq!(M256::from_signed_i64(-5).abs_signed(), (M256::from(5u64), true));
+        assert_eq!(M256::from(5u64).abs_signed(), (M256::from(5u64), false));
+
+        // Pseudo-random values from a xorshift generator.
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for _ in 0..64 {
+            let mut bytes = [0u8; 32];
+            for byte in bytes.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                *byte = seed as u8;
+            }
+            let value = M256::from_big_endian(&bytes);
+            assert_eq!(value.neg().neg(), value);
+            assert_eq!(value + value.neg(), M256::zero());
+        }
+    }
+}
@@ -1,2 +1,9 @@
 //! Utilities of big integers, address, gas and opcodes
 pub mod opcode;
+pub mod address;
+pub mod hex;
+pub mod m256;
+pub mod gas;
+pub mod revert;
+pub mod calldata;
+pub mod analysis;
@@ -7,15 +7,15 @@ pub enum Opcode {
     STOP, ADD, MUL, SUB, DIV, SDIV, MOD, SMOD, ADDMOD, MULMOD, EXP,
     SIGNEXTEND,
 
-    LT, GT, SLT, SGT, EQ, ISZERO, AND, OR, XOR, NOT, BYTE,
+    LT, GT, SLT, SGT, EQ, ISZERO, AND, OR, XOR, NOT, BYTE, SHL, SHR, SAR,
 
     SHA3,
 
     ADDRESS, BALANCE, ORIGIN, CALLER, CALLVALUE, CALLDATALOAD,
     CALLDATASIZE, CALLDATACOPY, CODESIZE, CODECOPY, GASPRICE,
-    EXTCODESIZE, EXTCODECOPY, RETURNDATASIZE, RETURNDATACOPY,
+    EXTCODESIZE, EXTCODECOPY, RETURNDATASIZE, RETURNDATACOPY, EXTCODEHASH,
 
-    BLOCKHASH, COINBASE, TIMESTAMP, NUMBER, DIFFICULTY, GASLIMIT,
+    BLOCKHASH, COINBASE, TIMESTAMP, NUMBER, DIFFICULTY, GASLIMIT, BASEFEE,
 
     POP, MLOAD, MSTORE, MSTORE8, SLOAD, SSTORE, JUMP, JUMPI, PC,
     MSIZE, GAS, JUMPDEST,
@@ -57,6 +57,9 @@ impl From<u8> for Opcode {
             0x18 => Opcode::XOR,
             0x19 => Opcode::NOT,
             0x1a => Opcode::BYTE,
+            0x1b => Opcode::SHL,
+            0x1c => Opcode::SHR,
+            0x1d => Opcode::SAR,
 
             0x20 => Opcode::SHA3,
 
@@ -75,6 +78,7 @@ impl From<u8> for Opcode {
             0x3c => Opcode::EXTCODECOPY,
             0x3d => Opcode::RETURNDATASIZE,
             0x3e => Opcode::RETURNDATACOPY,
+            0x3f => Opcode::EXTCODEHASH,
 
             0x40 => Opcode::BLOCKHASH,
             0x41 => Opcode::COINBASE,
@@ -82,6 +86,7 @@ impl From<u8> for Opcode {
             0x43 => Opcode::NUMBER,
             0x44 => Opcode::DIFFICULTY,
             0x45 => Opcode::GASLIMIT,
+            0x48 => Opcode::BASEFEE,
 
             0x50 => Opcode::POP,
             0x51 => Opcode::MLOAD,
@@ -210,6 +215,9 @@ impl Into<u8> for Opcode {
             Opcode::XOR => 0x18,
             Opcode::NOT => 0x19,
             Opcode::BYTE => 0x1a,
+            Opcode::SHL => 0x1b,
+            Opcode::SHR => 0x1c,
+            Opcode::SAR => 0x1d,
 
             Opcode::SHA3 => 0x20,
 
@@ -228,6 +236,7 @@ impl Into<u8> for Opcode {
             Opcode::EXTCODECOPY => 0x3c,
             Opcode::RETURNDATASIZE => 0x3d,
             Opcode::RETURNDATACOPY => 0x3e,
+            Opcode::EXTCODEHASH => 0x3f,
 
             Opcode::BLOCKHASH => 0x40,
             Opcode::COINBASE => 0x41,
@@ -235,6 +244,7 @@ impl Into<u8> for Opcode {
             Opcode::NUMBER => 0x43,
             Opcode::DIFFICULTY => 0x44,
             Opcode::GASLIMIT => 0x45,
+            Opcode::BASEFEE => 0x48,
 
             Opcode::POP => 0x50,
             Opcode::MLOAD => 0x51,
@@ -282,3 +292,57 @@ impl Into<u8> for Opcode {
         }
     }
 }
+
+impl Opcode {
+    /// The number of stack items the opcode pops and pushes.
+    pub fn stack_io(&self) -> (usize, usize) {
+        match *self {
+            Opcode::STOP => (0, 0),
+            Opcode::ADD | Opcode::MUL | Opcode::SUB | Opcode::DIV |
+            Opcode::SDIV | Opcode::MOD | Opcode::SMOD | Opcode::EXP |
+            Opcode::SIGNEXTEND => (2, 1),
+            Opcode::ADDMOD | Opcode::MULMOD => (3, 1),
+
+            Opcode::LT | Opcode::GT | Opcode::SLT | Opcode::SGT | Opcode::EQ |
+            Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::BYTE |
+            Opcode::SHL | Opcode::SHR | Opcode::SAR => (2, 1),
+            Opcode::ISZERO | Opcode::NOT => (1, 1),
+
+            Opcode::SHA3 => (2, 1),
+
+            Opcode::ADDRESS | Opcode::ORIGIN | Opcode::CALLER |
+            Opcode::CALLVALUE | Opcode::CALLDATASIZE | Opcode::CODESIZE |
+            Opcode::GASPRICE | Opcode::RETURNDATASIZE => (0, 1),
+            Opcode::BALANCE | Opcode::CALLDATALOAD | Opcode::EXTCODESIZE |
+            Opcode::EXTCODEHASH => (1, 1),
+            Opcode::CALLDATACOPY | Opcode::CODECOPY |
+            Opcode::RETURNDATACOPY => (3, 0),
+            Opcode::EXTCODECOPY => (4, 0),
+
+            Opcode::BLOCKHASH => (1, 1),
+            Opcode::COINBASE | Opcode::TIMESTAMP | Opcode::NUMBER |
+            Opcode::DIFFICULTY | Opcode::GASLIMIT | Opcode::BASEFEE => (0, 1),
+
+            Opcode::POP => (1, 0),
+            Opcode::MLOAD | Opcode::SLOAD => (1, 1),
+            Opcode::MSTORE | Opcode::MSTORE8 | Opcode::SSTORE => (2, 0),
+            Opcode::JUMP => (1, 0),
+            Opcode::JUMPI => (2, 0),
+            Opcode::PC | Opcode::MSIZE | Opcode::GAS => (0, 1),
+            Opcode::JUMPDEST => (0, 0),
+
+            Opcode::PUSH(_) => (0, 1),
+            Opcode::DUP(v) => (v, v + 1),
+            Opcode::SWAP(v) => (v + 1, v + 1),
+            Opcode::LOG(v) => (v + 2, 0),
+
+            Opcode::CREATE => (3, 1),
+            Opcode::CALL | Opcode::CALLCODE => (7, 1),
+            Opcode::DELEGATECALL | Opcode::STATICCALL => (6, 1),
+            Opcode::RETURN | Opcode::REVERT => (2, 0),
+
+            Opcode::INVALID => (0, 0),
+            Opcode::SUICIDE => (1, 0),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Decoding of revert reasons
+
+#[cfg(not(feature = "std"))]
+use alloc::String;
+
+use bigint::M256;
+use super::m256::M256Ext;
+
+/// Selector of the Solidity `Error(string)` revert payload.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Decode the message of a REVERT output encoded as the Solidity
+/// `Error(string)`. Returns `None` for any other output.
+pub fn revert_reason(out: &[u8]) -> Option<String> {
+    if out.len() < 4 || out[0..4] != ERROR_SELECTOR {
+        return None;
+    }
+    let data = &out[4..];
+
+    let offset = word(data, 0)?;
+    let len = word(data, offset)?;
+    let start = offset.checked_add(32)?;
+    let end = start.checked_add(len)?;
+    if end > data.len() {
+        return None;
+    }
+
+    String::from_utf8(data[start..end].to_vec()).ok()
+}
+
+fn word(data: &[u8], offset: usize) -> Option<usize> {
+    let end = offset.checked_add(32)?;
+    if end > data.len() {
+        return None;
+    }
+    M256::from(&data[offset..end]).to_usize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::hex::hex_to_bytes;
+
+    #[test]
+    fn error_string() {
+        // Error("Not enough Ether provided.")
+        let out = hex_to_bytes(&("08c379a0".to_string() +
+            "0000000000000000000000000000000000000000000000000000000000000020" +
+            "000000000000000000000000000000000000000000000000000000000000001a" +
+            "4e6f7420656e6f7567682045746865722070726f76696465642e000000000000")).unwrap();
+        assert_eq!(revert_reason(&out), Some("Not enough Ether provided.".to_string()));
+    }
+
+    #[test]
+    fn other_payloads() {
+        assert_eq!(revert_reason(&[]), None);
+        // A custom error selector.
+        assert_eq!(revert_reason(&[0xde, 0xad, 0xbe, 0xef]), None);
+        // The length runs past the end of the output.
+        let out = hex_to_bytes(&("08c379a0".to_string() +
+            "0000000000000000000000000000000000000000000000000000000000000020" +
+            "00000000000000000000000000000000000000000000000000000000000000ff")).unwrap();
+        assert_eq!(revert_reason(&out), None);
+        // The offset does not fit in usize.
+        let out = hex_to_bytes(&("08c379a0".to_string() + &"ff".repeat(64))).unwrap();
+        assert_eq!(revert_reason(&out), None);
+    }
+}
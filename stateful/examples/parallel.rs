@@ -9,15 +9,15 @@ extern crate sputnikvm_network_classic;
 
 use hexutil::*;
 use block::TransactionAction;
-use bigint::{Address, U256, Gas};
-use sputnikvm::{AccountChange, HeaderParams, SeqTransactionVM, VM, Storage, ValidTransaction};
+use bigint::{Address, U256, M256, H256, Gas};
+use sputnikvm::{AccountChange, HeaderParams, SeqTransactionVM, VM, Storage, ValidTransaction,
+                AccessListItem};
 use sputnikvm_network_classic::MainnetEIP160Patch;
 use trie::MemoryDatabase;
 use sputnikvm_stateful::{MemoryStateful, LiteralAccount};
 use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::rc::Rc;
 use std::ops::Deref;
 use std::str::FromStr;
 
@@ -101,7 +101,7 @@ impl Into<AccountChange> for SendableAccountChange {
             SendableAccountChange::Full { nonce, address, balance, changing_storage, code } => {
                 AccountChange::Full {
                     nonce, address, balance, changing_storage,
-                    code: Rc::new(code),
+                    code: Arc::new(code),
                 }
             },
             SendableAccountChange::IncreaseBalance(address, balance) =>
@@ -109,7 +109,7 @@ impl Into<AccountChange> for SendableAccountChange {
             SendableAccountChange::Create { nonce, address, balance, storage, code } => {
                 AccountChange::Create {
                     nonce, address, balance, storage,
-                    code: Rc::new(code),
+                    code: Arc::new(code),
                 }
             },
             SendableAccountChange::Nonexist(address) => AccountChange::Nonexist(address),
@@ -125,14 +125,15 @@ pub struct SendableValidTransaction {
     pub value: U256,
     pub input: Vec<u8>,
     pub nonce: U256,
+    pub access_list: Vec<AccessListItem>,
 }
 
 impl From<ValidTransaction> for SendableValidTransaction {
     fn from(transaction: ValidTransaction) -> SendableValidTransaction {
         match transaction {
-            ValidTransaction { caller, gas_price, gas_limit, action, value, input, nonce } => {
+            ValidTransaction { caller, gas_price, gas_limit, action, value, input, nonce, access_list } => {
                 SendableValidTransaction {
-                    caller, gas_price, gas_limit, action, value, nonce,
+                    caller, gas_price, gas_limit, action, value, nonce, access_list,
                     input: input.deref().clone(),
                 }
             }
@@ -143,10 +144,10 @@ impl From<ValidTransaction> for SendableValidTransaction {
 impl Into<ValidTransaction> for SendableValidTransaction {
     fn into(self) -> ValidTransaction {
         match self {
-            SendableValidTransaction { caller, gas_price, gas_limit, action, value, input, nonce } => {
+            SendableValidTransaction { caller, gas_price, gas_limit, action, value, input, nonce, access_list } => {
                 ValidTransaction {
-                    caller, gas_price, gas_limit, action, value, nonce,
-                    input: Rc::new(input),
+                    caller, gas_price, gas_limit, action, value, nonce, access_list,
+                    input: Arc::new(input),
                 }
             }
         }
@@ -171,6 +172,8 @@ pub fn parallel_execute(
         number: U256::zero(),
         difficulty: U256::zero(),
         gas_limit: Gas::max_value(),
+        base_fee: M256::zero(),
+        prev_randao: H256::default(),
     };
 
     let stateful = Arc::new(stateful);
@@ -267,8 +270,9 @@ fn main() {
             gas_price: Gas::zero(),
             gas_limit: Gas::max_value(),
             value: U256::from_str("0x1000").unwrap(),
-            input: Rc::new(Vec::new()),
+            input: Arc::new(Vec::new()),
             nonce: U256::zero(),
+            access_list: Vec::new(),
         },
         ValidTransaction {
             caller: Some(addr2),
@@ -276,8 +280,9 @@ fn main() {
             gas_price: Gas::zero(),
             gas_limit: Gas::max_value(),
             value: U256::from_str("0x1000").unwrap(),
-            input: Rc::new(Vec::new()),
+            input: Arc::new(Vec::new()),
             nonce: U256::zero(),
+            access_list: Vec::new(),
         },
         ValidTransaction {
             caller: Some(addr3),
@@ -285,8 +290,9 @@ fn main() {
             gas_price: Gas::zero(),
             gas_limit: Gas::max_value(),
             value: U256::from_str("0x1000").unwrap(),
-            input: Rc::new(Vec::new()),
+            input: Arc::new(Vec::new()),
             nonce: U256::zero(),
+            access_list: Vec::new(),
         },
     ]);
 
@@ -19,7 +19,7 @@ use trie::{FixedSecureTrie, DatabaseGuard, MemoryDatabase, Database, DatabaseOwn
 use block::{Account, Transaction};
 use std::collections::HashMap;
 use std::cmp::min;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::ops::Deref;
 
 pub struct LiteralAccount {
@@ -103,7 +103,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
                                 nonce: account.nonce,
                                 address: address,
                                 balance: account.balance,
-                                code: Rc::new(code),
+                                code: Arc::new(code),
                             }).unwrap();
                         },
                         None => {
@@ -124,7 +124,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
 
                             vm.commit_account(AccountCommitment::Code {
                                 address: address,
-                                code: Rc::new(code),
+                                code: Arc::new(code),
                             }).unwrap();
                         },
                         None => {
@@ -179,7 +179,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
                             nonce: account.nonce,
                             address: address,
                             balance: account.balance,
-                            code: Rc::new(code),
+                            code: Arc::new(code),
                         }
                     },
                     None => {
@@ -200,7 +200,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
 
                         AccountCommitment::Code {
                             address: address,
-                            code: Rc::new(code),
+                            code: Arc::new(code),
                         }
                     },
                     None => {
@@ -259,7 +259,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
                                 nonce: account.nonce,
                                 address: address,
                                 balance: account.balance,
-                                code: Rc::new(code),
+                                code: Arc::new(code),
                             }).unwrap();
                         },
                         None => {
@@ -280,7 +280,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
 
                             vm.commit_account(AccountCommitment::Code {
                                 address: address,
-                                code: Rc::new(code),
+                                code: Arc::new(code),
                             }).unwrap();
                         },
                         None => {
@@ -469,7 +469,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
                                 nonce: account.nonce,
                                 address: address,
                                 balance: account.balance,
-                                code: Rc::new(code),
+                                code: Arc::new(code),
                             }).unwrap();
                         },
                         None => {
@@ -490,7 +490,7 @@ impl<'b, D: DatabaseOwned> Stateful<'b, D> {
 
                             account_state.commit(AccountCommitment::Code {
                                 address: address,
-                                code: Rc::new(code),
+                                code: Arc::new(code),
                             }).unwrap();
                         },
                         None => {
@@ -14,7 +14,7 @@ extern crate sha3;
 extern crate bigint;
 
 use sha3::{Digest, Keccak256};
-use bigint::{H256, U256, Address, Gas};
+use bigint::{H256, U256, M256, Address, Gas};
 use sputnikvm::{ValidTransaction, VM, SeqTransactionVM, HeaderParams, VMStatus};
 use sputnikvm_network_classic::MainnetEIP160Patch;
 use sputnikvm_stateful::{MemoryStateful, LiteralAccount};
@@ -22,7 +22,7 @@ use block::TransactionAction;
 use trie::{Database, MemoryDatabase};
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::rc::Rc;
+use std::sync::Arc;
 use rand::Rng;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -88,7 +88,7 @@ fn genesis_state_root() {
 
     let mut accounts: Vec<(&String, &JSONAccount)> = GENESIS_ACCOUNTS.iter().collect();
     rng.shuffle(&mut accounts);
-    let empty_input = Rc::new(Vec::new());
+    let empty_input = Arc::new(Vec::new());
 
     for (key, value) in accounts {
         let address = Address::from_str(key).unwrap();
@@ -102,12 +102,15 @@ fn genesis_state_root() {
             value: balance,
             input: empty_input.clone(),
             nonce: U256::zero(),
+            access_list: Vec::new(),
         }, HeaderParams {
             beneficiary: Address::default(),
             timestamp: 0,
             number: U256::zero(),
             difficulty: U256::zero(),
-            gas_limit: Gas::max_value()
+            gas_limit: Gas::max_value(),
+            base_fee: M256::zero(),
+            prev_randao: H256::default()
         }, &[]);
         match vm.status() {
             VMStatus::ExitedOk => (),